@@ -0,0 +1,159 @@
+//! Imports historical cleanup data from a CSV or GeoJSON file through the Delitter API
+//! Usage: cargo run --bin import_litter -- [--dry-run] <username> <password> <file>
+//!        cargo run --bin import_litter -- [--dry-run] --token <api_token> <file>
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct AuthResponse {
+    jwt: String,
+}
+
+#[derive(Deserialize)]
+struct RowError {
+    row: usize,
+    field: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct ImportReport {
+    rows: usize,
+    reports: usize,
+    items: usize,
+    duplicates: usize,
+    created: usize,
+    errors: Vec<RowError>,
+}
+
+async fn login(
+    client: &reqwest::Client,
+    base_url: &str,
+    username: &str,
+    password: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let url = format!("{}/public/auth/signin", base_url);
+    let payload = LoginRequest {
+        username: username.to_string(),
+        password: password.to_string(),
+    };
+
+    let response = client.post(&url).json(&payload).send().await?;
+
+    if response.status() == reqwest::StatusCode::ACCEPTED {
+        return Err("Account uses two-factor authentication, use --token instead".into());
+    }
+    if !response.status().is_success() {
+        return Err(format!("Authentication failed: {}", response.status()).into());
+    }
+
+    let auth_response: AuthResponse = response.json().await?;
+    Ok(auth_response.jwt)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Load .env file if it exists
+    dotenvy::dotenv().ok();
+
+    let mut args: Vec<String> = env::args().collect();
+    let dry_run = args.get(1).is_some_and(|a| a == "--dry-run");
+    if dry_run {
+        args.remove(1);
+    }
+
+    if args.len() != 4 {
+        eprintln!("Delitter Litter Import\n");
+        eprintln!(
+            "Usage: {} [--dry-run] <username> <password> <file>",
+            args[0]
+        );
+        eprintln!("       {} [--dry-run] --token <api_token> <file>", args[0]);
+        eprintln!("\nFiles ending in .csv are sent as CSV, .geojson and .json as GeoJSON.");
+        eprintln!("Importing a file again skips the reports it created before.");
+        eprintln!("\nEnvironment variables (.env file is loaded if present):");
+        eprintln!("  BACKEND_URL - Backend API base URL (default: http://localhost:8080/v1)");
+        std::process::exit(1);
+    }
+
+    let path = Path::new(&args[3]);
+    let format = match path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase)
+        .as_deref()
+    {
+        Some("csv") => "csv",
+        Some("geojson" | "json") => "geojson",
+        _ => return Err("File must end in .csv, .geojson or .json".into()),
+    };
+    let data = fs::read(path)?;
+
+    let base_url =
+        env::var("BACKEND_URL").unwrap_or_else(|_| "http://localhost:8080/v1".to_string());
+    let client = reqwest::Client::new();
+
+    let token = if args[1] == "--token" {
+        args[2].clone()
+    } else {
+        login(&client, &base_url, &args[1], &args[2]).await?
+    };
+
+    println!(
+        "📤 {} {}...",
+        if dry_run { "Validating" } else { "Importing" },
+        path.display()
+    );
+
+    let url = format!("{}/protected/litter/import", base_url);
+    let response = client
+        .post(&url)
+        .query(&[("format", format), ("dry_run", &dry_run.to_string())])
+        .header("Authorization", format!("Bearer {}", token))
+        .body(data)
+        .send()
+        .await?;
+
+    let status = response.status();
+    if status != reqwest::StatusCode::OK && status != reqwest::StatusCode::UNPROCESSABLE_ENTITY {
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("Import failed: {} {}", status, body).into());
+    }
+    let Ok(report) = response.json::<ImportReport>().await else {
+        return Err(format!("Import failed: {}", status).into());
+    };
+
+    println!(
+        "  Rows: {} | Reports: {} | Items: {} | Already imported: {}",
+        report.rows, report.reports, report.items, report.duplicates
+    );
+
+    if !report.errors.is_empty() {
+        println!("\n❌ {} errors, nothing was imported:", report.errors.len());
+        for error in &report.errors {
+            println!("  Row {}: {} {}", error.row, error.field, error.message);
+        }
+        std::process::exit(1);
+    }
+
+    if dry_run {
+        println!(
+            "\n✅ File is valid, {} reports would be created",
+            report.reports - report.duplicates
+        );
+    } else {
+        println!("\n✅ Created {} reports", report.created);
+    }
+
+    Ok(())
+}
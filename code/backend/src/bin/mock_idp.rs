@@ -0,0 +1,241 @@
+//! Minimal OpenID Connect provider for local development and tests.
+//! Every authorization request is approved right away for the configured user.
+//! Usage: cargo run --bin mock_idp, the OIDC tests of the backend start it in-process.
+//!
+//! Environment: MOCK_IDP_PORT (9090), MOCK_IDP_CLIENT_ID (delitter),
+//! MOCK_IDP_CLIENT_SECRET (mock-secret), MOCK_IDP_SUBJECT (scout),
+//! MOCK_IDP_EMAIL (scout@example.org)
+
+use std::{
+    collections::HashMap,
+    env,
+    net::TcpListener,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{App, HttpResponse, HttpServer, Responder, dev::Server, get, post, web};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{EncodingKey, Header, encode};
+use password_hash::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+pub struct Config {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub subject: String,
+    pub email: String,
+}
+
+impl Config {
+    /// Settings from the environment, with the defaults above.
+    pub fn from_env(issuer: String) -> Self {
+        let var =
+            |name: &str, default: &str| env::var(name).unwrap_or_else(|_| default.to_string());
+        Self {
+            issuer,
+            client_id: var("MOCK_IDP_CLIENT_ID", "delitter"),
+            client_secret: var("MOCK_IDP_CLIENT_SECRET", "mock-secret"),
+            subject: var("MOCK_IDP_SUBJECT", "scout"),
+            email: var("MOCK_IDP_EMAIL", "scout@example.org"),
+        }
+    }
+}
+
+struct IssuedCode {
+    redirect_uri: String,
+    code_challenge: String,
+    nonce: Option<String>,
+}
+
+pub struct State {
+    config: Config,
+    codes: Mutex<HashMap<String, IssuedCode>>,
+}
+
+impl State {
+    pub fn new(config: Config) -> web::Data<Self> {
+        web::Data::new(Self {
+            config,
+            codes: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    nonce: Option<String>,
+    code_challenge: String,
+    code_challenge_method: String,
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    grant_type: String,
+    code: String,
+    redirect_uri: String,
+    client_id: String,
+    client_secret: Option<String>,
+    code_verifier: String,
+}
+
+#[derive(Serialize)]
+struct IdTokenClaims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    aud: &'a str,
+    exp: u64,
+    iat: u64,
+    nonce: Option<String>,
+    email: &'a str,
+    email_verified: bool,
+    preferred_username: &'a str,
+}
+
+fn oauth_error(error: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({ "error": error }))
+}
+
+#[get("/.well-known/openid-configuration")]
+async fn discovery(state: web::Data<State>) -> impl Responder {
+    let issuer = &state.config.issuer;
+    web::Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{issuer}/authorize"),
+        "token_endpoint": format!("{issuer}/token"),
+        "jwks_uri": format!("{issuer}/jwks"),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["HS256"],
+        "code_challenge_methods_supported": ["S256"],
+    }))
+}
+
+#[get("/authorize")]
+async fn authorize(query: web::Query<AuthorizeQuery>, state: web::Data<State>) -> HttpResponse {
+    if query.client_id != state.config.client_id {
+        return oauth_error("unauthorized_client");
+    }
+    if query.code_challenge_method != "S256" {
+        return oauth_error("invalid_request");
+    }
+
+    let mut bytes = [0u8; 24];
+    OsRng.fill_bytes(&mut bytes);
+    let code = URL_SAFE_NO_PAD.encode(bytes);
+
+    state.codes.lock().unwrap().insert(
+        code.clone(),
+        IssuedCode {
+            redirect_uri: query.redirect_uri.clone(),
+            code_challenge: query.code_challenge.clone(),
+            nonce: query.nonce.clone(),
+        },
+    );
+
+    let Ok(location) = reqwest::Url::parse_with_params(
+        &query.redirect_uri,
+        &[("code", code.as_str()), ("state", query.state.as_str())],
+    ) else {
+        return oauth_error("invalid_request");
+    };
+
+    HttpResponse::Found()
+        .append_header(("Location", location.to_string()))
+        .finish()
+}
+
+#[post("/token")]
+async fn token(form: web::Form<TokenForm>, state: web::Data<State>) -> HttpResponse {
+    let config = &state.config;
+
+    if form.grant_type != "authorization_code" {
+        return oauth_error("unsupported_grant_type");
+    }
+    if form.client_id != config.client_id
+        || form.client_secret.as_deref() != Some(config.client_secret.as_str())
+    {
+        return HttpResponse::Unauthorized().json(json!({ "error": "invalid_client" }));
+    }
+
+    let Some(issued) = state.codes.lock().unwrap().remove(&form.code) else {
+        return oauth_error("invalid_grant");
+    };
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(form.code_verifier.as_bytes()));
+    if issued.redirect_uri != form.redirect_uri || issued.code_challenge != challenge {
+        return oauth_error("invalid_grant");
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    let claims = IdTokenClaims {
+        iss: &config.issuer,
+        sub: &config.subject,
+        aud: &config.client_id,
+        exp: now + 300,
+        iat: now,
+        nonce: issued.nonce,
+        email: &config.email,
+        email_verified: true,
+        preferred_username: &config.subject,
+    };
+
+    match encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(config.client_secret.as_bytes()),
+    ) {
+        Ok(id_token) => HttpResponse::Ok().json(json!({
+            "access_token": URL_SAFE_NO_PAD.encode(Sha256::digest(id_token.as_bytes())),
+            "token_type": "Bearer",
+            "expires_in": 300,
+            "id_token": id_token,
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "error": e.to_string() })),
+    }
+}
+
+#[get("/jwks")]
+async fn jwks() -> impl Responder {
+    // Id tokens are signed with the client secret, there are no public keys.
+    web::Json(json!({ "keys": [] }))
+}
+
+/// Registers the provider endpoints.
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(discovery)
+        .service(authorize)
+        .service(token)
+        .service(jwks);
+}
+
+/// Serves the provider on `listener` until the returned server is stopped.
+pub fn serve(listener: TcpListener, state: web::Data<State>) -> std::io::Result<Server> {
+    Ok(
+        HttpServer::new(move || App::new().app_data(state.clone()).configure(routes))
+            .workers(1)
+            .listen(listener)?
+            .run(),
+    )
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let port: u16 = env::var("MOCK_IDP_PORT")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(9090);
+
+    let state = State::new(Config::from_env(format!("http://localhost:{port}")));
+    println!("🪪 Mock identity provider on {}", state.config.issuer);
+
+    serve(TcpListener::bind(("127.0.0.1", port))?, state)?.await
+}
@@ -1,5 +1,7 @@
-/// Test client for Delitter API
-/// Usage: cargo run --bin test_api -- <username> <password> [file_or_directory]
+//! Test client for Delitter API
+//! Usage: cargo run --bin test_api -- <username> <password> [file_or_directory]
+//!        cargo run --bin test_api -- --oidc [file_or_directory]
+//!        cargo run --bin test_api -- --token <api_token> [file_or_directory]
 
 use std::env;
 use std::fs;
@@ -31,15 +33,17 @@ struct LitterCreateResponse {
     id: String,
 }
 
+#[derive(Deserialize, Debug)]
+struct LitterEntryGetData {
+    category: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 struct LitterGetData {
     id: String,
     lat: f64,
     lng: f64,
-    category: String,
-    material: String,
-    weight: f64,
-    brand: String,
+    entries: Vec<LitterEntryGetData>,
     date: String,
 }
 
@@ -69,6 +73,9 @@ impl ApiClient {
 
         let response = self.client.post(&url).json(&payload).send().await?;
 
+        if response.status() == reqwest::StatusCode::ACCEPTED {
+            return Err("Account uses two-factor authentication, use --token instead".into());
+        }
         if !response.status().is_success() {
             return Err(format!("Authentication failed: {}", response.status()).into());
         }
@@ -80,6 +87,71 @@ impl ApiClient {
         Ok(())
     }
 
+    /// Runs the OpenID Connect login by following the redirects by hand,
+    /// e.g. against `cargo run --bin mock_idp`. Also checks that the callback
+    /// rejects a foreign state and a replayed one.
+    async fn login_oidc(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        println!("🔐 Authenticating via OpenID Connect...");
+
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+
+        // Backend -> identity provider, keeping the cookie binding the state to this client
+        let response = client
+            .get(format!("{}/public/auth/oidc/login", self.base_url))
+            .send()
+            .await?;
+        let cookie = response
+            .headers()
+            .get("Set-Cookie")
+            .and_then(|c| c.to_str().ok())
+            .and_then(|c| c.split(';').next())
+            .ok_or("Login without state cookie")?
+            .to_string();
+        let authorize = redirect_location(response)?;
+
+        // Identity provider -> backend callback
+        let callback = redirect_location(client.get(&authorize).send().await?)?;
+
+        let follow = |url: String| {
+            let request = client.get(url).header("Cookie", cookie.as_str());
+            async move { redirect_location(request.send().await?) }
+        };
+
+        let mut foreign = reqwest::Url::parse(&callback)?;
+        let pairs: Vec<(String, String)> = foreign
+            .query_pairs()
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect();
+        foreign.query_pairs_mut().clear().extend_pairs(
+            pairs
+                .iter()
+                .map(|(k, v)| (k.as_str(), if k == "state" { "foreign" } else { v.as_str() })),
+        );
+        let url = follow(foreign.to_string()).await?;
+        if !url.ends_with("?error=invalid_state") {
+            return Err(format!("Callback accepted a foreign state: {}", url).into());
+        }
+
+        // Backend callback -> frontend
+        let url = follow(callback.clone()).await?;
+        let jwt = url
+            .split_once("#jwt=")
+            .map(|(_, jwt)| jwt.to_string())
+            .ok_or_else(|| format!("OIDC login failed: {}", url))?;
+
+        let url = follow(callback).await?;
+        if !url.ends_with("?error=invalid_state") {
+            return Err(format!("Callback accepted a replayed state: {}", url).into());
+        }
+
+        self.token = Some(jwt);
+
+        println!("✅ Authentication successful!\n");
+        Ok(())
+    }
+
     async fn upload_litter(
         &self,
         file_path: &str,
@@ -192,6 +264,18 @@ impl ApiClient {
     }
 }
 
+fn redirect_location(response: reqwest::Response) -> Result<String, Box<dyn std::error::Error>> {
+    if !response.status().is_redirection() {
+        return Err(format!("OIDC login failed: {}", response.status()).into());
+    }
+    Ok(response
+        .headers()
+        .get("Location")
+        .and_then(|l| l.to_str().ok())
+        .ok_or("Redirect without location")?
+        .to_string())
+}
+
 fn collect_image_files(dir_path: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     let mut image_files = Vec::new();
     let extensions = ["jpg", "jpeg", "png", "gif", "bmp"];
@@ -200,14 +284,12 @@ fn collect_image_files(dir_path: &str) -> Result<Vec<String>, Box<dyn std::error
         let entry = entry?;
         let path = entry.path();
 
-        if path.is_file() {
-            if let Some(ext) = path.extension().and_then(|s| s.to_str()) {
-                if extensions.contains(&ext.to_lowercase().as_str()) {
-                    if let Some(path_str) = path.to_str() {
-                        image_files.push(path_str.to_string());
-                    }
-                }
-            }
+        if path.is_file()
+            && let Some(ext) = path.extension().and_then(|s| s.to_str())
+            && extensions.contains(&ext.to_lowercase().as_str())
+            && let Some(path_str) = path.to_str()
+        {
+            image_files.push(path_str.to_string());
         }
     }
 
@@ -221,9 +303,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let args: Vec<String> = env::args().collect();
 
-    if args.len() < 3 {
+    let oidc = args.get(1).is_some_and(|a| a == "--oidc");
+
+    if args.len() < 3 && !oidc {
         eprintln!("Delitter API Test Client\n");
         eprintln!("Usage: {} <username> <password> [file_or_directory]", args[0]);
+        eprintln!("       {} --oidc [file_or_directory]", args[0]);
+        eprintln!("       {} --token <api_token> [file_or_directory]", args[0]);
         eprintln!("\nExamples:");
         eprintln!(
             "  {} myuser mypass test.jpg          # Upload single file",
@@ -243,9 +329,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::process::exit(1);
     }
 
-    let username = &args[1];
-    let password = &args[2];
-    let target = args.get(3).map(|s| s.as_str());
+    let target = args.get(if oidc { 2 } else { 3 }).map(|s| s.as_str());
 
     let base_url =
         env::var("BACKEND_URL").unwrap_or_else(|_| "http://localhost:8080/v1".to_string());
@@ -253,7 +337,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut client = ApiClient::new(base_url);
 
     // Login
-    client.login(username, password).await?;
+    if oidc {
+        client.login_oidc().await?;
+    } else if args[1] == "--token" {
+        // Personal API tokens are sent as they are, no signin needed.
+        client.token = Some(args[2].clone());
+    } else {
+        client.login(&args[1], &args[2]).await?;
+    }
 
     // Upload files if provided
     let mut upload_count = 0;
@@ -304,13 +395,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             } else {
                 item.id.clone()
             };
+            let category = item
+                .entries
+                .first()
+                .and_then(|e| e.category.as_deref())
+                .unwrap_or("-");
             println!(
                 "  {}. ID: {:15} | Location: ({:7.4}, {:7.4}) | Category: {:10} | Date: {}",
                 i + 1,
                 id,
                 item.lat,
                 item.lng,
-                item.category,
+                category,
                 &item.date[..19.min(item.date.len())]
             );
         }
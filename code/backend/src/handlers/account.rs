@@ -0,0 +1,166 @@
+use actix_web::{
+    HttpRequest, Responder, post, put,
+    web::{self},
+};
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::{
+    handlers::{HttpError, ValidationErrorResponse},
+    middleware::AuthRateLimit,
+    services::{
+        self,
+        account::{AccountError, PasswordResetLimiter},
+        auth::UserSession,
+        mailer::Mailer,
+    },
+};
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct EmailData {
+    email: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct VerifyEmailData {
+    token: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PasswordResetRequestData {
+    email: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PasswordResetConfirmData {
+    token: String,
+    password: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MessageResponse {
+    message: String,
+}
+
+impl From<AccountError> for HttpError {
+    fn from(err: AccountError) -> Self {
+        log::info!("Account operation failed with {:?}", err);
+
+        match err {
+            AccountError::InvalidInput(errors) => HttpError::ValidationFailed(errors),
+            AccountError::InvalidEmail => HttpError::InvalidEmail,
+            AccountError::EmailAlreadyInUse => HttpError::EmailAlreadyInUse,
+            AccountError::InvalidToken => HttpError::InvalidToken,
+            AccountError::NetworkError => HttpError::NetworkError,
+            AccountError::UnknownError => HttpError::NetworkError,
+        }
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/v1/protected/account/email",
+    request_body = EmailData,
+    responses(
+        (status = 200, description = "Email stored, verification mail sent", body = MessageResponse),
+        (status = 400, description = "Invalid email address"),
+        (status = 401, description = "Invalid credentials"),
+        (status = 409, description = "Email address already in use"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Account",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[put("/v1/protected/account/email")]
+pub async fn set_email(
+    data: web::Json<EmailData>,
+    db: web::Data<Database>,
+    mailer: web::Data<dyn Mailer>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    services::account::set_email(db, mailer, usersession.id, &data.email).await?;
+
+    Ok(web::Json(json!({
+        "message": "Verification mail sent",
+    })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/public/auth/verify-email",
+    request_body = VerifyEmailData,
+    responses(
+        (status = 200, description = "Email address verified", body = MessageResponse),
+        (status = 403, description = "Invalid or expired token"),
+        (status = 409, description = "Email address already in use"),
+        (status = 429, description = "Too many requests, see Retry-After header")
+    ),
+    tag = "Account"
+)]
+#[post("/v1/public/auth/verify-email", wrap = "AuthRateLimit")]
+pub async fn verify_email(
+    data: web::Json<VerifyEmailData>,
+    db: web::Data<Database>,
+) -> Result<impl Responder, HttpError> {
+    services::account::verify_email(db, &data.token).await?;
+
+    Ok(web::Json(json!({
+        "message": "Email address verified",
+    })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/public/auth/password-reset",
+    request_body = PasswordResetRequestData,
+    responses(
+        (status = 200, description = "Request accepted. The response is the same whether or not the account exists", body = MessageResponse),
+        (status = 429, description = "Too many requests, see Retry-After header")
+    ),
+    tag = "Account"
+)]
+#[post("/v1/public/auth/password-reset", wrap = "AuthRateLimit")]
+pub async fn request_password_reset(
+    req: HttpRequest,
+    data: web::Json<PasswordResetRequestData>,
+    db: web::Data<Database>,
+    mailer: web::Data<dyn Mailer>,
+    limiter: web::Data<PasswordResetLimiter>,
+) -> impl Responder {
+    let client_ip = services::rate_limit::client_ip(req.peer_addr(), req.headers());
+
+    services::account::request_password_reset(db, mailer, &limiter, &client_ip, &data.email).await;
+
+    web::Json(json!({
+        "message": "If an account with a verified address exists, a reset link has been sent",
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/public/auth/password-reset/confirm",
+    request_body = PasswordResetConfirmData,
+    responses(
+        (status = 200, description = "Password changed", body = MessageResponse),
+        (status = 403, description = "Invalid, expired or already used token"),
+        (status = 422, description = "Password does not meet the policy", body = ValidationErrorResponse),
+        (status = 429, description = "Too many requests, see Retry-After header"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Account"
+)]
+#[post("/v1/public/auth/password-reset/confirm", wrap = "AuthRateLimit")]
+pub async fn confirm_password_reset(
+    data: web::Json<PasswordResetConfirmData>,
+    db: web::Data<Database>,
+) -> Result<impl Responder, HttpError> {
+    services::account::reset_password(db, &data.token, &data.password).await?;
+
+    Ok(web::Json(json!({
+        "message": "Password changed",
+    })))
+}
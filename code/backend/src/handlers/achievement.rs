@@ -0,0 +1,87 @@
+use actix_web::{
+    get, post,
+    web::{self, Json},
+};
+use mongodb::Database;
+
+use crate::{
+    handlers::HttpError,
+    services::{
+        self,
+        achievement::{Achievement, AchievementBackfill, AchievementError, AchievementStatus},
+        auth::{RequireRole, UserSession, roles},
+    },
+};
+
+impl From<AchievementError> for HttpError {
+    fn from(err: AchievementError) -> Self {
+        log::info!("Achievement operation failed with {:?}", err);
+
+        match err {
+            AchievementError::NotFound => HttpError::InvalidCredentials,
+            AchievementError::NetworkError => HttpError::NetworkError,
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/public/achievements",
+    responses(
+        (status = 200, description = "Badges that can be earned", body = Vec<Achievement>)
+    ),
+    tag = "Achievements"
+)]
+#[get("/v1/public/achievements")]
+pub async fn list_achievements() -> Json<&'static [Achievement]> {
+    web::Json(services::achievement::rules())
+}
+
+/// All badges with the user's progress, earned ones first.
+#[utoipa::path(
+    get,
+    path = "/v1/protected/achievements",
+    responses(
+        (status = 200, description = "Badges and progress of the user", body = Vec<AchievementStatus>),
+        (status = 401, description = "Invalid credentials"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Achievements",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/achievements")]
+pub async fn get_achievements(
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<Vec<AchievementStatus>>, HttpError> {
+    let status = services::achievement::status(&db, usersession.id).await?;
+
+    Ok(web::Json(status))
+}
+
+/// Evaluates the rules for all users against their existing reports, e.g. after adding
+/// rules. Badges are dated to the report that reached the threshold.
+#[utoipa::path(
+    post,
+    path = "/v1/admin/achievements/backfill",
+    responses(
+        (status = 200, description = "Users evaluated and badges awarded", body = AchievementBackfill),
+        (status = 403, description = "Admin role required"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Admin",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/admin/achievements/backfill")]
+pub async fn backfill(
+    db: web::Data<Database>,
+    _admin: RequireRole<roles::Admin>,
+) -> Result<Json<AchievementBackfill>, HttpError> {
+    let result = services::achievement::backfill(&db).await?;
+
+    Ok(web::Json(result))
+}
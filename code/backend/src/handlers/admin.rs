@@ -0,0 +1,162 @@
+use actix_web::{
+    Responder, get, put,
+    web::{self, Json},
+};
+use mongodb::{Database, bson::oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    handlers::HttpError,
+    models::user::{Role, User},
+    services::{
+        self,
+        admin::AdminError,
+        auth::{RequireRole, roles},
+    },
+};
+
+impl From<AdminError> for HttpError {
+    fn from(err: AdminError) -> Self {
+        log::info!("Admin operation failed with {:?}", err);
+
+        match err {
+            AdminError::NotFound => HttpError::NotFound,
+            AdminError::Forbidden => HttpError::Forbidden,
+            AdminError::NetworkError => HttpError::NetworkError,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct UserListQuery {
+    /// Number of users to skip
+    skip: Option<u64>,
+    /// Page size, at most 200 (default 50)
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AdminUserData {
+    id: String,
+    username: String,
+    email: Option<String>,
+    email_verified: bool,
+    role: Role,
+    disabled: bool,
+}
+
+impl From<User> for AdminUserData {
+    fn from(user: User) -> Self {
+        AdminUserData {
+            id: user._id.map(|id| id.to_hex()).unwrap_or_default(),
+            username: user.username,
+            email: user.email,
+            email_verified: user.email_verified,
+            role: user.role,
+            disabled: user.disabled,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RoleData {
+    role: Role,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DisabledData {
+    disabled: bool,
+}
+
+fn parse_id(id: &str) -> Result<ObjectId, HttpError> {
+    ObjectId::parse_str(id).map_err(|_| HttpError::NotFound)
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/admin/users",
+    params(UserListQuery),
+    responses(
+        (status = 200, description = "List of users", body = Vec<AdminUserData>),
+        (status = 403, description = "Moderator role required")
+    ),
+    tag = "Admin",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/admin/users")]
+pub async fn list_users(
+    query: web::Query<UserListQuery>,
+    db: web::Data<Database>,
+    _moderator: RequireRole<roles::Moderator>,
+) -> Result<Json<Vec<AdminUserData>>, HttpError> {
+    let users = services::admin::list_users(
+        db,
+        query.skip.unwrap_or(0),
+        query.limit.unwrap_or(50).clamp(1, 200),
+    )
+    .await?;
+
+    Ok(web::Json(users.into_iter().map(|u| u.into()).collect()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/v1/admin/users/{id}/role",
+    request_body = RoleData,
+    params(("id" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "Role changed"),
+        (status = 403, description = "Admin role required, or own account"),
+        (status = 404, description = "User not found")
+    ),
+    tag = "Admin",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[put("/v1/admin/users/{id}/role")]
+pub async fn set_role(
+    id: web::Path<String>,
+    data: web::Json<RoleData>,
+    db: web::Data<Database>,
+    admin: RequireRole<roles::Admin>,
+) -> Result<impl Responder, HttpError> {
+    let id = parse_id(&id)?;
+    services::admin::set_role(db, &admin.session, id, data.role).await?;
+
+    Ok(web::Json(json!({ "id": id.to_hex(), "role": data.role })))
+}
+
+#[utoipa::path(
+    put,
+    path = "/v1/admin/users/{id}/disabled",
+    request_body = DisabledData,
+    params(("id" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "Account enabled or disabled"),
+        (status = 403, description = "Moderator role required, target is privileged, or own account"),
+        (status = 404, description = "User not found")
+    ),
+    tag = "Admin",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[put("/v1/admin/users/{id}/disabled")]
+pub async fn set_disabled(
+    id: web::Path<String>,
+    data: web::Json<DisabledData>,
+    db: web::Data<Database>,
+    moderator: RequireRole<roles::Moderator>,
+) -> Result<impl Responder, HttpError> {
+    let id = parse_id(&id)?;
+    services::admin::set_disabled(db, &moderator.session, id, data.disabled).await?;
+
+    Ok(web::Json(
+        json!({ "id": id.to_hex(), "disabled": data.disabled }),
+    ))
+}
@@ -0,0 +1,145 @@
+use actix_web::{
+    HttpResponse, Responder, delete, get, post,
+    web::{self, Json},
+};
+use mongodb::{Database, bson::oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::{
+    handlers::{HttpError, ValidationErrorResponse},
+    models::api_token::{ApiToken, TokenScope},
+    services::{self, api_token::ApiTokenError, auth::UserSession},
+};
+
+impl From<ApiTokenError> for HttpError {
+    fn from(err: ApiTokenError) -> Self {
+        log::info!("API token operation failed with {:?}", err);
+
+        match err {
+            ApiTokenError::InvalidInput(errors) => HttpError::ValidationFailed(errors),
+            ApiTokenError::NotFound => HttpError::NotFound,
+            ApiTokenError::NetworkError => HttpError::NetworkError,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ApiTokenCreateData {
+    name: String,
+    scope: TokenScope,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiTokenData {
+    id: String,
+    name: String,
+    scope: TokenScope,
+    /// First characters of the token
+    prefix: String,
+    created_at: String,
+    last_used_at: Option<String>,
+}
+
+impl From<ApiToken> for ApiTokenData {
+    fn from(token: ApiToken) -> Self {
+        ApiTokenData {
+            id: token._id.map(|id| id.to_hex()).unwrap_or_default(),
+            name: token.name,
+            scope: token.scope,
+            prefix: token.prefix,
+            created_at: token.created_at.to_string(),
+            last_used_at: token.last_used_at.map(|t| t.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiTokenCreateResponse {
+    #[serde(flatten)]
+    data: ApiTokenData,
+    /// The token itself, only shown once
+    token: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/protected/account/tokens",
+    request_body = ApiTokenCreateData,
+    responses(
+        (status = 201, description = "Token created, the plain token is only returned here", body = ApiTokenCreateResponse),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "API tokens cannot create tokens"),
+        (status = 422, description = "Invalid name or too many tokens", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Account",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/account/tokens")]
+pub async fn create_token(
+    data: web::Json<ApiTokenCreateData>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let (token, plain) =
+        services::api_token::create(db, usersession.id, &data.name, data.scope).await?;
+
+    Ok(HttpResponse::Created().json(ApiTokenCreateResponse {
+        data: token.into(),
+        token: plain,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/protected/account/tokens",
+    responses(
+        (status = 200, description = "Personal API tokens of the current user", body = Vec<ApiTokenData>),
+        (status = 401, description = "Invalid credentials"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Account",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/account/tokens")]
+pub async fn list_tokens(
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<Vec<ApiTokenData>>, HttpError> {
+    let tokens = services::api_token::list(db, usersession.id).await?;
+
+    Ok(web::Json(tokens.into_iter().map(|t| t.into()).collect()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/protected/account/tokens/{id}",
+    params(("id" = String, Path, description = "Token id")),
+    responses(
+        (status = 200, description = "Token revoked"),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "API tokens cannot revoke tokens"),
+        (status = 404, description = "Token not found")
+    ),
+    tag = "Account",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[delete("/v1/protected/account/tokens/{id}")]
+pub async fn revoke_token(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let id = ObjectId::parse_str(id.as_str()).map_err(|_| HttpError::NotFound)?;
+    services::api_token::revoke(db, usersession.id, id).await?;
+
+    Ok(web::Json(json!({ "id": id.to_hex(), "revoked": true })))
+}
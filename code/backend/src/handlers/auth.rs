@@ -1,5 +1,5 @@
 use actix_web::{
-    Responder, post,
+    HttpResponse, Responder, post,
     web::{self},
 };
 use mongodb::{Database, bson::doc};
@@ -7,8 +7,12 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use utoipa::ToSchema;
 
-use crate::{handlers::HttpError, services};
-use crate::services::auth::SignupError;
+use crate::services::auth::{SigninError, SigninOutcome, SignupError};
+use crate::{
+    handlers::{HttpError, ValidationErrorResponse},
+    middleware::AuthRateLimit,
+    services,
+};
 
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct Claims {
@@ -27,6 +31,25 @@ pub struct AuthResponse {
     jwt: String,
 }
 
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TwoFactorChallengeResponse {
+    two_factor_required: bool,
+    /// Short-lived token for `/v1/public/auth/signin/2fa`
+    challenge: String,
+}
+
+impl From<SigninError> for HttpError {
+    fn from(err: SigninError) -> Self {
+        log::info!("Login failed with {:?}", err);
+
+        match err {
+            SigninError::InvalidCredentials => HttpError::InvalidCredentials,
+            SigninError::AccountDisabled => HttpError::AccountDisabled,
+            SigninError::InvalidChallenge => HttpError::InvalidToken,
+        }
+    }
+}
+
 #[utoipa::path(
     post,
     path = "/v1/public/auth/signup",
@@ -34,11 +57,13 @@ pub struct AuthResponse {
     responses(
         (status = 200, description = "User successfully registered", body = AuthResponse),
         (status = 409, description = "User already exists"),
+        (status = 422, description = "Username or password does not meet the policy", body = ValidationErrorResponse),
+        (status = 429, description = "Too many requests, see Retry-After header"),
         (status = 500, description = "Network error")
     ),
     tag = "Authentication"
 )]
-#[post("/v1/public/auth/signup")]
+#[post("/v1/public/auth/signup", wrap = "AuthRateLimit")]
 pub async fn signup(
     data: web::Json<SignupData>,
     db: web::Data<Database>,
@@ -57,6 +82,7 @@ pub async fn signup(
             log::info!("Signup failed with {:?}", err);
 
             match err {
+                SignupError::InvalidInput(errors) => Err(HttpError::ValidationFailed(errors)),
                 SignupError::UserAlreadyExists => Err(HttpError::UserAlreadyExists),
                 SignupError::NetworkError => Err(HttpError::NetworkError),
                 SignupError::UnknownError => Err(HttpError::NetworkError), // or InternalServerError, but since NetworkError is 500
@@ -77,29 +103,67 @@ pub struct LoginData {
     request_body = LoginData,
     responses(
         (status = 200, description = "Login successful", body = AuthResponse),
-        (status = 401, description = "Invalid credentials")
+        (status = 202, description = "Password correct, second factor required", body = TwoFactorChallengeResponse),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Account disabled"),
+        (status = 429, description = "Too many requests or attempts, see Retry-After header")
     ),
     tag = "Authentication"
 )]
-#[post("/v1/public/auth/signin")]
+#[post("/v1/public/auth/signin", wrap = "AuthRateLimit")]
 pub async fn signin(
     data: web::Json<LoginData>,
     db: web::Data<Database>,
 ) -> Result<impl Responder, HttpError> {
-    let res = services::auth::signin(db, &data.username, &data.password).await;
+    let (id, outcome) = services::auth::signin(db, &data.username, &data.password).await?;
 
-    match res {
-        Some((id, jwt)) => {
+    match outcome {
+        SigninOutcome::Session(jwt) => {
             log::info!("Login successful: {}", id);
 
-            Ok(web::Json(json!({
+            Ok(HttpResponse::Ok().json(json!({
                 "jwt": jwt,
             })))
         }
-        None => {
-            log::info!("Login failed");
+        SigninOutcome::TwoFactorRequired(challenge) => {
+            log::info!("Password accepted, second factor required: {}", id);
 
-            Err(HttpError::InvalidCredentials)
+            Ok(HttpResponse::Accepted().json(TwoFactorChallengeResponse {
+                two_factor_required: true,
+                challenge,
+            }))
         }
     }
 }
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TwoFactorSigninData {
+    challenge: String,
+    /// TOTP code or recovery code
+    code: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/public/auth/signin/2fa",
+    request_body = TwoFactorSigninData,
+    responses(
+        (status = 200, description = "Login successful", body = AuthResponse),
+        (status = 401, description = "Invalid code"),
+        (status = 403, description = "Invalid or expired challenge, or account disabled"),
+        (status = 429, description = "Too many requests or attempts, see Retry-After header")
+    ),
+    tag = "Authentication"
+)]
+#[post("/v1/public/auth/signin/2fa", wrap = "AuthRateLimit")]
+pub async fn signin_two_factor(
+    data: web::Json<TwoFactorSigninData>,
+    db: web::Data<Database>,
+) -> Result<impl Responder, HttpError> {
+    let (id, jwt) = services::auth::signin_two_factor(db, &data.challenge, &data.code).await?;
+    log::info!("Login with second factor successful: {}", id);
+
+    Ok(web::Json(json!({
+        "jwt": jwt,
+    })))
+}
@@ -0,0 +1,123 @@
+use actix_web::{
+    HttpResponse, get,
+    http::header,
+    web::{self},
+};
+use mongodb::{Database, bson::DateTime};
+use serde::Deserialize;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    handlers::{HttpError, ValidationErrorResponse},
+    services::{
+        self,
+        auth::UserSession,
+        brand_report::BrandReport,
+        litter::{LitterFilter, LitterScope, range_filter},
+    },
+};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Csv,
+    Pdf,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct BrandReportQuery {
+    /// `own` reports (default) or the `community` reports of all users
+    scope: Option<LitterScope>,
+    /// Only reports inside `min_lng,min_lat,max_lng,max_lat`
+    bbox: Option<String>,
+    /// Reports at or after this date (YYYY-MM-DD or RFC 3339)
+    from: Option<String>,
+    /// Reports before this date (YYYY-MM-DD or RFC 3339)
+    to: Option<String>,
+    /// Only this brand (case-insensitive)
+    brand: Option<String>,
+    /// Number of brands, 1 to 100 (default 20)
+    limit: Option<i64>,
+    /// `json` (default), `csv` or `pdf`
+    format: Option<ReportFormat>,
+}
+
+impl BrandReportQuery {
+    fn filter(&self) -> Result<LitterFilter, HttpError> {
+        let filter = range_filter(
+            self.from.as_deref(),
+            self.to.as_deref(),
+            self.bbox.as_deref(),
+        )
+        .map_err(HttpError::ValidationFailed)?;
+
+        Ok(LitterFilter {
+            brand: self.brand.clone(),
+            ..filter
+        })
+    }
+}
+
+/// Items, weight, materials, hotspots and example photos per brand. Example photos are
+/// taken from the caller's own reports, moderators also get those of the community.
+#[utoipa::path(
+    get,
+    path = "/v1/protected/reports/brands",
+    params(BrandReportQuery),
+    responses(
+        (status = 200, description = "Brand report as JSON, CSV (`text/csv`) or PDF (`application/pdf`)", body = BrandReport),
+        (status = 401, description = "Invalid credentials"),
+        (status = 422, description = "Invalid date, bounding box or limit", body = ValidationErrorResponse)
+    ),
+    tag = "Litter",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/reports/brands")]
+pub async fn get_brand_report(
+    query: web::Query<BrandReportQuery>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<HttpResponse, HttpError> {
+    let filter = query.filter()?;
+    let scope = query.scope.unwrap_or_default();
+
+    let brands = services::brand_report::brand_report(
+        db,
+        usersession.id,
+        usersession.role,
+        scope,
+        &filter,
+        query.limit,
+    )
+    .await?;
+    let report = BrandReport {
+        generated_at: DateTime::now().try_to_rfc3339_string().unwrap_or_default(),
+        scope,
+        from: query.from.clone(),
+        to: query.to.clone(),
+        bbox: query.bbox.clone(),
+        brands,
+    };
+
+    let attachment = |extension: &str| {
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"brand-report.{extension}\""),
+        )
+    };
+    Ok(match query.format.unwrap_or_default() {
+        ReportFormat::Json => HttpResponse::Ok().json(report),
+        ReportFormat::Csv => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header(attachment("csv"))
+            .body(report.to_csv()?),
+        ReportFormat::Pdf => HttpResponse::Ok()
+            .content_type("application/pdf")
+            .insert_header(attachment("pdf"))
+            .body(report.to_pdf()),
+    })
+}
@@ -0,0 +1,204 @@
+use actix_web::{
+    HttpResponse, Responder, delete, get, post,
+    web::{self, Json},
+};
+use mongodb::{Database, bson::oid::ObjectId};
+use serde::Deserialize;
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    handlers::{HttpError, ValidationErrorResponse},
+    models::challenge::ChallengeMetric,
+    services::{
+        self,
+        auth::UserSession,
+        challenge::{ChallengeData, ChallengeError, NewChallenge},
+        litter::parse_date,
+    },
+};
+
+impl From<ChallengeError> for HttpError {
+    fn from(err: ChallengeError) -> Self {
+        log::info!("Challenge operation failed with {:?}", err);
+
+        match err {
+            ChallengeError::InvalidInput(errors) => HttpError::ValidationFailed(errors),
+            ChallengeError::NotFound => HttpError::NotFound,
+            ChallengeError::Forbidden => HttpError::Forbidden,
+            ChallengeError::NetworkError => HttpError::NetworkError,
+        }
+    }
+}
+
+fn challenge_id(id: &str) -> Result<ObjectId, HttpError> {
+    ObjectId::parse_str(id).map_err(|_| HttpError::NotFound)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ChallengeCreateData {
+    title: String,
+    description: Option<String>,
+    /// Group whose members' reports count, which the user must lead. A personal challenge
+    /// counting only the user's reports if missing.
+    group_id: Option<String>,
+    metric: ChallengeMetric,
+    /// Only items of this category count, e.g. `can`
+    category: Option<String>,
+    /// Only items of this material count
+    material: Option<String>,
+    /// Items, grams or reports to reach
+    target: f64,
+    /// Start of the counted time (RFC 3339), now if missing
+    starts_at: Option<String>,
+    /// End of the counted time (RFC 3339), at most a year after the start
+    deadline: String,
+}
+
+impl ChallengeCreateData {
+    fn challenge(&self) -> Result<NewChallenge, HttpError> {
+        let group_id = self.group_id.as_deref().map(challenge_id).transpose()?;
+        let starts_at = self
+            .starts_at
+            .as_deref()
+            .map(|s| parse_date("starts_at", s));
+        let deadline = parse_date("deadline", &self.deadline);
+        let (starts_at, deadline) = match (starts_at.transpose(), deadline) {
+            (Ok(start), Ok(end)) => (start, end),
+            (start, end) => {
+                return Err(HttpError::ValidationFailed(
+                    [start.err(), end.err()].into_iter().flatten().collect(),
+                ));
+            }
+        };
+
+        Ok(NewChallenge {
+            title: self.title.clone(),
+            description: self.description.clone(),
+            group_id,
+            metric: self.metric,
+            category: self.category.clone(),
+            material: self.material.clone(),
+            target: self.target,
+            starts_at,
+            deadline,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ChallengeListQuery {
+    /// Challenges whose deadline passed, latest first, instead of open ones
+    #[serde(default)]
+    past: bool,
+}
+
+/// Sets a goal such as "collect 50 kg before the end of the summer camp" for a group the
+/// user leads, or a personal one. Progress counts the reports between the start and the
+/// deadline; members are mailed once the target is reached.
+#[utoipa::path(
+    post,
+    path = "/v1/protected/challenges",
+    request_body = ChallengeCreateData,
+    responses(
+        (status = 201, description = "Challenge created", body = ChallengeData),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Not a leader of the group, or an API token"),
+        (status = 404, description = "No group of the user with this id"),
+        (status = 422, description = "Invalid title, target or deadline, or too many open challenges", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Challenges",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/challenges")]
+pub async fn create_challenge(
+    data: web::Json<ChallengeCreateData>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let challenge = services::challenge::create(&db, usersession.id, data.challenge()?).await?;
+
+    Ok(HttpResponse::Created().json(challenge))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/protected/challenges",
+    params(ChallengeListQuery),
+    responses(
+        (status = 200, description = "Up to 100 challenges of the user and the user's groups with live progress, open ones closest deadline first", body = Vec<ChallengeData>),
+        (status = 401, description = "Invalid credentials"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Challenges",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/challenges")]
+pub async fn list_challenges(
+    query: web::Query<ChallengeListQuery>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<Vec<ChallengeData>>, HttpError> {
+    let challenges = services::challenge::list(&db, usersession.id, query.past).await?;
+
+    Ok(web::Json(challenges))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/protected/challenges/{id}",
+    params(("id" = String, Path, description = "Challenge id")),
+    responses(
+        (status = 200, description = "Challenge with live progress", body = ChallengeData),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "No challenge of the user or the user's groups with this id"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Challenges",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/challenges/{id}")]
+pub async fn get_challenge(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<ChallengeData>, HttpError> {
+    let challenge = services::challenge::get(&db, usersession.id, challenge_id(&id)?).await?;
+
+    Ok(web::Json(challenge))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/protected/challenges/{id}",
+    params(("id" = String, Path, description = "Challenge id")),
+    responses(
+        (status = 200, description = "Challenge deleted"),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Not a leader of the group, or an API token"),
+        (status = 404, description = "No challenge of the user or the user's groups with this id"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Challenges",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[delete("/v1/protected/challenges/{id}")]
+pub async fn delete_challenge(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let id = challenge_id(&id)?;
+    services::challenge::delete(&db, usersession.id, id).await?;
+
+    Ok(web::Json(json!({ "id": id.to_hex(), "deleted": true })))
+}
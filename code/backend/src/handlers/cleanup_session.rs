@@ -0,0 +1,502 @@
+use actix_web::{
+    HttpRequest, HttpResponse, Responder, get,
+    http::header,
+    post,
+    web::{self, Json},
+};
+use mongodb::{Database, bson::oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    handlers::{HttpError, ValidationErrorResponse, read_body},
+    models::cleanup_session::TrackPoint,
+    services::{
+        self,
+        auth::{UploadSession, UserSession},
+        cleanup_session::{SessionError, SessionSummary, TrackPointData},
+        track::{MAX_TRACK_FILE_BYTES, TrackFormat},
+        validation::FieldError,
+    },
+};
+
+impl From<SessionError> for HttpError {
+    fn from(err: SessionError) -> Self {
+        log::info!("Cleanup session operation failed with {:?}", err);
+
+        match err {
+            SessionError::InvalidInput(errors) => HttpError::ValidationFailed(errors),
+            SessionError::NotFound => HttpError::NotFound,
+            SessionError::AlreadyActive => HttpError::SessionAlreadyActive,
+            SessionError::Ended => HttpError::SessionEnded,
+            SessionError::TrackChanged => HttpError::TrackChanged,
+            SessionError::Forbidden => HttpError::Forbidden,
+            SessionError::NetworkError => HttpError::NetworkError,
+        }
+    }
+}
+
+fn session_id(id: &str) -> Result<ObjectId, HttpError> {
+    ObjectId::parse_str(id).map_err(|_| HttpError::NotFound)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SessionStartData {
+    name: Option<String>,
+    /// Usernames of other users to invite, they take part once they join
+    #[serde(default)]
+    participants: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TrackData {
+    points: Vec<TrackPointData>,
+    /// Start a new segment instead of continuing the last one, e.g. after a pause
+    #[serde(default)]
+    new_segment: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TrackPointGetData {
+    lat: f64,
+    lng: f64,
+    /// Missing on points imported without times
+    time: Option<String>,
+    /// Continuous part of the track the point belongs to
+    segment: u32,
+}
+
+impl From<TrackPoint> for TrackPointGetData {
+    fn from(point: TrackPoint) -> Self {
+        TrackPointGetData {
+            lat: point.lat,
+            lng: point.lng,
+            time: point.time.and_then(|t| t.try_to_rfc3339_string().ok()),
+            segment: point.segment,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct TrackQuery {
+    /// Leave out points closer than this many metres to the simplified line, 0 to 1000
+    tolerance: Option<f64>,
+}
+
+impl TrackQuery {
+    /// Tolerance of the request, `default` (the configured one if `None`) if not given.
+    fn tolerance(&self, default: Option<f64>) -> Result<f64, HttpError> {
+        services::track::tolerance(self.tolerance.or(default))
+            .map_err(|e| HttpError::ValidationFailed(vec![e]))
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct TrackImportQuery {
+    /// `gpx` or `kml`, defaults to the `Content-Type` of the body
+    format: Option<TrackFormat>,
+    /// Replace the recorded track instead of adding the file as new segments
+    #[serde(default)]
+    replace: bool,
+}
+
+/// Starts an outing. Reports uploaded with its `session_id` while it runs are counted
+/// in its summary.
+#[utoipa::path(
+    post,
+    path = "/v1/protected/sessions",
+    request_body = SessionStartData,
+    responses(
+        (status = 201, description = "Session started", body = SessionSummary),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Read-only API token"),
+        (status = 409, description = "The user already takes part in a running session"),
+        (status = 422, description = "Invalid name or unknown participants", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Sessions",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/sessions")]
+pub async fn start_session(
+    data: web::Json<SessionStartData>,
+    db: web::Data<Database>,
+    UploadSession(usersession): UploadSession,
+) -> Result<impl Responder, HttpError> {
+    let summary = services::cleanup_session::start(
+        &db,
+        usersession.id,
+        data.name.as_deref(),
+        &data.participants,
+    )
+    .await?;
+
+    Ok(HttpResponse::Created().json(summary))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/protected/sessions/{id}/stop",
+    params(("id" = String, Path, description = "Session id")),
+    responses(
+        (status = 200, description = "Session ended, with its final totals", body = SessionSummary),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Read-only API token"),
+        (status = 404, description = "No session of the user with this id"),
+        (status = 409, description = "Session already ended"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Sessions",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/sessions/{id}/stop")]
+pub async fn stop_session(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    UploadSession(usersession): UploadSession,
+) -> Result<Json<SessionSummary>, HttpError> {
+    let summary = services::cleanup_session::stop(&db, usersession.id, session_id(&id)?).await?;
+
+    Ok(web::Json(summary))
+}
+
+/// Accepts the invitation to a running session. Users take part in one running session at
+/// a time.
+#[utoipa::path(
+    post,
+    path = "/v1/protected/sessions/{id}/join",
+    params(("id" = String, Path, description = "Session id")),
+    responses(
+        (status = 200, description = "Joined the session", body = SessionSummary),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Read-only API token"),
+        (status = 404, description = "No invitation of the user to this session"),
+        (status = 409, description = "Session already ended or the user already takes part in a running session"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Sessions",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/sessions/{id}/join")]
+pub async fn join_session(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    UploadSession(usersession): UploadSession,
+) -> Result<Json<SessionSummary>, HttpError> {
+    let summary = services::cleanup_session::join(&db, usersession.id, session_id(&id)?).await?;
+
+    Ok(web::Json(summary))
+}
+
+/// Leaves a running session or declines the invitation to it. The owner stops the session
+/// instead.
+#[utoipa::path(
+    post,
+    path = "/v1/protected/sessions/{id}/leave",
+    params(("id" = String, Path, description = "Session id")),
+    responses(
+        (status = 200, description = "Left the session"),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Read-only API token or the user owns the session"),
+        (status = 404, description = "No session of the user with this id"),
+        (status = 409, description = "Session already ended"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Sessions",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/sessions/{id}/leave")]
+pub async fn leave_session(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    UploadSession(usersession): UploadSession,
+) -> Result<impl Responder, HttpError> {
+    let id = session_id(&id)?;
+    services::cleanup_session::leave(&db, usersession.id, id).await?;
+
+    Ok(web::Json(json!({ "id": id.to_hex(), "left": true })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/protected/sessions",
+    responses(
+        (status = 200, description = "The 100 latest sessions the user started, took part in or is invited to", body = Vec<SessionSummary>),
+        (status = 401, description = "Invalid credentials"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Sessions",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/sessions")]
+pub async fn list_sessions(
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<Vec<SessionSummary>>, HttpError> {
+    let sessions = services::cleanup_session::list(&db, usersession.id).await?;
+
+    Ok(web::Json(sessions))
+}
+
+/// Totals of a session: reports, items and weight collected by all participants, the
+/// duration and the distance walked.
+#[utoipa::path(
+    get,
+    path = "/v1/protected/sessions/{id}",
+    params(("id" = String, Path, description = "Session id")),
+    responses(
+        (status = 200, description = "Session summary", body = SessionSummary),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "No session of the user with this id"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Sessions",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/sessions/{id}")]
+pub async fn get_session(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<SessionSummary>, HttpError> {
+    let summary = services::cleanup_session::get(&db, usersession.id, session_id(&id)?).await?;
+
+    Ok(web::Json(summary))
+}
+
+/// GPS track of the session for display, simplified with the configured tolerance unless
+/// the request sets one.
+#[utoipa::path(
+    get,
+    path = "/v1/protected/sessions/{id}/track",
+    params(("id" = String, Path, description = "Session id"), TrackQuery),
+    responses(
+        (status = 200, description = "Track points by segment, oldest first", body = Vec<TrackPointGetData>),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "No session of the user with this id"),
+        (status = 422, description = "Invalid tolerance", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Sessions",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/sessions/{id}/track")]
+pub async fn get_track(
+    id: web::Path<String>,
+    query: web::Query<TrackQuery>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<Vec<TrackPointGetData>>, HttpError> {
+    let tolerance = query.tolerance(None)?;
+    let track = services::cleanup_session::track(&db, usersession.id, session_id(&id)?).await?;
+    let track = services::track::simplify(&track, tolerance);
+
+    Ok(web::Json(track.into_iter().map(|p| p.into()).collect()))
+}
+
+/// Appends GPS points to the track of a running session. Only the owner records the
+/// track; points can be sent in batches while walking.
+#[utoipa::path(
+    post,
+    path = "/v1/protected/sessions/{id}/track",
+    params(("id" = String, Path, description = "Session id")),
+    request_body = TrackData,
+    responses(
+        (status = 200, description = "Points added, with the updated totals", body = SessionSummary),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Read-only API token"),
+        (status = 404, description = "No session owned by the user with this id"),
+        (status = 409, description = "Session already ended, or other uploads kept changing the track"),
+        (status = 422, description = "Invalid points or track too long", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Sessions",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/sessions/{id}/track")]
+pub async fn add_track_points(
+    id: web::Path<String>,
+    data: web::Json<TrackData>,
+    db: web::Data<Database>,
+    UploadSession(usersession): UploadSession,
+) -> Result<Json<SessionSummary>, HttpError> {
+    let summary = services::cleanup_session::add_track_points(
+        &db,
+        usersession.id,
+        session_id(&id)?,
+        &data.points,
+        data.new_segment,
+    )
+    .await?;
+
+    Ok(web::Json(summary))
+}
+
+/// Adds the tracks of a GPX file (`trkseg`s) or KML file (`LineString`s and `gx:Track`s)
+/// to the session, e.g. recorded by a separate GPS device. Works after the session ended.
+#[utoipa::path(
+    post,
+    path = "/v1/protected/sessions/{id}/track/import",
+    params(("id" = String, Path, description = "Session id"), TrackImportQuery),
+    request_body(content = String, description = "GPX or KML file, at most 10 MiB", content_type = "application/gpx+xml"),
+    responses(
+        (status = 200, description = "Track imported, with the updated totals", body = SessionSummary),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Read-only API token"),
+        (status = 404, description = "No session owned by the user with this id"),
+        (status = 409, description = "Other uploads kept changing the track, retry"),
+        (status = 413, description = "File too large"),
+        (status = 422, description = "Unknown format, invalid file or track too long", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Sessions",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/sessions/{id}/track/import")]
+pub async fn import_track(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<TrackImportQuery>,
+    payload: web::Payload,
+    db: web::Data<Database>,
+    UploadSession(usersession): UploadSession,
+) -> Result<Json<SessionSummary>, HttpError> {
+    let id = session_id(&id)?;
+    let format = match query.format {
+        Some(format) => format,
+        None => match req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(t) if t.starts_with("application/gpx+xml") => TrackFormat::Gpx,
+            Some(t) if t.starts_with("application/vnd.google-earth.kml+xml") => TrackFormat::Kml,
+            _ => {
+                return Err(HttpError::ValidationFailed(vec![FieldError {
+                    field: "format".to_string(),
+                    message: "must be gpx or kml, or given by the Content-Type".to_string(),
+                }]));
+            }
+        },
+    };
+
+    let data = read_body(payload, MAX_TRACK_FILE_BYTES).await?;
+    let track =
+        services::track::parse(format, &data).map_err(|e| HttpError::ValidationFailed(vec![e]))?;
+    let summary =
+        services::cleanup_session::import_track(&db, usersession.id, id, track, query.replace)
+            .await?;
+
+    Ok(web::Json(summary))
+}
+
+async fn track_file(
+    id: &str,
+    query: &TrackQuery,
+    db: &Database,
+    usersession: &UserSession,
+    format: TrackFormat,
+) -> Result<HttpResponse, HttpError> {
+    // Files keep every point unless a tolerance is asked for
+    let tolerance = query.tolerance(Some(0.0))?;
+    let (summary, track, finds) =
+        services::cleanup_session::finds(db, usersession.id, session_id(id)?).await?;
+    let track = services::track::simplify(&track, tolerance);
+
+    let (content_type, extension, body) = match format {
+        TrackFormat::Gpx => (
+            "application/gpx+xml",
+            "gpx",
+            services::track::to_gpx(&summary, &track, &finds),
+        ),
+        TrackFormat::Kml => (
+            "application/vnd.google-earth.kml+xml",
+            "kml",
+            services::track::to_kml(&summary, &track, &finds),
+        ),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"session-{}.{extension}\"",
+                summary.id
+            ),
+        ))
+        .body(body))
+}
+
+/// Track and finds of the session as GPX, the finds as waypoints.
+#[utoipa::path(
+    get,
+    path = "/v1/protected/sessions/{id}/track.gpx",
+    params(("id" = String, Path, description = "Session id"), TrackQuery),
+    responses(
+        (status = 200, description = "GPX 1.1 file", content_type = "application/gpx+xml", body = String),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "No session of the user with this id"),
+        (status = 422, description = "Invalid tolerance", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Sessions",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/sessions/{id}/track.gpx")]
+pub async fn export_gpx(
+    id: web::Path<String>,
+    query: web::Query<TrackQuery>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<HttpResponse, HttpError> {
+    track_file(&id, &query, &db, &usersession, TrackFormat::Gpx).await
+}
+
+/// Track and finds of the session as KML, the finds in a folder of placemarks.
+#[utoipa::path(
+    get,
+    path = "/v1/protected/sessions/{id}/track.kml",
+    params(("id" = String, Path, description = "Session id"), TrackQuery),
+    responses(
+        (status = 200, description = "KML 2.2 file", content_type = "application/vnd.google-earth.kml+xml", body = String),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "No session of the user with this id"),
+        (status = 422, description = "Invalid tolerance", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Sessions",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/sessions/{id}/track.kml")]
+pub async fn export_kml(
+    id: web::Path<String>,
+    query: web::Query<TrackQuery>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<HttpResponse, HttpError> {
+    track_file(&id, &query, &db, &usersession, TrackFormat::Kml).await
+}
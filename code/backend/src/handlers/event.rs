@@ -0,0 +1,286 @@
+use actix_web::{
+    HttpResponse, Responder, delete, get, post,
+    web::{self, Json},
+};
+use mongodb::{Database, bson::oid::ObjectId};
+use serde::Deserialize;
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    handlers::{HttpError, ValidationErrorResponse},
+    services::{
+        self,
+        auth::{RequireRole, UserSession, roles},
+        event::{EventData, EventError, EventResults, NewEvent, ResultsCache},
+        litter::parse_date,
+    },
+};
+
+impl From<EventError> for HttpError {
+    fn from(err: EventError) -> Self {
+        log::info!("Event operation failed with {:?}", err);
+
+        match err {
+            EventError::InvalidInput(errors) => HttpError::ValidationFailed(errors),
+            EventError::NotFound => HttpError::NotFound,
+            EventError::Forbidden => HttpError::Forbidden,
+            EventError::AlreadyRegistered => HttpError::AlreadyRegistered,
+            EventError::Full => HttpError::EventFull,
+            EventError::Closed => HttpError::EventEnded,
+            EventError::NetworkError => HttpError::NetworkError,
+        }
+    }
+}
+
+fn event_id(id: &str) -> Result<ObjectId, HttpError> {
+    ObjectId::parse_str(id).map_err(|_| HttpError::NotFound)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct EventCreateData {
+    title: String,
+    description: Option<String>,
+    /// Corners of the cleanup area as `[lng, lat]`, closed automatically
+    area: Vec<[f64; 2]>,
+    /// Start of the time window (RFC 3339)
+    starts_at: String,
+    /// End of the time window (RFC 3339), at most 7 days after the start
+    ends_at: String,
+    /// Most participants, unlimited if missing
+    capacity: Option<u32>,
+}
+
+impl EventCreateData {
+    fn event(&self) -> Result<NewEvent, HttpError> {
+        let (starts_at, ends_at) = match (
+            parse_date("starts_at", &self.starts_at),
+            parse_date("ends_at", &self.ends_at),
+        ) {
+            (Ok(start), Ok(end)) => (start, end),
+            (start, end) => {
+                return Err(HttpError::ValidationFailed(
+                    [start.err(), end.err()].into_iter().flatten().collect(),
+                ));
+            }
+        };
+
+        Ok(NewEvent {
+            title: self.title.clone(),
+            description: self.description.clone(),
+            area: self.area.clone(),
+            starts_at,
+            ends_at,
+            capacity: self.capacity,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct EventListQuery {
+    /// Ended events, latest first, instead of upcoming and running ones
+    #[serde(default)]
+    past: bool,
+}
+
+/// Announces a public cleanup day. Reports of registered participants inside the area and
+/// time window count for the event. Needs at least the account role `group_leader`.
+#[utoipa::path(
+    post,
+    path = "/v1/protected/events",
+    request_body = EventCreateData,
+    responses(
+        (status = 201, description = "Event created", body = EventData),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Account role below group_leader, or an API token"),
+        (status = 422, description = "Invalid title, area, time window or capacity", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Events",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/events")]
+pub async fn create_event(
+    data: web::Json<EventCreateData>,
+    db: web::Data<Database>,
+    organiser: RequireRole<roles::GroupLeader>,
+) -> Result<impl Responder, HttpError> {
+    let event = services::event::create(&db, organiser.session.id, data.event()?).await?;
+
+    Ok(HttpResponse::Created().json(event))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/protected/events",
+    responses(
+        (status = 200, description = "The 100 latest events the user organises or registered for", body = Vec<EventData>),
+        (status = 401, description = "Invalid credentials"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Events",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/events")]
+pub async fn list_own_events(
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<Vec<EventData>>, HttpError> {
+    let events = services::event::list_own(&db, usersession.id).await?;
+
+    Ok(web::Json(events))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/protected/events/{id}/registration",
+    params(("id" = String, Path, description = "Event id")),
+    responses(
+        (status = 200, description = "Registered for the event", body = EventData),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "API tokens cannot register"),
+        (status = 404, description = "Event not found"),
+        (status = 409, description = "Already registered, event full or ended"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Events",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/events/{id}/registration")]
+pub async fn register(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<EventData>, HttpError> {
+    let event = services::event::register(&db, usersession.id, event_id(&id)?).await?;
+
+    Ok(web::Json(event))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/protected/events/{id}/registration",
+    params(("id" = String, Path, description = "Event id")),
+    responses(
+        (status = 200, description = "Registration withdrawn", body = EventData),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "API tokens cannot register"),
+        (status = 404, description = "Event not found or not registered"),
+        (status = 409, description = "Event ended"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Events",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[delete("/v1/protected/events/{id}/registration")]
+pub async fn unregister(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<EventData>, HttpError> {
+    let event = services::event::unregister(&db, usersession.id, event_id(&id)?).await?;
+
+    Ok(web::Json(event))
+}
+
+/// Cancels the event. Reports of the participants are kept.
+#[utoipa::path(
+    delete,
+    path = "/v1/protected/events/{id}",
+    params(("id" = String, Path, description = "Event id")),
+    responses(
+        (status = 200, description = "Event deleted"),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Neither organiser nor moderator, or an API token"),
+        (status = 404, description = "Event not found"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Events",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[delete("/v1/protected/events/{id}")]
+pub async fn delete_event(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let id = event_id(&id)?;
+    services::event::delete(&db, &usersession, id).await?;
+
+    Ok(web::Json(json!({ "id": id.to_hex(), "deleted": true })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/public/events",
+    params(EventListQuery),
+    responses(
+        (status = 200, description = "Up to 100 events, upcoming and running ones soonest first", body = Vec<EventData>),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Events"
+)]
+#[get("/v1/public/events")]
+pub async fn list_events(
+    query: web::Query<EventListQuery>,
+    db: web::Data<Database>,
+) -> Result<Json<Vec<EventData>>, HttpError> {
+    let events = services::event::list(&db, query.past).await?;
+
+    Ok(web::Json(events))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/public/events/{id}",
+    params(("id" = String, Path, description = "Event id")),
+    responses(
+        (status = 200, description = "Event", body = EventData),
+        (status = 404, description = "Event not found"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Events"
+)]
+#[get("/v1/public/events/{id}")]
+pub async fn get_event(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+) -> Result<Json<EventData>, HttpError> {
+    let event = services::event::get(&db, event_id(&id)?).await?;
+
+    Ok(web::Json(event))
+}
+
+/// Combined results of all participants: totals, top categories and a map of the reports,
+/// anonymised. Updated every minute while the event runs.
+#[utoipa::path(
+    get,
+    path = "/v1/public/events/{id}/results",
+    params(("id" = String, Path, description = "Event id")),
+    responses(
+        (status = 200, description = "Results of the event", body = EventResults),
+        (status = 404, description = "Event not found"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Events"
+)]
+#[get("/v1/public/events/{id}/results")]
+pub async fn get_results(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    cache: web::Data<ResultsCache>,
+) -> Result<Json<EventResults>, HttpError> {
+    let results = services::event::results(&db, &cache, event_id(&id)?).await?;
+
+    Ok(web::Json(results))
+}
@@ -0,0 +1,339 @@
+use actix_web::{
+    HttpResponse, Responder, delete, get, post, put,
+    web::{self, Json},
+};
+use mongodb::{Database, bson::oid::ObjectId};
+use serde::Deserialize;
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::{
+    handlers::{HttpError, ValidationErrorResponse},
+    models::group::GroupRole,
+    services::{
+        self,
+        auth::{RequireRole, UserSession, roles},
+        group::{GroupDetail, GroupError, GroupSummary},
+        litter::LitterFilter,
+    },
+};
+
+impl From<GroupError> for HttpError {
+    fn from(err: GroupError) -> Self {
+        log::info!("Group operation failed with {:?}", err);
+
+        match err {
+            GroupError::InvalidInput(errors) => HttpError::ValidationFailed(errors),
+            GroupError::NotFound => HttpError::NotFound,
+            GroupError::Forbidden => HttpError::Forbidden,
+            GroupError::AlreadyMember => HttpError::AlreadyGroupMember,
+            GroupError::LastLeader => HttpError::LastGroupLeader,
+            GroupError::NetworkError => HttpError::NetworkError,
+        }
+    }
+}
+
+fn group_id(id: &str) -> Result<ObjectId, HttpError> {
+    ObjectId::parse_str(id).map_err(|_| HttpError::NotFound)
+}
+
+/// Restricts `filter` to the reports of the members of `group`, which the user must belong to.
+pub(crate) async fn restrict_to_group(
+    db: &Database,
+    usersession: &UserSession,
+    group: &str,
+    filter: &mut LitterFilter,
+) -> Result<(), HttpError> {
+    let members = services::group::member_ids(db, usersession.id, group_id(group)?).await?;
+    filter.owners = Some(members);
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct GroupCreateData {
+    name: String,
+    description: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct GroupJoinData {
+    /// Invite code shared by a leader of the group
+    code: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct GroupRoleData {
+    role: GroupRole,
+}
+
+/// Creates a group, e.g. for a scout troop, school class or company, with the current user
+/// as its leader. Needs at least the account role `group_leader`.
+#[utoipa::path(
+    post,
+    path = "/v1/protected/groups",
+    request_body = GroupCreateData,
+    responses(
+        (status = 201, description = "Group created", body = GroupSummary),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Account role below group_leader, or an API token"),
+        (status = 422, description = "Invalid name or description, or too many groups", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Groups",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/groups")]
+pub async fn create_group(
+    data: web::Json<GroupCreateData>,
+    db: web::Data<Database>,
+    leader: RequireRole<roles::GroupLeader>,
+) -> Result<impl Responder, HttpError> {
+    let group = services::group::create(
+        &db,
+        leader.session.id,
+        &data.name,
+        data.description.as_deref(),
+    )
+    .await?;
+
+    Ok(HttpResponse::Created().json(group))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/protected/groups",
+    responses(
+        (status = 200, description = "Groups the user is a member of, by name", body = Vec<GroupSummary>),
+        (status = 401, description = "Invalid credentials"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Groups",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/groups")]
+pub async fn list_groups(
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<Vec<GroupSummary>>, HttpError> {
+    let groups = services::group::list(&db, usersession.id).await?;
+
+    Ok(web::Json(groups))
+}
+
+/// Joins the group with the invite code, as a member.
+#[utoipa::path(
+    post,
+    path = "/v1/protected/groups/join",
+    request_body = GroupJoinData,
+    responses(
+        (status = 200, description = "Joined the group", body = GroupSummary),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "API tokens cannot manage groups"),
+        (status = 404, description = "Unknown or revoked invite code"),
+        (status = 409, description = "Already a member of the group"),
+        (status = 422, description = "The group is full or the user is in too many groups", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Groups",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/groups/join")]
+pub async fn join_group(
+    data: web::Json<GroupJoinData>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<GroupSummary>, HttpError> {
+    let group = services::group::join(&db, usersession.id, &data.code).await?;
+
+    Ok(web::Json(group))
+}
+
+/// Group with its members. The invite code is only shown to leaders.
+#[utoipa::path(
+    get,
+    path = "/v1/protected/groups/{id}",
+    params(("id" = String, Path, description = "Group id")),
+    responses(
+        (status = 200, description = "Group and members", body = GroupDetail),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "No group of the user with this id"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Groups",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/groups/{id}")]
+pub async fn get_group(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<GroupDetail>, HttpError> {
+    let group = services::group::get(&db, usersession.id, group_id(&id)?).await?;
+
+    Ok(web::Json(group))
+}
+
+/// Deletes the group for all members. Their reports are kept.
+#[utoipa::path(
+    delete,
+    path = "/v1/protected/groups/{id}",
+    params(("id" = String, Path, description = "Group id")),
+    responses(
+        (status = 200, description = "Group deleted"),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Not a leader of the group, or an API token"),
+        (status = 404, description = "No group of the user with this id"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Groups",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[delete("/v1/protected/groups/{id}")]
+pub async fn delete_group(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let id = group_id(&id)?;
+    services::group::delete(&db, usersession.id, id).await?;
+
+    Ok(web::Json(json!({ "id": id.to_hex(), "deleted": true })))
+}
+
+/// Leaves the group. Leaders have to make someone else leader first, the group is deleted
+/// when its last member leaves.
+#[utoipa::path(
+    post,
+    path = "/v1/protected/groups/{id}/leave",
+    params(("id" = String, Path, description = "Group id")),
+    responses(
+        (status = 200, description = "Left the group"),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "API tokens cannot manage groups"),
+        (status = 404, description = "No group of the user with this id"),
+        (status = 409, description = "The user is the last leader of the group"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Groups",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/groups/{id}/leave")]
+pub async fn leave_group(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let id = group_id(&id)?;
+    services::group::leave(&db, usersession.id, id).await?;
+
+    Ok(web::Json(json!({ "id": id.to_hex(), "left": true })))
+}
+
+/// Replaces the invite code, old codes and links stop working.
+#[utoipa::path(
+    post,
+    path = "/v1/protected/groups/{id}/invite",
+    params(("id" = String, Path, description = "Group id")),
+    responses(
+        (status = 200, description = "Group with the new invite code", body = GroupSummary),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Not a leader of the group, or an API token"),
+        (status = 404, description = "No group of the user with this id"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Groups",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/groups/{id}/invite")]
+pub async fn renew_invite(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<GroupSummary>, HttpError> {
+    let group = services::group::renew_invite(&db, usersession.id, group_id(&id)?).await?;
+
+    Ok(web::Json(group))
+}
+
+#[utoipa::path(
+    put,
+    path = "/v1/protected/groups/{id}/members/{username}",
+    params(
+        ("id" = String, Path, description = "Group id"),
+        ("username" = String, Path, description = "Username of the member")
+    ),
+    request_body = GroupRoleData,
+    responses(
+        (status = 200, description = "Role changed", body = GroupDetail),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Not a leader of the group, or an API token"),
+        (status = 404, description = "No such group or member"),
+        (status = 409, description = "The group would be left without a leader"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Groups",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[put("/v1/protected/groups/{id}/members/{username}")]
+pub async fn set_member_role(
+    path: web::Path<(String, String)>,
+    data: web::Json<GroupRoleData>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<GroupDetail>, HttpError> {
+    let (id, username) = path.into_inner();
+    let group =
+        services::group::set_role(&db, usersession.id, group_id(&id)?, &username, data.role)
+            .await?;
+
+    Ok(web::Json(group))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/protected/groups/{id}/members/{username}",
+    params(
+        ("id" = String, Path, description = "Group id"),
+        ("username" = String, Path, description = "Username of the member")
+    ),
+    responses(
+        (status = 200, description = "Member removed"),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Not a leader of the group, or an API token"),
+        (status = 404, description = "No such group or member"),
+        (status = 409, description = "The member is the last leader of the group"),
+        (status = 422, description = "Leaders leave instead of removing themselves", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Groups",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[delete("/v1/protected/groups/{id}/members/{username}")]
+pub async fn remove_member(
+    path: web::Path<(String, String)>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let (id, username) = path.into_inner();
+    services::group::remove_member(&db, usersession.id, group_id(&id)?, &username).await?;
+
+    Ok(web::Json(json!({ "username": username, "removed": true })))
+}
@@ -0,0 +1,109 @@
+use actix_web::{
+    HttpRequest, HttpResponse,
+    http::header,
+    post,
+    web::{self},
+};
+use mongodb::Database;
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::{
+    handlers::{HttpError, read_body},
+    services::{
+        self,
+        auth::UploadSession,
+        import::{ImportError, ImportFormat, ImportReport, MAX_IMPORT_BYTES},
+        mailer::Mailer,
+        validation::FieldError,
+    },
+};
+
+impl From<ImportError> for HttpError {
+    fn from(err: ImportError) -> Self {
+        log::info!("Litter import failed with {:?}", err);
+
+        match err {
+            ImportError::Conflict => HttpError::ImportConflict,
+            ImportError::NetworkError => HttpError::NetworkError,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ImportQuery {
+    /// `csv` or `geojson`, defaults to the `Content-Type` of the body
+    format: Option<ImportFormat>,
+    /// Only validate the file and report what would be imported
+    #[serde(default)]
+    dry_run: bool,
+}
+
+impl ImportQuery {
+    fn format(&self, req: &HttpRequest) -> Result<ImportFormat, HttpError> {
+        if let Some(format) = self.format {
+            return Ok(format);
+        }
+        let content_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        match content_type.split(';').next().unwrap_or_default().trim() {
+            "text/csv" => Ok(ImportFormat::Csv),
+            "application/geo+json" | "application/json" => Ok(ImportFormat::Geojson),
+            _ => Err(HttpError::ValidationFailed(vec![FieldError {
+                field: "format".to_string(),
+                message: "must be csv or geojson, or given by the Content-Type".to_string(),
+            }])),
+        }
+    }
+}
+
+/// Imports reports without photos from a CSV file (one row per item, rows with the same
+/// `id`, or position and date, form one report) or a GeoJSON FeatureCollection of points.
+/// Nothing is imported if a row is invalid; reports imported before are skipped.
+#[utoipa::path(
+    post,
+    path = "/v1/protected/litter/import",
+    params(ImportQuery),
+    request_body(content = String, description = "CSV or GeoJSON file, at most 20 MiB", content_type = "text/csv"),
+    responses(
+        (status = 200, description = "Import done, or validated on a dry run", body = ImportReport),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Read-only API token"),
+        (status = 409, description = "A concurrent import added some of the reports"),
+        (status = 413, description = "File too large"),
+        (status = 422, description = "Invalid rows, nothing was imported; or an unknown format", body = ImportReport),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Litter",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/litter/import")]
+pub async fn import_litter(
+    req: HttpRequest,
+    query: web::Query<ImportQuery>,
+    payload: web::Payload,
+    db: web::Data<Database>,
+    mailer: web::Data<dyn Mailer>,
+    UploadSession(usersession): UploadSession,
+) -> Result<HttpResponse, HttpError> {
+    let format = query.format(&req)?;
+
+    let data = read_body(payload, MAX_IMPORT_BYTES).await?;
+
+    let report =
+        services::import::import(&db, usersession.id, format, &data, query.dry_run).await?;
+    if report.created > 0 {
+        services::challenge::check(&db, &mailer, usersession.id, None).await;
+    }
+
+    if report.errors.is_empty() || report.dry_run {
+        Ok(HttpResponse::Ok().json(report))
+    } else {
+        Ok(HttpResponse::UnprocessableEntity().json(report))
+    }
+}
@@ -0,0 +1,163 @@
+use actix_web::{
+    get, put,
+    web::{self, Json},
+};
+use mongodb::{Database, bson::oid::ObjectId};
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::{
+    handlers::{HttpError, ValidationErrorResponse},
+    services::{
+        self,
+        auth::UserSession,
+        leaderboard::{
+            Board, Leaderboard, LeaderboardError, LeaderboardRegion, LeaderboardSettings, Metric,
+            Period,
+        },
+        validation::FieldError,
+    },
+};
+
+impl From<LeaderboardError> for HttpError {
+    fn from(err: LeaderboardError) -> Self {
+        log::info!("Leaderboard operation failed with {:?}", err);
+
+        match err {
+            LeaderboardError::InvalidInput(errors) => HttpError::ValidationFailed(errors),
+            LeaderboardError::NetworkError => HttpError::NetworkError,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct LeaderboardQuery {
+    /// What users are ranked by, `items` by default
+    metric: Option<Metric>,
+    /// Time the totals cover, `week` by default
+    period: Option<Period>,
+    /// Ranks the members of this group of the user, including those who opted out
+    group: Option<String>,
+    /// Ranks reports inside this region, see `/v1/public/leaderboard/regions`
+    region: Option<String>,
+    /// Number of entries, 50 by default and at most 100
+    limit: Option<i64>,
+}
+
+/// Users ranked by their totals, refreshed every few minutes. Without `group` or `region`
+/// all reports count. Users who opted out are only listed within their groups.
+#[utoipa::path(
+    get,
+    path = "/v1/protected/leaderboard",
+    params(LeaderboardQuery),
+    responses(
+        (status = 200, description = "Leaderboard with the user's own place", body = Leaderboard),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "No group of the user with this id"),
+        (status = 422, description = "Unknown region, both group and region, or invalid limit", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Leaderboards",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/leaderboard")]
+pub async fn get_leaderboard(
+    query: web::Query<LeaderboardQuery>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<Leaderboard>, HttpError> {
+    let board = match (&query.group, &query.region) {
+        (Some(_), Some(_)) => {
+            return Err(HttpError::ValidationFailed(vec![FieldError {
+                field: "group".to_string(),
+                message: "cannot be combined with region".to_string(),
+            }]));
+        }
+        (Some(group), None) => {
+            let group = ObjectId::parse_str(group).map_err(|_| HttpError::NotFound)?;
+            Board::Group(services::group::member_ids(&db, usersession.id, group).await?)
+        }
+        (None, Some(region)) => Board::Region(region.clone()),
+        (None, None) => Board::Global,
+    };
+
+    let leaderboard = services::leaderboard::leaderboard(
+        &db,
+        usersession.id,
+        board,
+        query.metric.unwrap_or_default(),
+        query.period.unwrap_or_default(),
+        query.limit,
+    )
+    .await?;
+
+    Ok(web::Json(leaderboard))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/public/leaderboard/regions",
+    responses(
+        (status = 200, description = "Regions with their own leaderboard", body = Vec<LeaderboardRegion>)
+    ),
+    tag = "Leaderboards"
+)]
+#[get("/v1/public/leaderboard/regions")]
+pub async fn list_regions() -> Json<&'static [LeaderboardRegion]> {
+    web::Json(services::leaderboard::regions())
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/protected/account/leaderboard",
+    responses(
+        (status = 200, description = "How the user appears on leaderboards", body = LeaderboardSettings),
+        (status = 401, description = "Invalid credentials"),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Leaderboards",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/account/leaderboard")]
+pub async fn get_settings(
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<LeaderboardSettings>, HttpError> {
+    let settings = services::leaderboard::settings(&db, usersession.id).await?;
+
+    Ok(web::Json(settings))
+}
+
+/// Opts out of public leaderboards or sets a pseudonym. An empty pseudonym shows the
+/// username again. Both apply right away.
+#[utoipa::path(
+    put,
+    path = "/v1/protected/account/leaderboard",
+    request_body = LeaderboardSettings,
+    responses(
+        (status = 200, description = "Settings stored", body = LeaderboardSettings),
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "API tokens cannot change settings"),
+        (status = 422, description = "Invalid or already used pseudonym", body = ValidationErrorResponse),
+        (status = 500, description = "Network error")
+    ),
+    tag = "Leaderboards",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[put("/v1/protected/account/leaderboard")]
+pub async fn set_settings(
+    data: web::Json<LeaderboardSettings>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<Json<LeaderboardSettings>, HttpError> {
+    let settings =
+        services::leaderboard::update_settings(&db, usersession.id, data.into_inner()).await?;
+
+    Ok(web::Json(settings))
+}
@@ -1,19 +1,38 @@
 use actix_web::{
-    Responder, get, post,
-    web::{self, Json},
+    HttpRequest, HttpResponse, Responder, get,
+    http::header,
+    post,
+    web::{self},
 };
 use mongodb::{
     Database,
-    bson::{Binary, doc, oid::ObjectId},
+    bson::{self, Binary, doc, oid::ObjectId},
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 
 use crate::{
-    handlers::HttpError,
-    models::{self, litter::Litter},
-    services::auth::UserSession,
+    handlers::{HttpError, ValidationErrorResponse, group::restrict_to_group},
+    models::{
+        self,
+        litter::{AnalysisStatus, GeoPoint, Litter},
+    },
+    services::{
+        self,
+        auth::{UploadSession, UserSession},
+        cleanup_session::SessionError,
+        cluster::{Cluster, ClusterCache},
+        export::Column,
+        impact::{Impact, Region},
+        litter::{
+            Cursor, GeoArea, LitterError, LitterFilter, LitterScope, PageRequest, SortOrder,
+            range_filter, validate_position,
+        },
+        mailer::Mailer,
+        mvt::VectorTileCache,
+        validation::FieldError,
+    },
 };
 
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
@@ -29,27 +48,52 @@ pub struct LitterData {
     #[schema(format = "binary")]
     file: Vec<u8>,
     r#type: String,
+    /// Running cleanup session the report is collected in
+    #[serde(default)]
+    session_id: Option<String>,
 }
 
-impl Into<Litter> for LitterData {
-    fn into(self) -> Litter {
+impl From<LitterData> for Litter {
+    fn from(data: LitterData) -> Self {
         let file_binary = Binary {
             subtype: mongodb::bson::spec::BinarySubtype::Generic, // Set the correct subtype for your use case
-            bytes: self.file.clone(),
+            bytes: data.file.clone(),
         };
 
         Litter {
-            lng: self.lng,
-            lat: self.lat,
+            lng: data.lng,
+            lat: data.lat,
+            location: Some(GeoPoint::new(data.lng, data.lat)),
             file: Some(file_binary),
-            r#type: self.r#type,
+            r#type: data.r#type,
             entries: vec![],
             _id: ObjectId::new(),
             time_stamp: mongodb::bson::DateTime::now(),
+            status: Some(AnalysisStatus::Pending),
+            import_key: None,
+            session_id: None,
         }
     }
 }
 
+/// Id of a running session the user takes part in, other ids are rejected as invalid input.
+async fn open_session(db: &Database, user_id: ObjectId, id: &str) -> Result<ObjectId, HttpError> {
+    let invalid = |message: &str| {
+        HttpError::ValidationFailed(vec![FieldError {
+            field: "session_id".to_string(),
+            message: message.to_string(),
+        }])
+    };
+
+    let id = ObjectId::parse_str(id).map_err(|_| invalid("is not a session"))?;
+    match services::cleanup_session::check_open(db, user_id, id).await {
+        Ok(()) => Ok(id),
+        Err(SessionError::NotFound) => Err(invalid("is not a session you take part in")),
+        Err(SessionError::Ended) => Err(invalid("belongs to a session that has ended")),
+        Err(e) => Err(e.into()),
+    }
+}
+
 #[derive(Debug, Serialize, ToSchema)]
 pub struct LitterCreateResponse {
     id: String,
@@ -62,6 +106,8 @@ pub struct LitterCreateResponse {
     responses(
         (status = 200, description = "Litter successfully created", body = LitterCreateResponse),
         (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Read-only API token"),
+        (status = 422, description = "Coordinates out of range or session not running", body = ValidationErrorResponse),
         (status = 500, description = "Network error")
     ),
     tag = "Litter",
@@ -73,39 +119,57 @@ pub struct LitterCreateResponse {
 pub async fn create_litter(
     data: web::Json<LitterData>,
     db: web::Data<Database>,
-    usersession: UserSession,
+    mailer: web::Data<dyn Mailer>,
+    UploadSession(usersession): UploadSession,
 ) -> Result<impl Responder, HttpError> {
-    let file = data.file.clone();
+    let errors = validate_position(data.lat, data.lng);
+    if !errors.is_empty() {
+        return Err(HttpError::ValidationFailed(errors));
+    }
 
-    let mut user = match models::user::User::from_id(&db, usersession.id).await {
-        Some(u) => u,
-        None => {
-            log::info!("Not logged in!");
-            return Err(HttpError::InvalidCredentials);
-        }
+    let session_id = match data.session_id.as_deref() {
+        Some(id) => Some(open_session(&db, usersession.id, id).await?),
+        None => None,
     };
 
+    let file = data.file.clone();
+
     let mut litter: Litter = data.0.into();
+    litter.session_id = session_id;
     let id = litter._id.to_hex();
 
-    // Clone what you need because it's moved into the new task
-    // let db_clone = db.clone();
-    user.litter.push(litter.clone());
+    let report = bson::to_bson(&litter).map_err(|e| {
+        log::error!("Failed to serialize litter: {:?}", e);
+        HttpError::InternalError
+    })?;
+    // Only the new report is written, so concurrent changes to the user are kept
+    let result = db
+        .collection::<models::user::User>("users")
+        .update_one(doc! { "_id": usersession.id }, doc! { "$push": { "litter": report } })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to add litter: {:?}", e);
+            HttpError::NetworkError
+        })?;
+    if result.matched_count == 0 {
+        log::info!("Not logged in!");
+        return Err(HttpError::InvalidCredentials);
+    }
 
     // Spawn a new asynchronous task for analysis
-    if let Err(e) = user.persist(&db).await {
-        log::error!("Failed to persist user: {:?}", e);
-        return Err(HttpError::NetworkError);
-    }
 
     tokio::spawn(async move {
+        services::achievement::check(&db, usersession.id).await;
+        services::challenge::check(&db, &mailer, usersession.id, Some(&litter)).await;
+
         // Call your analyze function
-        let res = match crate::services::analyzer::analyze(file).await {
-            Ok(r) => r,
-            Err(e) => {
-                log::error!("Error while analysing image: {}", e);
-                return;
-            }
+        let res = crate::services::analyzer::analyze(file)
+            .await
+            .map_err(|e| log::error!("Error while analysing image: {}", e));
+        let Ok(res) = res else {
+            litter.status = Some(AnalysisStatus::Failed);
+            let _ = litter.persist(&db, usersession.id).await;
+            return;
         };
 
         // Can Metal Pepsi 5g
@@ -122,10 +186,14 @@ pub async fn create_litter(
                 material: obj.material,
                 weight: Some(obj.weight_g_estimate),
                 brand: obj.brand,
+                confidence: Some(obj.confidence),
             });
         }
+        litter.status = Some(AnalysisStatus::Done);
 
         let _ = litter.persist(&db, usersession.id).await;
+        services::achievement::check(&db, usersession.id).await;
+        services::challenge::check(&db, &mailer, usersession.id, Some(&litter)).await;
     });
 
     // Immediately return the ID to the client
@@ -138,6 +206,7 @@ pub struct LitterEntryGetData {
     material: Option<String>,
     weight: Option<f64>,
     brand: Option<String>,
+    confidence: Option<f64>,
 }
 #[derive(Debug, Serialize, ToSchema)]
 pub struct LitterGetData {
@@ -149,17 +218,23 @@ pub struct LitterGetData {
     entries: Vec<LitterEntryGetData>,
     id: String,
     date: String,
+    status: AnalysisStatus,
+    impact: Impact,
+    session_id: Option<String>,
 }
 
-impl Into<LitterGetData> for Litter {
-    fn into(self) -> LitterGetData {
+impl LitterGetData {
+    fn new(litter: Litter, region: Region) -> Self {
+        let status = litter.analysis_status();
         LitterGetData {
-            lat: self.lat,
-            lng: self.lng,
-            file: self.file.map(|f| f.bytes).unwrap_or_default(),
-            r#type: self.r#type,
+            status,
+            impact: region.entries_impact(&litter.entries),
+            lat: litter.lat,
+            lng: litter.lng,
+            file: litter.file.map(|f| f.bytes).unwrap_or_default(),
+            r#type: litter.r#type,
 
-            entries: self
+            entries: litter
                 .entries
                 .into_iter()
                 .map(|el| LitterEntryGetData {
@@ -167,10 +242,107 @@ impl Into<LitterGetData> for Litter {
                     material: el.material,
                     weight: el.weight,
                     brand: el.brand,
+                    confidence: el.confidence,
                 })
                 .collect(),
-            id: self._id.to_hex(),
-            date: self.time_stamp.to_string(),
+            id: litter._id.to_hex(),
+            date: litter.time_stamp.to_string(),
+            session_id: litter.session_id.map(|id| id.to_hex()),
+        }
+    }
+}
+
+/// Query parameters of the litter lists. Without `limit` all matching own reports are
+/// returned, community and group reports in pages of 500.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct LitterQuery {
+    /// `own` reports (default) or the anonymised `community` reports of all users
+    scope: Option<LitterScope>,
+    /// Id of a group of the user: the anonymised reports of all its members instead
+    group: Option<String>,
+    /// Page size, 1 to 500. Enables cursor pagination.
+    limit: Option<i64>,
+    /// `X-Next-Cursor` of the previous page
+    cursor: Option<String>,
+    /// Sort by time: `asc` (default) or `desc`
+    order: Option<SortOrder>,
+    /// Reports at or after this date (YYYY-MM-DD or RFC 3339)
+    from: Option<String>,
+    /// Reports before this date (YYYY-MM-DD or RFC 3339)
+    to: Option<String>,
+    /// Only reports with an entry of this category (case-insensitive)
+    category: Option<String>,
+    /// Only reports with an entry of this material (case-insensitive)
+    material: Option<String>,
+    /// Only reports with an entry of this brand (case-insensitive)
+    brand: Option<String>,
+    /// Only reports with this analysis status
+    status: Option<AnalysisStatus>,
+    /// Include the image bytes of own reports (default false), only together with `limit`
+    include_images: Option<bool>,
+    /// Impact factor table, e.g. `ch` or `de` (default from the configuration)
+    region: Option<String>,
+}
+
+impl LitterQuery {
+    fn filter(&self, area: Option<GeoArea>) -> Result<LitterFilter, HttpError> {
+        let filter = range_filter(self.from.as_deref(), self.to.as_deref(), None)
+            .map_err(HttpError::ValidationFailed)?;
+
+        Ok(LitterFilter {
+            from: filter.from,
+            to: filter.to,
+            category: self.category.clone(),
+            material: self.material.clone(),
+            brand: self.brand.clone(),
+            status: self.status,
+            area,
+            owners: None,
+        })
+    }
+
+    /// Scope and filter of the query, restricted to the members of `group` if given.
+    async fn scoped(
+        &self,
+        db: &Database,
+        usersession: &UserSession,
+        area: Option<GeoArea>,
+    ) -> Result<(LitterScope, LitterFilter), HttpError> {
+        let mut filter = self.filter(area)?;
+        match &self.group {
+            Some(group) => {
+                restrict_to_group(db, usersession, group, &mut filter).await?;
+                Ok((LitterScope::Community, filter))
+            }
+            None => Ok((self.scope.unwrap_or_default(), filter)),
+        }
+    }
+
+    fn page(&self) -> Result<PageRequest, HttpError> {
+        let cursor = self
+            .cursor
+            .as_deref()
+            .map(Cursor::decode)
+            .transpose()
+            .map_err(|e| HttpError::ValidationFailed(vec![e]))?;
+
+        Ok(PageRequest {
+            cursor,
+            limit: self.limit,
+            order: self.order.unwrap_or_default(),
+            include_images: self.include_images.unwrap_or(false),
+        })
+    }
+}
+
+impl From<LitterError> for HttpError {
+    fn from(err: LitterError) -> Self {
+        log::info!("Litter query failed with {:?}", err);
+
+        match err {
+            LitterError::InvalidInput(errors) => HttpError::ValidationFailed(errors),
+            LitterError::NetworkError => HttpError::NetworkError,
+            LitterError::InternalError => HttpError::InternalError,
         }
     }
 }
@@ -178,9 +350,17 @@ impl Into<LitterGetData> for Litter {
 #[utoipa::path(
     get,
     path = "/v1/protected/litter",
+    params(LitterQuery),
     responses(
-        (status = 200, description = "List of litter items", body = Vec<LitterGetData>),
-        (status = 401, description = "Invalid credentials")
+        (status = 200, description = "List of litter items", body = Vec<LitterGetData>,
+            headers(
+                ("X-Total-Count" = u64, description = "Number of reports matching the filters"),
+                ("X-Next-Cursor" = String, description = "Cursor of the next page, missing on the last page")
+            )
+        ),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "Not a member of `group`"),
+        (status = 422, description = "Invalid query parameters", body = ValidationErrorResponse)
     ),
     tag = "Litter",
     security(
@@ -189,17 +369,483 @@ impl Into<LitterGetData> for Litter {
 )]
 #[get("/v1/protected/litter")]
 pub async fn get_litter(
+    query: web::Query<LitterQuery>,
     db: web::Data<Database>,
     usersession: UserSession,
-) -> Result<Json<Vec<LitterGetData>>, HttpError> {
-    let user = match models::user::User::from_id(&db, usersession.id).await {
-        Some(u) => u,
-        None => {
-            log::info!("Not logged in!");
-            return Err(HttpError::InvalidCredentials);
-        }
-    };
-    Ok(web::Json(
-        user.litter.into_iter().map(|l| l.into()).collect(),
+) -> Result<impl Responder, HttpError> {
+    list_response(db, &usersession, &query, None).await
+}
+
+async fn list_response(
+    db: web::Data<Database>,
+    usersession: &UserSession,
+    query: &LitterQuery,
+    area: Option<GeoArea>,
+) -> Result<HttpResponse, HttpError> {
+    let (scope, filter) = query.scoped(&db, usersession, area).await?;
+    let page = query.page()?;
+    let region = services::impact::region(query.region.as_deref())
+        .map_err(|e| HttpError::ValidationFailed(vec![e]))?;
+
+    let result = services::litter::list_litter(db, usersession.id, scope, &filter, &page).await?;
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header(("X-Total-Count", result.total.to_string()));
+    if let Some(cursor) = result.next_cursor {
+        response.insert_header(("X-Next-Cursor", cursor));
+    }
+
+    Ok(response.json(
+        result
+            .items
+            .into_iter()
+            .map(|litter| LitterGetData::new(litter, region))
+            .collect::<Vec<_>>(),
     ))
 }
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct BboxQuery {
+    min_lat: f64,
+    min_lng: f64,
+    max_lat: f64,
+    max_lng: f64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/protected/litter/bbox",
+    params(BboxQuery, LitterQuery),
+    responses(
+        (status = 200, description = "Reports inside the bounding box", body = Vec<LitterGetData>,
+            headers(
+                ("X-Total-Count" = u64, description = "Number of reports matching the filters"),
+                ("X-Next-Cursor" = String, description = "Cursor of the next page, missing on the last page")
+            )
+        ),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "Not a member of `group`"),
+        (status = 422, description = "Invalid query parameters", body = ValidationErrorResponse)
+    ),
+    tag = "Litter",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/litter/bbox")]
+pub async fn get_litter_in_bbox(
+    bbox: web::Query<BboxQuery>,
+    query: web::Query<LitterQuery>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let area = GeoArea::BBox {
+        min_lng: bbox.min_lng,
+        min_lat: bbox.min_lat,
+        max_lng: bbox.max_lng,
+        max_lat: bbox.max_lat,
+    };
+    list_response(db, &usersession, &query, Some(area)).await
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct RadiusQuery {
+    lat: f64,
+    lng: f64,
+    /// Radius in metres, at most 1000 km
+    radius: f64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/protected/litter/radius",
+    params(RadiusQuery, LitterQuery),
+    responses(
+        (status = 200, description = "Reports within the radius around the point", body = Vec<LitterGetData>,
+            headers(
+                ("X-Total-Count" = u64, description = "Number of reports matching the filters"),
+                ("X-Next-Cursor" = String, description = "Cursor of the next page, missing on the last page")
+            )
+        ),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "Not a member of `group`"),
+        (status = 422, description = "Invalid query parameters", body = ValidationErrorResponse)
+    ),
+    tag = "Litter",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/litter/radius")]
+pub async fn get_litter_in_radius(
+    radius: web::Query<RadiusQuery>,
+    query: web::Query<LitterQuery>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let area = GeoArea::Radius {
+        lng: radius.lng,
+        lat: radius.lat,
+        metres: radius.radius,
+    };
+    list_response(db, &usersession, &query, Some(area)).await
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct PolygonQuery {
+    /// Corners as `lng,lat` pairs separated by `;`, e.g. `8.5,47.3;8.6,47.3;8.6,47.4`
+    points: String,
+}
+
+impl PolygonQuery {
+    fn area(&self) -> Result<GeoArea, HttpError> {
+        let invalid = || {
+            HttpError::ValidationFailed(vec![FieldError {
+                field: "points".to_string(),
+                message: "must be `lng,lat` pairs separated by `;`".to_string(),
+            }])
+        };
+
+        let points = self
+            .points
+            .split(';')
+            .filter(|p| !p.trim().is_empty())
+            .map(|point| {
+                let (lng, lat) = point.split_once(',')?;
+                Some([lng.trim().parse().ok()?, lat.trim().parse().ok()?])
+            })
+            .collect::<Option<Vec<[f64; 2]>>>()
+            .ok_or_else(invalid)?;
+
+        Ok(GeoArea::Polygon(points))
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/protected/litter/polygon",
+    params(PolygonQuery, LitterQuery),
+    responses(
+        (status = 200, description = "Reports inside the polygon", body = Vec<LitterGetData>,
+            headers(
+                ("X-Total-Count" = u64, description = "Number of reports matching the filters"),
+                ("X-Next-Cursor" = String, description = "Cursor of the next page, missing on the last page")
+            )
+        ),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "Not a member of `group`"),
+        (status = 422, description = "Invalid query parameters or polygon", body = ValidationErrorResponse)
+    ),
+    tag = "Litter",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/litter/polygon")]
+pub async fn get_litter_in_polygon(
+    polygon: web::Query<PolygonQuery>,
+    query: web::Query<LitterQuery>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let area = polygon.area()?;
+    list_response(db, &usersession, &query, Some(area)).await
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ExportQuery {
+    /// Only reports inside `min_lng,min_lat,max_lng,max_lat`
+    bbox: Option<String>,
+}
+
+/// All matching reports as a GeoJSON FeatureCollection, without images. The output is
+/// streamed, `limit` and `cursor` are ignored.
+#[utoipa::path(
+    get,
+    path = "/v1/protected/litter/export.geojson",
+    params(ExportQuery, LitterQuery),
+    responses(
+        (status = 200, description = "FeatureCollection of the reports, with entries, analysis status and date as properties", content_type = "application/geo+json", body = Object),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "Not a member of `group`"),
+        (status = 422, description = "Invalid query parameters", body = ValidationErrorResponse)
+    ),
+    tag = "Litter",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/litter/export.geojson")]
+pub async fn export_geojson(
+    export: web::Query<ExportQuery>,
+    query: web::Query<LitterQuery>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<HttpResponse, HttpError> {
+    let area = export.bbox.as_deref().map(parse_bbox).transpose()?;
+    let (scope, filter) = query.scoped(&db, &usersession, area).await?;
+    let order = query.order.unwrap_or_default();
+
+    let reports =
+        services::litter::litter_stream(&db, usersession.id, scope, &filter, order).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/geo+json")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"litter.geojson\"",
+        ))
+        .streaming(services::export::geojson(reports)))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct TableExportQuery {
+    /// Comma separated columns, default all of `report_id,lat,lng,date,category,material,brand,weight_g,confidence`
+    columns: Option<String>,
+    /// Locale of the CSV numbers, e.g. `de-DE` for decimal commas and semicolons between
+    /// fields. Defaults to the `Accept-Language` header.
+    locale: Option<String>,
+}
+
+impl TableExportQuery {
+    fn columns(&self) -> Result<Vec<Column>, HttpError> {
+        services::export::parse_columns(self.columns.as_deref())
+            .map_err(|e| HttpError::ValidationFailed(vec![e]))
+    }
+
+    fn decimal_comma(&self, req: &HttpRequest) -> bool {
+        let accept_language = || {
+            req.headers()
+                .get(header::ACCEPT_LANGUAGE)?
+                .to_str()
+                .ok()?
+                .split([',', ';'])
+                .next()
+                .map(str::to_string)
+        };
+        self.locale
+            .clone()
+            .or_else(accept_language)
+            .is_some_and(|locale| services::export::decimal_comma(&locale))
+    }
+}
+
+/// One CSV row per detected item of the matching reports. The output is streamed,
+/// `limit` and `cursor` are ignored.
+#[utoipa::path(
+    get,
+    path = "/v1/protected/litter/export.csv",
+    params(ExportQuery, TableExportQuery, LitterQuery),
+    responses(
+        (status = 200, description = "CSV with a header row", content_type = "text/csv", body = String),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "Not a member of `group`"),
+        (status = 422, description = "Invalid query parameters", body = ValidationErrorResponse)
+    ),
+    tag = "Litter",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/litter/export.csv")]
+pub async fn export_csv(
+    req: HttpRequest,
+    export: web::Query<ExportQuery>,
+    table: web::Query<TableExportQuery>,
+    query: web::Query<LitterQuery>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<HttpResponse, HttpError> {
+    let area = export.bbox.as_deref().map(parse_bbox).transpose()?;
+    let (scope, filter) = query.scoped(&db, &usersession, area).await?;
+    let columns = table.columns()?;
+    let order = query.order.unwrap_or_default();
+
+    let reports =
+        services::litter::litter_stream(&db, usersession.id, scope, &filter, order).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"litter.csv\"",
+        ))
+        .streaming(services::export::csv(
+            reports,
+            columns,
+            table.decimal_comma(&req),
+        )))
+}
+
+/// One spreadsheet row per detected item of the matching reports, limited to 100000
+/// items. `limit`, `cursor` and `locale` are ignored.
+#[utoipa::path(
+    get,
+    path = "/v1/protected/litter/export.xlsx",
+    params(ExportQuery, TableExportQuery, LitterQuery),
+    responses(
+        (status = 200, description = "Excel workbook with a header row", content_type = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", body = Vec<u8>),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "Not a member of `group`"),
+        (status = 422, description = "Invalid query parameters or too many items", body = ValidationErrorResponse)
+    ),
+    tag = "Litter",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/litter/export.xlsx")]
+pub async fn export_xlsx(
+    export: web::Query<ExportQuery>,
+    table: web::Query<TableExportQuery>,
+    query: web::Query<LitterQuery>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<HttpResponse, HttpError> {
+    let area = export.bbox.as_deref().map(parse_bbox).transpose()?;
+    let (scope, filter) = query.scoped(&db, &usersession, area).await?;
+    let columns = table.columns()?;
+    let order = query.order.unwrap_or_default();
+
+    let reports =
+        services::litter::litter_stream(&db, usersession.id, scope, &filter, order).await?;
+    let workbook = services::export::xlsx(reports, columns).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"litter.xlsx\"",
+        ))
+        .body(workbook))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ClusterQuery {
+    /// `min_lng,min_lat,max_lng,max_lat` of the visible map
+    bbox: String,
+    /// Map zoom level, 0 to 22
+    zoom: u8,
+    /// `own` reports (default) or the `community` reports of all users
+    scope: Option<LitterScope>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClusterResponse {
+    zoom: u8,
+    clusters: Vec<Cluster>,
+}
+
+pub(crate) fn parse_bbox(bbox: &str) -> Result<GeoArea, HttpError> {
+    services::litter::parse_bbox(bbox).map_err(|e| HttpError::ValidationFailed(vec![e]))
+}
+
+/// Clusters whole map tiles, so cells near the edges may lie slightly outside the bbox.
+///
+/// Like every route that needs a signed in user it lives under `/v1/protected`; there is
+/// no unauthenticated `/v1/litter/clusters`.
+#[utoipa::path(
+    get,
+    path = "/v1/protected/litter/clusters",
+    params(ClusterQuery),
+    responses(
+        (status = 200, description = "Grid clusters of the reports in the bounding box", body = ClusterResponse),
+        (status = 401, description = "Invalid credentials"),
+        (status = 422, description = "Invalid bounding box or zoom", body = ValidationErrorResponse)
+    ),
+    tag = "Litter",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/litter/clusters")]
+pub async fn get_litter_clusters(
+    query: web::Query<ClusterQuery>,
+    db: web::Data<Database>,
+    cache: web::Data<ClusterCache>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let bbox = parse_bbox(&query.bbox)?;
+    let scope = query.scope.unwrap_or_default();
+
+    let clusters =
+        services::cluster::clusters(db, &cache, usersession.id, scope, &bbox, query.zoom).await?;
+
+    Ok(web::Json(ClusterResponse {
+        zoom: query.zoom,
+        clusters,
+    }))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct TileQuery {
+    /// `own` reports (default) or the `community` reports of all users
+    scope: Option<LitterScope>,
+}
+
+/// Whether the client's `If-None-Match` header already names `etag`.
+fn etag_matches(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
+
+/// Mapbox Vector Tile with a `litter` point layer (`id`, `category`, `weight` in grams,
+/// `date`) and a `density` layer of grid cell centres (`count`, `weight`). Tiles with
+/// more than 20000 reports only carry the density layer.
+#[utoipa::path(
+    get,
+    path = "/tiles/litter/{z}/{x}/{y}.mvt",
+    params(
+        ("z" = u8, Path, description = "Zoom level, 0 to 22"),
+        ("x" = u32, Path, description = "Tile column"),
+        ("y" = u32, Path, description = "Tile row"),
+        TileQuery
+    ),
+    responses(
+        (status = 200, description = "Vector tile, empty if there are no reports", content_type = "application/vnd.mapbox-vector-tile", body = Vec<u8>),
+        (status = 304, description = "Tile unchanged since the ETag in If-None-Match"),
+        (status = 401, description = "Invalid credentials"),
+        (status = 422, description = "Tile outside the zoom level", body = ValidationErrorResponse)
+    ),
+    tag = "Litter",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/tiles/litter/{z}/{x}/{y}.mvt")]
+pub async fn get_litter_tile(
+    req: HttpRequest,
+    path: web::Path<(u8, u32, u32)>,
+    query: web::Query<TileQuery>,
+    db: web::Data<Database>,
+    cache: web::Data<VectorTileCache>,
+    usersession: UserSession,
+) -> Result<HttpResponse, HttpError> {
+    let (z, x, y) = path.into_inner();
+    let scope = query.scope.unwrap_or_default();
+
+    let tile = services::mvt::litter_tile(&db, &cache, usersession.id, scope, z, x, y).await?;
+
+    let not_modified = etag_matches(&req, &tile.etag);
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    // Tiles depend on the session, so shared caches must not keep them.
+    response
+        .insert_header((header::ETAG, tile.etag.clone()))
+        .insert_header((header::CACHE_CONTROL, "private, max-age=60"))
+        .insert_header((header::VARY, "Authorization"));
+
+    if not_modified {
+        return Ok(response.finish());
+    }
+    Ok(response
+        .content_type("application/vnd.mapbox-vector-tile")
+        .body(tile.bytes.clone()))
+}
@@ -3,18 +3,41 @@ use actix_web::{
     http::StatusCode,
     web::{self, Json},
 };
+use futures::StreamExt;
 use serde_json::json;
 use utoipa::ToSchema;
 
 use derive_more::derive::{Display, Error};
+
+use crate::services::validation::FieldError;
+pub mod account;
+pub mod achievement;
+pub mod admin;
+pub mod api_token;
 pub mod auth;
+pub mod brand_report;
+pub mod challenge;
+pub mod cleanup_session;
+pub mod event;
+pub mod group;
+pub mod import;
+pub mod leaderboard;
 pub mod litter;
+pub mod oidc;
+pub mod stats;
+pub mod two_factor;
 
 #[derive(Debug, Serialize, ToSchema)]
 pub struct ErrorResponse {
     message: String,
 }
 
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ValidationErrorResponse {
+    message: String,
+    errors: Vec<FieldError>,
+}
+
 use serde::Serialize;
 
 #[derive(Debug, Display, Error)]
@@ -23,27 +46,122 @@ pub enum HttpError {
     InvalidCredentials,
     #[display("Invalid Token")]
     InvalidToken,
+    #[display("Insufficient permissions")]
+    Forbidden,
+    #[display("Account disabled")]
+    AccountDisabled,
+    #[display("Not found")]
+    NotFound,
     #[display("The provided username already exists")]
     UserAlreadyExists,
+    #[display("Invalid email address")]
+    InvalidEmail,
+    #[display("The provided email address is already in use")]
+    EmailAlreadyInUse,
+    #[display("This external identity is already linked to another account")]
+    IdentityAlreadyLinked,
+    #[display("Two-factor authentication is already enabled")]
+    TwoFactorAlreadyEnabled,
+    #[display("Two-factor authentication is not enabled")]
+    TwoFactorNotEnabled,
     #[display("Network error")]
     NetworkError,
+    #[display("Internal server error")]
+    InternalError,
+    #[display("Validation failed")]
+    ValidationFailed(#[error(not(source))] Vec<FieldError>),
+    #[display("Request body too large")]
+    PayloadTooLarge,
+    #[display("Another import added some of these reports, retry to skip them")]
+    ImportConflict,
+    #[display("You already take part in a running cleanup session")]
+    SessionAlreadyActive,
+    #[display("The cleanup session has ended")]
+    SessionEnded,
+    #[display("Other uploads kept changing the track, retry the upload")]
+    TrackChanged,
+    #[display("You are already a member of this group")]
+    AlreadyGroupMember,
+    #[display("A group needs a leader, make someone else leader first")]
+    LastGroupLeader,
+    #[display("You are already registered for this event")]
+    AlreadyRegistered,
+    #[display("The event is fully booked")]
+    EventFull,
+    #[display("The event has ended")]
+    EventEnded,
+    #[display("Too many requests, retry in {retry_after} seconds")]
+    TooManyRequests { retry_after: u64 },
 }
 
 impl ResponseError for HttpError {
     fn status_code(&self) -> actix_web::http::StatusCode {
-        match *self {
+        match self {
             Self::InvalidCredentials => StatusCode::UNAUTHORIZED,
             Self::InvalidToken => StatusCode::FORBIDDEN,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::AccountDisabled => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
             Self::UserAlreadyExists => StatusCode::CONFLICT,
+            Self::InvalidEmail => StatusCode::BAD_REQUEST,
+            Self::EmailAlreadyInUse => StatusCode::CONFLICT,
+            Self::IdentityAlreadyLinked => StatusCode::CONFLICT,
+            Self::TwoFactorAlreadyEnabled => StatusCode::CONFLICT,
+            Self::TwoFactorNotEnabled => StatusCode::CONFLICT,
             Self::NetworkError => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::ValidationFailed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::ImportConflict => StatusCode::CONFLICT,
+            Self::SessionAlreadyActive => StatusCode::CONFLICT,
+            Self::SessionEnded => StatusCode::CONFLICT,
+            Self::TrackChanged => StatusCode::CONFLICT,
+            Self::AlreadyGroupMember => StatusCode::CONFLICT,
+            Self::LastGroupLeader => StatusCode::CONFLICT,
+            Self::AlreadyRegistered => StatusCode::CONFLICT,
+            Self::EventFull => StatusCode::CONFLICT,
+            Self::EventEnded => StatusCode::CONFLICT,
+            Self::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
         }
     }
 
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        HttpResponse::build(self.status_code()).json(Json(json!({
-            "message": self.to_string()
-        })))
+        match self {
+            Self::ValidationFailed(errors) => {
+                HttpResponse::build(self.status_code()).json(Json(json!({
+                    "message": self.to_string(),
+                    "errors": errors,
+                })))
+            }
+            Self::TooManyRequests { retry_after } => HttpResponse::build(self.status_code())
+                .insert_header(("Retry-After", retry_after.to_string()))
+                .json(Json(json!({
+                    "message": self.to_string()
+                }))),
+            _ => HttpResponse::build(self.status_code()).json(Json(json!({
+                "message": self.to_string()
+            }))),
+        }
+    }
+}
+
+/// Reads a raw request body of at most `limit` bytes, for uploads that are not JSON.
+pub(crate) async fn read_body(
+    mut payload: web::Payload,
+    limit: usize,
+) -> Result<web::BytesMut, HttpError> {
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| {
+            log::info!("Failed to read request body: {:?}", e);
+            HttpError::NetworkError
+        })?;
+        if data.len() + chunk.len() > limit {
+            return Err(HttpError::PayloadTooLarge);
+        }
+        data.extend_from_slice(&chunk);
     }
+    Ok(data)
 }
 
 #[get("/")]
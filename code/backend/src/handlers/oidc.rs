@@ -0,0 +1,149 @@
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    handlers::HttpError,
+    services::{
+        account::frontend_url,
+        auth::{SigninOutcome, UserSession},
+        oidc::{OidcClient, OidcError, STATE_COOKIE},
+    },
+};
+
+impl From<OidcError> for HttpError {
+    fn from(err: OidcError) -> Self {
+        log::info!("OIDC operation failed with {:?}", err);
+
+        match err {
+            OidcError::InvalidState | OidcError::InvalidIdToken => HttpError::InvalidToken,
+            OidcError::AccountDisabled => HttpError::AccountDisabled,
+            OidcError::IdentityAlreadyLinked => HttpError::IdentityAlreadyLinked,
+            OidcError::ProviderError | OidcError::NetworkError => HttpError::NetworkError,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct OidcLinkResponse {
+    url: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+fn client(oidc: Option<web::Data<OidcClient>>) -> Result<web::Data<OidcClient>, HttpError> {
+    oidc.ok_or(HttpError::NotFound)
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/public/auth/oidc/login",
+    responses(
+        (status = 302, description = "Redirect to the identity provider"),
+        (status = 404, description = "No identity provider configured"),
+        (status = 500, description = "Identity provider unreachable")
+    ),
+    tag = "Authentication"
+)]
+#[get("/v1/public/auth/oidc/login")]
+pub async fn login(oidc: Option<web::Data<OidcClient>>) -> Result<impl Responder, HttpError> {
+    let (url, cookie) = client(oidc)?.start_login(None).await?;
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", url))
+        .cookie(cookie)
+        .finish())
+}
+
+/// Starts linking an identity to the current account. Sets the `oidc_state` cookie the
+/// callback requires, so the request has to be sent with credentials.
+#[utoipa::path(
+    post,
+    path = "/v1/protected/auth/oidc/link",
+    responses(
+        (status = 200, description = "Provider URL to open for linking the identity to the current account", body = OidcLinkResponse),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "No identity provider configured")
+    ),
+    tag = "Authentication",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/auth/oidc/link")]
+pub async fn link(
+    oidc: Option<web::Data<OidcClient>>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let (url, cookie) = client(oidc)?.start_login(Some(usersession.id)).await?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(cookie)
+        .json(json!({ "url": url })))
+}
+
+/// Redirect target of the identity provider. Sends the browser back to the frontend with
+/// the Delitter JWT in the fragment (`#jwt=...`) or an `error` query parameter. Accounts with
+/// 2FA get `#challenge=...` instead, redeemed at `/v1/public/auth/signin/2fa`. Only
+/// completes logins started in the same browser, as told by the `oidc_state` cookie.
+#[utoipa::path(
+    get,
+    path = "/v1/public/auth/oidc/callback",
+    params(OidcCallbackQuery),
+    responses(
+        (status = 302, description = "Redirect to the frontend with the JWT, a 2FA challenge or an error code"),
+        (status = 404, description = "No identity provider configured")
+    ),
+    tag = "Authentication"
+)]
+#[get("/v1/public/auth/oidc/callback")]
+pub async fn callback(
+    req: HttpRequest,
+    query: web::Query<OidcCallbackQuery>,
+    db: web::Data<Database>,
+    oidc: Option<web::Data<OidcClient>>,
+) -> Result<impl Responder, HttpError> {
+    let oidc = client(oidc)?;
+    let target = format!("{}/oidc-callback", frontend_url());
+
+    let result = match (&query.code, &query.state, &query.error) {
+        (Some(code), Some(state), None) => {
+            let cookie = req.cookie(STATE_COOKIE);
+            oidc.finish_login(db, code, state, cookie.as_ref().map(|c| c.value()))
+                .await
+        }
+        (_, _, Some(error)) => {
+            log::info!("Identity provider returned error {}", error);
+            Err(OidcError::ProviderError)
+        }
+        _ => Err(OidcError::InvalidState),
+    };
+
+    let location = match result {
+        Ok((id, SigninOutcome::Session(jwt))) => {
+            log::info!("OIDC login successful: {}", id);
+            format!("{target}#jwt={}", jwt.as_str())
+        }
+        Ok((id, SigninOutcome::TwoFactorRequired(challenge))) => {
+            log::info!("OIDC login of {} needs a second factor", id);
+            format!("{target}#challenge={challenge}")
+        }
+        Err(err) => {
+            log::info!("OIDC login failed with {:?}", err);
+            format!("{target}?error={}", err.code())
+        }
+    };
+
+    // The state is used up either way
+    Ok(HttpResponse::Found()
+        .append_header(("Location", location))
+        .cookie(oidc.state_cookie(String::new()))
+        .finish())
+}
@@ -0,0 +1,85 @@
+use actix_web::{
+    Responder, get,
+    web::{self},
+};
+use mongodb::Database;
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::{
+    handlers::{HttpError, ValidationErrorResponse, group::restrict_to_group},
+    services::{
+        self,
+        auth::UserSession,
+        litter::{LitterFilter, LitterScope, range_filter},
+        stats::{Interval, LitterStats},
+    },
+};
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct StatsQuery {
+    /// `own` reports (default) or the `community` reports of all users
+    scope: Option<LitterScope>,
+    /// Id of a group of the user: the reports of all its members instead
+    group: Option<String>,
+    /// Only reports inside `min_lng,min_lat,max_lng,max_lat`
+    bbox: Option<String>,
+    /// Reports at or after this date (YYYY-MM-DD or RFC 3339)
+    from: Option<String>,
+    /// Reports before this date (YYYY-MM-DD or RFC 3339)
+    to: Option<String>,
+    /// Bucket size of the time series: `day` (default), `week` or `month`
+    interval: Option<Interval>,
+    /// Impact factor table, e.g. `ch` or `de` (default from the configuration)
+    region: Option<String>,
+}
+
+impl StatsQuery {
+    fn filter(&self) -> Result<LitterFilter, HttpError> {
+        range_filter(
+            self.from.as_deref(),
+            self.to.as_deref(),
+            self.bbox.as_deref(),
+        )
+        .map_err(HttpError::ValidationFailed)
+    }
+}
+
+/// Totals, breakdowns by category, material and brand, a time series and the estimated
+/// environmental impact, computed over all matching reports.
+#[utoipa::path(
+    get,
+    path = "/v1/protected/stats",
+    params(StatsQuery),
+    responses(
+        (status = 200, description = "Statistics of the matching reports", body = LitterStats),
+        (status = 401, description = "Invalid credentials"),
+        (status = 404, description = "Not a member of `group`"),
+        (status = 422, description = "Invalid date, bounding box or region", body = ValidationErrorResponse)
+    ),
+    tag = "Litter",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[get("/v1/protected/stats")]
+pub async fn get_stats(
+    query: web::Query<StatsQuery>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let mut filter = query.filter()?;
+    let mut scope = query.scope.unwrap_or_default();
+    if let Some(group) = &query.group {
+        restrict_to_group(&db, &usersession, group, &mut filter).await?;
+        scope = LitterScope::Community;
+    }
+    let interval = query.interval.unwrap_or_default();
+    let region = services::impact::region(query.region.as_deref())
+        .map_err(|e| HttpError::ValidationFailed(vec![e]))?;
+
+    let stats =
+        services::stats::litter_stats(db, usersession.id, scope, &filter, interval, region).await?;
+
+    Ok(web::Json(stats))
+}
@@ -0,0 +1,155 @@
+use actix_web::{
+    Responder, delete, post,
+    web::{self},
+};
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::{
+    handlers::HttpError,
+    middleware::AuthRateLimit,
+    services::{
+        self,
+        auth::{TwoFactorError, UserSession},
+    },
+};
+
+impl From<TwoFactorError> for HttpError {
+    fn from(err: TwoFactorError) -> Self {
+        log::info!("Two-factor operation failed with {:?}", err);
+
+        match err {
+            TwoFactorError::AlreadyEnabled => HttpError::TwoFactorAlreadyEnabled,
+            TwoFactorError::NotEnabled => HttpError::TwoFactorNotEnabled,
+            TwoFactorError::InvalidCode => HttpError::InvalidCredentials,
+            TwoFactorError::NetworkError => HttpError::NetworkError,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TwoFactorSetupResponse {
+    /// Base32 secret for manual entry
+    secret: String,
+    /// `otpauth://` URI to show as QR code
+    provisioning_uri: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TwoFactorCodeData {
+    /// TOTP code, or a recovery code where accepted
+    code: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RecoveryCodesResponse {
+    /// One-time codes, only shown once
+    recovery_codes: Vec<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/protected/account/2fa/setup",
+    responses(
+        (status = 200, description = "Pending secret created, confirm it with a code", body = TwoFactorSetupResponse),
+        (status = 401, description = "Invalid credentials"),
+        (status = 409, description = "Two-factor authentication already enabled")
+    ),
+    tag = "Account",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/account/2fa/setup")]
+pub async fn setup(
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let (secret, provisioning_uri) = services::auth::setup_two_factor(db, usersession.id).await?;
+
+    Ok(web::Json(TwoFactorSetupResponse {
+        secret,
+        provisioning_uri,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/protected/account/2fa/enable",
+    request_body = TwoFactorCodeData,
+    responses(
+        (status = 200, description = "Two-factor authentication enabled", body = RecoveryCodesResponse),
+        (status = 401, description = "Invalid code"),
+        (status = 409, description = "No pending setup, or already enabled"),
+        (status = 429, description = "Too many attempts, see Retry-After header")
+    ),
+    tag = "Account",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/account/2fa/enable", wrap = "AuthRateLimit")]
+pub async fn enable(
+    data: web::Json<TwoFactorCodeData>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let recovery_codes = services::auth::enable_two_factor(db, usersession.id, &data.code).await?;
+
+    Ok(web::Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/protected/account/2fa",
+    request_body = TwoFactorCodeData,
+    responses(
+        (status = 200, description = "Two-factor authentication disabled"),
+        (status = 401, description = "Invalid code"),
+        (status = 409, description = "Two-factor authentication not enabled"),
+        (status = 429, description = "Too many attempts, see Retry-After header")
+    ),
+    tag = "Account",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[delete("/v1/protected/account/2fa", wrap = "AuthRateLimit")]
+pub async fn disable(
+    data: web::Json<TwoFactorCodeData>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    services::auth::disable_two_factor(db, usersession.id, &data.code).await?;
+
+    Ok(web::Json(json!({ "two_factor_enabled": false })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/protected/account/2fa/recovery-codes",
+    request_body = TwoFactorCodeData,
+    responses(
+        (status = 200, description = "New recovery codes, the old ones are void", body = RecoveryCodesResponse),
+        (status = 401, description = "Invalid code"),
+        (status = 409, description = "Two-factor authentication not enabled"),
+        (status = 429, description = "Too many attempts, see Retry-After header")
+    ),
+    tag = "Account",
+    security(
+        ("bearer_auth" = [])
+    )
+)]
+#[post("/v1/protected/account/2fa/recovery-codes", wrap = "AuthRateLimit")]
+pub async fn regenerate_recovery_codes(
+    data: web::Json<TwoFactorCodeData>,
+    db: web::Data<Database>,
+    usersession: UserSession,
+) -> Result<impl Responder, HttpError> {
+    let recovery_codes =
+        services::auth::regenerate_recovery_codes(db, usersession.id, &data.code).await?;
+
+    Ok(web::Json(RecoveryCodesResponse { recovery_codes }))
+}
@@ -1,7 +1,7 @@
 use actix_cors::Cors;
 use actix_web::{web, App, HttpServer};
 use dotenvy::dotenv;
-use log::info;
+use log::{error, info, warn};
 use mongodb::{
     bson::doc,
     Client,
@@ -11,6 +11,7 @@ use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
 mod handlers;
+mod middleware;
 mod models;
 mod services;
 
@@ -21,27 +22,177 @@ mod services;
         handlers::version,
         handlers::auth::signup,
         handlers::auth::signin,
+        handlers::auth::signin_two_factor,
+        handlers::oidc::login,
+        handlers::oidc::link,
+        handlers::oidc::callback,
+        handlers::account::set_email,
+        handlers::account::verify_email,
+        handlers::account::request_password_reset,
+        handlers::account::confirm_password_reset,
+        handlers::api_token::create_token,
+        handlers::api_token::list_tokens,
+        handlers::api_token::revoke_token,
+        handlers::two_factor::setup,
+        handlers::two_factor::enable,
+        handlers::two_factor::disable,
+        handlers::two_factor::regenerate_recovery_codes,
         handlers::litter::create_litter,
         handlers::litter::get_litter,
+        handlers::litter::get_litter_in_bbox,
+        handlers::litter::get_litter_in_radius,
+        handlers::litter::get_litter_in_polygon,
+        handlers::litter::export_geojson,
+        handlers::litter::export_csv,
+        handlers::litter::export_xlsx,
+        handlers::import::import_litter,
+        handlers::cleanup_session::start_session,
+        handlers::cleanup_session::stop_session,
+        handlers::cleanup_session::join_session,
+        handlers::cleanup_session::leave_session,
+        handlers::cleanup_session::list_sessions,
+        handlers::cleanup_session::get_session,
+        handlers::cleanup_session::get_track,
+        handlers::cleanup_session::add_track_points,
+        handlers::cleanup_session::import_track,
+        handlers::cleanup_session::export_gpx,
+        handlers::cleanup_session::export_kml,
+        handlers::group::create_group,
+        handlers::group::list_groups,
+        handlers::group::join_group,
+        handlers::group::get_group,
+        handlers::group::delete_group,
+        handlers::group::leave_group,
+        handlers::group::renew_invite,
+        handlers::group::set_member_role,
+        handlers::group::remove_member,
+        handlers::event::create_event,
+        handlers::event::list_own_events,
+        handlers::event::register,
+        handlers::event::unregister,
+        handlers::event::delete_event,
+        handlers::event::list_events,
+        handlers::event::get_event,
+        handlers::event::get_results,
+        handlers::leaderboard::get_leaderboard,
+        handlers::leaderboard::list_regions,
+        handlers::leaderboard::get_settings,
+        handlers::leaderboard::set_settings,
+        handlers::achievement::list_achievements,
+        handlers::achievement::get_achievements,
+        handlers::challenge::create_challenge,
+        handlers::challenge::list_challenges,
+        handlers::challenge::get_challenge,
+        handlers::challenge::delete_challenge,
+        handlers::litter::get_litter_clusters,
+        handlers::litter::get_litter_tile,
+        handlers::stats::get_stats,
+        handlers::brand_report::get_brand_report,
+        handlers::admin::list_users,
+        handlers::admin::set_role,
+        handlers::admin::set_disabled,
+        handlers::achievement::backfill,
     ),
     components(
         schemas(
             handlers::auth::SignupData,
             handlers::auth::LoginData,
             handlers::auth::AuthResponse,
+            handlers::auth::TwoFactorChallengeResponse,
+            handlers::auth::TwoFactorSigninData,
             handlers::auth::Claims,
+            handlers::oidc::OidcLinkResponse,
+            handlers::account::EmailData,
+            handlers::account::VerifyEmailData,
+            handlers::account::PasswordResetRequestData,
+            handlers::account::PasswordResetConfirmData,
+            handlers::account::MessageResponse,
+            handlers::api_token::ApiTokenCreateData,
+            handlers::api_token::ApiTokenData,
+            handlers::api_token::ApiTokenCreateResponse,
+            models::api_token::TokenScope,
+            handlers::two_factor::TwoFactorSetupResponse,
+            handlers::two_factor::TwoFactorCodeData,
+            handlers::two_factor::RecoveryCodesResponse,
             handlers::litter::LitterData,
             handlers::litter::LitterGetData,
             handlers::litter::LitterCreateResponse,
+            models::litter::AnalysisStatus,
+            services::litter::SortOrder,
+            services::litter::LitterScope,
+            handlers::litter::ClusterResponse,
+            services::cluster::Cluster,
+            services::stats::Interval,
+            services::stats::LitterStats,
+            services::stats::Breakdown,
+            services::stats::BreakdownEntry,
+            services::stats::TimeBucket,
+            services::impact::Impact,
+            handlers::brand_report::ReportFormat,
+            services::brand_report::BrandReport,
+            services::brand_report::BrandSummary,
+            services::brand_report::MaterialCount,
+            services::brand_report::Hotspot,
+            services::brand_report::ExamplePhoto,
+            services::import::ImportFormat,
+            services::import::ImportReport,
+            services::import::RowError,
+            handlers::cleanup_session::SessionStartData,
+            handlers::cleanup_session::TrackData,
+            handlers::cleanup_session::TrackPointGetData,
+            services::cleanup_session::TrackPointData,
+            services::cleanup_session::SessionSummary,
+            services::track::TrackFormat,
+            handlers::group::GroupCreateData,
+            handlers::group::GroupJoinData,
+            handlers::group::GroupRoleData,
+            models::group::GroupRole,
+            services::group::GroupSummary,
+            services::group::GroupMemberData,
+            services::group::GroupDetail,
+            handlers::event::EventCreateData,
+            services::event::EventStatus,
+            services::event::EventData,
+            services::event::EventMapPoint,
+            services::event::EventResults,
+            services::leaderboard::Metric,
+            services::leaderboard::Period,
+            services::leaderboard::Leaderboard,
+            services::leaderboard::LeaderboardEntry,
+            services::leaderboard::LeaderboardRegion,
+            services::leaderboard::LeaderboardSettings,
+            services::achievement::Counted,
+            services::achievement::Per,
+            services::achievement::Achievement,
+            services::achievement::AchievementStatus,
+            services::achievement::AchievementBackfill,
+            handlers::challenge::ChallengeCreateData,
+            models::challenge::ChallengeMetric,
+            services::challenge::ChallengeStatus,
+            services::challenge::ChallengeData,
             handlers::litter::Claims,
+            handlers::admin::AdminUserData,
+            handlers::admin::RoleData,
+            handlers::admin::DisabledData,
+            models::user::Role,
             handlers::ErrorResponse,
+            handlers::ValidationErrorResponse,
+            services::validation::FieldError,
             handlers::VersionResponse,
         )
     ),
     tags(
         (name = "Health", description = "Health check endpoints"),
         (name = "Authentication", description = "User authentication endpoints"),
-        (name = "Litter", description = "Litter management endpoints")
+        (name = "Account", description = "Email verification, password recovery, API tokens and two-factor authentication"),
+        (name = "Litter", description = "Litter management endpoints"),
+        (name = "Sessions", description = "Cleanup outings with participants, GPS track and totals"),
+        (name = "Groups", description = "Scout troops, schools, companies and other teams with shared reports and statistics"),
+        (name = "Events", description = "Public cleanup days with registration and combined results"),
+        (name = "Leaderboards", description = "Rankings by items, weight and sessions per period, group and region"),
+        (name = "Achievements", description = "Badges earned with reports, declared as rules in config"),
+        (name = "Challenges", description = "Goals of groups and users with live progress and completion mails"),
+        (name = "Admin", description = "User management for moderators and admins")
     ),
     modifiers(&SecurityAddon)
 )]
@@ -79,11 +230,43 @@ async fn main() -> std::io::Result<()> {
     let db = client.database(db_name);
     let users = db.collection::<mongodb::bson::Document>("users");
 
-    if let Err(e) = ensure_indexes(&users).await {
-        eprintln!("⚠️ Failed to ensure MongoDB indexes: {:?}", e);
-    } else {
-        info!("✅ Ensured MongoDB indexes for 'users' collection");
-    }
+    // Before the 2dsphere index, so older reports are found by geo queries.
+    services::litter::backfill_locations(&db).await;
+
+    ensure_indexes(&users).await;
+
+    services::api_token::ensure_indexes(&db).await;
+    services::cleanup_session::ensure_indexes(&db).await;
+    services::cleanup_session::backfill_track_totals(&db).await;
+    services::group::ensure_indexes(&db).await;
+    services::event::ensure_indexes(&db).await;
+    services::leaderboard::ensure_indexes(&db).await;
+    services::challenge::ensure_indexes(&db).await;
+    services::auth::init();
+    services::rate_limit::init();
+    services::impact::init();
+    services::track::init();
+    services::leaderboard::init();
+    services::achievement::init();
+    services::admin::promote_bootstrap_admins(&db).await;
+    services::leaderboard::spawn_refresh(db.clone());
+
+    let mailer = web::Data::from(services::mailer::from_env());
+    let reset_limiter = web::Data::new(services::account::PasswordResetLimiter::new());
+    let auth_limiter = web::Data::new(services::rate_limit::AuthLimiter::from_env(&db).await);
+    let cluster_cache = web::Data::new(services::cluster::ClusterCache::new());
+    let tile_cache = web::Data::new(services::mvt::VectorTileCache::new());
+    let results_cache = web::Data::new(services::event::ResultsCache::new());
+
+    let oidc = match services::oidc::OidcConfig::from_env() {
+        Some(config) => {
+            info!("🔑 OpenID Connect login via {}", config.issuer_url);
+            Some(web::Data::new(
+                services::oidc::OidcClient::new(config, &db).await,
+            ))
+        }
+        None => None,
+    };
 
     let port: u16 = env::var("PORT")
         .map(|p| p.parse().expect("Port must be a valid 16-bit integer"))
@@ -98,6 +281,17 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .wrap(Cors::permissive())
             .app_data(web::Data::new(db.clone()))
+            .app_data(mailer.clone())
+            .app_data(reset_limiter.clone())
+            .app_data(auth_limiter.clone())
+            .app_data(cluster_cache.clone())
+            .app_data(tile_cache.clone())
+            .app_data(results_cache.clone())
+            .configure(|cfg| {
+                if let Some(oidc) = &oidc {
+                    cfg.app_data(oidc.clone());
+                }
+            })
             .service(
                 SwaggerUi::new("/docs/{_:.*}")
                     .url("/api-docs/openapi.json", openapi.clone())
@@ -106,29 +300,204 @@ async fn main() -> std::io::Result<()> {
             .service(handlers::alive)
             .service(handlers::version)
             .service(handlers::auth::signin)
+            .service(handlers::auth::signin_two_factor)
             .service(handlers::auth::signup)
+            .service(handlers::oidc::login)
+            .service(handlers::oidc::link)
+            .service(handlers::oidc::callback)
+            .service(handlers::account::set_email)
+            .service(handlers::account::verify_email)
+            .service(handlers::account::request_password_reset)
+            .service(handlers::account::confirm_password_reset)
+            .service(handlers::api_token::create_token)
+            .service(handlers::api_token::list_tokens)
+            .service(handlers::api_token::revoke_token)
+            .service(handlers::two_factor::setup)
+            .service(handlers::two_factor::enable)
+            .service(handlers::two_factor::disable)
+            .service(handlers::two_factor::regenerate_recovery_codes)
             .service(handlers::litter::create_litter)
             .service(handlers::litter::get_litter)
+            .service(handlers::litter::get_litter_in_bbox)
+            .service(handlers::litter::get_litter_in_radius)
+            .service(handlers::litter::get_litter_in_polygon)
+            .service(handlers::litter::export_geojson)
+            .service(handlers::litter::export_csv)
+            .service(handlers::litter::export_xlsx)
+            .service(handlers::import::import_litter)
+            .service(handlers::cleanup_session::start_session)
+            .service(handlers::cleanup_session::stop_session)
+            .service(handlers::cleanup_session::join_session)
+            .service(handlers::cleanup_session::leave_session)
+            .service(handlers::cleanup_session::list_sessions)
+            .service(handlers::cleanup_session::get_session)
+            .service(handlers::cleanup_session::get_track)
+            .service(handlers::cleanup_session::add_track_points)
+            .service(handlers::cleanup_session::import_track)
+            .service(handlers::cleanup_session::export_gpx)
+            .service(handlers::cleanup_session::export_kml)
+            .service(handlers::group::create_group)
+            .service(handlers::group::list_groups)
+            .service(handlers::group::join_group)
+            .service(handlers::group::get_group)
+            .service(handlers::group::delete_group)
+            .service(handlers::group::leave_group)
+            .service(handlers::group::renew_invite)
+            .service(handlers::group::set_member_role)
+            .service(handlers::group::remove_member)
+            .service(handlers::event::create_event)
+            .service(handlers::event::list_own_events)
+            .service(handlers::event::register)
+            .service(handlers::event::unregister)
+            .service(handlers::event::delete_event)
+            .service(handlers::event::list_events)
+            .service(handlers::event::get_event)
+            .service(handlers::event::get_results)
+            .service(handlers::leaderboard::get_leaderboard)
+            .service(handlers::leaderboard::list_regions)
+            .service(handlers::leaderboard::get_settings)
+            .service(handlers::leaderboard::set_settings)
+            .service(handlers::achievement::list_achievements)
+            .service(handlers::achievement::get_achievements)
+            .service(handlers::challenge::create_challenge)
+            .service(handlers::challenge::list_challenges)
+            .service(handlers::challenge::get_challenge)
+            .service(handlers::challenge::delete_challenge)
+            .service(handlers::litter::get_litter_clusters)
+            .service(handlers::litter::get_litter_tile)
+            .service(handlers::stats::get_stats)
+            .service(handlers::brand_report::get_brand_report)
+            .service(handlers::admin::list_users)
+            .service(handlers::admin::set_role)
+            .service(handlers::admin::set_disabled)
+            .service(handlers::achievement::backfill)
     })
     .bind(("0.0.0.0", port))?
     .run()
     .await
 }
 
-async fn ensure_indexes(users: &mongodb::Collection<mongodb::bson::Document>) -> mongodb::error::Result<()> {
+/// Creates the indexes of the users collection one by one, so one that existing data
+/// violates does not keep the others from being built.
+async fn ensure_indexes(users: &mongodb::Collection<mongodb::bson::Document>) {
     use mongodb::options::IndexOptions;
 
+    // Usernames are unique regardless of case ("Bob" and "bob" are the same user).
     let index_model = mongodb::IndexModel::builder()
         .keys(doc! { "username": 1 })
         .options(
             IndexOptions::builder()
                 .unique(true)
-                .name(Some("unique_username".to_string()))
+                .collation(models::user::username_collation())
+                .name(Some("unique_username_ci".to_string()))
                 .build(),
         )
         .build();
 
-    users.create_index(index_model).await?;
-    Ok(())
+    // Only verified addresses are unique, so nobody can block an address by claiming it.
+    let email_index = mongodb::IndexModel::builder()
+        .keys(doc! { "email": 1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .partial_filter_expression(doc! { "email_verified": true })
+                .name(Some("unique_verified_email".to_string()))
+                .build(),
+        )
+        .build();
+
+    let identity_index = mongodb::IndexModel::builder()
+        .keys(doc! { "external_identities.issuer": 1, "external_identities.subject": 1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .partial_filter_expression(doc! { "external_identities": { "$exists": true } })
+                .name(Some("unique_external_identity".to_string()))
+                .build(),
+        )
+        .build();
+
+    // Pseudonyms are unique regardless of case as well, signups check them against usernames.
+    let pseudonym_index = mongodb::IndexModel::builder()
+        .keys(doc! { "pseudonym": 1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .collation(models::user::username_collation())
+                .partial_filter_expression(doc! { "pseudonym": { "$exists": true } })
+                .name(Some("unique_pseudonym_ci".to_string()))
+                .build(),
+        )
+        .build();
+
+    let location_index = mongodb::IndexModel::builder()
+        .keys(doc! { "litter.location": "2dsphere" })
+        .options(
+            IndexOptions::builder()
+                .name(Some("litter_location_2dsphere".to_string()))
+                .build(),
+        )
+        .build();
+
+    let indexes = [
+        (index_model, Some("username")),
+        (email_index, None),
+        (identity_index, None),
+        (pseudonym_index, Some("pseudonym")),
+        (location_index, None),
+    ];
+    let mut failed = false;
+    for (index, case_insensitive) in indexes {
+        let name = index.options.as_ref().and_then(|o| o.name.clone()).unwrap_or_default();
+        if let Err(e) = users.create_index(index).await {
+            failed = true;
+            error!("Failed to create index '{}' on 'users': {:?}", name, e);
+            if let Some(field) = case_insensitive {
+                log_case_duplicates(users, field).await;
+            }
+        }
+    }
+    if !failed {
+        info!("✅ Ensured MongoDB indexes for 'users' collection");
+    }
+
+    // Superseded by the case-insensitive index above.
+    if users.drop_index("unique_username").await.is_ok() {
+        info!("Dropped legacy case-sensitive 'unique_username' index");
+    }
+}
+
+/// Logs the values of `field` that differ only by case, which keep its case-insensitive
+/// unique index from being built until they are renamed.
+async fn log_case_duplicates(users: &mongodb::Collection<mongodb::bson::Document>, field: &str) {
+    use futures::TryStreamExt;
+
+    let pipeline = vec![
+        doc! { "$match": { field: { "$type": "string" } } },
+        doc! { "$group": {
+            "_id": { "$toLower": format!("${field}") },
+            "values": { "$push": format!("${field}") },
+            "count": { "$sum": 1 },
+        } },
+        doc! { "$match": { "count": { "$gt": 1 } } },
+    ];
+    let duplicates: Result<Vec<mongodb::bson::Document>, _> = match users.aggregate(pipeline).await {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    match duplicates {
+        Ok(duplicates) => {
+            for duplicate in duplicates {
+                let values: Vec<&str> = duplicate
+                    .get_array("values")
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|v| v.as_str())
+                    .collect();
+                warn!("Users with the {} {} differ only by case", field, values.join(", "));
+            }
+        }
+        Err(e) => error!("Failed to look for duplicate {}s: {:?}", field, e),
+    }
 }
 
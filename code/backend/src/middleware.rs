@@ -0,0 +1,114 @@
+use std::{
+    future::{Ready, ready},
+    rc::Rc,
+};
+
+use actix_web::{
+    Error,
+    dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+    http::StatusCode,
+    web,
+};
+use futures::future::LocalBoxFuture;
+use serde::Deserialize;
+
+use crate::{
+    handlers::HttpError,
+    services::{self, rate_limit::AuthLimiter},
+};
+
+/// Throttles an authentication endpoint per client ip and per username.
+///
+/// Uses the `AuthLimiter` registered as app data. Responses with `401` count as failed attempts
+/// and lead to exponential lockouts, `200` responses clear the failures of the username. A `202`
+/// (password accepted, second factor pending) keeps them, so code guesses cannot reset the count.
+pub struct AuthRateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for AuthRateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AuthRateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthRateLimitMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AuthRateLimitMiddleware<S> {
+    service: Rc<S>,
+}
+
+#[derive(Deserialize)]
+struct UsernameBody {
+    username: Option<String>,
+    /// 2FA challenge, whose account is throttled like a username.
+    challenge: Option<String>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthRateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            let Some(limiter) = req.app_data::<web::Data<AuthLimiter>>().cloned() else {
+                log::warn!("AuthRateLimit used without an AuthLimiter, request not throttled");
+                return service.call(req).await;
+            };
+
+            let ip = services::rate_limit::client_ip(req.peer_addr(), req.headers());
+
+            // Peek at the json body for the username and put it back for the handler.
+            let body = req.extract::<web::Bytes>().await?;
+            let username = serde_json::from_slice::<UsernameBody>(&body)
+                .ok()
+                .and_then(|b| {
+                    b.username.or_else(|| {
+                        b.challenge
+                            .as_deref()
+                            .and_then(services::auth::two_factor_username)
+                    })
+                })
+                .map(|u| u.trim().to_lowercase())
+                .filter(|u| !u.is_empty());
+            req.set_payload(Payload::from(body));
+
+            if let Some(wait) = limiter.check(&ip, username.as_deref()).await {
+                log::info!("Throttled auth request from {ip} for {:?}", username);
+                return Err(HttpError::TooManyRequests {
+                    retry_after: wait.as_secs().max(1),
+                }
+                .into());
+            }
+
+            let res = service.call(req).await?;
+
+            if res.status() == StatusCode::UNAUTHORIZED {
+                limiter.record_failure(&ip, username.as_deref()).await;
+            } else if res.status() == StatusCode::OK
+                && let Some(username) = &username
+            {
+                limiter.record_success(username).await;
+            }
+
+            Ok(res)
+        })
+    }
+}
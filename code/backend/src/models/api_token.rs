@@ -0,0 +1,27 @@
+use mongodb::bson::{DateTime, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// What a personal API token may do. Both scopes may read, only `upload` may report litter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    ReadOnly,
+    Upload,
+}
+
+/// Long-lived token for scripts, stored only as its SHA-256 hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub _id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub name: String,
+    pub scope: TokenScope,
+    pub token_hash: String,
+    /// First characters of the token, so users can tell their tokens apart.
+    pub prefix: String,
+    pub created_at: DateTime,
+    #[serde(default)]
+    pub last_used_at: Option<DateTime>,
+}
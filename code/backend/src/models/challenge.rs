@@ -0,0 +1,44 @@
+use mongodb::bson::{DateTime, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// What a challenge adds up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChallengeMetric {
+    /// Detected pieces of litter
+    Items,
+    /// Estimated weight in grams
+    Weight,
+    Reports,
+}
+
+/// Goal such as "collect 50 kg before the end of the summer camp". Counts the reports of a
+/// group's members, or of its owner for personal challenges, between `starts_at` and
+/// `deadline`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Challenge {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub _id: Option<ObjectId>,
+    pub title: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Personal challenge of the owner if missing.
+    #[serde(default)]
+    pub group_id: Option<ObjectId>,
+    pub owner: ObjectId,
+    pub metric: ChallengeMetric,
+    /// Only items of this category count.
+    #[serde(default)]
+    pub category: Option<String>,
+    /// Only items of this material count.
+    #[serde(default)]
+    pub material: Option<String>,
+    pub target: f64,
+    pub starts_at: DateTime,
+    pub deadline: DateTime,
+    /// Set once when the target is reached, members are notified then.
+    #[serde(default)]
+    pub completed_at: Option<DateTime>,
+    pub created_at: DateTime,
+}
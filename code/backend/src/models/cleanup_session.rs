@@ -0,0 +1,46 @@
+use mongodb::bson::{DateTime, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+
+/// Position recorded during an outing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackPoint {
+    pub lat: f64,
+    pub lng: f64,
+    /// Missing on points imported from files without times, e.g. KML lines.
+    #[serde(default)]
+    pub time: Option<DateTime>,
+    /// Continuous parts of the track, the gaps between them were not walked.
+    #[serde(default)]
+    pub segment: u32,
+}
+
+/// Outing in which one or more users pick up litter. Reports collected during it carry
+/// its id as `session_id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CleanupSession {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub _id: Option<ObjectId>,
+    pub owner: ObjectId,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Users taking part, including the owner.
+    pub participants: Vec<ObjectId>,
+    /// Users asked to take part who have not joined yet.
+    #[serde(default)]
+    pub invited: Vec<ObjectId>,
+    pub started_at: DateTime,
+    /// Missing while the session is running.
+    #[serde(default)]
+    pub ended_at: Option<DateTime>,
+    /// In the order points arrived, see `services::track::ordered`.
+    #[serde(default)]
+    pub track: Vec<TrackPoint>,
+    /// Counts changes of the track, imports only write it if nobody changed it in between.
+    #[serde(default)]
+    pub track_version: i64,
+    /// Walked length of `track` in metres, stored so sessions can be listed without it.
+    #[serde(default)]
+    pub distance_m: f64,
+    #[serde(default)]
+    pub track_points: usize,
+}
@@ -0,0 +1,56 @@
+use mongodb::bson::{DateTime, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+
+/// GeoJSON polygon with a single outer ring of `[lng, lat]` points.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeoPolygon {
+    pub r#type: String,
+    pub coordinates: Vec<Vec<[f64; 2]>>,
+}
+
+impl GeoPolygon {
+    /// Polygon of the ring, closed if its last point differs from the first.
+    pub fn new(mut ring: Vec<[f64; 2]>) -> Self {
+        if let Some(first) = ring.first().copied()
+            && ring.last() != Some(&first)
+        {
+            ring.push(first);
+        }
+        GeoPolygon {
+            r#type: "Polygon".to_string(),
+            coordinates: vec![ring],
+        }
+    }
+
+    pub fn ring(&self) -> &[[f64; 2]] {
+        self.coordinates.first().map_or(&[], Vec::as_slice)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventParticipant {
+    pub user_id: ObjectId,
+    pub registered_at: DateTime,
+}
+
+/// Public cleanup day. Reports of registered participants inside `area` and between
+/// `starts_at` and `ends_at` count for the event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub _id: Option<ObjectId>,
+    pub title: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub organiser: ObjectId,
+    /// Indexed, which also makes the database reject invalid polygons.
+    pub area: GeoPolygon,
+    pub starts_at: DateTime,
+    pub ends_at: DateTime,
+    /// Most participants that can register, unlimited if missing.
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    #[serde(default)]
+    pub participants: Vec<EventParticipant>,
+    pub created_at: DateTime,
+}
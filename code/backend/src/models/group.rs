@@ -0,0 +1,34 @@
+use mongodb::bson::{DateTime, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// What a member may do in a group. Leaders manage members and invites, every member sees
+/// the group's reports and statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupRole {
+    Leader,
+    Member,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMember {
+    pub user_id: ObjectId,
+    pub role: GroupRole,
+    pub joined_at: DateTime,
+}
+
+/// Scout troop, school class, company or other team collecting litter together.
+/// A group always has at least one leader.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Group {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub _id: Option<ObjectId>,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Secret that lets users join, replaced when leaders revoke it.
+    pub invite_code: String,
+    pub members: Vec<GroupMember>,
+    pub created_at: DateTime,
+}
@@ -4,15 +4,43 @@ use mongodb::{
     bson::{self, Binary, doc, oid::ObjectId},
 };
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 use super::user::User;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalysisStatus {
+    Pending,
+    Done,
+    Failed,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Entry {
     pub category: Option<String>,
     pub material: Option<String>,
     pub weight: Option<f64>,
     pub brand: Option<String>,
+    /// Certainty of the image analysis, from 0 to 1. Missing on older entries.
+    #[serde(default)]
+    pub confidence: Option<f64>,
+}
+
+/// GeoJSON point as required by the `2dsphere` index, coordinates are `[lng, lat]`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GeoPoint {
+    pub r#type: String,
+    pub coordinates: [f64; 2],
+}
+
+impl GeoPoint {
+    pub fn new(lng: f64, lat: f64) -> Self {
+        GeoPoint {
+            r#type: "Point".to_string(),
+            coordinates: [lng, lat],
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -20,20 +48,41 @@ pub struct Litter {
     pub _id: ObjectId,
     pub lng: f64,
     pub lat: f64,
+    /// Same position as `lng`/`lat`, indexed for geo queries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<GeoPoint>,
     pub file: Option<Binary>,
     pub r#type: String,
     #[serde(default)]
     pub entries: Vec<Entry>,
     pub time_stamp: mongodb::bson::DateTime,
+    /// Missing on reports from before the status was tracked, see `analysis_status`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<AnalysisStatus>,
+    /// Set on reports from a bulk import, recognises them when the file is imported again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub import_key: Option<String>,
+    /// Cleanup session the report was collected in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<ObjectId>,
 }
 
 impl Litter {
+    /// Older reports count as analysed once they have entries.
+    pub fn analysis_status(&self) -> AnalysisStatus {
+        match self.status {
+            Some(status) => status,
+            None if self.entries.is_empty() => AnalysisStatus::Pending,
+            None => AnalysisStatus::Done,
+        }
+    }
+
     fn collection(db: &web::Data<Database>) -> Collection<User> {
         db.collection::<User>("users")
     }
 
     pub async fn persist(&self, db: &web::Data<Database>, user_id: ObjectId) -> Option<ObjectId> {
-        let collection = Self::collection(&db);
+        let collection = Self::collection(db);
 
         let filter = doc! {
             "_id": user_id,
@@ -44,10 +93,12 @@ impl Litter {
             "$set": {
                 "litter.$.lng": self.lng,
                 "litter.$.lat": self.lat,
+                "litter.$.location": bson::to_bson(&self.location).ok()?,
                 "litter.$.file": self.file.clone(),  // Updated to use Binary
                 "litter.$.type": &self.r#type,
                 "litter.$.entries": bson::to_bson(&self.entries).ok()?,
                 "litter.$.time_stamp": self.time_stamp,
+                "litter.$.status": bson::to_bson(&self.status).ok()?,
             }
         };
 
@@ -55,7 +106,7 @@ impl Litter {
         match collection.update_one(filter, update).await {
             Ok(update_result) => {
                 if update_result.matched_count == 1 {
-                    Some(self._id.clone())
+                    Some(self._id)
                 } else {
                     None
                 }
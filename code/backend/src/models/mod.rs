@@ -1,2 +1,7 @@
+pub mod api_token;
+pub mod challenge;
+pub mod cleanup_session;
+pub mod event;
+pub mod group;
 pub mod user;
 pub mod litter;
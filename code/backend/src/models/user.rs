@@ -2,12 +2,66 @@ use actix_web::web;
 use log::error;
 use mongodb::{
     Collection, Database,
-    bson::{doc, oid::ObjectId, to_document},
+    bson::{DateTime, doc, oid::ObjectId, to_document},
+    options::{Collation, CollationStrength},
 };
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 use super::litter::Litter;
 
+/// Roles ordered by privilege, every role includes the rights of the ones before it.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    User,
+    GroupLeader,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::GroupLeader => "group_leader",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// Account at an external OpenID Connect provider that signs in as this user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExternalIdentity {
+    pub issuer: String,
+    pub subject: String,
+}
+
+/// Badge of a rule in the achievements config, see `services::achievement`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EarnedAchievement {
+    pub id: String,
+    /// Time of the report that reached the threshold.
+    pub earned_at: DateTime,
+}
+
+/// TOTP second factor. Until `enabled` is set, the secret is only a pending enrolment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TwoFactor {
+    pub secret: String,
+    pub enabled: bool,
+    /// Last time step a code was accepted for, so codes cannot be replayed.
+    #[serde(default)]
+    pub last_step: Option<i64>,
+    /// SHA-256 hashes of the unused recovery codes.
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct User {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -15,10 +69,44 @@ pub struct User {
     pub username: String,
     pub password_hash: String,
 
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub disabled: bool,
+
+    /// Hidden from global and regional leaderboards.
+    #[serde(default)]
+    pub leaderboard_opt_out: bool,
+    /// Shown on leaderboards instead of the username.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pseudonym: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub external_identities: Vec<ExternalIdentity>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub two_factor: Option<TwoFactor>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub achievements: Vec<EarnedAchievement>,
+
     #[serde(default)]
     pub litter: Vec<Litter>,
 }
 
+/// Case-insensitive comparison used for usernames, both for the unique index and lookups.
+pub fn username_collation() -> Collation {
+    Collation::builder()
+        .locale("en")
+        .strength(CollationStrength::Secondary)
+        .build()
+}
+
 impl User {
     fn collection(db: &web::Data<Database>) -> Collection<Self> {
         db.collection::<User>("users")
@@ -45,14 +133,4 @@ impl User {
             },
         }
     }
-
-    pub async fn from_id(db: &web::Data<Database>, id: ObjectId) -> Option<Self> {
-        match Self::collection(db).find_one(doc! {"_id": id}).await {
-            Ok(res) => res,
-            Err(e) => {
-                error!("failed to find user {:?}", e);
-                None
-            }
-        }
-    }
 }
@@ -0,0 +1,363 @@
+use std::{
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::web;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{DecodingKey, EncodingKey, Validation};
+use log::{error, info};
+use mongodb::{
+    Database,
+    bson::{doc, oid::ObjectId},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    models::user::User,
+    services::{
+        auth::{JWT_SECRET, hash_password},
+        mailer::{Mail, Mailer},
+        rate_limit::RateLimiter,
+        validation::{FieldError, validate_password},
+    },
+};
+
+/// Verification links stay valid for two days.
+const VERIFICATION_TOKEN_TTL: u64 = 2 * 24 * 60 * 60;
+/// Reset links are short-lived, one hour.
+const RESET_TOKEN_TTL: u64 = 60 * 60;
+
+#[derive(Debug, Clone, Serialize)]
+pub enum AccountError {
+    InvalidInput(Vec<FieldError>),
+    InvalidEmail,
+    EmailAlreadyInUse,
+    InvalidToken,
+    NetworkError,
+    UnknownError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TokenPurpose {
+    EmailVerification,
+    PasswordReset,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AccountClaims {
+    sub: String,
+    purpose: TokenPurpose,
+    exp: u64,
+    /// Address the verification token was issued for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    /// Fingerprint of the password hash, so a reset token dies once it was used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fp: Option<String>,
+}
+
+/// Limits password reset mails per client ip and per target address.
+pub struct PasswordResetLimiter {
+    by_ip: RateLimiter,
+    by_email: RateLimiter,
+}
+
+impl PasswordResetLimiter {
+    pub fn new() -> Self {
+        let hour = Duration::from_secs(60 * 60);
+        Self {
+            by_ip: RateLimiter::new(10, hour),
+            by_email: RateLimiter::new(3, hour),
+        }
+    }
+
+    fn allow(&self, ip: &str, email: &str) -> bool {
+        self.by_ip.check(ip) && self.by_email.check(email)
+    }
+}
+
+impl Default for PasswordResetLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn normalize_email(email: &str) -> Option<String> {
+    let email = email.trim().to_lowercase();
+    if email.len() > 254 || email.chars().any(char::is_whitespace) {
+        return None;
+    }
+
+    let (local, domain) = email.split_once('@')?;
+    if local.is_empty() || domain.contains('@') {
+        return None;
+    }
+    let labels_ok = domain.split('.').count() >= 2 && domain.split('.').all(|l| !l.is_empty());
+    if !labels_ok {
+        return None;
+    }
+
+    Some(email)
+}
+
+fn password_fingerprint(password_hash: &str) -> String {
+    let digest = Sha256::digest(password_hash.as_bytes());
+    URL_SAFE_NO_PAD.encode(&digest[..12])
+}
+
+pub(crate) fn frontend_url() -> String {
+    env::var("FRONTEND_URL")
+        .unwrap_or_else(|_| "http://localhost:5173".to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+fn issue_token(
+    user_id: ObjectId,
+    purpose: TokenPurpose,
+    ttl: u64,
+    email: Option<String>,
+    fp: Option<String>,
+) -> anyhow::Result<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let claims = AccountClaims {
+        sub: user_id.to_hex(),
+        purpose,
+        exp: now + ttl,
+        email,
+        fp,
+    };
+
+    Ok(jsonwebtoken::encode(
+        &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS512),
+        &claims,
+        &EncodingKey::from_secret(JWT_SECRET.as_ref()),
+    )?)
+}
+
+fn decode_token(
+    token: &str,
+    purpose: TokenPurpose,
+) -> Result<(ObjectId, AccountClaims), AccountError> {
+    let data = jsonwebtoken::decode::<AccountClaims>(
+        token,
+        &DecodingKey::from_secret(JWT_SECRET.as_ref()),
+        &Validation::new(jsonwebtoken::Algorithm::HS512),
+    )
+    .map_err(|_| AccountError::InvalidToken)?;
+
+    if data.claims.purpose != purpose {
+        return Err(AccountError::InvalidToken);
+    }
+    let id = data
+        .claims
+        .sub
+        .parse()
+        .map_err(|_| AccountError::InvalidToken)?;
+
+    Ok((id, data.claims))
+}
+
+pub(crate) fn send_in_background(mailer: web::Data<dyn Mailer>, mail: Mail) {
+    tokio::spawn(async move {
+        if let Err(e) = mailer.send(mail).await {
+            error!("Failed to send mail: {:?}", e);
+        }
+    });
+}
+
+fn map_db_error(e: mongodb::error::Error) -> AccountError {
+    let error_msg = e.to_string();
+    if error_msg.contains("E11000") {
+        return AccountError::EmailAlreadyInUse;
+    }
+    if error_msg.contains("Server selection") {
+        return AccountError::NetworkError;
+    }
+    error!("Unknown error when updating account: {:?}", e);
+    AccountError::UnknownError
+}
+
+/// Stores `email` as the (unverified) address of the user and mails a verification link.
+pub async fn set_email(
+    db: web::Data<Database>,
+    mailer: web::Data<dyn Mailer>,
+    user_id: ObjectId,
+    email: &str,
+) -> Result<(), AccountError> {
+    let email = normalize_email(email).ok_or(AccountError::InvalidEmail)?;
+    let users = db.collection::<User>("users");
+
+    let taken = users
+        .find_one(doc! { "email": &email, "email_verified": true, "_id": { "$ne": user_id } })
+        .projection(doc! { "_id": 1 })
+        .await
+        .map_err(map_db_error)?;
+    if taken.is_some() {
+        return Err(AccountError::EmailAlreadyInUse);
+    }
+
+    users
+        .update_one(
+            doc! { "_id": user_id },
+            doc! { "$set": { "email": &email, "email_verified": false } },
+        )
+        .await
+        .map_err(map_db_error)?;
+
+    let token = issue_token(
+        user_id,
+        TokenPurpose::EmailVerification,
+        VERIFICATION_TOKEN_TTL,
+        Some(email.clone()),
+        None,
+    )
+    .map_err(|e| {
+        error!("Error when creating verification token: {}", e);
+        AccountError::UnknownError
+    })?;
+
+    send_in_background(
+        mailer,
+        Mail {
+            to: email,
+            subject: "Confirm your Delitter email address".to_string(),
+            body: format!(
+                "Please confirm your email address by opening the following link:\n\n{}/verify-email?token={}\n\nThe link is valid for 48 hours.",
+                frontend_url(),
+                token
+            ),
+        },
+    );
+
+    Ok(())
+}
+
+pub async fn verify_email(db: web::Data<Database>, token: &str) -> Result<(), AccountError> {
+    let (user_id, claims) = decode_token(token, TokenPurpose::EmailVerification)?;
+    let email = claims.email.ok_or(AccountError::InvalidToken)?;
+
+    let result = db
+        .collection::<User>("users")
+        .update_one(
+            doc! { "_id": user_id, "email": &email },
+            doc! { "$set": { "email_verified": true } },
+        )
+        .await
+        .map_err(map_db_error)?;
+
+    if result.matched_count == 0 {
+        info!("Verification token for outdated address used");
+        return Err(AccountError::InvalidToken);
+    }
+
+    Ok(())
+}
+
+/// Mails a reset link if a verified account uses `email`.
+///
+/// Never reports whether such an account exists; throttled requests are dropped silently.
+pub async fn request_password_reset(
+    db: web::Data<Database>,
+    mailer: web::Data<dyn Mailer>,
+    limiter: &PasswordResetLimiter,
+    client_ip: &str,
+    email: &str,
+) {
+    let Some(email) = normalize_email(email) else {
+        return;
+    };
+
+    if !limiter.allow(client_ip, &email) {
+        info!("Password reset throttled for {client_ip}");
+        return;
+    }
+
+    let user = match db
+        .collection::<User>("users")
+        .find_one(doc! { "email": &email, "email_verified": true })
+        .projection(doc! { "_id": 1, "username": 1, "password_hash": 1 })
+        .await
+    {
+        Ok(Some(u)) => u,
+        Ok(None) => return,
+        Err(e) => {
+            error!("Error when searching for email in db: {}", e);
+            return;
+        }
+    };
+
+    let id = user._id.expect("Id is always there when reading");
+    let token = match issue_token(
+        id,
+        TokenPurpose::PasswordReset,
+        RESET_TOKEN_TTL,
+        None,
+        Some(password_fingerprint(&user.password_hash)),
+    ) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Error when creating reset token: {}", e);
+            return;
+        }
+    };
+
+    send_in_background(
+        mailer,
+        Mail {
+            to: email,
+            subject: "Reset your Delitter password".to_string(),
+            body: format!(
+                "Hi {},\n\nsomebody requested a password reset for your account. Open the following link to choose a new password:\n\n{}/reset-password?token={}\n\nThe link is valid for one hour. If you did not request this, you can ignore this mail.",
+                user.username,
+                frontend_url(),
+                token
+            ),
+        },
+    );
+}
+
+pub async fn reset_password(
+    db: web::Data<Database>,
+    token: &str,
+    password: &str,
+) -> Result<(), AccountError> {
+    let (user_id, claims) = decode_token(token, TokenPurpose::PasswordReset)?;
+    let users = db.collection::<User>("users");
+
+    let user = users
+        .find_one(doc! { "_id": user_id })
+        .projection(doc! { "_id": 1, "username": 1, "password_hash": 1 })
+        .await
+        .map_err(map_db_error)?
+        .ok_or(AccountError::InvalidToken)?;
+
+    if claims.fp.as_deref() != Some(password_fingerprint(&user.password_hash).as_str()) {
+        info!("Reset token was already used");
+        return Err(AccountError::InvalidToken);
+    }
+
+    let errors = validate_password(password, &user.username);
+    if !errors.is_empty() {
+        return Err(AccountError::InvalidInput(errors));
+    }
+
+    let password_hash = hash_password(password).map_err(|_| AccountError::UnknownError)?;
+
+    let result = users
+        .update_one(
+            doc! { "_id": user_id, "password_hash": &user.password_hash },
+            doc! { "$set": { "password_hash": password_hash } },
+        )
+        .await
+        .map_err(map_db_error)?;
+
+    if result.modified_count == 0 {
+        return Err(AccountError::InvalidToken);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,648 @@
+//! Badges for reaching goals with reports, such as 100 cigarette butts or 10 cleanups in a
+//! month. The rules live in `achievements.json` and can be replaced with `ACHIEVEMENTS_FILE`;
+//! they are evaluated when reports are created, imported or analysed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    sync::LazyLock,
+};
+
+use futures::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    Database,
+    bson::{DateTime, Document, doc, oid::ObjectId, to_bson},
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    models::{litter::Entry, user::EarnedAchievement},
+    services::impact::{matches, normalize},
+};
+
+static RULES: LazyLock<Vec<Achievement>> = LazyLock::new(load);
+
+#[derive(Debug, Deserialize)]
+struct AchievementFile {
+    achievements: Vec<Achievement>,
+}
+
+/// What a rule counts, over the items matching its category and material.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Counted {
+    /// Reports, with at least one matching item if filtered
+    Reports,
+    Items,
+    /// Estimated weight in grams
+    Weight,
+    /// Cleanup sessions with a report
+    Sessions,
+    /// Different materials
+    Materials,
+    /// Different categories
+    Categories,
+    /// Different brands
+    Brands,
+}
+
+/// Span the threshold has to be reached within.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Per {
+    Session,
+    Day,
+    /// Calendar week from Monday, UTC
+    Week,
+    /// Calendar month, UTC
+    Month,
+}
+
+/// Badge for reaching `threshold` of `count`, all time or within a single `per`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Achievement {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub count: Counted,
+    /// Only items of this category count
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    /// Only items of this material count
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub material: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per: Option<Per>,
+    pub threshold: f64,
+}
+
+fn load() -> Vec<Achievement> {
+    let file: AchievementFile = match env::var("ACHIEVEMENTS_FILE") {
+        Ok(path) => {
+            let raw = fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("Failed to read ACHIEVEMENTS_FILE {path}: {e}"));
+            serde_json::from_str(&raw)
+                .unwrap_or_else(|e| panic!("Invalid achievements in {path}: {e}"))
+        }
+        Err(_) => serde_json::from_str(include_str!("achievements.json"))
+            .expect("Built-in achievements are valid"),
+    };
+    let mut ids = HashSet::new();
+    for rule in &file.achievements {
+        assert!(
+            !rule.id.is_empty() && ids.insert(rule.id.as_str()),
+            "Achievement ids must be unique and not empty, found {:?}",
+            rule.id
+        );
+        assert!(
+            rule.threshold > 0.0,
+            "Achievement {} needs a positive threshold",
+            rule.id
+        );
+    }
+    file.achievements
+}
+
+/// Loads the rules, so a broken `ACHIEVEMENTS_FILE` stops the server at startup.
+pub fn init() {
+    LazyLock::force(&RULES);
+}
+
+pub fn rules() -> &'static [Achievement] {
+    &RULES
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum AchievementError {
+    NotFound,
+    NetworkError,
+}
+
+impl From<mongodb::error::Error> for AchievementError {
+    fn from(e: mongodb::error::Error) -> Self {
+        error!("Error when accessing achievements in db: {:?}", e);
+        AchievementError::NetworkError
+    }
+}
+
+/// A rule with the user's progress towards it.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AchievementStatus {
+    #[serde(flatten)]
+    pub achievement: Achievement,
+    /// Best value reached so far, at most the threshold
+    pub progress: f64,
+    /// Missing until earned
+    pub earned_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AchievementBackfill {
+    /// Users evaluated
+    pub users: u64,
+    /// Badges awarded
+    pub awarded: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Report {
+    time_stamp: DateTime,
+    #[serde(default)]
+    session_id: Option<ObjectId>,
+    #[serde(default)]
+    entries: Vec<Entry>,
+}
+
+/// The parts of a user the rules look at.
+#[derive(Debug, Deserialize)]
+struct Progress {
+    #[serde(default)]
+    litter: Vec<Report>,
+    #[serde(default)]
+    achievements: Vec<EarnedAchievement>,
+}
+
+async fn progress(db: &Database, user_id: ObjectId) -> Result<Progress, AchievementError> {
+    let mut progress = db
+        .collection::<Progress>("users")
+        .find_one(doc! { "_id": user_id })
+        .projection(doc! {
+            "litter.time_stamp": 1,
+            "litter.session_id": 1,
+            "litter.entries": 1,
+            "achievements": 1,
+        })
+        .await?
+        .ok_or(AchievementError::NotFound)?;
+    progress.litter.sort_by_key(|r| r.time_stamp);
+    Ok(progress)
+}
+
+/// Key of the span a report falls into, `None` if it belongs to none.
+fn span(per: Option<Per>, report: &Report) -> Option<String> {
+    let day = report.time_stamp.timestamp_millis().div_euclid(86_400_000);
+    match per {
+        None => Some(String::new()),
+        Some(Per::Session) => report.session_id.map(|id| id.to_hex()),
+        Some(Per::Day) => Some(day.to_string()),
+        // 1970-01-01 was a Thursday
+        Some(Per::Week) => Some((day + 3).div_euclid(7).to_string()),
+        Some(Per::Month) => report
+            .time_stamp
+            .try_to_rfc3339_string()
+            .ok()
+            .map(|t| t[..7].to_string()),
+    }
+}
+
+#[derive(Default)]
+struct Tally {
+    value: f64,
+    seen: HashSet<String>,
+}
+
+impl Tally {
+    fn add_distinct(&mut self, value: Option<&str>) {
+        if let Some(value) = value
+            && self.seen.insert(normalize(value))
+        {
+            self.value += 1.0;
+        }
+    }
+}
+
+/// Best value of any span and the time of the report that first reached the threshold.
+/// `reports` must be sorted by time.
+fn evaluate(rule: &Achievement, reports: &[Report]) -> (f64, Option<DateTime>) {
+    let filtered = rule.category.is_some() || rule.material.is_some();
+    let mut spans: HashMap<String, Tally> = HashMap::new();
+    let mut best: f64 = 0.0;
+    let mut reached = None;
+
+    for report in reports {
+        let Some(key) = span(rule.per, report) else {
+            continue;
+        };
+        let items: Vec<&Entry> = report
+            .entries
+            .iter()
+            .filter(|e| {
+                matches(&rule.category, e.category.as_deref())
+                    && matches(&rule.material, e.material.as_deref())
+            })
+            .collect();
+        let counts = !filtered || !items.is_empty();
+
+        let tally = spans.entry(key).or_default();
+        match rule.count {
+            Counted::Reports if counts => tally.value += 1.0,
+            Counted::Reports => {}
+            Counted::Items => tally.value += items.len() as f64,
+            Counted::Weight => tally.value += items.iter().filter_map(|e| e.weight).sum::<f64>(),
+            Counted::Sessions => {
+                if counts {
+                    tally.add_distinct(report.session_id.map(|id| id.to_hex()).as_deref());
+                }
+            }
+            Counted::Materials => items
+                .iter()
+                .for_each(|e| tally.add_distinct(e.material.as_deref())),
+            Counted::Categories => items
+                .iter()
+                .for_each(|e| tally.add_distinct(e.category.as_deref())),
+            Counted::Brands => items
+                .iter()
+                .for_each(|e| tally.add_distinct(e.brand.as_deref())),
+        }
+
+        best = best.max(tally.value);
+        if reached.is_none() && tally.value >= rule.threshold {
+            reached = Some(report.time_stamp);
+        }
+    }
+
+    (best.min(rule.threshold), reached)
+}
+
+/// Awards the badges the user's reports earn and does not have yet, returns the new ones.
+pub async fn award(
+    db: &Database,
+    user_id: ObjectId,
+) -> Result<Vec<EarnedAchievement>, AchievementError> {
+    let progress = progress(db, user_id).await?;
+    let earned: HashSet<&str> = progress
+        .achievements
+        .iter()
+        .map(|a| a.id.as_str())
+        .collect();
+
+    let mut awarded = vec![];
+    for rule in RULES.iter().filter(|r| !earned.contains(r.id.as_str())) {
+        let (_, Some(earned_at)) = evaluate(rule, &progress.litter) else {
+            continue;
+        };
+        let achievement = EarnedAchievement {
+            id: rule.id.clone(),
+            earned_at,
+        };
+        let bson = to_bson(&achievement).map_err(|e| {
+            error!("Failed to serialize achievement: {:?}", e);
+            AchievementError::NetworkError
+        })?;
+        // The id condition keeps concurrent evaluations from awarding a badge twice
+        let result = db
+            .collection::<Document>("users")
+            .update_one(
+                doc! { "_id": user_id, "achievements.id": { "$ne": &rule.id } },
+                doc! { "$push": { "achievements": bson } },
+            )
+            .await?;
+        if result.modified_count == 1 {
+            info!("{} earned achievement {}", user_id, rule.id);
+            awarded.push(achievement);
+        }
+    }
+    Ok(awarded)
+}
+
+/// Awards new badges after reports changed. Failures are logged and caught up with the next
+/// report or a backfill.
+pub async fn check(db: &Database, user_id: ObjectId) {
+    if let Err(e) = award(db, user_id).await {
+        error!("Failed to evaluate achievements of {}: {:?}", user_id, e);
+    }
+}
+
+/// All rules with the user's progress, earned ones first.
+pub async fn status(
+    db: &Database,
+    user_id: ObjectId,
+) -> Result<Vec<AchievementStatus>, AchievementError> {
+    let progress = progress(db, user_id).await?;
+
+    let mut status: Vec<AchievementStatus> = RULES
+        .iter()
+        .map(|rule| {
+            let (value, _) = evaluate(rule, &progress.litter);
+            let earned = progress.achievements.iter().find(|a| a.id == rule.id);
+            AchievementStatus {
+                achievement: rule.clone(),
+                // Earned badges stay complete even if reports were removed since
+                progress: if earned.is_some() {
+                    rule.threshold
+                } else {
+                    value
+                },
+                earned_at: earned.and_then(|a| a.earned_at.try_to_rfc3339_string().ok()),
+            }
+        })
+        .collect();
+    status.sort_by_key(|s| s.earned_at.is_none());
+    Ok(status)
+}
+
+/// Evaluates all users, e.g. after adding rules, with the time of the report that reached
+/// each threshold as earning time.
+pub async fn backfill(db: &Database) -> Result<AchievementBackfill, AchievementError> {
+    let mut users = db
+        .collection::<Document>("users")
+        .find(doc! {})
+        .projection(doc! { "_id": 1 })
+        .await?;
+
+    let mut result = AchievementBackfill {
+        users: 0,
+        awarded: 0,
+    };
+    while let Some(user) = users.try_next().await? {
+        let Ok(id) = user.get_object_id("_id") else {
+            continue;
+        };
+        match award(db, id).await {
+            Ok(awarded) => result.awarded += awarded.len() as u64,
+            // Deleted since the cursor read it
+            Err(AchievementError::NotFound) => continue,
+            Err(e) => return Err(e),
+        }
+        result.users += 1;
+    }
+
+    info!(
+        "Backfilled achievements of {} users, awarded {}",
+        result.users, result.awarded
+    );
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn built_in(id: &str) -> Achievement {
+        let file: AchievementFile =
+            serde_json::from_str(include_str!("achievements.json")).unwrap();
+        file.achievements
+            .into_iter()
+            .find(|rule| rule.id == id)
+            .unwrap_or_else(|| panic!("no built-in achievement {id}"))
+    }
+
+    fn at(time: &str) -> DateTime {
+        DateTime::parse_rfc3339_str(time).unwrap()
+    }
+
+    fn entry(category: &str, material: &str, weight: f64) -> Entry {
+        Entry {
+            category: Some(category.to_string()),
+            material: Some(material.to_string()),
+            weight: Some(weight),
+            brand: None,
+            confidence: None,
+        }
+    }
+
+    fn report(time: &str, session_id: Option<ObjectId>, entries: Vec<Entry>) -> Report {
+        Report {
+            time_stamp: at(time),
+            session_id,
+            entries,
+        }
+    }
+
+    fn items(count: usize) -> Vec<Entry> {
+        vec![entry("bottle", "plastic", 20.0); count]
+    }
+
+    fn key(per: Per, time: &str) -> Option<String> {
+        span(Some(per), &report(time, None, vec![]))
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        // 1970-01-01, day 0, was a Thursday
+        assert_eq!(
+            key(Per::Week, "1969-12-29T00:00:00Z"),
+            key(Per::Week, "1970-01-04T23:59:59Z")
+        );
+        assert_ne!(
+            key(Per::Week, "1969-12-28T23:59:59Z"),
+            key(Per::Week, "1969-12-29T00:00:00Z")
+        );
+        assert_ne!(
+            key(Per::Week, "1970-01-04T23:59:59Z"),
+            key(Per::Week, "1970-01-05T00:00:00Z")
+        );
+        assert_eq!(
+            key(Per::Week, "2024-05-06T00:00:00Z"),
+            key(Per::Week, "2024-05-12T23:59:59Z")
+        );
+        assert_ne!(
+            key(Per::Week, "2024-05-05T23:59:59Z"),
+            key(Per::Week, "2024-05-06T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn buckets_days_and_months_in_utc() {
+        assert_eq!(key(Per::Month, "2024-02-01T00:00:00Z").unwrap(), "2024-02");
+        assert_eq!(key(Per::Month, "2024-02-29T23:59:59Z").unwrap(), "2024-02");
+        assert_eq!(
+            key(Per::Month, "2024-02-01T00:30:00+01:00").unwrap(),
+            "2024-01"
+        );
+        assert_eq!(
+            key(Per::Day, "2024-05-01T00:00:00Z"),
+            key(Per::Day, "2024-05-01T23:59:59Z")
+        );
+        assert_ne!(
+            key(Per::Day, "2024-05-01T23:59:59Z"),
+            key(Per::Day, "2024-05-02T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn spans_sessions_and_all_time() {
+        let session = ObjectId::new();
+        let with = report("2024-05-01T10:00:00Z", Some(session), vec![]);
+        let without = report("2024-05-01T10:00:00Z", None, vec![]);
+
+        assert_eq!(span(Some(Per::Session), &with), Some(session.to_hex()));
+        assert_eq!(span(Some(Per::Session), &without), None);
+        assert_eq!(span(None, &with), span(None, &without));
+    }
+
+    #[test]
+    fn earns_the_first_report() {
+        let rule = built_in("first_report");
+        assert_eq!(evaluate(&rule, &[]), (0.0, None));
+
+        let reports = [
+            report("2024-05-01T10:00:00Z", None, vec![]),
+            report("2024-05-02T10:00:00Z", None, items(1)),
+        ];
+        assert_eq!(
+            evaluate(&rule, &reports),
+            (1.0, Some(at("2024-05-01T10:00:00Z")))
+        );
+    }
+
+    #[test]
+    fn earns_at_the_report_reaching_the_threshold() {
+        let reports = [
+            report("2024-05-01T10:00:00Z", None, items(60)),
+            report("2024-05-02T10:00:00Z", None, items(30)),
+            report("2024-05-03T10:00:00Z", None, items(20)),
+        ];
+
+        assert_eq!(
+            evaluate(&built_in("items_100"), &reports),
+            (100.0, Some(at("2024-05-03T10:00:00Z")))
+        );
+        assert_eq!(evaluate(&built_in("items_1000"), &reports), (110.0, None));
+        // 110 items of 20 g
+        assert_eq!(evaluate(&built_in("weight_10kg"), &reports), (2200.0, None));
+    }
+
+    #[test]
+    fn counts_items_within_a_day() {
+        let rule = built_in("items_day_50");
+        let reports = [
+            report("2024-05-01T20:00:00Z", None, items(30)),
+            report("2024-05-02T08:00:00Z", None, items(30)),
+            report("2024-05-02T18:00:00Z", None, items(10)),
+            report("2024-05-02T19:00:00Z", None, items(10)),
+        ];
+
+        assert_eq!(evaluate(&rule, &reports[..3]), (40.0, None));
+        assert_eq!(
+            evaluate(&rule, &reports),
+            (50.0, Some(at("2024-05-02T19:00:00Z")))
+        );
+    }
+
+    #[test]
+    fn filters_categories_through_normalize() {
+        let rule = built_in("butts_100");
+        let butts = |category: &str, count: usize| vec![entry(category, "paper", 0.2); count];
+        let mut first = butts("Cigarette Butt", 40);
+        first.extend(butts("cigarette-butt", 40));
+        first.extend(items(50));
+        let reports = [
+            report("2024-05-01T10:00:00Z", None, first),
+            report("2024-05-02T10:00:00Z", None, items(50)),
+            report("2024-05-03T10:00:00Z", None, butts(" cigarette_butt ", 20)),
+        ];
+
+        assert_eq!(evaluate(&rule, &reports[..2]), (80.0, None));
+        assert_eq!(
+            evaluate(&rule, &reports),
+            (100.0, Some(at("2024-05-03T10:00:00Z")))
+        );
+    }
+
+    #[test]
+    fn counts_distinct_materials_per_session() {
+        let rule = built_in("materials_session_5");
+        let materials = |names: &[&str]| -> Vec<Entry> {
+            names.iter().map(|m| entry("other", m, 1.0)).collect()
+        };
+        let (a, b) = (ObjectId::new(), ObjectId::new());
+        let reports = [
+            report(
+                "2024-05-01T10:00:00Z",
+                Some(a),
+                materials(&["plastic", "Plastic", "glass"]),
+            ),
+            report(
+                "2024-05-01T11:00:00Z",
+                Some(a),
+                materials(&["metal", " GLASS ", "paper"]),
+            ),
+            // Outside sessions, so it adds nothing
+            report("2024-05-01T12:00:00Z", None, materials(&["wood", "rubber"])),
+            report(
+                "2024-05-02T10:00:00Z",
+                Some(b),
+                materials(&["plastic", "glass", "metal"]),
+            ),
+            report(
+                "2024-05-02T11:00:00Z",
+                Some(b),
+                materials(&["paper", "textile"]),
+            ),
+        ];
+
+        assert_eq!(evaluate(&rule, &reports[..4]), (4.0, None));
+        assert_eq!(
+            evaluate(&rule, &reports),
+            (5.0, Some(at("2024-05-02T11:00:00Z")))
+        );
+    }
+
+    #[test]
+    fn counts_sessions_per_month() {
+        let rule = built_in("cleanups_month_10");
+        let sessions: Vec<ObjectId> = (0..10).map(|_| ObjectId::new()).collect();
+        let reports = |last: &str| -> Vec<Report> {
+            let mut reports: Vec<Report> = sessions[..9]
+                .iter()
+                .enumerate()
+                .flat_map(|(day, id)| {
+                    let time = format!("2024-05-{:02}T10:00:00Z", day + 1);
+                    // Two reports of the same session count once
+                    [
+                        report(&time, Some(*id), items(1)),
+                        report(&time, Some(*id), items(1)),
+                    ]
+                })
+                .collect();
+            // Reports outside sessions add nothing
+            reports.push(report("2024-05-20T10:00:00Z", None, items(1)));
+            reports.push(report(last, Some(sessions[9]), items(1)));
+            reports
+        };
+
+        assert_eq!(
+            evaluate(&rule, &reports("2024-06-01T00:00:00Z")),
+            (9.0, None)
+        );
+        assert_eq!(
+            evaluate(&rule, &reports("2024-05-31T23:59:59Z")),
+            (10.0, Some(at("2024-05-31T23:59:59Z")))
+        );
+    }
+
+    #[test]
+    fn filtered_report_rules_need_a_matching_item() {
+        let rule = Achievement {
+            id: "glass_reports_2".to_string(),
+            name: "Glass".to_string(),
+            description: "Report glass twice".to_string(),
+            count: Counted::Reports,
+            category: None,
+            material: Some("Glass".to_string()),
+            per: None,
+            threshold: 2.0,
+        };
+        let reports = [
+            report(
+                "2024-05-01T10:00:00Z",
+                None,
+                vec![entry("bottle", "glass", 300.0)],
+            ),
+            report("2024-05-02T10:00:00Z", None, items(3)),
+            report(
+                "2024-05-03T10:00:00Z",
+                None,
+                vec![entry("jar", "GLASS", 200.0)],
+            ),
+        ];
+
+        assert_eq!(
+            evaluate(&rule, &reports),
+            (2.0, Some(at("2024-05-03T10:00:00Z")))
+        );
+    }
+}
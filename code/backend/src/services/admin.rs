@@ -0,0 +1,144 @@
+use std::env;
+
+use actix_web::web;
+use futures::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    Database,
+    bson::{doc, oid::ObjectId},
+};
+use serde::Serialize;
+
+use crate::{
+    models::user::{Role, User, username_collation},
+    services::auth::UserSession,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub enum AdminError {
+    NotFound,
+    Forbidden,
+    NetworkError,
+}
+
+impl From<mongodb::error::Error> for AdminError {
+    fn from(e: mongodb::error::Error) -> Self {
+        error!("Error when accessing users in db: {:?}", e);
+        AdminError::NetworkError
+    }
+}
+
+/// Lists users without their litter, ordered by id (= signup time).
+pub async fn list_users(
+    db: web::Data<Database>,
+    skip: u64,
+    limit: i64,
+) -> Result<Vec<User>, AdminError> {
+    let users = db
+        .collection::<User>("users")
+        .find(doc! {})
+        .projection(doc! { "litter": 0 })
+        .sort(doc! { "_id": 1 })
+        .skip(skip)
+        .limit(limit)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(users)
+}
+
+async fn target_role(db: &web::Data<Database>, target: ObjectId) -> Result<Role, AdminError> {
+    let user = db
+        .collection::<User>("users")
+        .find_one(doc! { "_id": target })
+        .projection(doc! { "litter": 0 })
+        .await?
+        .ok_or(AdminError::NotFound)?;
+
+    Ok(user.role)
+}
+
+/// Nobody changes their own account (no accidental self-lockout), and only admins
+/// touch accounts with a role equal to or above moderator.
+fn ensure_may_manage(
+    actor: &UserSession,
+    target: ObjectId,
+    target_role: Role,
+) -> Result<(), AdminError> {
+    if actor.id == target {
+        return Err(AdminError::Forbidden);
+    }
+    if actor.role < Role::Admin && target_role >= Role::Moderator {
+        return Err(AdminError::Forbidden);
+    }
+    Ok(())
+}
+
+pub async fn set_role(
+    db: web::Data<Database>,
+    actor: &UserSession,
+    target: ObjectId,
+    role: Role,
+) -> Result<(), AdminError> {
+    let current = target_role(&db, target).await?;
+    ensure_may_manage(actor, target, current)?;
+
+    db.collection::<User>("users")
+        .update_one(
+            doc! { "_id": target },
+            doc! { "$set": { "role": role.as_str() } },
+        )
+        .await?;
+
+    info!("{} changed role of {} to {:?}", actor.id, target, role);
+    Ok(())
+}
+
+pub async fn set_disabled(
+    db: web::Data<Database>,
+    actor: &UserSession,
+    target: ObjectId,
+    disabled: bool,
+) -> Result<(), AdminError> {
+    let current = target_role(&db, target).await?;
+    ensure_may_manage(actor, target, current)?;
+
+    db.collection::<User>("users")
+        .update_one(
+            doc! { "_id": target },
+            doc! { "$set": { "disabled": disabled } },
+        )
+        .await?;
+
+    info!("{} set disabled={} for {}", actor.id, disabled, target);
+    Ok(())
+}
+
+/// Promotes the comma separated `ADMIN_USERNAMES` to admins, so a fresh
+/// installation has somebody who can hand out roles.
+pub async fn promote_bootstrap_admins(db: &Database) {
+    let Ok(usernames) = env::var("ADMIN_USERNAMES") else {
+        return;
+    };
+
+    for username in usernames
+        .split(',')
+        .map(str::trim)
+        .filter(|u| !u.is_empty())
+    {
+        match db
+            .collection::<User>("users")
+            .update_one(
+                doc! { "username": username },
+                doc! { "$set": { "role": Role::Admin.as_str() } },
+            )
+            .collation(username_collation())
+            .await
+        {
+            Ok(res) if res.matched_count == 1 => info!("👑 {username} is an admin"),
+            Ok(_) => info!("Admin user {username} does not exist (yet)"),
+            Err(e) => error!("Failed to promote {username} to admin: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use actix_web::web;
+use futures::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{DateTime, doc, oid::ObjectId},
+    options::{IndexOptions, ReturnDocument},
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    models::api_token::{ApiToken, TokenScope},
+    services::{auth::random_urlsafe, validation::FieldError},
+};
+
+/// Marks personal API tokens, so they can be told apart from session JWTs.
+pub const TOKEN_PREFIX: &str = "dlt_";
+pub const TOKEN_NAME_MAX_LEN: usize = 64;
+/// Keeps forgotten scripts from piling up tokens.
+pub const MAX_TOKENS_PER_USER: u64 = 50;
+
+#[derive(Debug, Clone, Serialize)]
+pub enum ApiTokenError {
+    InvalidInput(Vec<FieldError>),
+    NotFound,
+    NetworkError,
+}
+
+impl From<mongodb::error::Error> for ApiTokenError {
+    fn from(e: mongodb::error::Error) -> Self {
+        error!("Error when accessing api tokens in db: {:?}", e);
+        ApiTokenError::NetworkError
+    }
+}
+
+fn collection(db: &Database) -> Collection<ApiToken> {
+    db.collection::<ApiToken>("api_tokens")
+}
+
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+pub async fn ensure_indexes(db: &Database) {
+    let indexes = [
+        IndexModel::builder()
+            .keys(doc! { "token_hash": 1 })
+            .options(
+                IndexOptions::builder()
+                    .unique(true)
+                    .name(Some("unique_token_hash".to_string()))
+                    .build(),
+            )
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "user_id": 1 })
+            .options(
+                IndexOptions::builder()
+                    .name(Some("token_user".to_string()))
+                    .build(),
+            )
+            .build(),
+    ];
+
+    if let Err(e) = collection(db).create_indexes(indexes).await {
+        error!("Failed to ensure indexes for 'api_tokens': {:?}", e);
+    }
+}
+
+/// Creates a token and returns it together with its plain value, which is never stored.
+pub async fn create(
+    db: web::Data<Database>,
+    user_id: ObjectId,
+    name: &str,
+    scope: TokenScope,
+) -> Result<(ApiToken, String), ApiTokenError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > TOKEN_NAME_MAX_LEN {
+        return Err(ApiTokenError::InvalidInput(vec![FieldError {
+            field: "name".to_string(),
+            message: format!("must be between 1 and {TOKEN_NAME_MAX_LEN} characters long"),
+        }]));
+    }
+
+    let tokens = collection(&db);
+    if tokens.count_documents(doc! { "user_id": user_id }).await? >= MAX_TOKENS_PER_USER {
+        return Err(ApiTokenError::InvalidInput(vec![FieldError {
+            field: "name".to_string(),
+            message: format!(
+                "at most {MAX_TOKENS_PER_USER} tokens per account, revoke unused ones first"
+            ),
+        }]));
+    }
+
+    let plain = format!("{TOKEN_PREFIX}{}", random_urlsafe(32));
+    let mut token = ApiToken {
+        _id: None,
+        user_id,
+        name: name.to_string(),
+        scope,
+        token_hash: hash_token(&plain),
+        prefix: plain.chars().take(TOKEN_PREFIX.len() + 4).collect(),
+        created_at: DateTime::now(),
+        last_used_at: None,
+    };
+
+    let id = tokens.insert_one(&token).await?.inserted_id.as_object_id();
+    token._id = id;
+
+    info!("{} created api token {:?} ({:?})", user_id, id, scope);
+    Ok((token, plain))
+}
+
+pub async fn list(
+    db: web::Data<Database>,
+    user_id: ObjectId,
+) -> Result<Vec<ApiToken>, ApiTokenError> {
+    let tokens = collection(&db)
+        .find(doc! { "user_id": user_id })
+        .sort(doc! { "_id": 1 })
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(tokens)
+}
+
+pub async fn revoke(
+    db: web::Data<Database>,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<(), ApiTokenError> {
+    let res = collection(&db)
+        .delete_one(doc! { "_id": id, "user_id": user_id })
+        .await?;
+
+    if res.deleted_count == 0 {
+        return Err(ApiTokenError::NotFound);
+    }
+    info!("{} revoked api token {}", user_id, id);
+    Ok(())
+}
+
+/// Looks up a presented token and records its use.
+pub async fn authenticate(
+    db: &Database,
+    token: &str,
+) -> Result<Option<ApiToken>, mongodb::error::Error> {
+    collection(db)
+        .find_one_and_update(
+            doc! { "token_hash": hash_token(token) },
+            doc! { "$set": { "last_used_at": DateTime::now() } },
+        )
+        .return_document(ReturnDocument::After)
+        .await
+}
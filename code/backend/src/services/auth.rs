@@ -1,31 +1,111 @@
 use std::{
     collections::HashMap,
+    env,
+    marker::PhantomData,
     str::FromStr,
+    sync::LazyLock,
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use futures::future::{Ready, ready};
+use futures::future::LocalBoxFuture;
 
 use actix_web::{FromRequest, HttpRequest, dev::Payload, web};
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use jsonwebtoken::{DecodingKey, EncodingKey, TokenData, Validation};
 use log::{debug, error, info};
 use mongodb::{
     Database,
     bson::{doc, oid::ObjectId},
 };
-use password_hash::{SaltString, rand_core::OsRng};
+use password_hash::{
+    SaltString,
+    rand_core::{OsRng, RngCore},
+};
 use serde::{Deserialize, Serialize};
 
-use crate::{handlers::HttpError, models::user::User};
+use crate::{
+    handlers::HttpError,
+    models::{
+        api_token::TokenScope,
+        user::{Role, TwoFactor, User, username_collation},
+    },
+    services::{
+        api_token::{self, TOKEN_PREFIX},
+        leaderboard::pseudonym_taken,
+        totp,
+        validation::{FieldError, validate_signup},
+    },
+};
+
+pub(crate) const JWT_SECRET: &str = "secret";
+
+/// Time to enter the second factor after the password was accepted.
+const TWO_FACTOR_CHALLENGE_TTL: u64 = 5 * 60;
+const TWO_FACTOR_PURPOSE: &str = "two_factor";
+
+/// Argon2id parameters for new hashes, from `ARGON2_MEMORY_KIB`, `ARGON2_ITERATIONS`
+/// and `ARGON2_PARALLELISM`. Defaults are the OWASP recommendation (19 MiB, 2, 1).
+static ARGON2_PARAMS: LazyLock<Params> = LazyLock::new(|| {
+    let value = |name: &str, default: u32| match env::var(name) {
+        Ok(v) => v
+            .parse()
+            .unwrap_or_else(|_| panic!("{name} must be a valid number")),
+        Err(_) => default,
+    };
+
+    Params::new(
+        value("ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST),
+        value("ARGON2_ITERATIONS", Params::DEFAULT_T_COST),
+        value("ARGON2_PARALLELISM", Params::DEFAULT_P_COST),
+        None,
+    )
+    .expect("Invalid Argon2 parameters")
+});
+
+/// Hash verified for unknown usernames, so they take as long as a wrong password.
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| {
+    hash_password("delitter-dummy-password").expect("Hashing the dummy password failed")
+});
 
 #[derive(Debug, Clone, Serialize)]
 pub enum SignupError {
+    InvalidInput(Vec<FieldError>),
     UserAlreadyExists,
     NetworkError,
     UnknownError,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub enum SigninError {
+    InvalidCredentials,
+    AccountDisabled,
+    InvalidChallenge,
+}
+
+/// What signin hands out once the password is correct.
+#[derive(Debug, Clone)]
+pub enum SigninOutcome {
+    Session(Jwt),
+    /// The account uses 2FA, the challenge is redeemed with `signin_two_factor`.
+    TwoFactorRequired(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum TwoFactorError {
+    AlreadyEnabled,
+    NotEnabled,
+    InvalidCode,
+    NetworkError,
+}
+
+impl From<mongodb::error::Error> for TwoFactorError {
+    fn from(e: mongodb::error::Error) -> Self {
+        error!("Error when accessing two-factor settings in db: {:?}", e);
+        TwoFactorError::NetworkError
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Jwt(String);
 
@@ -34,14 +114,95 @@ struct Claims {
     exp: u64,
 }
 
+/// Proof that the password step of a 2FA signin succeeded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChallengeClaims {
+    sub: String,
+    username: String,
+    purpose: String,
+    exp: u64,
+}
+
 #[derive(Debug, Clone, Serialize)]
 struct Extras {
     id: String,
     username: String,
+    role: Role,
 }
 
 pub struct UserSession {
     pub id: ObjectId,
+    pub role: Role,
+    /// Scope of the personal API token used, `None` for a signed in session.
+    pub token_scope: Option<TokenScope>,
+}
+
+/// A `UserSession` that may report litter: a signed in session or an API token with
+/// the `upload` scope. Plain `UserSession`s only accept API tokens for reading.
+pub struct UploadSession(pub UserSession);
+
+/// Minimum role demanded by a `RequireRole` extractor.
+pub trait RoleRequirement {
+    const MIN: Role;
+}
+
+pub mod roles {
+    use super::{Role, RoleRequirement};
+
+    pub struct GroupLeader;
+    pub struct Moderator;
+    pub struct Admin;
+
+    impl RoleRequirement for GroupLeader {
+        const MIN: Role = Role::GroupLeader;
+    }
+    impl RoleRequirement for Moderator {
+        const MIN: Role = Role::Moderator;
+    }
+    impl RoleRequirement for Admin {
+        const MIN: Role = Role::Admin;
+    }
+}
+
+/// A `UserSession` whose user has at least the role `R` (e.g. `RequireRole<roles::Admin>`).
+pub struct RequireRole<R> {
+    pub session: UserSession,
+    _role: PhantomData<R>,
+}
+
+/// The parts of a user checked on every authenticated request.
+#[derive(Debug, Deserialize)]
+struct SessionState {
+    #[serde(default)]
+    role: Role,
+    #[serde(default)]
+    disabled: bool,
+}
+
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, ARGON2_PARAMS.clone())
+}
+
+/// Random url safe string made of `bytes` random bytes.
+pub(crate) fn random_urlsafe(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buf);
+    URL_SAFE_NO_PAD.encode(buf)
+}
+
+/// Forces the lazily computed Argon2 setup, so the first sign-in is not slower than the rest.
+pub fn init() {
+    LazyLock::force(&DUMMY_HASH);
+}
+
+pub(crate) fn hash_password(password: &str) -> Result<String, password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let argon2 = argon2();
+
+    argon2
+        .hash_password(password.as_bytes(), &salt)
+        .map(|o| o.to_string())
+        .inspect_err(|e| error!("Password hashing failed: {}", e))
 }
 
 pub async fn signup(
@@ -49,16 +210,22 @@ pub async fn signup(
     user: &str,
     password: &str,
 ) -> Result<(ObjectId, Jwt), SignupError> {
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
+    let errors = validate_signup(user, password);
+    if !errors.is_empty() {
+        return Err(SignupError::InvalidInput(errors));
+    }
 
-    let password_hash = match argon2.hash_password(password.as_bytes(), &salt) {
-        Ok(o) => o.to_string(),
+    // Usernames are unique by index, pseudonyms of other users have to be checked
+    match pseudonym_taken(&db, user).await {
+        Ok(true) => return Err(SignupError::UserAlreadyExists),
+        Ok(false) => {}
         Err(e) => {
-            error!("Password hashing failed: {}", e);
-            return Err(SignupError::UnknownError);
+            error!("Failed to check pseudonyms: {:?}", e);
+            return Err(SignupError::NetworkError);
         }
-    };
+    }
+
+    let password_hash = hash_password(password).map_err(|_| SignupError::UnknownError)?;
 
     let new_user = User {
         username: user.to_string(),
@@ -84,6 +251,7 @@ pub async fn signup(
     let jwt = Jwt::new(Extras {
         id: result_id.to_hex(),
         username: new_user.username,
+        role: new_user.role,
     });
 
     if let Err(e) = &jwt {
@@ -99,15 +267,19 @@ pub async fn signin(
     db: web::Data<Database>,
     user: &str,
     password: &str,
-) -> Option<(ObjectId, Jwt)> {
+) -> Result<(ObjectId, SigninOutcome), SigninError> {
     let users = db.collection::<User>("users");
 
     let user: Result<Option<User>, _> = users
         .find_one(doc! { "username": &user })
+        .collation(username_collation())
         .projection(doc! {
             "username":1,
             "password_hash":1,
             "_id":1,
+            "role":1,
+            "disabled":1,
+            "two_factor":1,
 
         })
         .await;
@@ -116,55 +288,396 @@ pub async fn signin(
         Ok(u) => u,
         Err(e) => {
             error!("Error when searching for username in db: {}", e);
-            return None;
+            None
         }
     };
+
+    // Every failure path runs exactly one verification, so the response time
+    // does not tell whether the username exists.
+    let verified = verify_password(
+        password,
+        user.as_ref()
+            .map_or(DUMMY_HASH.as_str(), |u| u.password_hash.as_str()),
+    );
     let user = match user {
-        Some(u) => u,
-        None => {
-            info!("Username not found in db!");
-            return None;
+        Some(u) if verified => u,
+        _ => {
+            info!("Invalid credentials!");
+            return Err(SigninError::InvalidCredentials);
         }
     };
 
-    let parsed_hash = match PasswordHash::new(&user.password_hash) {
-        Ok(o) => o,
-        Err(e) => {
-            error!("Password hashing failed: {}", e);
-            return None;
-        }
-    };
+    if user.disabled {
+        info!("Signin to disabled account {:?}", user._id);
+        return Err(SigninError::AccountDisabled);
+    }
 
-    let argon2 = Argon2::default();
-    if argon2
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .is_err()
-    {
-        info!("Invalid credentials!");
-        return None;
+    if needs_rehash(&user.password_hash) {
+        rehash_in_background(db.clone(), &user, password);
     }
 
     let id = user._id.expect("Id is always there when reading");
+
+    if user.two_factor.as_ref().is_some_and(|t| t.enabled) {
+        let challenge = issue_challenge(id, &user.username).map_err(|e| {
+            error!("Error when creating 2FA challenge: {}", e);
+            SigninError::InvalidCredentials
+        })?;
+        return Ok((id, SigninOutcome::TwoFactorRequired(challenge)));
+    }
+
     let jwt = Jwt::new(Extras {
         id: id.to_hex(),
         username: user.username,
+        role: user.role,
     });
 
     if let Err(e) = &jwt {
         error!("Error when inserting user / creating jwt: {}", e);
     }
 
-    jwt.ok().map(|jwt| (id, jwt))
+    jwt.map(|jwt| (id, SigninOutcome::Session(jwt)))
+        .map_err(|_| SigninError::InvalidCredentials)
+}
+
+/// Challenge redeemed with `signin_two_factor`, handed out instead of a session for accounts
+/// with 2FA.
+pub(crate) fn issue_challenge(id: ObjectId, username: &str) -> anyhow::Result<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let claims = ChallengeClaims {
+        sub: id.to_hex(),
+        username: username.to_string(),
+        purpose: TWO_FACTOR_PURPOSE.to_string(),
+        exp: now + TWO_FACTOR_CHALLENGE_TTL,
+    };
+
+    Ok(jsonwebtoken::encode(
+        &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS512),
+        &claims,
+        &EncodingKey::from_secret(JWT_SECRET.as_ref()),
+    )?)
+}
+
+fn decode_challenge(challenge: &str) -> Option<ChallengeClaims> {
+    let claims = jsonwebtoken::decode::<ChallengeClaims>(
+        challenge,
+        &DecodingKey::from_secret(JWT_SECRET.as_ref()),
+        &Validation::new(jsonwebtoken::Algorithm::HS512),
+    )
+    .ok()?
+    .claims;
+
+    (claims.purpose == TWO_FACTOR_PURPOSE).then_some(claims)
+}
+
+/// Username behind a 2FA challenge, so code guesses count against the account.
+pub fn two_factor_username(challenge: &str) -> Option<String> {
+    decode_challenge(challenge).map(|c| c.username)
+}
+
+/// Second signin step: checks a TOTP or recovery code and issues the session token.
+pub async fn signin_two_factor(
+    db: web::Data<Database>,
+    challenge: &str,
+    code: &str,
+) -> Result<(ObjectId, Jwt), SigninError> {
+    let claims = decode_challenge(challenge).ok_or(SigninError::InvalidChallenge)?;
+    let id: ObjectId = claims
+        .sub
+        .parse()
+        .map_err(|_| SigninError::InvalidChallenge)?;
+
+    let user = db
+        .collection::<User>("users")
+        .find_one(doc! { "_id": id })
+        .projection(doc! { "litter": 0 })
+        .await
+        .map_err(|e| {
+            error!("Error when loading user for 2FA: {}", e);
+            SigninError::InvalidCredentials
+        })?
+        .ok_or(SigninError::InvalidChallenge)?;
+
+    if user.disabled {
+        info!("Signin to disabled account {}", id);
+        return Err(SigninError::AccountDisabled);
+    }
+    let Some(two_factor) = user.two_factor.as_ref().filter(|t| t.enabled) else {
+        return Err(SigninError::InvalidChallenge);
+    };
+
+    match check_second_factor(&db, id, two_factor, code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            info!("Invalid second factor for {}", id);
+            return Err(SigninError::InvalidCredentials);
+        }
+        Err(e) => {
+            error!("Error when checking second factor: {}", e);
+            return Err(SigninError::InvalidCredentials);
+        }
+    }
+
+    let jwt = issue_session_token(id, &user).map_err(|e| {
+        error!("Error when creating jwt: {}", e);
+        SigninError::InvalidCredentials
+    })?;
+    Ok((id, jwt))
+}
+
+/// Accepts a TOTP code or an unused recovery code. Both are consumed atomically,
+/// so concurrent requests cannot use the same code twice.
+async fn check_second_factor(
+    db: &Database,
+    id: ObjectId,
+    two_factor: &TwoFactor,
+    code: &str,
+) -> Result<bool, mongodb::error::Error> {
+    let users = db.collection::<User>("users");
+    let code = code.trim();
+
+    if totp::is_totp_code(code) {
+        let last_step = two_factor.last_step.map(|s| s as u64);
+        let Some(step) = totp::verify(&two_factor.secret, code, last_step) else {
+            return Ok(false);
+        };
+        let step = step as i64;
+
+        let res = users
+            .update_one(
+                doc! {
+                    "_id": id,
+                    "two_factor.secret": &two_factor.secret,
+                    "$or": [
+                        { "two_factor.last_step": null },
+                        { "two_factor.last_step": { "$lt": step } },
+                    ],
+                },
+                doc! { "$set": { "two_factor.last_step": step } },
+            )
+            .await?;
+        return Ok(res.modified_count == 1);
+    }
+
+    let hash = totp::hash_recovery_code(code);
+    let res = users
+        .update_one(
+            doc! { "_id": id, "two_factor.enabled": true, "two_factor.recovery_codes": &hash },
+            doc! { "$pull": { "two_factor.recovery_codes": &hash } },
+        )
+        .await?;
+    if res.modified_count == 1 {
+        info!("{} signed in with a recovery code", id);
+    }
+    Ok(res.modified_count == 1)
+}
+
+async fn load_two_factor(db: &Database, id: ObjectId) -> Result<User, TwoFactorError> {
+    db.collection::<User>("users")
+        .find_one(doc! { "_id": id })
+        .projection(doc! { "litter": 0 })
+        .await?
+        .ok_or(TwoFactorError::NotEnabled)
+}
+
+/// Starts (or restarts) the enrolment: stores a new pending secret and returns it
+/// together with the provisioning URI for authenticator apps.
+pub async fn setup_two_factor(
+    db: web::Data<Database>,
+    id: ObjectId,
+) -> Result<(String, String), TwoFactorError> {
+    let user = load_two_factor(&db, id).await?;
+    if user.two_factor.as_ref().is_some_and(|t| t.enabled) {
+        return Err(TwoFactorError::AlreadyEnabled);
+    }
+
+    let secret = totp::generate_secret();
+    let res = db
+        .collection::<User>("users")
+        .update_one(
+            doc! { "_id": id, "two_factor.enabled": { "$ne": true } },
+            doc! { "$set": { "two_factor": {
+                "secret": &secret,
+                "enabled": false,
+                "recovery_codes": [],
+            } } },
+        )
+        .await?;
+    if res.matched_count == 0 {
+        return Err(TwoFactorError::AlreadyEnabled);
+    }
+
+    let uri = totp::provisioning_uri(&secret, &user.username);
+    Ok((secret, uri))
+}
+
+/// Finishes the enrolment with a code from the app and returns the recovery codes.
+pub async fn enable_two_factor(
+    db: web::Data<Database>,
+    id: ObjectId,
+    code: &str,
+) -> Result<Vec<String>, TwoFactorError> {
+    let user = load_two_factor(&db, id).await?;
+    let two_factor = user.two_factor.ok_or(TwoFactorError::NotEnabled)?;
+    if two_factor.enabled {
+        return Err(TwoFactorError::AlreadyEnabled);
+    }
+    if !check_second_factor(&db, id, &two_factor, code).await? {
+        return Err(TwoFactorError::InvalidCode);
+    }
+
+    let codes = totp::generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|c| totp::hash_recovery_code(c)).collect();
+    db.collection::<User>("users")
+        .update_one(
+            doc! { "_id": id, "two_factor.secret": &two_factor.secret },
+            doc! { "$set": { "two_factor.enabled": true, "two_factor.recovery_codes": hashes } },
+        )
+        .await?;
+
+    info!("{} enabled two-factor authentication", id);
+    Ok(codes)
+}
+
+pub async fn disable_two_factor(
+    db: web::Data<Database>,
+    id: ObjectId,
+    code: &str,
+) -> Result<(), TwoFactorError> {
+    let user = load_two_factor(&db, id).await?;
+    let two_factor = user
+        .two_factor
+        .filter(|t| t.enabled)
+        .ok_or(TwoFactorError::NotEnabled)?;
+    if !check_second_factor(&db, id, &two_factor, code).await? {
+        return Err(TwoFactorError::InvalidCode);
+    }
+
+    db.collection::<User>("users")
+        .update_one(doc! { "_id": id }, doc! { "$unset": { "two_factor": "" } })
+        .await?;
+
+    info!("{} disabled two-factor authentication", id);
+    Ok(())
+}
+
+/// Replaces all recovery codes, e.g. after some were used up.
+pub async fn regenerate_recovery_codes(
+    db: web::Data<Database>,
+    id: ObjectId,
+    code: &str,
+) -> Result<Vec<String>, TwoFactorError> {
+    let user = load_two_factor(&db, id).await?;
+    let two_factor = user
+        .two_factor
+        .filter(|t| t.enabled)
+        .ok_or(TwoFactorError::NotEnabled)?;
+    if !check_second_factor(&db, id, &two_factor, code).await? {
+        return Err(TwoFactorError::InvalidCode);
+    }
+
+    let codes = totp::generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|c| totp::hash_recovery_code(c)).collect();
+    db.collection::<User>("users")
+        .update_one(
+            doc! { "_id": id, "two_factor.enabled": true },
+            doc! { "$set": { "two_factor.recovery_codes": hashes } },
+        )
+        .await?;
+
+    info!("{} regenerated recovery codes", id);
+    Ok(codes)
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    // Accounts created through an identity provider have no local password.
+    if password_hash.is_empty() {
+        let _ = verify_password(password, &DUMMY_HASH);
+        return false;
+    }
+
+    let parsed_hash = match PasswordHash::new(password_hash) {
+        Ok(o) => o,
+        Err(e) => {
+            error!("Stored password hash is invalid: {}", e);
+            // Still spend the time of a verification.
+            let _ = verify_password(password, &DUMMY_HASH);
+            return false;
+        }
+    };
+
+    argon2()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .is_ok()
+}
+
+/// Whether a hash was created with other parameters than the configured ones.
+fn needs_rehash(password_hash: &str) -> bool {
+    let Ok(parsed_hash) = PasswordHash::new(password_hash) else {
+        return true;
+    };
+    let Ok(params) = Params::try_from(&parsed_hash) else {
+        return true;
+    };
+
+    parsed_hash.algorithm != Algorithm::Argon2id.ident()
+        || parsed_hash.version != Some(Version::V0x13.into())
+        || params.m_cost() != ARGON2_PARAMS.m_cost()
+        || params.t_cost() != ARGON2_PARAMS.t_cost()
+        || params.p_cost() != ARGON2_PARAMS.p_cost()
+}
+
+/// Replaces the hash of `user` with one using the current parameters, without delaying the login.
+fn rehash_in_background(db: web::Data<Database>, user: &User, password: &str) {
+    let Some(id) = user._id else {
+        return;
+    };
+    let old_hash = user.password_hash.clone();
+    let password = password.to_string();
+
+    tokio::spawn(async move {
+        let Ok(new_hash) = hash_password(&password) else {
+            return;
+        };
+        // Matching the old hash avoids overwriting a password that changed in the meantime.
+        match db
+            .collection::<User>("users")
+            .update_one(
+                doc! { "_id": id, "password_hash": old_hash },
+                doc! { "$set": { "password_hash": new_hash } },
+            )
+            .await
+        {
+            Ok(_) => info!("Rehashed password of {} with current parameters", id),
+            Err(e) => error!("Failed to store rehashed password: {:?}", e),
+        }
+    });
+}
+
+/// Session token for `user`, as handed out by signin.
+pub(crate) fn issue_session_token(id: ObjectId, user: &User) -> anyhow::Result<Jwt> {
+    Jwt::new(Extras {
+        id: id.to_hex(),
+        username: user.username.clone(),
+        role: user.role,
+    })
 }
 
 impl Jwt {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
     fn new(extras: Extras) -> anyhow::Result<Self> {
         let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS512);
-        header.extras = HashMap::with_capacity(1);
+        header.extras = HashMap::with_capacity(3);
         header.extras.insert("id".to_string(), extras.id);
         header
             .extras
             .insert("username".to_string(), extras.username);
+        header
+            .extras
+            .insert("role".to_string(), extras.role.as_str().to_string());
 
         let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
 
@@ -176,7 +689,7 @@ impl Jwt {
             &Claims {
                 exp: now + one_week,
             },
-            &EncodingKey::from_secret("secret".as_ref()),
+            &EncodingKey::from_secret(JWT_SECRET.as_ref()),
         )?;
 
         Ok(Self(token))
@@ -189,7 +702,7 @@ impl TryInto<ObjectId> for Jwt {
     fn try_into(self) -> Result<ObjectId, Self::Error> {
         let tokendata: TokenData<Claims> = jsonwebtoken::decode(
             self.0,
-            &DecodingKey::from_secret("secret".as_ref()),
+            &DecodingKey::from_secret(JWT_SECRET.as_ref()),
             &Validation::new(jsonwebtoken::Algorithm::HS512),
         )?;
         debug!("Tokendata could be extracted {:?}", tokendata);
@@ -206,32 +719,148 @@ impl TryInto<ObjectId> for Jwt {
     }
 }
 
+enum BearerToken {
+    Session(ObjectId),
+    Api(String),
+}
+
+fn bearer_token(req: &HttpRequest) -> Result<BearerToken, HttpError> {
+    let auth_header = req
+        .headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok());
+    debug!("Entering session check with {:?}", auth_header);
+
+    if let Some(header_value) = auth_header {
+        if let Some(token) = header_value.strip_prefix("Bearer ") {
+            if token.starts_with(TOKEN_PREFIX) {
+                return Ok(BearerToken::Api(token.to_string()));
+            }
+
+            let id: Result<ObjectId, _> = Jwt(token.to_string()).try_into();
+
+            debug!("Result from JWT {:?}", id);
+
+            return id
+                .map(BearerToken::Session)
+                .map_err(|_| HttpError::InvalidToken);
+        }
+        debug!("Auth header does not start with 'Bearer '. Request invalid!");
+    }
+    debug!("No auth header. Request invalid!");
+
+    Err(HttpError::InvalidToken)
+}
+
+/// Accepts a valid token of an existing, enabled account.
+/// The role is read from the database, so changes apply before the token expires.
+async fn load_session(
+    token: BearerToken,
+    db: Option<web::Data<Database>>,
+) -> Result<UserSession, HttpError> {
+    let db = db.ok_or(HttpError::NetworkError)?;
+    let network_error = |e: mongodb::error::Error| {
+        error!("Error when loading session user: {}", e);
+        HttpError::NetworkError
+    };
+
+    let (id, token_scope) = match token {
+        BearerToken::Session(id) => (id, None),
+        BearerToken::Api(token) => {
+            let api_token = api_token::authenticate(&db, &token)
+                .await
+                .map_err(network_error)?
+                .ok_or(HttpError::InvalidToken)?;
+            (api_token.user_id, Some(api_token.scope))
+        }
+    };
+
+    let state = db
+        .collection::<SessionState>("users")
+        .find_one(doc! { "_id": id })
+        .projection(doc! { "role": 1, "disabled": 1 })
+        .await
+        .map_err(network_error)?
+        .ok_or(HttpError::InvalidToken)?;
+
+    if state.disabled {
+        debug!("Account {} is disabled. Request invalid!", id);
+        return Err(HttpError::AccountDisabled);
+    }
+
+    Ok(UserSession {
+        id,
+        role: state.role,
+        token_scope,
+    })
+}
+
 impl FromRequest for UserSession {
     type Error = HttpError;
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let auth_header = req
-            .headers()
-            .get("Authorization")
-            .and_then(|v| v.to_str().ok());
-        debug!("Entering session check with {:?}", auth_header);
-
-        if let Some(header_value) = auth_header {
-            if let Some(token) = header_value.strip_prefix("Bearer ") {
-                let id: Result<ObjectId, _> = Jwt(token.to_string()).try_into();
-
-                debug!("Result from JWT {:?}", id);
-
-                match id {
-                    Ok(id) => return ready(Ok(UserSession { id })),
-                    Err(_) => return ready(Err(HttpError::InvalidToken)),
-                }
+        let token = bearer_token(req);
+        let db = req.app_data::<web::Data<Database>>().cloned();
+        let read_only = req.method().is_safe();
+
+        Box::pin(async move {
+            let session = load_session(token?, db).await?;
+            if session.token_scope.is_some() && !read_only {
+                debug!("API token used for a modifying request. Request invalid!");
+                return Err(HttpError::Forbidden);
             }
-            debug!("Auth header does not start with 'Bearer '. Request invalid!");
-        }
-        debug!("No auth header. Request invalid!");
+            Ok(session)
+        })
+    }
+}
+
+impl FromRequest for UploadSession {
+    type Error = HttpError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = bearer_token(req);
+        let db = req.app_data::<web::Data<Database>>().cloned();
+
+        Box::pin(async move {
+            let session = load_session(token?, db).await?;
+            if session.token_scope == Some(TokenScope::ReadOnly) {
+                debug!("Read-only API token used for an upload. Request invalid!");
+                return Err(HttpError::Forbidden);
+            }
+            Ok(UploadSession(session))
+        })
+    }
+}
+
+impl<R: RoleRequirement + 'static> FromRequest for RequireRole<R> {
+    type Error = HttpError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
 
-        ready(Err(HttpError::InvalidToken))
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let session = UserSession::from_request(req, payload);
+
+        Box::pin(async move {
+            let session = session.await?;
+            // Administration needs a signed in user, not a script.
+            if session.token_scope.is_some() {
+                debug!("API token used for an admin endpoint. Request invalid!");
+                return Err(HttpError::Forbidden);
+            }
+            if session.role < R::MIN {
+                debug!(
+                    "Role {:?} is below {:?}. Request invalid!",
+                    session.role,
+                    R::MIN
+                );
+                return Err(HttpError::Forbidden);
+            }
+
+            Ok(RequireRole {
+                session,
+                _role: PhantomData,
+            })
+        })
     }
 }
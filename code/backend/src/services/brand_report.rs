@@ -0,0 +1,560 @@
+//! Per-brand litter footprint, exported as JSON, CSV or PDF to confront producers.
+
+use actix_web::web;
+use futures::TryStreamExt;
+use log::error;
+use mongodb::{
+    Database,
+    bson::{Bson, Document, doc, oid::ObjectId},
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    models::user::{Role, User},
+    services::{
+        cluster::number,
+        export::spreadsheet_text,
+        litter::{
+            LitterError, LitterFilter, LitterScope, filter_collation, litter_stages,
+            litter_stages_without_images,
+        },
+        pdf::{MARGIN, PAGE_HEIGHT, PAGE_WIDTH, PdfDocument},
+        validation::invalid,
+    },
+};
+
+pub const DEFAULT_BRANDS: i64 = 20;
+pub const MAX_BRANDS: i64 = 100;
+/// Hotspots listed per brand.
+const HOTSPOTS: i32 = 5;
+/// Photos per brand, only for the first `PHOTO_BRANDS` brands.
+const PHOTOS: i64 = 3;
+const PHOTO_BRANDS: usize = 10;
+/// Hotspot cells are 0.01 degrees wide, about a kilometre.
+const CELLS_PER_DEGREE: f64 = 100.0;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct MaterialCount {
+    pub material: Option<String>,
+    pub items: u64,
+    /// Estimated weight in grams
+    pub weight: f64,
+}
+
+/// Area of about a square kilometre where many items of a brand were found.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Hotspot {
+    /// Mean position of the items
+    pub lat: f64,
+    pub lng: f64,
+    pub items: u64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ExamplePhoto {
+    pub report_id: String,
+    pub date: String,
+    pub r#type: String,
+    #[schema(format = "binary")]
+    pub file: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BrandSummary {
+    pub brand: String,
+    pub items: u64,
+    /// Estimated weight in grams
+    pub weight: f64,
+    /// Reports with at least one item of the brand
+    pub reports: u64,
+    pub materials: Vec<MaterialCount>,
+    /// Places with the most items, most first
+    pub hotspots: Vec<Hotspot>,
+    pub example_photos: Vec<ExamplePhoto>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BrandReport {
+    pub generated_at: String,
+    pub scope: LitterScope,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// `min_lng,min_lat,max_lng,max_lat` of the region, if restricted
+    pub bbox: Option<String>,
+    /// Brands with the most items first
+    pub brands: Vec<BrandSummary>,
+}
+
+/// Brands are grouped ignoring case, so the names of different facets are compared the same way.
+fn brand_key(brand: &str) -> String {
+    brand.trim().to_lowercase()
+}
+
+fn docs(result: &Document, key: &str) -> Vec<Document> {
+    result
+        .get_array(key)
+        .into_iter()
+        .flatten()
+        .filter_map(Bson::as_document)
+        .cloned()
+        .collect()
+}
+
+async fn example_photos(
+    db: &Database,
+    user_id: ObjectId,
+    scope: LitterScope,
+    filter: &LitterFilter,
+    brand: &str,
+) -> Result<Vec<ExamplePhoto>, LitterError> {
+    let filter = LitterFilter {
+        brand: Some(brand.to_string()),
+        ..filter.clone()
+    };
+    let mut pipeline = litter_stages(user_id, scope, &filter);
+    pipeline.extend([
+        doc! { "$match": { "file": { "$ne": null } } },
+        doc! { "$sort": { "time_stamp": -1, "_id": -1 } },
+        doc! { "$limit": PHOTOS },
+        doc! { "$project": { "file": 1, "type": 1, "time_stamp": 1 } },
+    ]);
+
+    let photos: Vec<Document> = db
+        .collection::<User>("users")
+        .aggregate(pipeline)
+        .collation(filter_collation())
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(photos
+        .iter()
+        .filter_map(|photo| {
+            Some(ExamplePhoto {
+                report_id: photo.get_object_id("_id").ok()?.to_hex(),
+                date: photo.get_datetime("time_stamp").ok()?.to_string(),
+                r#type: photo.get_str("type").unwrap_or_default().to_string(),
+                file: photo.get_binary_generic("file").ok()?.clone(),
+            })
+        })
+        .filter(|photo| !photo.file.is_empty())
+        .collect())
+}
+
+/// Items, weight, materials, hotspots and example photos of the `limit` brands with the
+/// most items. Photos of other users are only included for moderators.
+pub async fn brand_report(
+    db: web::Data<Database>,
+    user_id: ObjectId,
+    role: Role,
+    scope: LitterScope,
+    filter: &LitterFilter,
+    limit: Option<i64>,
+) -> Result<Vec<BrandSummary>, LitterError> {
+    let mut errors = filter
+        .area
+        .as_ref()
+        .map(|a| a.validate())
+        .unwrap_or_default();
+    let limit = limit.unwrap_or(DEFAULT_BRANDS);
+    if !(1..=MAX_BRANDS).contains(&limit) {
+        errors.push(invalid(
+            "limit",
+            format!("must be between 1 and {MAX_BRANDS}"),
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(LitterError::InvalidInput(errors));
+    }
+
+    let mut items = doc! { "entries.brand": { "$nin": [null, ""] } };
+    if let Some(brand) = &filter.brand {
+        items.insert("entries.brand", brand.trim());
+    }
+    let cell = |field: &str| {
+        doc! { "$floor": { "$multiply": [format!("${field}"), CELLS_PER_DEGREE] } }
+    };
+
+    let mut items_pipeline = litter_stages_without_images(user_id, scope, filter);
+    items_pipeline.extend([
+        doc! { "$project": { "lat": 1, "lng": 1, "entries": 1 } },
+        doc! { "$unwind": "$entries" },
+        doc! { "$match": items },
+    ]);
+
+    let mut brands_pipeline = items_pipeline.clone();
+    brands_pipeline.extend([
+        doc! { "$group": {
+            "_id": { "brand": "$entries.brand", "report": "$_id" },
+            "items": { "$sum": 1 },
+            "weight": { "$sum": "$entries.weight" },
+        } },
+        doc! { "$group": {
+            "_id": "$_id.brand",
+            "items": { "$sum": "$items" },
+            "weight": { "$sum": "$weight" },
+            "reports": { "$sum": 1 },
+        } },
+        doc! { "$sort": { "items": -1, "_id": 1 } },
+        doc! { "$limit": limit },
+    ]);
+
+    // The collation makes "Coca-Cola" and "coca-cola" one brand.
+    let users = db.collection::<User>("users");
+    let top: Vec<Document> = users
+        .aggregate(brands_pipeline)
+        .collation(filter_collation())
+        .await?
+        .try_collect()
+        .await?;
+    if top.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // Materials and hotspots only of the top brands, not of every brand ever reported.
+    let names: Vec<&str> = top.iter().filter_map(|b| b.get_str("_id").ok()).collect();
+    let mut details_pipeline = items_pipeline;
+    details_pipeline.extend([
+        doc! { "$match": { "entries.brand": { "$in": names } } },
+        doc! { "$facet": {
+            "materials": [
+                { "$group": {
+                    "_id": { "brand": "$entries.brand", "material": "$entries.material" },
+                    "items": { "$sum": 1 },
+                    "weight": { "$sum": "$entries.weight" },
+                } },
+                { "$sort": { "items": -1 } },
+            ],
+            "hotspots": [
+                { "$group": {
+                    "_id": { "brand": "$entries.brand", "x": cell("lng"), "y": cell("lat") },
+                    "items": { "$sum": 1 },
+                    "lat": { "$avg": "$lat" },
+                    "lng": { "$avg": "$lng" },
+                } },
+                { "$group": {
+                    "_id": "$_id.brand",
+                    "top": { "$topN": {
+                        "n": HOTSPOTS,
+                        "sortBy": { "items": -1 },
+                        "output": { "items": "$items", "lat": "$lat", "lng": "$lng" },
+                    } },
+                } },
+            ],
+        } },
+    ]);
+    let details: Vec<Document> = users
+        .aggregate(details_pipeline)
+        .collation(filter_collation())
+        .await?
+        .try_collect()
+        .await?;
+    let result = details.into_iter().next().unwrap_or_default();
+
+    let materials = docs(&result, "materials");
+    let hotspots = docs(&result, "hotspots");
+    let mut brands: Vec<BrandSummary> = top
+        .iter()
+        .filter_map(|group| {
+            let brand = group.get_str("_id").ok()?.to_string();
+            let key = brand_key(&brand);
+            let group_brand = |g: &Document| {
+                g.get_document("_id")
+                    .ok()
+                    .and_then(|id| id.get_str("brand").ok())
+                    .or_else(|| g.get_str("_id").ok())
+                    .is_some_and(|b| brand_key(b) == key)
+            };
+
+            Some(BrandSummary {
+                items: number(group, "items") as u64,
+                weight: number(group, "weight"),
+                reports: number(group, "reports") as u64,
+                materials: materials
+                    .iter()
+                    .filter(|m| group_brand(m))
+                    .map(|m| MaterialCount {
+                        material: m
+                            .get_document("_id")
+                            .ok()
+                            .and_then(|id| id.get_str("material").ok())
+                            .map(str::to_string),
+                        items: number(m, "items") as u64,
+                        weight: number(m, "weight"),
+                    })
+                    .collect(),
+                hotspots: hotspots
+                    .iter()
+                    .filter(|h| group_brand(h))
+                    .flat_map(|h| docs(h, "top"))
+                    .map(|h| Hotspot {
+                        lat: number(&h, "lat"),
+                        lng: number(&h, "lng"),
+                        items: number(&h, "items") as u64,
+                    })
+                    .collect(),
+                brand,
+                example_photos: vec![],
+            })
+        })
+        .collect();
+
+    // Photos of other users are never handed out to regular users.
+    let photo_scope = if role >= Role::Moderator {
+        scope
+    } else {
+        LitterScope::Own
+    };
+    for summary in brands.iter_mut().take(PHOTO_BRANDS) {
+        summary.example_photos =
+            example_photos(&db, user_id, photo_scope, filter, &summary.brand).await?;
+    }
+
+    Ok(brands)
+}
+
+impl BrandReport {
+    pub fn to_csv(&self) -> Result<Vec<u8>, LitterError> {
+        let mut writer = csv::Writer::from_writer(vec![]);
+        let rows = self.brands.iter().map(|brand| {
+            let materials = brand
+                .materials
+                .iter()
+                .map(|m| format!("{}:{}", m.material.as_deref().unwrap_or("unknown"), m.items))
+                .collect::<Vec<_>>()
+                .join(";");
+            let hotspot = brand.hotspots.first();
+            [
+                spreadsheet_text(brand.brand.clone()),
+                brand.items.to_string(),
+                format!("{:.1}", brand.weight),
+                brand.reports.to_string(),
+                materials,
+                hotspot.map(|h| format!("{:.5}", h.lat)).unwrap_or_default(),
+                hotspot.map(|h| format!("{:.5}", h.lng)).unwrap_or_default(),
+                hotspot.map(|h| h.items.to_string()).unwrap_or_default(),
+            ]
+        });
+
+        let header = [
+            "brand",
+            "items",
+            "weight_g",
+            "reports",
+            "materials",
+            "hotspot_lat",
+            "hotspot_lng",
+            "hotspot_items",
+        ];
+        std::iter::once(header.map(str::to_string))
+            .chain(rows)
+            .try_for_each(|row| writer.write_record(&row))
+            .and_then(|_| writer.flush().map_err(csv::Error::from))
+            .map_err(|e| {
+                error!("Failed to write brand report CSV: {:?}", e);
+                LitterError::InternalError
+            })?;
+        writer.into_inner().map_err(|e| {
+            error!("Failed to write brand report CSV: {:?}", e);
+            LitterError::InternalError
+        })
+    }
+
+    fn period(&self) -> String {
+        match (&self.from, &self.to) {
+            (None, None) => "all time".to_string(),
+            (from, to) => format!(
+                "{} to {}",
+                from.as_deref().unwrap_or("start"),
+                to.as_deref().unwrap_or("today")
+            ),
+        }
+    }
+
+    pub fn to_pdf(&self) -> Vec<u8> {
+        let mut pdf = PdfDocument::new("Brand litter report");
+        let left = MARGIN;
+        let right = PAGE_WIDTH - MARGIN;
+        let mut y = PAGE_HEIGHT - MARGIN - 10.0;
+
+        pdf.text(left, y, 20.0, true, "Brand litter report");
+        y -= 24.0;
+        let scope = match self.scope {
+            LitterScope::Own => "own reports",
+            LitterScope::Community => "all community reports",
+        };
+        for line in [
+            format!("Period: {}", self.period()),
+            format!("Region: {}", self.bbox.as_deref().unwrap_or("everywhere")),
+            format!("Data: {scope}"),
+            format!("Generated: {}", self.generated_at),
+        ] {
+            pdf.text(left, y, 10.0, false, &line);
+            y -= 14.0;
+        }
+
+        // Overview table
+        y -= 14.0;
+        let columns = [left, left + 230.0, left + 300.0, left + 380.0];
+        for (x, title) in columns
+            .iter()
+            .zip(["Brand", "Items", "Weight (g)", "Reports"])
+        {
+            pdf.text(*x, y, 10.0, true, title);
+        }
+        y -= 6.0;
+        pdf.line(left, y, right, y, 0.5);
+        y -= 14.0;
+        for brand in &self.brands {
+            if y < MARGIN {
+                pdf.new_page();
+                y = PAGE_HEIGHT - MARGIN;
+            }
+            let cells = [
+                brand.brand.clone(),
+                brand.items.to_string(),
+                format!("{:.0}", brand.weight),
+                brand.reports.to_string(),
+            ];
+            for (x, cell) in columns.iter().zip(cells) {
+                pdf.text(*x, y, 10.0, false, &cell);
+            }
+            y -= 14.0;
+        }
+        if self.brands.is_empty() {
+            pdf.text(left, y, 10.0, false, "No branded litter found.");
+        }
+
+        for brand in &self.brands {
+            self.brand_page(&mut pdf, brand);
+        }
+
+        pdf.finish()
+    }
+
+    fn brand_page(&self, pdf: &mut PdfDocument, brand: &BrandSummary) {
+        pdf.new_page();
+        let left = MARGIN;
+        let mut y = PAGE_HEIGHT - MARGIN - 10.0;
+
+        pdf.text(left, y, 18.0, true, &brand.brand);
+        y -= 22.0;
+        pdf.text(
+            left,
+            y,
+            10.0,
+            false,
+            &format!(
+                "{} items, {:.0} g in {} reports ({})",
+                brand.items,
+                brand.weight,
+                brand.reports,
+                self.period()
+            ),
+        );
+
+        y -= 28.0;
+        pdf.text(left, y, 12.0, true, "Materials");
+        y -= 16.0;
+        for material in brand.materials.iter().take(8) {
+            pdf.text(
+                left,
+                y,
+                10.0,
+                false,
+                &format!(
+                    "{}: {} items, {:.0} g",
+                    material.material.as_deref().unwrap_or("unknown"),
+                    material.items,
+                    material.weight
+                ),
+            );
+            y -= 14.0;
+        }
+
+        y -= 14.0;
+        pdf.text(left, y, 12.0, true, "Hotspots");
+        y -= 16.0;
+        let map_size = 180.0;
+        let map_top = y + 10.0;
+        for (i, hotspot) in brand.hotspots.iter().enumerate() {
+            pdf.text(
+                left,
+                y,
+                10.0,
+                false,
+                &format!(
+                    "{}. {:.4}, {:.4}: {} items",
+                    i + 1,
+                    hotspot.lat,
+                    hotspot.lng,
+                    hotspot.items
+                ),
+            );
+            y -= 14.0;
+        }
+        hotspot_map(
+            pdf,
+            &brand.hotspots,
+            PAGE_WIDTH - MARGIN - map_size,
+            map_top - map_size,
+            map_size,
+        );
+        y = y.min(map_top - map_size) - 24.0;
+
+        if !brand.example_photos.is_empty() {
+            pdf.text(left, y, 12.0, true, "Example photos");
+            y -= 12.0;
+            let size = 150.0;
+            let mut x = left;
+            for photo in &brand.example_photos {
+                if let Some(width) = pdf.jpeg(&photo.file, x, y - size, size, size) {
+                    pdf.text(x, y - size - 12.0, 8.0, false, &photo.date);
+                    x += width + 15.0;
+                }
+            }
+        }
+    }
+}
+
+/// Hotspots drawn as squares sized by their items, on a box around them with north up.
+fn hotspot_map(pdf: &mut PdfDocument, hotspots: &[Hotspot], x: f32, y: f32, size: f32) {
+    pdf.stroke_rect(x, y, size, size, 0.6);
+    if hotspots.is_empty() {
+        return;
+    }
+
+    let (mut min_lat, mut max_lat) = (f64::MAX, f64::MIN);
+    let (mut min_lng, mut max_lng) = (f64::MAX, f64::MIN);
+    for h in hotspots {
+        min_lat = min_lat.min(h.lat);
+        max_lat = max_lat.max(h.lat);
+        min_lng = min_lng.min(h.lng);
+        max_lng = max_lng.max(h.lng);
+    }
+    // Keep some room around the points and the aspect of a square
+    let span = (max_lat - min_lat).max(max_lng - min_lng).max(0.01) * 1.2;
+    let (center_lat, center_lng) = ((min_lat + max_lat) / 2.0, (min_lng + max_lng) / 2.0);
+    let most = hotspots.iter().map(|h| h.items).max().unwrap_or(1).max(1) as f32;
+
+    for h in hotspots {
+        let px = x + ((h.lng - center_lng) / span + 0.5) as f32 * size;
+        let py = y + ((h.lat - center_lat) / span + 0.5) as f32 * size;
+        let side = 4.0 + 12.0 * (h.items as f32 / most);
+        pdf.fill_rect(
+            px - side / 2.0,
+            py - side / 2.0,
+            side,
+            side,
+            [0.85, 0.25, 0.2],
+        );
+    }
+    pdf.text(
+        x,
+        y - 10.0,
+        7.0,
+        false,
+        &format!("{:.3}, {:.3}", center_lat, center_lng),
+    );
+}
@@ -0,0 +1,602 @@
+use actix_web::web;
+use futures::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{Bson, DateTime, Document, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    models::{
+        challenge::{Challenge, ChallengeMetric},
+        litter::Litter,
+        user::User,
+    },
+    services::{
+        account::{frontend_url, send_in_background},
+        cluster::number,
+        group::{self, GroupError},
+        litter::{LitterFilter, filter_collation},
+        mailer::{Mail, Mailer},
+        validation::{FieldError, invalid},
+    },
+};
+
+pub const CHALLENGE_TITLE_MAX_LEN: usize = 100;
+pub const CHALLENGE_DESCRIPTION_MAX_LEN: usize = 500;
+pub const CHALLENGE_FILTER_MAX_LEN: usize = 64;
+/// Running and upcoming challenges per group, or per user for personal ones.
+pub const MAX_OPEN_CHALLENGES: u64 = 20;
+/// Longest time from start to deadline.
+const MAX_CHALLENGE_DURATION_MS: i64 = 366 * 24 * 60 * 60 * 1000;
+/// Challenges listed per request.
+const MAX_LISTED_CHALLENGES: i64 = 100;
+
+#[derive(Debug, Clone, Serialize)]
+pub enum ChallengeError {
+    InvalidInput(Vec<FieldError>),
+    /// No such challenge, or not one of the user's or the user's groups.
+    NotFound,
+    /// Only group leaders may do this.
+    Forbidden,
+    NetworkError,
+}
+
+impl From<mongodb::error::Error> for ChallengeError {
+    fn from(e: mongodb::error::Error) -> Self {
+        error!("Error when accessing challenges in db: {:?}", e);
+        ChallengeError::NetworkError
+    }
+}
+
+impl From<GroupError> for ChallengeError {
+    fn from(e: GroupError) -> Self {
+        match e {
+            GroupError::InvalidInput(errors) => ChallengeError::InvalidInput(errors),
+            GroupError::NotFound => ChallengeError::NotFound,
+            GroupError::Forbidden => ChallengeError::Forbidden,
+            _ => ChallengeError::NetworkError,
+        }
+    }
+}
+
+fn collection(db: &Database) -> Collection<Challenge> {
+    db.collection::<Challenge>("challenges")
+}
+
+pub async fn ensure_indexes(db: &Database) {
+    let indexes = [
+        IndexModel::builder()
+            .keys(doc! { "group_id": 1, "deadline": -1 })
+            .options(
+                IndexOptions::builder()
+                    .name(Some("challenge_group".to_string()))
+                    .build(),
+            )
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "owner": 1, "deadline": -1 })
+            .options(
+                IndexOptions::builder()
+                    .name(Some("challenge_owner".to_string()))
+                    .build(),
+            )
+            .build(),
+    ];
+
+    if let Err(e) = collection(db).create_indexes(indexes).await {
+        error!("Failed to ensure indexes for 'challenges': {:?}", e);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChallengeStatus {
+    Upcoming,
+    Running,
+    /// Target reached, reports still count until the deadline
+    Completed,
+    /// Deadline passed without reaching the target
+    Failed,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChallengeData {
+    pub id: String,
+    pub title: String,
+    pub description: Option<String>,
+    /// Missing for personal challenges
+    pub group_id: Option<String>,
+    pub metric: ChallengeMetric,
+    pub category: Option<String>,
+    pub material: Option<String>,
+    /// Items, grams or reports to reach
+    pub target: f64,
+    /// Counted so far, updated live with every report
+    pub progress: f64,
+    /// `progress` relative to `target`, capped at 100
+    pub percent: f64,
+    pub starts_at: String,
+    pub deadline: String,
+    pub status: ChallengeStatus,
+    pub completed_at: Option<String>,
+}
+
+/// Challenge as entered by a group leader or user, times already parsed.
+#[derive(Debug)]
+pub struct NewChallenge {
+    pub title: String,
+    pub description: Option<String>,
+    pub group_id: Option<ObjectId>,
+    pub metric: ChallengeMetric,
+    pub category: Option<String>,
+    pub material: Option<String>,
+    pub target: f64,
+    /// Now if missing
+    pub starts_at: Option<DateTime>,
+    pub deadline: DateTime,
+}
+
+/// Trimmed text, `None` if empty.
+fn text(value: Option<&str>) -> Option<String> {
+    value
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+impl NewChallenge {
+    fn validate(&self, starts_at: DateTime) -> Vec<FieldError> {
+        let mut errors = vec![];
+        let title = self.title.trim();
+        if title.is_empty() || title.chars().count() > CHALLENGE_TITLE_MAX_LEN {
+            errors.push(invalid(
+                "title",
+                format!("must be between 1 and {CHALLENGE_TITLE_MAX_LEN} characters long"),
+            ));
+        }
+        if text(self.description.as_deref())
+            .is_some_and(|d| d.chars().count() > CHALLENGE_DESCRIPTION_MAX_LEN)
+        {
+            errors.push(invalid(
+                "description",
+                format!("must be at most {CHALLENGE_DESCRIPTION_MAX_LEN} characters long"),
+            ));
+        }
+        for (field, value) in [("category", &self.category), ("material", &self.material)] {
+            if text(value.as_deref()).is_some_and(|v| v.chars().count() > CHALLENGE_FILTER_MAX_LEN)
+            {
+                errors.push(invalid(
+                    field,
+                    format!("must be at most {CHALLENGE_FILTER_MAX_LEN} characters long"),
+                ));
+            }
+        }
+        if !self.target.is_finite() || self.target <= 0.0 {
+            errors.push(invalid("target", "must be a positive number"));
+        }
+
+        let (start, end) = (
+            starts_at.timestamp_millis(),
+            self.deadline.timestamp_millis(),
+        );
+        if end <= start {
+            errors.push(invalid("deadline", "must be after starts_at"));
+        } else if end - start > MAX_CHALLENGE_DURATION_MS {
+            errors.push(invalid(
+                "deadline",
+                "must be at most a year after starts_at",
+            ));
+        }
+        if end <= DateTime::now().timestamp_millis() {
+            errors.push(invalid("deadline", "must be in the future"));
+        }
+        errors
+    }
+}
+
+/// Entries of a report that count for the challenge: items of other categories or
+/// materials on a counted report do not.
+fn counted_entries(challenge: &Challenge) -> Bson {
+    let mut item = vec![];
+    for (field, value) in [
+        ("category", &challenge.category),
+        ("material", &challenge.material),
+    ] {
+        if let Some(value) = value {
+            item.push(doc! { "$eq": [format!("$$e.{field}"), value] });
+        }
+    }
+    let entries = doc! { "$ifNull": ["$entries", []] };
+    if item.is_empty() {
+        Bson::Document(entries)
+    } else {
+        Bson::Document(doc! { "$filter": {
+            "input": entries,
+            "as": "e",
+            "cond": { "$and": item },
+        } })
+    }
+}
+
+/// Sum of each challenge's metric over the reports counted for it, in the order given.
+/// One aggregation over the reports of everyone taking part answers all challenges.
+async fn progress(db: &Database, challenges: &[Challenge]) -> Result<Vec<f64>, ChallengeError> {
+    if challenges.is_empty() {
+        return Ok(vec![]);
+    }
+    let group_ids: Vec<ObjectId> = challenges.iter().filter_map(|c| c.group_id).collect();
+    let members = group::members_of(db, &group_ids).await?;
+
+    let mut users = vec![];
+    let mut facets = Document::new();
+    for (i, challenge) in challenges.iter().enumerate() {
+        let mut filter = LitterFilter {
+            from: Some(challenge.starts_at),
+            to: Some(challenge.deadline),
+            category: challenge.category.clone(),
+            material: challenge.material.clone(),
+            ..Default::default()
+        }
+        .to_match();
+        // Group challenges count the reports of current members that are not disabled
+        let owners = match challenge.group_id {
+            Some(id) => {
+                filter.insert("owner_disabled", doc! { "$ne": true });
+                members.get(&id).cloned().unwrap_or_default()
+            }
+            None => vec![challenge.owner],
+        };
+        filter.insert("owner", doc! { "$in": &owners });
+        users.extend(owners);
+
+        facets.insert(
+            i.to_string(),
+            vec![
+                doc! { "$match": filter },
+                doc! { "$project": { "entries": counted_entries(challenge) } },
+                doc! { "$group": {
+                    "_id": null,
+                    "reports": { "$sum": 1 },
+                    "items": { "$sum": { "$size": "$entries" } },
+                    "weight": { "$sum": { "$sum": "$entries.weight" } },
+                } },
+            ],
+        );
+    }
+    users.sort();
+    users.dedup();
+    let from = challenges.iter().map(|c| c.starts_at).min();
+    let to = challenges.iter().map(|c| c.deadline).max();
+
+    let pipeline = vec![
+        doc! { "$match": { "_id": { "$in": users } } },
+        doc! { "$project": { "litter.file": 0 } },
+        doc! { "$unwind": "$litter" },
+        doc! { "$match": { "litter.time_stamp": { "$gte": from, "$lt": to } } },
+        doc! { "$replaceRoot": { "newRoot": { "$mergeObjects": [
+            "$litter",
+            { "owner": "$_id", "owner_disabled": "$disabled" },
+        ] } } },
+        doc! { "$facet": facets },
+    ];
+    let result: Option<Document> = db
+        .collection::<User>("users")
+        .aggregate(pipeline)
+        .collation(filter_collation())
+        .await?
+        .try_next()
+        .await?;
+    let result = result.unwrap_or_default();
+
+    Ok(challenges
+        .iter()
+        .enumerate()
+        .map(|(i, challenge)| {
+            let totals = result
+                .get_array(i.to_string())
+                .ok()
+                .and_then(|t| t.first())
+                .and_then(Bson::as_document)
+                .cloned()
+                .unwrap_or_default();
+            match challenge.metric {
+                ChallengeMetric::Items => number(&totals, "items"),
+                ChallengeMetric::Weight => number(&totals, "weight"),
+                ChallengeMetric::Reports => number(&totals, "reports"),
+            }
+        })
+        .collect())
+}
+
+/// Whether the report may have changed the challenge's progress. Before the analysis only
+/// the report itself counts, its entries change filtered challenges and the sums.
+fn may_count(challenge: &Challenge, report: &Litter) -> bool {
+    if report.time_stamp < challenge.starts_at || report.time_stamp >= challenge.deadline {
+        return false;
+    }
+    let filtered = challenge.category.is_some() || challenge.material.is_some();
+    if report.entries.is_empty() {
+        !filtered && challenge.metric == ChallengeMetric::Reports
+    } else {
+        filtered || challenge.metric != ChallengeMetric::Reports
+    }
+}
+
+fn status(challenge: &Challenge, now: DateTime) -> ChallengeStatus {
+    if challenge.completed_at.is_some() {
+        ChallengeStatus::Completed
+    } else if now < challenge.starts_at {
+        ChallengeStatus::Upcoming
+    } else if now < challenge.deadline {
+        ChallengeStatus::Running
+    } else {
+        ChallengeStatus::Failed
+    }
+}
+
+fn challenge_data(challenge: Challenge, progress: f64) -> ChallengeData {
+    let rfc3339 = |t: DateTime| t.try_to_rfc3339_string().unwrap_or_default();
+
+    ChallengeData {
+        id: challenge._id.map(|id| id.to_hex()).unwrap_or_default(),
+        group_id: challenge.group_id.map(|id| id.to_hex()),
+        metric: challenge.metric,
+        target: challenge.target,
+        progress,
+        percent: (progress / challenge.target * 100.0).min(100.0),
+        starts_at: rfc3339(challenge.starts_at),
+        deadline: rfc3339(challenge.deadline),
+        status: status(&challenge, DateTime::now()),
+        completed_at: challenge.completed_at.map(rfc3339),
+        title: challenge.title,
+        description: challenge.description,
+        category: challenge.category,
+        material: challenge.material,
+    }
+}
+
+/// Challenges of the user's groups and the user's personal ones.
+async fn visible(db: &Database, user_id: ObjectId) -> Result<Document, ChallengeError> {
+    let groups = group::group_ids(db, user_id).await?;
+    Ok(doc! { "$or": [
+        { "group_id": { "$in": groups } },
+        { "group_id": null, "owner": user_id },
+    ] })
+}
+
+/// Creates a challenge for a group the user leads, or a personal one without `group_id`.
+pub async fn create(
+    db: &Database,
+    user_id: ObjectId,
+    new: NewChallenge,
+) -> Result<ChallengeData, ChallengeError> {
+    let now = DateTime::now();
+    let starts_at = new.starts_at.unwrap_or(now);
+    let errors = new.validate(starts_at);
+    if !errors.is_empty() {
+        return Err(ChallengeError::InvalidInput(errors));
+    }
+
+    let mut open = doc! { "deadline": { "$gt": now } };
+    match new.group_id {
+        Some(id) => {
+            group::leadership(db, user_id, id).await?;
+            open.insert("group_id", id);
+        }
+        None => {
+            open.insert("group_id", Bson::Null);
+            open.insert("owner", user_id);
+        }
+    }
+    if collection(db).count_documents(open).await? >= MAX_OPEN_CHALLENGES {
+        return Err(ChallengeError::InvalidInput(vec![invalid(
+            "group_id",
+            format!("already has {MAX_OPEN_CHALLENGES} open challenges"),
+        )]));
+    }
+
+    let mut challenge = Challenge {
+        _id: None,
+        title: new.title.trim().to_string(),
+        description: text(new.description.as_deref()),
+        group_id: new.group_id,
+        owner: user_id,
+        metric: new.metric,
+        category: text(new.category.as_deref()),
+        material: text(new.material.as_deref()),
+        target: new.target,
+        starts_at,
+        deadline: new.deadline,
+        completed_at: None,
+        created_at: now,
+    };
+    challenge._id = collection(db)
+        .insert_one(&challenge)
+        .await?
+        .inserted_id
+        .as_object_id();
+
+    info!("{} created challenge {:?}", user_id, challenge._id);
+    let progress = progress(db, std::slice::from_ref(&challenge)).await?;
+    Ok(challenge_data(challenge, progress[0]))
+}
+
+/// Open challenges, closest deadline first, or with `past` the history of challenges whose
+/// deadline passed, latest first.
+pub async fn list(
+    db: &Database,
+    user_id: ObjectId,
+    past: bool,
+) -> Result<Vec<ChallengeData>, ChallengeError> {
+    let now = DateTime::now();
+    let mut filter = visible(db, user_id).await?;
+    let sort = if past {
+        filter.insert("deadline", doc! { "$lte": now });
+        doc! { "deadline": -1 }
+    } else {
+        filter.insert("deadline", doc! { "$gt": now });
+        doc! { "deadline": 1 }
+    };
+    let challenges: Vec<Challenge> = collection(db)
+        .find(filter)
+        .sort(sort)
+        .limit(MAX_LISTED_CHALLENGES)
+        .await?
+        .try_collect()
+        .await?;
+
+    let progress = progress(db, &challenges).await?;
+    Ok(challenges
+        .into_iter()
+        .zip(progress)
+        .map(|(challenge, progress)| challenge_data(challenge, progress))
+        .collect())
+}
+
+async fn find(db: &Database, user_id: ObjectId, id: ObjectId) -> Result<Challenge, ChallengeError> {
+    let mut filter = visible(db, user_id).await?;
+    filter.insert("_id", id);
+    collection(db)
+        .find_one(filter)
+        .await?
+        .ok_or(ChallengeError::NotFound)
+}
+
+pub async fn get(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<ChallengeData, ChallengeError> {
+    let challenge = find(db, user_id, id).await?;
+    let progress = progress(db, std::slice::from_ref(&challenge)).await?;
+    Ok(challenge_data(challenge, progress[0]))
+}
+
+/// Deletes a personal challenge, or one of a group the user leads.
+pub async fn delete(db: &Database, user_id: ObjectId, id: ObjectId) -> Result<(), ChallengeError> {
+    let challenge = find(db, user_id, id).await?;
+    if let Some(group_id) = challenge.group_id {
+        group::leadership(db, user_id, group_id).await?;
+    }
+    collection(db).delete_one(doc! { "_id": id }).await?;
+
+    info!("{} deleted challenge {}", user_id, id);
+    Ok(())
+}
+
+/// Mails the members of the challenge's group, or its owner, that the target is reached.
+async fn notify(
+    db: &Database,
+    mailer: &web::Data<dyn Mailer>,
+    challenge: &Challenge,
+    progress: f64,
+) -> Result<(), ChallengeError> {
+    let (recipients, group_name) = match challenge.group_id {
+        Some(id) => match group::find(db, id).await? {
+            Some(group) => (
+                group.members.iter().map(|m| m.user_id).collect(),
+                Some(group.name),
+            ),
+            None => return Ok(()),
+        },
+        None => (vec![challenge.owner], None),
+    };
+    let unit = match challenge.metric {
+        ChallengeMetric::Items => "items",
+        ChallengeMetric::Weight => "g",
+        ChallengeMetric::Reports => "reports",
+    };
+    let who = match &group_name {
+        Some(name) => format!("Your group {name}"),
+        None => "You".to_string(),
+    };
+
+    let mut users = db
+        .collection::<Document>("users")
+        .find(doc! {
+            "_id": { "$in": recipients },
+            "email_verified": true,
+            "disabled": { "$ne": true },
+        })
+        .projection(doc! { "email": 1 })
+        .await?;
+    while let Some(user) = users.try_next().await? {
+        let Ok(email) = user.get_str("email") else {
+            continue;
+        };
+        send_in_background(
+            mailer.clone(),
+            Mail {
+                to: email.to_string(),
+                subject: format!("Challenge completed: {}", challenge.title),
+                body: format!(
+                    "{} reached the goal of the challenge \"{}\": {} of {} {} collected.\n\n{}/challenges/{}",
+                    who,
+                    challenge.title,
+                    progress.round(),
+                    challenge.target,
+                    unit,
+                    frontend_url(),
+                    challenge._id.map(|id| id.to_hex()).unwrap_or_default()
+                ),
+            },
+        );
+    }
+    Ok(())
+}
+
+async fn complete_reached(
+    db: &Database,
+    mailer: &web::Data<dyn Mailer>,
+    user_id: ObjectId,
+    report: Option<&Litter>,
+) -> Result<(), ChallengeError> {
+    let now = DateTime::now();
+    let mut filter = visible(db, user_id).await?;
+    filter.insert("completed_at", Bson::Null);
+    filter.insert("starts_at", doc! { "$lte": now });
+    filter.insert("deadline", doc! { "$gt": now });
+    let challenges: Vec<Challenge> = collection(db)
+        .find(filter)
+        .await?
+        .try_filter(|c| std::future::ready(report.is_none_or(|r| may_count(c, r))))
+        .try_collect()
+        .await?;
+
+    let progress = progress(db, &challenges).await?;
+    for (challenge, progress) in challenges.into_iter().zip(progress) {
+        if progress < challenge.target {
+            continue;
+        }
+        // Only the first evaluation to get here notifies
+        let result = collection(db)
+            .update_one(
+                doc! { "_id": challenge._id, "completed_at": Bson::Null },
+                doc! { "$set": { "completed_at": now } },
+            )
+            .await?;
+        if result.modified_count == 1 {
+            info!("Challenge {:?} completed", challenge._id);
+            notify(db, mailer, &challenge, progress).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Completes the running challenges the user's reports count for once their target is
+/// reached. With a `report`, only the challenges it may have changed are evaluated, without
+/// one (e.g. after an import) all of them. Failures are logged and caught up with the next
+/// report.
+pub async fn check(
+    db: &Database,
+    mailer: &web::Data<dyn Mailer>,
+    user_id: ObjectId,
+    report: Option<&Litter>,
+) {
+    if let Err(e) = complete_reached(db, mailer, user_id, report).await {
+        error!("Failed to check challenges of {}: {:?}", user_id, e);
+    }
+}
@@ -0,0 +1,777 @@
+use std::collections::HashMap;
+
+use futures::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{self, DateTime, Document, doc, oid::ObjectId},
+    options::{IndexOptions, ReturnDocument},
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    models::{
+        cleanup_session::{CleanupSession, TrackPoint},
+        litter::Litter,
+        user::{User, username_collation},
+    },
+    services::{
+        self,
+        cluster::number,
+        litter::validate_position,
+        validation::{FieldError, invalid},
+    },
+};
+
+pub const SESSION_NAME_MAX_LEN: usize = 64;
+pub const MAX_PARTICIPANTS: usize = 50;
+/// About 14 hours at one point per second.
+pub const MAX_TRACK_POINTS: usize = 50_000;
+/// Sessions listed per request, newest first.
+const MAX_LISTED_SESSIONS: i64 = 100;
+/// Allowed clock difference between the phone recording a track and the server.
+const CLOCK_SKEW_MS: i64 = 5 * 60 * 1000;
+/// Attempts at storing a track while other uploads keep changing it.
+const TRACK_WRITE_ATTEMPTS: usize = 3;
+/// Segments faster than this are GPS jumps or rides, not walked.
+const MAX_WALKING_SPEED_MPS: f64 = 12.0;
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+#[derive(Debug, Clone, Serialize)]
+pub enum SessionError {
+    InvalidInput(Vec<FieldError>),
+    NotFound,
+    /// The user already takes part in a running session.
+    AlreadyActive,
+    Ended,
+    /// Other uploads kept changing the track while storing points.
+    TrackChanged,
+    /// Only other participants may leave a session, its owner stops it.
+    Forbidden,
+    NetworkError,
+}
+
+impl From<mongodb::error::Error> for SessionError {
+    fn from(e: mongodb::error::Error) -> Self {
+        error!("Error when accessing cleanup sessions in db: {:?}", e);
+        SessionError::NetworkError
+    }
+}
+
+fn collection(db: &Database) -> Collection<CleanupSession> {
+    db.collection::<CleanupSession>("cleanup_sessions")
+}
+
+pub async fn ensure_indexes(db: &Database) {
+    let indexes = [
+        IndexModel::builder()
+            .keys(doc! { "participants": 1, "started_at": -1 })
+            .options(
+                IndexOptions::builder()
+                    .name(Some("session_participants".to_string()))
+                    .build(),
+            )
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "invited": 1, "started_at": -1 })
+            .options(
+                IndexOptions::builder()
+                    .name(Some("session_invited".to_string()))
+                    .build(),
+            )
+            .build(),
+    ];
+
+    if let Err(e) = collection(db).create_indexes(indexes).await {
+        error!("Failed to ensure indexes for 'cleanup_sessions': {:?}", e);
+    }
+}
+
+/// Stores the track totals on sessions from before they were kept there. Sessions whose
+/// track changes in the meantime get them from that write.
+pub async fn backfill_track_totals(db: &Database) {
+    let sessions = collection(db);
+    let result: Result<usize, mongodb::error::Error> = async {
+        let mut cursor = sessions
+            .find(doc! { "distance_m": { "$exists": false } })
+            .await?;
+        let mut updated = 0;
+        while let Some(session) = cursor.try_next().await? {
+            let filter = doc! {
+                "_id": session._id,
+                "track_version": track_version_filter(session.track_version),
+            };
+            let update = doc! { "$set": track_totals(&session.track) };
+            updated += sessions.update_one(filter, update).await?.modified_count as usize;
+        }
+        Ok(updated)
+    }
+    .await;
+
+    match result {
+        Ok(0) => {}
+        Ok(updated) => info!("Stored the track totals of {updated} cleanup sessions"),
+        Err(e) => error!(
+            "Failed to store the track totals of cleanup sessions: {:?}",
+            e
+        ),
+    }
+}
+
+/// Great-circle distance between two points in metres.
+pub fn haversine_m(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat1, lng1) = (a.0.to_radians(), a.1.to_radians());
+    let (lat2, lng2) = (b.0.to_radians(), b.1.to_radians());
+    let h = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lng2 - lng1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
+/// Length of a track in metres, leaving out gaps between segments and stretches too fast
+/// to be walked.
+pub fn walked_distance_m(track: &[TrackPoint]) -> f64 {
+    let track = services::track::ordered(track.to_vec());
+    services::track::segments(&track)
+        .flat_map(|segment| segment.windows(2))
+        .map(|w| {
+            let distance = haversine_m((w[0].lat, w[0].lng), (w[1].lat, w[1].lng));
+            let too_fast = match (w[0].time, w[1].time) {
+                (Some(a), Some(b)) => {
+                    let seconds = (b.timestamp_millis() - a.timestamp_millis()) as f64 / 1000.0;
+                    distance > seconds * MAX_WALKING_SPEED_MPS
+                }
+                _ => false,
+            };
+            if too_fast { 0.0 } else { distance }
+        })
+        .sum()
+}
+
+/// Totals of a session, computed from the reports collected in it.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SessionSummary {
+    pub id: String,
+    pub name: Option<String>,
+    /// Username of the user who started the session
+    pub owner: String,
+    /// Usernames of everyone taking part, including the owner
+    pub participants: Vec<String>,
+    /// Usernames of invited users who have not joined yet
+    pub invited: Vec<String>,
+    pub started_at: String,
+    pub ended_at: Option<String>,
+    pub active: bool,
+    /// Seconds from start to end, or to now while running
+    pub duration_s: i64,
+    /// Length of the GPS track in metres, without jumps faster than walking
+    pub distance_m: f64,
+    pub track_points: usize,
+    pub reports: u64,
+    pub items: u64,
+    /// Total weight of the items in grams
+    pub total_weight: f64,
+}
+
+#[derive(Debug)]
+struct Totals {
+    reports: u64,
+    items: u64,
+    weight: f64,
+}
+
+/// Report totals per session, over the litter of all participants.
+async fn totals(
+    db: &Database,
+    sessions: &[CleanupSession],
+) -> Result<HashMap<ObjectId, Totals>, SessionError> {
+    let ids: Vec<ObjectId> = sessions.iter().filter_map(|s| s._id).collect();
+    let mut users: Vec<ObjectId> = sessions
+        .iter()
+        .flat_map(|s| s.participants.iter().copied())
+        .collect();
+    users.sort();
+    users.dedup();
+
+    let pipeline = vec![
+        doc! { "$match": { "_id": { "$in": users }, "litter.session_id": { "$in": &ids } } },
+        doc! { "$project": { "litter.file": 0 } },
+        doc! { "$unwind": "$litter" },
+        doc! { "$match": { "litter.session_id": { "$in": &ids } } },
+        doc! { "$group": {
+            "_id": "$litter.session_id",
+            "reports": { "$sum": 1 },
+            "items": { "$sum": { "$size": { "$ifNull": ["$litter.entries", []] } } },
+            "weight": { "$sum": { "$sum": "$litter.entries.weight" } },
+        } },
+    ];
+
+    let docs: Vec<Document> = db
+        .collection::<User>("users")
+        .aggregate(pipeline)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(docs
+        .iter()
+        .filter_map(|doc| {
+            let totals = Totals {
+                reports: number(doc, "reports") as u64,
+                items: number(doc, "items") as u64,
+                weight: number(doc, "weight"),
+            };
+            Some((doc.get_object_id("_id").ok()?, totals))
+        })
+        .collect())
+}
+
+/// Usernames of the given users, missing for deleted accounts.
+pub(crate) async fn usernames(
+    db: &Database,
+    ids: impl IntoIterator<Item = ObjectId>,
+) -> Result<HashMap<ObjectId, String>, mongodb::error::Error> {
+    let ids: Vec<ObjectId> = ids.into_iter().collect();
+    let docs: Vec<Document> = db
+        .collection::<Document>("users")
+        .find(doc! { "_id": { "$in": ids } })
+        .projection(doc! { "username": 1 })
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(docs
+        .iter()
+        .filter_map(|d| {
+            Some((
+                d.get_object_id("_id").ok()?,
+                d.get_str("username").ok()?.to_string(),
+            ))
+        })
+        .collect())
+}
+
+async fn summaries(
+    db: &Database,
+    sessions: Vec<CleanupSession>,
+) -> Result<Vec<SessionSummary>, SessionError> {
+    let totals = totals(db, &sessions).await?;
+    let names = usernames(
+        db,
+        sessions
+            .iter()
+            .flat_map(|s| s.participants.iter().chain(&s.invited).copied()),
+    )
+    .await?;
+    let name = |id: &ObjectId| names.get(id).cloned().unwrap_or_default();
+    let rfc3339 = |t: DateTime| t.try_to_rfc3339_string().unwrap_or_default();
+    let now = DateTime::now();
+
+    Ok(sessions
+        .into_iter()
+        .map(|session| {
+            let id = session._id.unwrap_or_default();
+            let total = totals.get(&id);
+            let end = session.ended_at.unwrap_or(now);
+            SessionSummary {
+                id: id.to_hex(),
+                name: session.name,
+                owner: name(&session.owner),
+                participants: session.participants.iter().map(name).collect(),
+                invited: session.invited.iter().map(name).collect(),
+                started_at: rfc3339(session.started_at),
+                ended_at: session.ended_at.map(rfc3339),
+                active: session.ended_at.is_none(),
+                duration_s: (end.timestamp_millis() - session.started_at.timestamp_millis()).max(0)
+                    / 1000,
+                distance_m: session.distance_m,
+                track_points: session.track_points,
+                reports: total.map_or(0, |t| t.reports),
+                items: total.map_or(0, |t| t.items),
+                total_weight: total.map_or(0.0, |t| t.weight),
+            }
+        })
+        .collect())
+}
+
+async fn summary(db: &Database, session: CleanupSession) -> Result<SessionSummary, SessionError> {
+    summaries(db, vec![session])
+        .await?
+        .pop()
+        .ok_or(SessionError::NetworkError)
+}
+
+/// Starts a session owned by the user and invites the given usernames, who take part once
+/// they join.
+pub async fn start(
+    db: &Database,
+    user_id: ObjectId,
+    name: Option<&str>,
+    participants: &[String],
+) -> Result<SessionSummary, SessionError> {
+    let mut errors = vec![];
+    let name = name.map(str::trim).filter(|n| !n.is_empty());
+    if name.is_some_and(|n| n.chars().count() > SESSION_NAME_MAX_LEN) {
+        errors.push(invalid(
+            "name",
+            format!("must be at most {SESSION_NAME_MAX_LEN} characters long"),
+        ));
+    }
+    if participants.len() >= MAX_PARTICIPANTS {
+        errors.push(invalid(
+            "participants",
+            format!("at most {} other participants", MAX_PARTICIPANTS - 1),
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(SessionError::InvalidInput(errors));
+    }
+
+    let found: Vec<Document> = db
+        .collection::<Document>("users")
+        .find(doc! { "username": { "$in": participants }, "disabled": { "$ne": true } })
+        .projection(doc! { "username": 1 })
+        .collation(username_collation())
+        .await?
+        .try_collect()
+        .await?;
+    let unknown: Vec<&str> = participants
+        .iter()
+        .filter(|p| {
+            !found.iter().any(|d| {
+                d.get_str("username")
+                    .is_ok_and(|u| u.to_lowercase() == p.trim().to_lowercase())
+            })
+        })
+        .map(String::as_str)
+        .collect();
+    if !unknown.is_empty() {
+        return Err(SessionError::InvalidInput(vec![invalid(
+            "participants",
+            format!("unknown users: {}", unknown.join(", ")),
+        )]));
+    }
+
+    let mut invited = vec![];
+    for id in found.iter().filter_map(|d| d.get_object_id("_id").ok()) {
+        if id != user_id && !invited.contains(&id) {
+            invited.push(id);
+        }
+    }
+
+    let sessions = collection(db);
+    if sessions
+        .find_one(doc! { "participants": user_id, "ended_at": null })
+        .await?
+        .is_some()
+    {
+        return Err(SessionError::AlreadyActive);
+    }
+
+    let mut session = CleanupSession {
+        _id: None,
+        owner: user_id,
+        name: name.map(str::to_string),
+        participants: vec![user_id],
+        invited,
+        started_at: DateTime::now(),
+        ended_at: None,
+        track: vec![],
+        track_version: 0,
+        distance_m: 0.0,
+        track_points: 0,
+    };
+    session._id = sessions
+        .insert_one(&session)
+        .await?
+        .inserted_id
+        .as_object_id();
+
+    info!("{} started cleanup session {:?}", user_id, session._id);
+    summary(db, session).await
+}
+
+/// Ends a running session, only its owner can.
+pub async fn stop(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<SessionSummary, SessionError> {
+    let sessions = collection(db);
+    let session = sessions
+        .find_one(doc! { "_id": id, "owner": user_id })
+        .await?
+        .ok_or(SessionError::NotFound)?;
+    if session.ended_at.is_some() {
+        return Err(SessionError::Ended);
+    }
+
+    let ended_at = DateTime::now();
+    let res = sessions
+        .update_one(
+            doc! { "_id": id, "ended_at": null },
+            doc! { "$set": { "ended_at": ended_at } },
+        )
+        .await?;
+    if res.modified_count == 0 {
+        return Err(SessionError::Ended);
+    }
+
+    info!("{} stopped cleanup session {}", user_id, id);
+    summary(
+        db,
+        CleanupSession {
+            ended_at: Some(ended_at),
+            ..session
+        },
+    )
+    .await
+}
+
+/// Joins a running session the user was invited to. Users take part in one running
+/// session at a time.
+pub async fn join(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<SessionSummary, SessionError> {
+    let sessions = collection(db);
+    let session = sessions
+        .find_one(doc! { "_id": id, "invited": user_id })
+        .await?
+        .ok_or(SessionError::NotFound)?;
+    if session.ended_at.is_some() {
+        return Err(SessionError::Ended);
+    }
+    if sessions
+        .find_one(doc! { "participants": user_id, "ended_at": null })
+        .await?
+        .is_some()
+    {
+        return Err(SessionError::AlreadyActive);
+    }
+
+    let session = sessions
+        .find_one_and_update(
+            doc! { "_id": id, "invited": user_id, "ended_at": null },
+            doc! {
+                "$pull": { "invited": user_id },
+                "$addToSet": { "participants": user_id },
+            },
+        )
+        .return_document(ReturnDocument::After)
+        .await?
+        .ok_or(SessionError::Ended)?;
+
+    info!("{} joined cleanup session {}", user_id, id);
+    summary(db, session).await
+}
+
+/// Leaves a running session, or declines the invitation to it. Reports of the user no
+/// longer count towards the session afterwards.
+pub async fn leave(db: &Database, user_id: ObjectId, id: ObjectId) -> Result<(), SessionError> {
+    let sessions = collection(db);
+    let session = sessions
+        .find_one(doc! { "_id": id, "$or": [{ "participants": user_id }, { "invited": user_id }] })
+        .await?
+        .ok_or(SessionError::NotFound)?;
+    if session.owner == user_id {
+        return Err(SessionError::Forbidden);
+    }
+    if session.ended_at.is_some() {
+        return Err(SessionError::Ended);
+    }
+
+    sessions
+        .update_one(
+            doc! { "_id": id, "ended_at": null },
+            doc! { "$pull": { "participants": user_id, "invited": user_id } },
+        )
+        .await?;
+
+    info!("{} left cleanup session {}", user_id, id);
+    Ok(())
+}
+
+/// Session the user takes part in.
+async fn find(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<CleanupSession, SessionError> {
+    collection(db)
+        .find_one(doc! { "_id": id, "participants": user_id })
+        .await?
+        .ok_or(SessionError::NotFound)
+}
+
+/// Session the user takes part in or is invited to.
+pub async fn get(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<SessionSummary, SessionError> {
+    let session = collection(db)
+        .find_one(doc! { "_id": id, "$or": [{ "participants": user_id }, { "invited": user_id }] })
+        .await?
+        .ok_or(SessionError::NotFound)?;
+    summary(db, session).await
+}
+
+pub async fn track(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<Vec<TrackPoint>, SessionError> {
+    let track = find(db, user_id, id).await?.track;
+    Ok(services::track::ordered(track))
+}
+
+/// Sessions the user started, takes part in or is invited to, newest first.
+pub async fn list(db: &Database, user_id: ObjectId) -> Result<Vec<SessionSummary>, SessionError> {
+    let sessions: Vec<CleanupSession> = collection(db)
+        .find(doc! { "$or": [{ "participants": user_id }, { "invited": user_id }] })
+        .projection(doc! { "track": 0 })
+        .sort(doc! { "started_at": -1 })
+        .limit(MAX_LISTED_SESSIONS)
+        .await?
+        .try_collect()
+        .await?;
+
+    summaries(db, sessions).await
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TrackPointData {
+    pub lat: f64,
+    pub lng: f64,
+    /// RFC 3339 timestamp of the GPS fix
+    pub time: String,
+}
+
+/// Segment that points of the next batch go into.
+fn next_segment(track: &[TrackPoint], new_segment: bool) -> u32 {
+    match track.iter().map(|p| p.segment).max() {
+        Some(last) if new_segment => last + 1,
+        Some(last) => last,
+        None => 0,
+    }
+}
+
+fn check_track_size(track: &[TrackPoint], added: usize) -> Result<(), SessionError> {
+    if track.len() + added > MAX_TRACK_POINTS {
+        return Err(SessionError::InvalidInput(vec![invalid(
+            "points",
+            format!("a track has at most {MAX_TRACK_POINTS} points"),
+        )]));
+    }
+    Ok(())
+}
+
+fn track_bson(track: &[TrackPoint]) -> Result<bson::Bson, SessionError> {
+    bson::to_bson(track).map_err(|e| {
+        error!("Failed to serialize track points: {:?}", e);
+        SessionError::NetworkError
+    })
+}
+
+/// Stored totals of a track, written together with it.
+fn track_totals(track: &[TrackPoint]) -> Document {
+    doc! {
+        "distance_m": walked_distance_m(track),
+        "track_points": track.len() as i64,
+    }
+}
+
+/// Matches the track version a session was read with. Sessions from before versioning
+/// have no `track_version` yet.
+fn track_version_filter(version: i64) -> bson::Bson {
+    match version {
+        0 => bson::bson!({ "$in": [0, null] }),
+        v => v.into(),
+    }
+}
+
+/// Writes the new track of a session into the copy that was read, after storing it.
+fn apply_track(session: &mut CleanupSession, track: Vec<TrackPoint>) {
+    session.distance_m = walked_distance_m(&track);
+    session.track_points = track.len();
+    session.track = track;
+    session.track_version += 1;
+}
+
+/// Adds GPS points to the track of a running session, only its owner records the track.
+/// Points continue the last segment unless `new_segment` is set, e.g. after a pause.
+pub async fn add_track_points(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+    points: &[TrackPointData],
+    new_segment: bool,
+) -> Result<SessionSummary, SessionError> {
+    let sessions = collection(db);
+    for _ in 0..TRACK_WRITE_ATTEMPTS {
+        let mut session = sessions
+            .find_one(doc! { "_id": id, "owner": user_id })
+            .await?
+            .ok_or(SessionError::NotFound)?;
+        if session.ended_at.is_some() {
+            return Err(SessionError::Ended);
+        }
+        check_track_size(&session.track, points.len())?;
+
+        let segment = next_segment(&session.track, new_segment);
+        let earliest = session.started_at.timestamp_millis() - CLOCK_SKEW_MS;
+        let latest = DateTime::now().timestamp_millis() + CLOCK_SKEW_MS;
+        let mut errors = vec![];
+        let mut added = vec![];
+        for (i, point) in points.iter().enumerate() {
+            let field = |name: &str| format!("points[{i}].{name}");
+            for e in validate_position(point.lat, point.lng) {
+                errors.push(invalid(&field(&e.field), e.message));
+            }
+            match DateTime::parse_rfc3339_str(&point.time) {
+                Ok(time) if (earliest..=latest).contains(&time.timestamp_millis()) => {
+                    added.push(TrackPoint {
+                        lat: point.lat,
+                        lng: point.lng,
+                        time: Some(time),
+                        segment,
+                    });
+                }
+                Ok(_) => errors.push(invalid(&field("time"), "must be during the session")),
+                Err(_) => errors.push(invalid(&field("time"), "must be an RFC 3339 timestamp")),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(SessionError::InvalidInput(errors));
+        }
+
+        let track: Vec<TrackPoint> = session.track.iter().chain(&added).cloned().collect();
+        let filter = doc! {
+            "_id": id,
+            "ended_at": null,
+            "track_version": track_version_filter(session.track_version),
+        };
+        let update = doc! {
+            "$push": { "track": { "$each": track_bson(&added)? } },
+            "$set": track_totals(&track),
+            "$inc": { "track_version": 1 },
+        };
+        // Otherwise the session ended or another batch came first, the next read tells
+        if sessions.update_one(filter, update).await?.matched_count == 1 {
+            apply_track(&mut session, track);
+            return summary(db, session).await;
+        }
+    }
+
+    Err(SessionError::TrackChanged)
+}
+
+/// Adds the segments of an imported file to the track, or replaces the track with them.
+/// Unlike point batches this works after the session ended, for tracks recorded by other
+/// devices. The track is only written if no other upload changed it since it was read,
+/// otherwise the import starts over.
+pub async fn import_track(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+    imported: Vec<TrackPoint>,
+    replace: bool,
+) -> Result<SessionSummary, SessionError> {
+    let sessions = collection(db);
+    for _ in 0..TRACK_WRITE_ATTEMPTS {
+        let mut session = sessions
+            .find_one(doc! { "_id": id, "owner": user_id })
+            .await?
+            .ok_or(SessionError::NotFound)?;
+
+        if replace {
+            session.track.clear();
+        }
+        check_track_size(&session.track, imported.len())?;
+
+        let first = if session.track.is_empty() {
+            0
+        } else {
+            next_segment(&session.track, true)
+        };
+        let added: Vec<TrackPoint> = imported
+            .iter()
+            .map(|p| TrackPoint {
+                segment: first + p.segment,
+                ..p.clone()
+            })
+            .collect();
+
+        let points = track_bson(&added)?;
+        let track: Vec<TrackPoint> = session.track.iter().chain(&added).cloned().collect();
+        let mut totals = track_totals(&track);
+        let update = if replace {
+            totals.insert("track", points);
+            doc! { "$set": totals, "$inc": { "track_version": 1 } }
+        } else {
+            doc! {
+                "$push": { "track": { "$each": points } },
+                "$set": totals,
+                "$inc": { "track_version": 1 },
+            }
+        };
+        let filter =
+            doc! { "_id": id, "track_version": track_version_filter(session.track_version) };
+        if sessions.update_one(filter, update).await?.matched_count == 1 {
+            apply_track(&mut session, track);
+            info!("{} imported a track into cleanup session {}", user_id, id);
+            return summary(db, session).await;
+        }
+    }
+
+    Err(SessionError::TrackChanged)
+}
+
+/// Reports collected in a session by any of its participants, without images.
+pub async fn finds(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<(SessionSummary, Vec<TrackPoint>, Vec<Litter>), SessionError> {
+    let session = find(db, user_id, id).await?;
+
+    let pipeline = vec![
+        doc! { "$match": { "_id": { "$in": &session.participants }, "litter.session_id": id } },
+        doc! { "$project": { "litter.file": 0 } },
+        doc! { "$unwind": "$litter" },
+        doc! { "$match": { "litter.session_id": id } },
+        doc! { "$replaceRoot": { "newRoot": "$litter" } },
+        doc! { "$sort": { "time_stamp": 1 } },
+    ];
+    let docs: Vec<Document> = db
+        .collection::<User>("users")
+        .aggregate(pipeline)
+        .await?
+        .try_collect()
+        .await?;
+    let finds = docs
+        .into_iter()
+        .filter_map(|doc| {
+            bson::from_document::<Litter>(doc)
+                .map_err(|e| error!("Stored litter is malformed: {:?}", e))
+                .ok()
+        })
+        .collect();
+
+    let track = services::track::ordered(session.track.clone());
+    Ok((summary(db, session).await?, track, finds))
+}
+
+/// Checks that a new report of the user may be added to the session.
+pub async fn check_open(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<(), SessionError> {
+    match find(db, user_id, id).await?.ended_at {
+        Some(_) => Err(SessionError::Ended),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    collections::HashMap,
+    f64::consts::PI,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::web;
+use futures::{TryStreamExt, future::try_join_all};
+use mongodb::{
+    Database,
+    bson::{Bson, Document, doc, oid::ObjectId},
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    models::user::User,
+    services::{
+        litter::{GeoArea, LitterError, LitterFilter, LitterScope, litter_stages_without_images},
+        validation::FieldError,
+    },
+};
+
+pub const MAX_ZOOM: u8 = 22;
+/// Largest number of map tiles one request may cover.
+pub const MAX_TILES: usize = 64;
+/// Edge of a grid cell in screen pixels, a 256 px tile holds 4x4 cells.
+const CELL_PX: f64 = 64.0;
+const TILE_PX: f64 = 256.0;
+/// Widest box handed to the 2dsphere index, wider tiles are split into strips. Boxes of
+/// 180 degrees or more are not valid polygons, and the edge bulge grows without bound.
+const MAX_INDEX_WIDTH: f64 = 90.0;
+/// Web Mercator cuts off the poles here.
+const MAX_LAT: f64 = 85.051_128_78;
+
+const CACHE_TTL: Duration = Duration::from_secs(60);
+const CACHE_CAPACITY: usize = 10_000;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Cluster {
+    /// Mean position of the reports in the cell
+    pub lat: f64,
+    pub lng: f64,
+    pub count: u64,
+    /// Sum of the estimated weights in grams
+    pub total_weight: f64,
+    /// Most frequent category, if any report was analysed
+    pub dominant_category: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct TileKey {
+    /// `None` for the community scope, which is shared by all users.
+    pub(crate) owner: Option<ObjectId>,
+    pub(crate) z: u8,
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+}
+
+type CachedTile<T> = (Instant, Arc<T>);
+
+/// Results of recently requested tiles. Entries expire after a minute, so new
+/// reports show up with at most that delay.
+pub struct TileCache<T> {
+    tiles: Mutex<HashMap<TileKey, CachedTile<T>>>,
+}
+
+pub type ClusterCache = TileCache<Vec<Cluster>>;
+
+impl<T> TileCache<T> {
+    pub fn new() -> Self {
+        Self {
+            tiles: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &TileKey) -> Option<Arc<T>> {
+        let tiles = self.tiles.lock().unwrap();
+        tiles
+            .get(key)
+            .filter(|(at, _)| at.elapsed() < CACHE_TTL)
+            .map(|(_, value)| value.clone())
+    }
+
+    pub(crate) fn insert(&self, key: TileKey, value: Arc<T>) {
+        let mut tiles = self.tiles.lock().unwrap();
+        if tiles.len() >= CACHE_CAPACITY {
+            tiles.retain(|_, (at, _)| at.elapsed() < CACHE_TTL);
+            if tiles.len() >= CACHE_CAPACITY {
+                tiles.clear();
+            }
+        }
+        tiles.insert(key, (Instant::now(), value));
+    }
+}
+
+impl<T> Default for TileCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) fn tiles_per_axis(z: u8) -> f64 {
+    f64::from(1u32 << z)
+}
+
+/// Position on the Web Mercator plane, in tiles from the north-west corner of the world.
+pub(crate) fn world_position(lng: f64, lat: f64, z: u8) -> (f64, f64) {
+    let n = tiles_per_axis(z);
+    let lat = lat.clamp(-MAX_LAT, MAX_LAT).to_radians();
+    let x = (lng + 180.0) / 360.0 * n;
+    let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * n;
+    (x, y)
+}
+
+fn lng_to_tile(lng: f64, z: u8) -> u32 {
+    let (x, _) = world_position(lng, 0.0, z);
+    x.floor().clamp(0.0, tiles_per_axis(z) - 1.0) as u32
+}
+
+fn lat_to_tile(lat: f64, z: u8) -> u32 {
+    let (_, y) = world_position(0.0, lat, z);
+    y.floor().clamp(0.0, tiles_per_axis(z) - 1.0) as u32
+}
+
+pub(crate) fn tile_to_lng(x: u32, z: u8) -> f64 {
+    f64::from(x) / tiles_per_axis(z) * 360.0 - 180.0
+}
+
+pub(crate) fn tile_to_lat(y: u32, z: u8) -> f64 {
+    let n = PI * (1.0 - 2.0 * f64::from(y) / tiles_per_axis(z));
+    n.sinh().atan().to_degrees()
+}
+
+/// Box around a tile for the index lookup. Box edges are great circles that bulge towards
+/// the pole, so the equatorward edge is moved out far enough to keep the whole tile inside.
+fn index_area(west: f64, south: f64, east: f64, north: f64) -> GeoArea {
+    let widest = south.abs().max(north.abs()).min(MAX_LAT).to_radians();
+    let half_width = ((east - west) / 2.0).to_radians();
+    let bulge = (widest.tan() / half_width.cos()).atan().to_degrees() - widest.to_degrees();
+    let pad = bulge + 0.01;
+
+    GeoArea::BBox {
+        min_lng: west,
+        min_lat: (south - pad).max(-90.0),
+        max_lng: east,
+        max_lat: (north + pad).min(90.0),
+    }
+}
+
+/// Padded boxes covering a tile, one per strip of at most [`MAX_INDEX_WIDTH`] degrees.
+fn index_areas(west: f64, south: f64, east: f64, north: f64) -> Vec<GeoArea> {
+    let strips = ((east - west) / MAX_INDEX_WIDTH).ceil().max(1.0);
+    let width = (east - west) / strips;
+    (0..strips as u32)
+        .map(|i| {
+            let strip_west = west + f64::from(i) * width;
+            index_area(strip_west, south, strip_west + width, north)
+        })
+        .collect()
+}
+
+/// Stages selecting the reports inside a tile: an index lookup on padded boxes,
+/// then the exact tile bounds.
+pub(crate) fn tile_stages(user_id: ObjectId, scope: LitterScope, key: TileKey) -> Vec<Document> {
+    let TileKey { z, x, y, .. } = key;
+    let (west, east) = (tile_to_lng(x, z), tile_to_lng(x + 1, z));
+    let (north, south) = (tile_to_lat(y, z), tile_to_lat(y + 1, z));
+
+    let mut areas = index_areas(west, south, east, north);
+    let mut pipeline = if areas.len() == 1 {
+        let filter = LitterFilter {
+            area: areas.pop(),
+            ..Default::default()
+        };
+        litter_stages_without_images(user_id, scope, &filter)
+    } else {
+        // Low zooms: each strip can use the index on its own.
+        let mut pipeline = litter_stages_without_images(user_id, scope, &LitterFilter::default());
+        let strips: Vec<Document> = areas
+            .iter()
+            .map(|area| doc! { "litter.location": area.geo_within() })
+            .collect();
+        if let Ok(users) = pipeline[0].get_document_mut("$match") {
+            users.insert("$or", strips);
+        }
+        pipeline
+    };
+    pipeline.push(doc! { "$match": {
+        "lng": { "$gte": west, "$lt": east },
+        "lat": { "$gt": south, "$lte": north },
+    } });
+    pipeline
+}
+
+/// `$project` fields with the column `cx` and row `cy` of each report on a world-wide
+/// Web Mercator grid of `cells` x `cells` cells.
+pub(crate) fn grid_cell_fields(cells: f64) -> Document {
+    let lat_rad = doc! { "$degreesToRadians": "$lat" };
+    doc! {
+        "cx": { "$floor": { "$multiply": [
+            { "$divide": [{ "$add": ["$lng", 180] }, 360] },
+            cells,
+        ] } },
+        "cy": { "$floor": { "$multiply": [
+            { "$divide": [
+                { "$subtract": [1, { "$divide": [
+                    { "$ln": { "$add": [
+                        { "$tan": lat_rad.clone() },
+                        { "$divide": [1, { "$cos": lat_rad }] },
+                    ] } },
+                    PI,
+                ] }] },
+                2,
+            ] },
+            cells,
+        ] } },
+    }
+}
+
+async fn tile_clusters(
+    db: &Database,
+    user_id: ObjectId,
+    scope: LitterScope,
+    key: TileKey,
+) -> Result<Vec<Cluster>, LitterError> {
+    let cells = tiles_per_axis(key.z) * TILE_PX / CELL_PX;
+
+    let mut project = doc! {
+        "lat": 1,
+        "lng": 1,
+        "weight": { "$sum": "$entries.weight" },
+        "categories": "$entries.category",
+    };
+    project.extend(grid_cell_fields(cells));
+
+    let mut pipeline = tile_stages(user_id, scope, key);
+    pipeline.extend([
+        doc! { "$project": project },
+        doc! { "$group": {
+            "_id": { "x": "$cx", "y": "$cy" },
+            "count": { "$sum": 1 },
+            "total_weight": { "$sum": "$weight" },
+            "lat": { "$avg": "$lat" },
+            "lng": { "$avg": "$lng" },
+            "categories": { "$push": "$categories" },
+        } },
+    ]);
+
+    let groups: Vec<Document> = db
+        .collection::<User>("users")
+        .aggregate(pipeline)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(groups.iter().map(cluster_from_group).collect())
+}
+
+pub(crate) fn number(doc: &Document, key: &str) -> f64 {
+    match doc.get(key) {
+        Some(Bson::Double(v)) => *v,
+        Some(Bson::Int32(v)) => f64::from(*v),
+        Some(Bson::Int64(v)) => *v as f64,
+        _ => 0.0,
+    }
+}
+
+/// Most frequent of `categories`. Ties go to the alphabetically first category, so cached
+/// and fresh results agree.
+pub(crate) fn dominant_category<'a>(categories: impl Iterator<Item = &'a str>) -> Option<String> {
+    let mut counts: HashMap<&str, u64> = HashMap::new();
+    for category in categories {
+        *counts.entry(category).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(a.0)))
+        .map(|(category, _)| category.to_string())
+}
+
+fn cluster_from_group(group: &Document) -> Cluster {
+    let categories = group
+        .get_array("categories")
+        .into_iter()
+        .flatten()
+        .filter_map(Bson::as_array)
+        .flatten()
+        .filter_map(Bson::as_str);
+    let dominant_category = dominant_category(categories);
+
+    Cluster {
+        lat: number(group, "lat"),
+        lng: number(group, "lng"),
+        count: number(group, "count") as u64,
+        total_weight: number(group, "total_weight"),
+        dominant_category,
+    }
+}
+
+/// Clusters the reports inside the bounding box on a grid of 64 px cells at `zoom`.
+/// Every tile is aggregated and cached on its own, so panning only computes new tiles.
+pub async fn clusters(
+    db: web::Data<Database>,
+    cache: &ClusterCache,
+    user_id: ObjectId,
+    scope: LitterScope,
+    bbox: &GeoArea,
+    zoom: u8,
+) -> Result<Vec<Cluster>, LitterError> {
+    let mut errors = bbox.validate();
+    if zoom > MAX_ZOOM {
+        errors.push(FieldError {
+            field: "zoom".to_string(),
+            message: format!("must be between 0 and {MAX_ZOOM}"),
+        });
+    }
+    let GeoArea::BBox {
+        min_lng,
+        min_lat,
+        max_lng,
+        max_lat,
+    } = *bbox
+    else {
+        errors.push(FieldError {
+            field: "bbox".to_string(),
+            message: "must be a bounding box".to_string(),
+        });
+        return Err(LitterError::InvalidInput(errors));
+    };
+    if !errors.is_empty() {
+        return Err(LitterError::InvalidInput(errors));
+    }
+
+    let (x_min, x_max) = (lng_to_tile(min_lng, zoom), lng_to_tile(max_lng, zoom));
+    let (y_min, y_max) = (lat_to_tile(max_lat, zoom), lat_to_tile(min_lat, zoom));
+    let tile_count = (x_max - x_min + 1) as usize * (y_max - y_min + 1) as usize;
+    if tile_count > MAX_TILES {
+        return Err(LitterError::InvalidInput(vec![FieldError {
+            field: "bbox".to_string(),
+            message: format!("covers {tile_count} tiles at this zoom, at most {MAX_TILES} allowed"),
+        }]));
+    }
+
+    let owner = (scope == LitterScope::Own).then_some(user_id);
+    let keys = (x_min..=x_max).flat_map(|x| {
+        (y_min..=y_max).map(move |y| TileKey {
+            owner,
+            z: zoom,
+            x,
+            y,
+        })
+    });
+
+    let tiles = try_join_all(keys.map(|key| {
+        let db = db.clone();
+        async move {
+            if let Some(cached) = cache.get(&key) {
+                return Ok(cached);
+            }
+            let clusters = Arc::new(tile_clusters(&db, user_id, scope, key).await?);
+            cache.insert(key, clusters.clone());
+            Ok::<_, LitterError>(clusters)
+        }
+    }))
+    .await?;
+
+    Ok(tiles
+        .iter()
+        .flat_map(|clusters| clusters.iter().cloned())
+        .collect())
+}
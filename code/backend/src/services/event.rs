@@ -0,0 +1,584 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{Bson, DateTime, Document, doc, oid::ObjectId},
+    error::{ErrorKind, WriteFailure},
+    options::IndexOptions,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    models::{
+        event::{Event, EventParticipant, GeoPolygon},
+        user::{Role, User},
+    },
+    services::{
+        auth::UserSession,
+        cleanup_session::usernames,
+        cluster::number,
+        litter::{
+            GeoArea, LitterFilter, LitterScope, filter_collation, litter_stages_without_images,
+        },
+        stats::{Breakdown, breakdown, breakdown_facet},
+        validation::{FieldError, invalid},
+    },
+};
+
+pub const EVENT_TITLE_MAX_LEN: usize = 100;
+pub const EVENT_DESCRIPTION_MAX_LEN: usize = 2000;
+pub const MAX_EVENT_CAPACITY: u32 = 10_000;
+/// Longest time window of an event.
+const MAX_EVENT_DURATION_MS: i64 = 7 * 24 * 60 * 60 * 1000;
+/// Events listed per request.
+const MAX_LISTED_EVENTS: i64 = 100;
+/// Reports shown on the results map, the totals count all.
+pub const MAX_MAP_POINTS: i64 = 5000;
+/// Server error for documents whose geometry the 2dsphere index cannot use.
+const GEO_KEYS_ERROR: i32 = 16755;
+const RESULTS_TTL: Duration = Duration::from_secs(60);
+const RESULTS_CAPACITY: usize = 1000;
+
+#[derive(Debug, Clone, Serialize)]
+pub enum EventError {
+    InvalidInput(Vec<FieldError>),
+    NotFound,
+    /// Only the organiser or a moderator may do this.
+    Forbidden,
+    AlreadyRegistered,
+    Full,
+    /// The event has ended.
+    Closed,
+    NetworkError,
+}
+
+impl From<mongodb::error::Error> for EventError {
+    fn from(e: mongodb::error::Error) -> Self {
+        error!("Error when accessing events in db: {:?}", e);
+        EventError::NetworkError
+    }
+}
+
+fn collection(db: &Database) -> Collection<Event> {
+    db.collection::<Event>("events")
+}
+
+pub async fn ensure_indexes(db: &Database) {
+    let indexes = [
+        IndexModel::builder()
+            .keys(doc! { "area": "2dsphere" })
+            .options(
+                IndexOptions::builder()
+                    .name(Some("event_area".to_string()))
+                    .build(),
+            )
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "ends_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .name(Some("event_end".to_string()))
+                    .build(),
+            )
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "participants.user_id": 1 })
+            .options(
+                IndexOptions::builder()
+                    .name(Some("event_participants".to_string()))
+                    .build(),
+            )
+            .build(),
+    ];
+
+    if let Err(e) = collection(db).create_indexes(indexes).await {
+        error!("Failed to ensure indexes for 'events': {:?}", e);
+    }
+}
+
+/// Whether an event is still ahead, running or over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EventStatus {
+    Upcoming,
+    Running,
+    Ended,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct EventData {
+    pub id: String,
+    pub title: String,
+    pub description: Option<String>,
+    /// Username of the organiser
+    pub organiser: String,
+    /// Closed ring of `[lng, lat]` points
+    pub area: Vec<[f64; 2]>,
+    pub starts_at: String,
+    pub ends_at: String,
+    pub status: EventStatus,
+    /// Unlimited if missing
+    pub capacity: Option<u32>,
+    pub registered: usize,
+}
+
+/// Report on the results map, without owner or photo.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct EventMapPoint {
+    pub lat: f64,
+    pub lng: f64,
+    pub items: u64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct EventResults {
+    #[serde(flatten)]
+    pub event: EventData,
+    pub reports: u64,
+    /// Detected pieces of litter over all reports
+    pub items: u64,
+    /// Estimated weight in grams
+    pub total_weight: f64,
+    /// Participants with at least one report counted
+    pub contributors: u64,
+    /// Most collected categories first
+    pub categories: Breakdown,
+    /// Up to 5000 reports, latest first
+    pub map: Vec<EventMapPoint>,
+}
+
+/// Event as entered by its organiser, times already parsed.
+#[derive(Debug)]
+pub struct NewEvent {
+    pub title: String,
+    pub description: Option<String>,
+    pub area: Vec<[f64; 2]>,
+    pub starts_at: DateTime,
+    pub ends_at: DateTime,
+    pub capacity: Option<u32>,
+}
+
+impl NewEvent {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = vec![];
+        let title = self.title.trim();
+        if title.is_empty() || title.chars().count() > EVENT_TITLE_MAX_LEN {
+            errors.push(invalid(
+                "title",
+                format!("must be between 1 and {EVENT_TITLE_MAX_LEN} characters long"),
+            ));
+        }
+        if self
+            .description
+            .as_deref()
+            .is_some_and(|d| d.trim().chars().count() > EVENT_DESCRIPTION_MAX_LEN)
+        {
+            errors.push(invalid(
+                "description",
+                format!("must be at most {EVENT_DESCRIPTION_MAX_LEN} characters long"),
+            ));
+        }
+        errors.extend(
+            GeoArea::Polygon(self.area.clone())
+                .validate()
+                .into_iter()
+                .map(|e| match e.field.as_str() {
+                    "points" => invalid("area", e.message),
+                    _ => invalid(
+                        "area",
+                        format!("has a point whose {} {}", e.field, e.message),
+                    ),
+                }),
+        );
+
+        let (start, end) = (
+            self.starts_at.timestamp_millis(),
+            self.ends_at.timestamp_millis(),
+        );
+        if end <= start {
+            errors.push(invalid("ends_at", "must be after starts_at"));
+        } else if end - start > MAX_EVENT_DURATION_MS {
+            errors.push(invalid("ends_at", "must be at most 7 days after starts_at"));
+        }
+        if end <= DateTime::now().timestamp_millis() {
+            errors.push(invalid("ends_at", "must be in the future"));
+        }
+        if self
+            .capacity
+            .is_some_and(|c| !(1..=MAX_EVENT_CAPACITY).contains(&c))
+        {
+            errors.push(invalid(
+                "capacity",
+                format!("must be between 1 and {MAX_EVENT_CAPACITY}"),
+            ));
+        }
+        errors
+    }
+}
+
+fn status(event: &Event, now: DateTime) -> EventStatus {
+    if now < event.starts_at {
+        EventStatus::Upcoming
+    } else if now < event.ends_at {
+        EventStatus::Running
+    } else {
+        EventStatus::Ended
+    }
+}
+
+async fn event_data(db: &Database, events: Vec<Event>) -> Result<Vec<EventData>, EventError> {
+    let names = usernames(db, events.iter().map(|e| e.organiser)).await?;
+    let rfc3339 = |t: DateTime| t.try_to_rfc3339_string().unwrap_or_default();
+    let now = DateTime::now();
+
+    Ok(events
+        .into_iter()
+        .map(|event| EventData {
+            id: event._id.map(|id| id.to_hex()).unwrap_or_default(),
+            organiser: names.get(&event.organiser).cloned().unwrap_or_default(),
+            area: event.area.ring().to_vec(),
+            starts_at: rfc3339(event.starts_at),
+            ends_at: rfc3339(event.ends_at),
+            status: status(&event, now),
+            capacity: event.capacity,
+            registered: event.participants.len(),
+            title: event.title,
+            description: event.description,
+        })
+        .collect())
+}
+
+async fn single(db: &Database, event: Event) -> Result<EventData, EventError> {
+    event_data(db, vec![event])
+        .await?
+        .pop()
+        .ok_or(EventError::NetworkError)
+}
+
+pub async fn create(
+    db: &Database,
+    organiser: ObjectId,
+    new: NewEvent,
+) -> Result<EventData, EventError> {
+    let errors = new.validate();
+    if !errors.is_empty() {
+        return Err(EventError::InvalidInput(errors));
+    }
+
+    let mut event = Event {
+        _id: None,
+        title: new.title.trim().to_string(),
+        description: new
+            .description
+            .map(|d| d.trim().to_string())
+            .filter(|d| !d.is_empty()),
+        organiser,
+        area: GeoPolygon::new(new.area),
+        starts_at: new.starts_at,
+        ends_at: new.ends_at,
+        capacity: new.capacity,
+        participants: vec![],
+        created_at: DateTime::now(),
+    };
+    // The 2dsphere index refuses self-intersecting and otherwise invalid polygons.
+    event._id = match collection(db).insert_one(&event).await {
+        Ok(res) => res.inserted_id.as_object_id(),
+        Err(e) if is_geo_keys_error(&e) => {
+            info!("Rejected event area: {}", e);
+            return Err(EventError::InvalidInput(vec![invalid(
+                "area",
+                "is not a valid polygon",
+            )]));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    info!("{} created event {:?}", organiser, event._id);
+    single(db, event).await
+}
+
+fn is_geo_keys_error(e: &mongodb::error::Error) -> bool {
+    matches!(
+        *e.kind,
+        ErrorKind::Write(WriteFailure::WriteError(ref write)) if write.code == GEO_KEYS_ERROR
+    )
+}
+
+/// Upcoming and running events, soonest first, or with `past` the ended ones, latest first.
+pub async fn list(db: &Database, past: bool) -> Result<Vec<EventData>, EventError> {
+    let now = DateTime::now();
+    let (filter, sort) = if past {
+        (doc! { "ends_at": { "$lte": now } }, doc! { "ends_at": -1 })
+    } else {
+        (doc! { "ends_at": { "$gt": now } }, doc! { "starts_at": 1 })
+    };
+    let events: Vec<Event> = collection(db)
+        .find(filter)
+        .sort(sort)
+        .limit(MAX_LISTED_EVENTS)
+        .await?
+        .try_collect()
+        .await?;
+
+    event_data(db, events).await
+}
+
+/// Events the user organises or registered for, latest start first.
+pub async fn list_own(db: &Database, user_id: ObjectId) -> Result<Vec<EventData>, EventError> {
+    let events: Vec<Event> = collection(db)
+        .find(doc! { "$or": [{ "organiser": user_id }, { "participants.user_id": user_id }] })
+        .sort(doc! { "starts_at": -1 })
+        .limit(MAX_LISTED_EVENTS)
+        .await?
+        .try_collect()
+        .await?;
+
+    event_data(db, events).await
+}
+
+async fn find(db: &Database, id: ObjectId) -> Result<Event, EventError> {
+    collection(db)
+        .find_one(doc! { "_id": id })
+        .await?
+        .ok_or(EventError::NotFound)
+}
+
+pub async fn get(db: &Database, id: ObjectId) -> Result<EventData, EventError> {
+    single(db, find(db, id).await?).await
+}
+
+/// Registers the user, until the event ends and while there is room.
+pub async fn register(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<EventData, EventError> {
+    let mut event = find(db, id).await?;
+    let now = DateTime::now();
+    if event.ends_at <= now {
+        return Err(EventError::Closed);
+    }
+    if event.participants.iter().any(|p| p.user_id == user_id) {
+        return Err(EventError::AlreadyRegistered);
+    }
+
+    let participant = EventParticipant {
+        user_id,
+        registered_at: now,
+    };
+    let mut filter = doc! {
+        "_id": id,
+        "ends_at": { "$gt": now },
+        "participants.user_id": { "$ne": user_id },
+    };
+    if let Some(capacity) = event.capacity {
+        filter.insert(
+            format!("participants.{}", capacity.saturating_sub(1)),
+            doc! { "$exists": false },
+        );
+    }
+    let res = collection(db)
+        .update_one(
+            filter,
+            doc! { "$push": { "participants": {
+                "user_id": user_id,
+                "registered_at": now,
+            } } },
+        )
+        .await?;
+    if res.modified_count == 0 {
+        let current = find(db, id).await?;
+        return Err(
+            if current.participants.iter().any(|p| p.user_id == user_id) {
+                EventError::AlreadyRegistered
+            } else if current.ends_at <= now {
+                EventError::Closed
+            } else {
+                EventError::Full
+            },
+        );
+    }
+
+    info!("{} registered for event {}", user_id, id);
+    event.participants.push(participant);
+    single(db, event).await
+}
+
+/// Withdraws a registration, only before the event ends so results stay put.
+pub async fn unregister(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<EventData, EventError> {
+    let mut event = find(db, id).await?;
+    if event.ends_at <= DateTime::now() {
+        return Err(EventError::Closed);
+    }
+    let res = collection(db)
+        .update_one(
+            doc! { "_id": id, "participants.user_id": user_id },
+            doc! { "$pull": { "participants": { "user_id": user_id } } },
+        )
+        .await?;
+    if res.modified_count == 0 {
+        return Err(EventError::NotFound);
+    }
+
+    info!("{} unregistered from event {}", user_id, id);
+    event.participants.retain(|p| p.user_id != user_id);
+    single(db, event).await
+}
+
+/// Cancels an event, by its organiser or a moderator.
+pub async fn delete(db: &Database, session: &UserSession, id: ObjectId) -> Result<(), EventError> {
+    let event = find(db, id).await?;
+    if event.organiser != session.id && session.role < Role::Moderator {
+        return Err(EventError::Forbidden);
+    }
+    collection(db).delete_one(doc! { "_id": id }).await?;
+
+    info!("{} deleted event {}", session.id, id);
+    Ok(())
+}
+
+/// Recently computed event results. Entries expire after a minute, so the public results
+/// page cannot make every request aggregate the reports of all participants.
+pub struct ResultsCache {
+    results: Mutex<HashMap<ObjectId, (Instant, EventResults)>>,
+}
+
+impl ResultsCache {
+    pub fn new() -> Self {
+        Self {
+            results: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, id: &ObjectId) -> Option<EventResults> {
+        let results = self.results.lock().unwrap();
+        results
+            .get(id)
+            .filter(|(at, _)| at.elapsed() < RESULTS_TTL)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn insert(&self, id: ObjectId, value: EventResults) {
+        let mut results = self.results.lock().unwrap();
+        if results.len() >= RESULTS_CAPACITY {
+            results.retain(|_, (at, _)| at.elapsed() < RESULTS_TTL);
+            if results.len() >= RESULTS_CAPACITY {
+                results.clear();
+            }
+        }
+        results.insert(id, (Instant::now(), value));
+    }
+}
+
+impl Default for ResultsCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Totals, top categories and a map of the reports counted for the event, at most a
+/// minute old.
+pub async fn results(
+    db: &Database,
+    cache: &ResultsCache,
+    id: ObjectId,
+) -> Result<EventResults, EventError> {
+    if let Some(cached) = cache.get(&id) {
+        return Ok(cached);
+    }
+    let results = aggregate_results(db, id).await?;
+    cache.insert(id, results.clone());
+    Ok(results)
+}
+
+async fn aggregate_results(db: &Database, id: ObjectId) -> Result<EventResults, EventError> {
+    let event = find(db, id).await?;
+    let filter = LitterFilter {
+        from: Some(event.starts_at),
+        to: Some(event.ends_at),
+        area: Some(GeoArea::Polygon(event.area.ring().to_vec())),
+        owners: Some(event.participants.iter().map(|p| p.user_id).collect()),
+        ..Default::default()
+    };
+
+    let mut pipeline =
+        litter_stages_without_images(event.organiser, LitterScope::Community, &filter);
+    pipeline.extend([
+        doc! { "$project": { "lat": 1, "lng": 1, "time_stamp": 1, "entries": 1 } },
+        doc! { "$facet": {
+            "totals": [
+                { "$group": {
+                    "_id": null,
+                    "reports": { "$sum": 1 },
+                    "items": { "$sum": { "$size": { "$ifNull": ["$entries", []] } } },
+                    "weight": { "$sum": { "$sum": "$entries.weight" } },
+                } },
+            ],
+            "categories": breakdown_facet("category"),
+            "map": [
+                { "$sort": { "time_stamp": -1, "_id": -1 } },
+                { "$limit": MAX_MAP_POINTS },
+                { "$project": {
+                    "_id": 0,
+                    "lat": 1,
+                    "lng": 1,
+                    "items": { "$size": { "$ifNull": ["$entries", []] } },
+                } },
+            ],
+        } },
+    ]);
+
+    let users = db.collection::<User>("users");
+    let docs: Vec<Document> = users
+        .aggregate(pipeline)
+        .collation(filter_collation())
+        .await?
+        .try_collect()
+        .await?;
+    let contributors = users
+        .count_documents(doc! {
+            "_id": { "$in": filter.owners.as_deref().unwrap_or_default() },
+            "disabled": { "$ne": true },
+            "litter": { "$elemMatch": filter.to_match() },
+        })
+        .await?;
+    let result = docs.into_iter().next().unwrap_or_default();
+    let totals = result
+        .get_array("totals")
+        .ok()
+        .and_then(|t| t.first())
+        .and_then(Bson::as_document)
+        .cloned()
+        .unwrap_or_default();
+
+    Ok(EventResults {
+        reports: number(&totals, "reports") as u64,
+        items: number(&totals, "items") as u64,
+        total_weight: number(&totals, "weight"),
+        contributors,
+        categories: breakdown(result.get_array("categories").ok()),
+        map: result
+            .get_array("map")
+            .into_iter()
+            .flatten()
+            .filter_map(Bson::as_document)
+            .map(|point| EventMapPoint {
+                lat: number(point, "lat"),
+                lng: number(point, "lng"),
+                items: number(point, "items") as u64,
+            })
+            .collect(),
+        event: single(db, event).await?,
+    })
+}
@@ -0,0 +1,377 @@
+//! Exports of litter reports for other tools, written while the reports are read.
+
+use actix_web::web::Bytes;
+use futures::{Stream, StreamExt, TryStreamExt, stream};
+use log::error;
+use rust_xlsxwriter::{ExcelDateTime, Format, Workbook, XlsxError};
+use serde_json::json;
+
+use crate::{
+    models::litter::{Entry, Litter},
+    services::{litter::LitterError, validation::FieldError},
+};
+
+/// Spreadsheets are built in memory, larger exports have to use CSV.
+const MAX_XLSX_ROWS: usize = 100_000;
+
+fn stream_error(err: LitterError) -> std::io::Error {
+    error!("Export aborted with {:?}", err);
+    std::io::Error::other("export aborted")
+}
+
+/// GeoJSON feature of a report, images are left out.
+fn geojson_feature(litter: &Litter) -> serde_json::Value {
+    let entries: Vec<_> = litter
+        .entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "category": entry.category,
+                "material": entry.material,
+                "weight": entry.weight,
+                "brand": entry.brand,
+                "confidence": entry.confidence,
+            })
+        })
+        .collect();
+
+    json!({
+        "type": "Feature",
+        "id": litter._id.to_hex(),
+        "geometry": {
+            "type": "Point",
+            "coordinates": [litter.lng, litter.lat],
+        },
+        "properties": {
+            "id": litter._id.to_hex(),
+            "type": litter.r#type,
+            "status": litter.analysis_status(),
+            "date": litter.time_stamp.try_to_rfc3339_string().ok(),
+            "item_count": litter.entries.len(),
+            "total_weight": litter.entries.iter().filter_map(|e| e.weight).sum::<f64>(),
+            "entries": entries,
+        },
+    })
+}
+
+/// RFC 7946 FeatureCollection of `reports`, one feature per line. A database error
+/// midway ends the stream, leaving the document incomplete.
+pub fn geojson(
+    reports: impl Stream<Item = Result<Litter, LitterError>> + 'static,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + 'static {
+    let features = reports.enumerate().map(|(i, litter)| {
+        let litter = litter.map_err(stream_error)?;
+        let separator = if i == 0 { "\n" } else { ",\n" };
+        Ok(Bytes::from(format!(
+            "{separator}{}",
+            geojson_feature(&litter)
+        )))
+    });
+
+    stream::once(async {
+        Ok(Bytes::from_static(
+            br#"{"type":"FeatureCollection","features":["#,
+        ))
+    })
+    .chain(features)
+    .chain(stream::once(async { Ok(Bytes::from_static(b"\n]}\n")) }))
+}
+
+/// Column of the CSV and spreadsheet exports, which have one row per detected item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    ReportId,
+    Lat,
+    Lng,
+    Date,
+    Category,
+    Material,
+    Brand,
+    Weight,
+    Confidence,
+}
+
+impl Column {
+    pub const ALL: [Column; 9] = [
+        Column::ReportId,
+        Column::Lat,
+        Column::Lng,
+        Column::Date,
+        Column::Category,
+        Column::Material,
+        Column::Brand,
+        Column::Weight,
+        Column::Confidence,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::ReportId => "report_id",
+            Column::Lat => "lat",
+            Column::Lng => "lng",
+            Column::Date => "date",
+            Column::Category => "category",
+            Column::Material => "material",
+            Column::Brand => "brand",
+            Column::Weight => "weight_g",
+            Column::Confidence => "confidence",
+        }
+    }
+}
+
+/// Columns from a comma separated list of names, `None` selects all of them in the
+/// default order.
+pub fn parse_columns(value: Option<&str>) -> Result<Vec<Column>, FieldError> {
+    let Some(value) = value else {
+        return Ok(Column::ALL.to_vec());
+    };
+
+    let mut columns = vec![];
+    for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let column = Column::ALL
+            .into_iter()
+            .find(|c| c.name() == name)
+            .ok_or_else(|| FieldError {
+                field: "columns".to_string(),
+                message: format!(
+                    "unknown column {name}, must be one of {}",
+                    Column::ALL.map(Column::name).join(", ")
+                ),
+            })?;
+        if !columns.contains(&column) {
+            columns.push(column);
+        }
+    }
+
+    if columns.is_empty() {
+        return Err(FieldError {
+            field: "columns".to_string(),
+            message: "must name at least one column".to_string(),
+        });
+    }
+    Ok(columns)
+}
+
+/// Languages writing decimal numbers with a comma, as in `1,5`.
+const DECIMAL_COMMA_LANGUAGES: &[&str] = &[
+    "af", "az", "be", "bg", "bs", "ca", "cs", "da", "de", "el", "es", "et", "eu", "fi", "fr", "gl",
+    "hr", "hu", "id", "is", "it", "ka", "kk", "lt", "lv", "mk", "nb", "nl", "nn", "no", "pl", "pt",
+    "rm", "ro", "ru", "sk", "sl", "sq", "sr", "sv", "tr", "uk", "uz", "vi",
+];
+
+/// Whether numbers are written with a decimal comma in `locale`, a tag such as `de-DE`
+/// or `fr_CH`. German, Italian and Romansh in Switzerland and Liechtenstein as well as
+/// Spanish in Mexico, the US and Central America keep the decimal point.
+pub fn decimal_comma(locale: &str) -> bool {
+    let locale = locale.trim().replace('_', "-").to_lowercase();
+    let mut parts = locale.split('-');
+    let language = parts.next().unwrap_or_default();
+    let region = parts.find(|p| p.len() == 2).unwrap_or_default();
+
+    let decimal_point = match language {
+        "de" | "it" | "rm" => ["ch", "li"].contains(&region),
+        "es" => ["mx", "us", "pr", "do", "gt", "hn", "ni", "pa", "sv"].contains(&region),
+        _ => false,
+    };
+    !decimal_point && DECIMAL_COMMA_LANGUAGES.contains(&language)
+}
+
+enum Cell {
+    Text(String),
+    Number(f64),
+    Date(i64),
+    Empty,
+}
+
+fn cell(column: Column, litter: &Litter, entry: &Entry) -> Cell {
+    let text = |value: &Option<String>| value.clone().map_or(Cell::Empty, Cell::Text);
+    let number = |value: Option<f64>| value.map_or(Cell::Empty, Cell::Number);
+    match column {
+        Column::ReportId => Cell::Text(litter._id.to_hex()),
+        Column::Lat => Cell::Number(litter.lat),
+        Column::Lng => Cell::Number(litter.lng),
+        Column::Date => Cell::Date(litter.time_stamp.timestamp_millis()),
+        Column::Category => text(&entry.category),
+        Column::Material => text(&entry.material),
+        Column::Brand => text(&entry.brand),
+        Column::Weight => number(entry.weight),
+        Column::Confidence => number(entry.confidence),
+    }
+}
+
+/// Rows of a report, one per entry. Reports without detected items have none.
+fn rows<'a>(columns: &'a [Column], litter: &'a Litter) -> impl Iterator<Item = Vec<Cell>> + 'a {
+    litter
+        .entries
+        .iter()
+        .map(move |entry| columns.iter().map(|c| cell(*c, litter, entry)).collect())
+}
+
+/// Prefixes text that spreadsheet apps would run as a formula with `'`, so brands such as
+/// `=HYPERLINK(...)` stay text (CSV injection).
+pub(crate) fn spreadsheet_text(value: String) -> String {
+    if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{value}")
+    } else {
+        value
+    }
+}
+
+fn csv_record(cells: Vec<Cell>, decimal_comma: bool) -> Vec<String> {
+    cells
+        .into_iter()
+        .map(|cell| match cell {
+            Cell::Text(value) => spreadsheet_text(value),
+            Cell::Number(value) if decimal_comma => value.to_string().replace('.', ","),
+            Cell::Number(value) => value.to_string(),
+            Cell::Date(millis) => mongodb::bson::DateTime::from_millis(millis)
+                .try_to_rfc3339_string()
+                .unwrap_or_default(),
+            Cell::Empty => String::new(),
+        })
+        .collect()
+}
+
+fn csv_bytes(
+    records: impl IntoIterator<Item = Vec<String>>,
+    decimal_comma: bool,
+) -> Result<Bytes, csv::Error> {
+    // Spreadsheets in decimal comma locales expect semicolons between fields
+    let delimiter = if decimal_comma { b';' } else { b',' };
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(vec![]);
+    for record in records {
+        writer.write_record(&record)?;
+    }
+    let data = writer
+        .into_inner()
+        .map_err(|e| csv::Error::from(e.into_error()))?;
+    Ok(Bytes::from(data))
+}
+
+fn csv_error(err: csv::Error) -> std::io::Error {
+    error!("Export aborted with {:?}", err);
+    std::io::Error::other("export aborted")
+}
+
+/// CSV of the `columns` of every detected item in `reports`, starting with a header. A
+/// database error midway ends the stream, leaving the file incomplete.
+pub fn csv(
+    reports: impl Stream<Item = Result<Litter, LitterError>> + 'static,
+    columns: Vec<Column>,
+    decimal_comma: bool,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + 'static {
+    let header = csv_bytes(
+        [columns.iter().map(|c| c.name().to_string()).collect()],
+        decimal_comma,
+    )
+    .map_err(csv_error);
+
+    let rows = reports.map(move |litter| {
+        let litter = litter.map_err(stream_error)?;
+        let records = rows(&columns, &litter).map(|cells| csv_record(cells, decimal_comma));
+        csv_bytes(records, decimal_comma).map_err(csv_error)
+    });
+
+    stream::once(async { header }).chain(rows)
+}
+
+fn xlsx_error(err: XlsxError) -> LitterError {
+    error!("Failed to write spreadsheet: {:?}", err);
+    LitterError::InternalError
+}
+
+/// Excel workbook of the `columns` of every detected item in `reports`, with numbers and
+/// dates as native cells. Fails with invalid input above `MAX_XLSX_ROWS` items.
+pub async fn xlsx(
+    reports: impl Stream<Item = Result<Litter, LitterError>>,
+    columns: Vec<Column>,
+) -> Result<Vec<u8>, LitterError> {
+    let mut workbook = Workbook::new();
+    let sheet = workbook
+        .add_worksheet()
+        .set_name("Litter")
+        .map_err(xlsx_error)?;
+    let bold = Format::new().set_bold();
+    let date_format = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+
+    for (col, column) in columns.iter().enumerate() {
+        sheet
+            .write_string_with_format(0, col as u16, column.name(), &bold)
+            .map_err(xlsx_error)?;
+    }
+
+    let mut row = 0u32;
+    let mut reports = std::pin::pin!(reports);
+    while let Some(litter) = reports.try_next().await? {
+        for cells in rows(&columns, &litter) {
+            if row as usize >= MAX_XLSX_ROWS {
+                return Err(LitterError::InvalidInput(vec![FieldError {
+                    field: "format".to_string(),
+                    message: format!(
+                        "spreadsheets are limited to {MAX_XLSX_ROWS} items, narrow the filters or export CSV"
+                    ),
+                }]));
+            }
+            row += 1;
+
+            for (col, cell) in cells.into_iter().enumerate() {
+                let col = col as u16;
+                match cell {
+                    Cell::Text(value) => sheet
+                        .write_string(row, col, spreadsheet_text(value))
+                        .map(|_| ()),
+                    Cell::Number(value) => sheet.write_number(row, col, value).map(|_| ()),
+                    Cell::Date(millis) => ExcelDateTime::from_timestamp(millis.div_euclid(1000))
+                        .and_then(|date| {
+                            sheet
+                                .write_datetime_with_format(row, col, &date, &date_format)
+                                .map(|_| ())
+                        }),
+                    Cell::Empty => Ok(()),
+                }
+                .map_err(xlsx_error)?;
+            }
+        }
+    }
+
+    sheet.set_freeze_panes(1, 0).map_err(xlsx_error)?;
+    sheet
+        .autofilter(0, 0, row, columns.len() as u16 - 1)
+        .map_err(xlsx_error)?;
+    workbook.save_to_buffer().map_err(xlsx_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neutralizes_formulas() {
+        let record = csv_record(
+            vec![
+                Cell::Text("=HYPERLINK(\"http://evil\")".to_string()),
+                Cell::Text("+41 79".to_string()),
+                Cell::Text("-1+1".to_string()),
+                Cell::Text("@SUM(A1)".to_string()),
+                Cell::Text("Coca-Cola".to_string()),
+                Cell::Number(-1.5),
+                Cell::Empty,
+            ],
+            false,
+        );
+        assert_eq!(
+            record,
+            [
+                "'=HYPERLINK(\"http://evil\")",
+                "'+41 79",
+                "'-1+1",
+                "'@SUM(A1)",
+                "Coca-Cola",
+                "-1.5",
+                "",
+            ]
+        );
+    }
+}
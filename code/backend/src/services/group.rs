@@ -0,0 +1,534 @@
+use std::collections::HashMap;
+
+use futures::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{DateTime, Document, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    models::{
+        group::{Group, GroupMember, GroupRole},
+        user::username_collation,
+    },
+    services::{
+        account::frontend_url,
+        auth::random_urlsafe,
+        cleanup_session::usernames,
+        validation::{FieldError, invalid},
+    },
+};
+
+pub const GROUP_NAME_MAX_LEN: usize = 64;
+pub const GROUP_DESCRIPTION_MAX_LEN: usize = 500;
+pub const MAX_GROUP_MEMBERS: usize = 500;
+pub const MAX_GROUPS_PER_USER: u64 = 20;
+
+#[derive(Debug, Clone, Serialize)]
+pub enum GroupError {
+    InvalidInput(Vec<FieldError>),
+    /// No such group, or the user is not a member.
+    NotFound,
+    /// Only leaders may do this.
+    Forbidden,
+    AlreadyMember,
+    /// The group would be left without a leader.
+    LastLeader,
+    NetworkError,
+}
+
+impl From<mongodb::error::Error> for GroupError {
+    fn from(e: mongodb::error::Error) -> Self {
+        error!("Error when accessing groups in db: {:?}", e);
+        GroupError::NetworkError
+    }
+}
+
+fn collection(db: &Database) -> Collection<Group> {
+    db.collection::<Group>("groups")
+}
+
+fn new_invite_code() -> String {
+    random_urlsafe(12)
+}
+
+pub async fn ensure_indexes(db: &Database) {
+    let indexes = [
+        IndexModel::builder()
+            .keys(doc! { "invite_code": 1 })
+            .options(
+                IndexOptions::builder()
+                    .unique(true)
+                    .name(Some("unique_invite_code".to_string()))
+                    .build(),
+            )
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "members.user_id": 1 })
+            .options(
+                IndexOptions::builder()
+                    .name(Some("group_members".to_string()))
+                    .build(),
+            )
+            .build(),
+    ];
+
+    if let Err(e) = collection(db).create_indexes(indexes).await {
+        error!("Failed to ensure indexes for 'groups': {:?}", e);
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GroupSummary {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    /// Role of the current user
+    pub role: GroupRole,
+    pub member_count: usize,
+    pub created_at: String,
+    /// Only shown to leaders
+    pub invite_code: Option<String>,
+    /// Frontend link that joins the group, only shown to leaders
+    pub invite_link: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GroupMemberData {
+    pub username: String,
+    pub role: GroupRole,
+    pub joined_at: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GroupDetail {
+    #[serde(flatten)]
+    pub group: GroupSummary,
+    /// Leaders first, then by joining date
+    pub members: Vec<GroupMemberData>,
+}
+
+fn role_of(group: &Group, user_id: ObjectId) -> Option<GroupRole> {
+    group
+        .members
+        .iter()
+        .find(|m| m.user_id == user_id)
+        .map(|m| m.role)
+}
+
+fn summary(group: &Group, user_id: ObjectId) -> GroupSummary {
+    let role = role_of(group, user_id).unwrap_or(GroupRole::Member);
+    let leader = role == GroupRole::Leader;
+    GroupSummary {
+        id: group._id.map(|id| id.to_hex()).unwrap_or_default(),
+        name: group.name.clone(),
+        description: group.description.clone(),
+        role,
+        member_count: group.members.len(),
+        created_at: group.created_at.try_to_rfc3339_string().unwrap_or_default(),
+        invite_code: leader.then(|| group.invite_code.clone()),
+        invite_link: leader
+            .then(|| format!("{}/groups/join?code={}", frontend_url(), group.invite_code)),
+    }
+}
+
+/// Checks name and description, trimmed and with empty descriptions dropped.
+fn validate(name: &str, description: Option<&str>) -> Result<(String, Option<String>), GroupError> {
+    let mut errors = vec![];
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > GROUP_NAME_MAX_LEN {
+        errors.push(invalid(
+            "name",
+            format!("must be between 1 and {GROUP_NAME_MAX_LEN} characters long"),
+        ));
+    }
+    let description = description.map(str::trim).filter(|d| !d.is_empty());
+    if description.is_some_and(|d| d.chars().count() > GROUP_DESCRIPTION_MAX_LEN) {
+        errors.push(invalid(
+            "description",
+            format!("must be at most {GROUP_DESCRIPTION_MAX_LEN} characters long"),
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(GroupError::InvalidInput(errors));
+    }
+
+    Ok((name.to_string(), description.map(str::to_string)))
+}
+
+async fn check_group_count(
+    db: &Database,
+    user_id: ObjectId,
+    field: &str,
+) -> Result<(), GroupError> {
+    if collection(db)
+        .count_documents(doc! { "members.user_id": user_id })
+        .await?
+        >= MAX_GROUPS_PER_USER
+    {
+        return Err(GroupError::InvalidInput(vec![invalid(
+            field,
+            format!("at most {MAX_GROUPS_PER_USER} groups per account, leave one first"),
+        )]));
+    }
+    Ok(())
+}
+
+/// Creates a group with the user as its leader.
+pub async fn create(
+    db: &Database,
+    user_id: ObjectId,
+    name: &str,
+    description: Option<&str>,
+) -> Result<GroupSummary, GroupError> {
+    let (name, description) = validate(name, description)?;
+    check_group_count(db, user_id, "name").await?;
+
+    let now = DateTime::now();
+    let mut group = Group {
+        _id: None,
+        name,
+        description,
+        invite_code: new_invite_code(),
+        members: vec![GroupMember {
+            user_id,
+            role: GroupRole::Leader,
+            joined_at: now,
+        }],
+        created_at: now,
+    };
+    group._id = collection(db)
+        .insert_one(&group)
+        .await?
+        .inserted_id
+        .as_object_id();
+
+    info!("{} created group {:?}", user_id, group._id);
+    Ok(summary(&group, user_id))
+}
+
+/// Group the user is a member of, with the user's role.
+async fn membership(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<(Group, GroupRole), GroupError> {
+    let group = collection(db)
+        .find_one(doc! { "_id": id, "members.user_id": user_id })
+        .await?
+        .ok_or(GroupError::NotFound)?;
+    let role = role_of(&group, user_id).ok_or(GroupError::NotFound)?;
+    Ok((group, role))
+}
+
+/// Group the user leads.
+pub(crate) async fn leadership(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<Group, GroupError> {
+    match membership(db, user_id, id).await? {
+        (group, GroupRole::Leader) => Ok(group),
+        _ => Err(GroupError::Forbidden),
+    }
+}
+
+/// Groups the user is a member of, by name.
+pub async fn list(db: &Database, user_id: ObjectId) -> Result<Vec<GroupSummary>, GroupError> {
+    let groups: Vec<Group> = collection(db)
+        .find(doc! { "members.user_id": user_id })
+        .sort(doc! { "name": 1, "_id": 1 })
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(groups.iter().map(|g| summary(g, user_id)).collect())
+}
+
+pub async fn get(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<GroupDetail, GroupError> {
+    let (mut group, _) = membership(db, user_id, id).await?;
+    let names = usernames(db, group.members.iter().map(|m| m.user_id)).await?;
+
+    group
+        .members
+        .sort_by_key(|m| (m.role != GroupRole::Leader, m.joined_at));
+    let members = group
+        .members
+        .iter()
+        .filter_map(|m| {
+            Some(GroupMemberData {
+                username: names.get(&m.user_id)?.clone(),
+                role: m.role,
+                joined_at: m.joined_at.try_to_rfc3339_string().unwrap_or_default(),
+            })
+        })
+        .collect();
+
+    Ok(GroupDetail {
+        group: summary(&group, user_id),
+        members,
+    })
+}
+
+/// Ids of the members, for listings and statistics over the whole group.
+pub async fn member_ids(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<Vec<ObjectId>, GroupError> {
+    let (group, _) = membership(db, user_id, id).await?;
+    Ok(group.members.iter().map(|m| m.user_id).collect())
+}
+
+/// Ids of the groups the user is a member of.
+pub(crate) async fn group_ids(
+    db: &Database,
+    user_id: ObjectId,
+) -> Result<Vec<ObjectId>, mongodb::error::Error> {
+    let groups: Vec<Document> = db
+        .collection::<Document>("groups")
+        .find(doc! { "members.user_id": user_id })
+        .projection(doc! { "_id": 1 })
+        .await?
+        .try_collect()
+        .await?;
+    Ok(groups
+        .iter()
+        .filter_map(|g| g.get_object_id("_id").ok())
+        .collect())
+}
+
+/// Group by id, without checking membership.
+pub(crate) async fn find(
+    db: &Database,
+    id: ObjectId,
+) -> Result<Option<Group>, mongodb::error::Error> {
+    collection(db).find_one(doc! { "_id": id }).await
+}
+
+/// Member ids of each of the groups, deleted groups are left out.
+pub(crate) async fn members_of(
+    db: &Database,
+    ids: &[ObjectId],
+) -> Result<HashMap<ObjectId, Vec<ObjectId>>, mongodb::error::Error> {
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let groups: Vec<Group> = collection(db)
+        .find(doc! { "_id": { "$in": ids } })
+        .await?
+        .try_collect()
+        .await?;
+    Ok(groups
+        .into_iter()
+        .filter_map(|g| Some((g._id?, g.members.iter().map(|m| m.user_id).collect())))
+        .collect())
+}
+
+/// Adds the user to the group with this invite code.
+pub async fn join(
+    db: &Database,
+    user_id: ObjectId,
+    code: &str,
+) -> Result<GroupSummary, GroupError> {
+    let groups = collection(db);
+    let code = code.trim();
+    let group = groups
+        .find_one(doc! { "invite_code": code })
+        .await?
+        .ok_or(GroupError::NotFound)?;
+    if role_of(&group, user_id).is_some() {
+        return Err(GroupError::AlreadyMember);
+    }
+    check_group_count(db, user_id, "code").await?;
+
+    let member = GroupMember {
+        user_id,
+        role: GroupRole::Member,
+        joined_at: DateTime::now(),
+    };
+    // Conditions repeat the checks above, in case the code was revoked or the group
+    // filled up meanwhile.
+    let res = groups
+        .update_one(
+            doc! {
+                "_id": group._id,
+                "invite_code": code,
+                "members.user_id": { "$ne": user_id },
+                format!("members.{}", MAX_GROUP_MEMBERS - 1): { "$exists": false },
+            },
+            doc! { "$push": { "members": {
+                "user_id": user_id,
+                "role": "member",
+                "joined_at": member.joined_at,
+            } } },
+        )
+        .await?;
+    if res.modified_count == 0 {
+        let current = groups
+            .find_one(doc! { "_id": group._id, "invite_code": code })
+            .await?
+            .ok_or(GroupError::NotFound)?;
+        if role_of(&current, user_id).is_some() {
+            return Err(GroupError::AlreadyMember);
+        }
+        return Err(GroupError::InvalidInput(vec![invalid(
+            "code",
+            format!("the group is full, at most {MAX_GROUP_MEMBERS} members"),
+        )]));
+    }
+
+    info!("{} joined group {:?}", user_id, group._id);
+    let mut group = group;
+    group.members.push(member);
+    Ok(summary(&group, user_id))
+}
+
+/// Removes `member` from the group. Leaders can only go while another leader stays, the
+/// group is deleted when its last member leaves.
+async fn remove(db: &Database, group: &Group, member: ObjectId) -> Result<(), GroupError> {
+    let groups = collection(db);
+    let id = group._id;
+
+    if group.members.len() == 1 && group.members[0].user_id == member {
+        groups
+            .delete_one(doc! { "_id": id, "members": { "$size": 1 } })
+            .await?;
+        info!("Deleted group {:?} after its last member left", id);
+        return Ok(());
+    }
+
+    let mut filter = doc! { "_id": id, "members.user_id": member };
+    if role_of(group, member) == Some(GroupRole::Leader) {
+        filter.insert(
+            "members",
+            doc! { "$elemMatch": { "role": "leader", "user_id": { "$ne": member } } },
+        );
+    }
+    let res = groups
+        .update_one(
+            filter,
+            doc! { "$pull": { "members": { "user_id": member } } },
+        )
+        .await?;
+    if res.modified_count == 0 {
+        return Err(match role_of(group, member) {
+            Some(GroupRole::Leader) => GroupError::LastLeader,
+            _ => GroupError::NotFound,
+        });
+    }
+    Ok(())
+}
+
+pub async fn leave(db: &Database, user_id: ObjectId, id: ObjectId) -> Result<(), GroupError> {
+    let (group, _) = membership(db, user_id, id).await?;
+    remove(db, &group, user_id).await?;
+
+    info!("{} left group {}", user_id, id);
+    Ok(())
+}
+
+/// Member with this username, case-insensitively.
+async fn find_member(db: &Database, group: &Group, username: &str) -> Result<ObjectId, GroupError> {
+    let user: Option<Document> = db
+        .collection::<Document>("users")
+        .find_one(doc! { "username": username.trim() })
+        .projection(doc! { "_id": 1 })
+        .collation(username_collation())
+        .await?;
+
+    user.and_then(|u| u.get_object_id("_id").ok())
+        .filter(|id| role_of(group, *id).is_some())
+        .ok_or(GroupError::NotFound)
+}
+
+/// Removes another member, only leaders can.
+pub async fn remove_member(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+    username: &str,
+) -> Result<(), GroupError> {
+    let group = leadership(db, user_id, id).await?;
+    let member = find_member(db, &group, username).await?;
+    if member == user_id {
+        return Err(GroupError::InvalidInput(vec![invalid(
+            "username",
+            "leave the group instead of removing yourself",
+        )]));
+    }
+    remove(db, &group, member).await?;
+
+    info!("{} removed {} from group {}", user_id, member, id);
+    Ok(())
+}
+
+/// Makes a member leader or member, only leaders can.
+pub async fn set_role(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+    username: &str,
+    role: GroupRole,
+) -> Result<GroupDetail, GroupError> {
+    let group = leadership(db, user_id, id).await?;
+    let member = find_member(db, &group, username).await?;
+
+    let mut filter = doc! { "_id": id, "members.user_id": member };
+    if role != GroupRole::Leader {
+        filter.insert(
+            "members",
+            doc! { "$elemMatch": { "role": "leader", "user_id": { "$ne": member } } },
+        );
+    }
+    let role_name = match role {
+        GroupRole::Leader => "leader",
+        GroupRole::Member => "member",
+    };
+    let res = collection(db)
+        .update_one(filter, doc! { "$set": { "members.$[m].role": role_name } })
+        .array_filters(vec![doc! { "m.user_id": member }])
+        .await?;
+    if res.matched_count == 0 {
+        return Err(GroupError::LastLeader);
+    }
+
+    info!("{} made {} {} of group {}", user_id, member, role_name, id);
+    get(db, user_id, id).await
+}
+
+/// Replaces the invite code, so old codes and links stop working. Only leaders can.
+pub async fn renew_invite(
+    db: &Database,
+    user_id: ObjectId,
+    id: ObjectId,
+) -> Result<GroupSummary, GroupError> {
+    let mut group = leadership(db, user_id, id).await?;
+    group.invite_code = new_invite_code();
+    collection(db)
+        .update_one(
+            doc! { "_id": id },
+            doc! { "$set": { "invite_code": &group.invite_code } },
+        )
+        .await?;
+
+    info!("{} renewed the invite code of group {}", user_id, id);
+    Ok(summary(&group, user_id))
+}
+
+/// Deletes the group for all members, only leaders can. Reports stay with their owners.
+pub async fn delete(db: &Database, user_id: ObjectId, id: ObjectId) -> Result<(), GroupError> {
+    leadership(db, user_id, id).await?;
+    collection(db).delete_one(doc! { "_id": id }).await?;
+
+    info!("{} deleted group {}", user_id, id);
+    Ok(())
+}
@@ -0,0 +1,187 @@
+//! Environmental impact of collected litter, estimated from per-region factor tables.
+//! The built-in tables live in `impact_factors.json` and can be replaced with
+//! `IMPACT_FACTORS_FILE`.
+
+use std::{collections::BTreeMap, env, fs, sync::LazyLock};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{models::litter::Entry, services::validation::FieldError};
+
+static TABLES: LazyLock<ImpactTables> = LazyLock::new(load);
+
+#[derive(Debug, Deserialize)]
+struct ImpactTables {
+    default_region: String,
+    regions: BTreeMap<String, RegionFactors>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegionFactors {
+    currency: String,
+    /// Checked in order, the first factor matching an item applies.
+    factors: Vec<Factor>,
+}
+
+/// Factor for items of a category and/or material, missing fields match anything.
+#[derive(Debug, Deserialize)]
+struct Factor {
+    category: Option<String>,
+    material: Option<String>,
+    /// kg CO2e emitted producing one kg of the item
+    co2e_per_kg: f64,
+    recyclable: bool,
+    /// Material value per kg when recycled
+    value_per_kg: f64,
+    /// Refund per item
+    deposit: f64,
+}
+
+/// Analyser output such as "Cigarette Butt" is compared as `cigarette_butt`.
+pub(crate) fn normalize(value: &str) -> String {
+    value.trim().to_lowercase().replace([' ', '-'], "_")
+}
+
+pub(crate) fn matches(pattern: &Option<String>, value: Option<&str>) -> bool {
+    match pattern {
+        None => true,
+        Some(pattern) => value.is_some_and(|v| normalize(v) == normalize(pattern)),
+    }
+}
+
+fn load() -> ImpactTables {
+    let tables: ImpactTables = match env::var("IMPACT_FACTORS_FILE") {
+        Ok(path) => {
+            let raw = fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("Failed to read IMPACT_FACTORS_FILE {path}: {e}"));
+            serde_json::from_str(&raw)
+                .unwrap_or_else(|e| panic!("Invalid impact factors in {path}: {e}"))
+        }
+        Err(_) => serde_json::from_str(include_str!("impact_factors.json"))
+            .expect("Built-in impact factors are valid"),
+    };
+    assert!(
+        tables.regions.contains_key(&tables.default_region),
+        "Impact factors lack the default region {}",
+        tables.default_region
+    );
+    tables
+}
+
+/// Loads the factor tables, so a broken `IMPACT_FACTORS_FILE` stops the server at startup.
+pub fn init() {
+    LazyLock::force(&TABLES);
+}
+
+/// Estimated impact of a set of items. Amounts of money are in `currency`.
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct Impact {
+    /// Greenhouse gas emissions of producing the items, in grams CO2e
+    pub co2e_g: f64,
+    /// Weight of the items that can be recycled in the region, in grams
+    pub recyclable_weight_g: f64,
+    /// Material value of the recyclable items
+    pub recycling_value: f64,
+    /// Deposits refunded for the items
+    pub deposit_value: f64,
+    pub region: String,
+    pub currency: String,
+}
+
+/// Factor table of one region.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub code: &'static str,
+    factors: &'static RegionFactors,
+}
+
+/// Looks up a region by its code, `None` picks the configured default.
+pub fn region(code: Option<&str>) -> Result<Region, FieldError> {
+    let code = code.map_or(TABLES.default_region.as_str(), str::trim);
+    let code = code.to_lowercase();
+
+    TABLES
+        .regions
+        .get_key_value(&code)
+        .map(|(code, factors)| Region {
+            code: code.as_str(),
+            factors,
+        })
+        .ok_or_else(|| FieldError {
+            field: "region".to_string(),
+            message: format!(
+                "must be one of {}",
+                TABLES
+                    .regions
+                    .keys()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        })
+}
+
+impl Region {
+    /// Impact of `items` pieces of litter weighing `weight_g` grams in total. Items
+    /// without a matching factor count as zero.
+    fn add(
+        &self,
+        impact: &mut Impact,
+        category: Option<&str>,
+        material: Option<&str>,
+        items: u64,
+        weight_g: f64,
+    ) {
+        let Some(factor) = self
+            .factors
+            .factors
+            .iter()
+            .find(|f| matches(&f.category, category) && matches(&f.material, material))
+        else {
+            return;
+        };
+
+        impact.co2e_g += weight_g * factor.co2e_per_kg;
+        if factor.recyclable {
+            impact.recyclable_weight_g += weight_g;
+            impact.recycling_value += weight_g / 1000.0 * factor.value_per_kg;
+        }
+        impact.deposit_value += items as f64 * factor.deposit;
+    }
+
+    fn empty(&self) -> Impact {
+        Impact {
+            region: self.code.to_string(),
+            currency: self.factors.currency.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Impact of the entries of one report.
+    pub fn entries_impact(&self, entries: &[Entry]) -> Impact {
+        let mut impact = self.empty();
+        for entry in entries {
+            self.add(
+                &mut impact,
+                entry.category.as_deref(),
+                entry.material.as_deref(),
+                1,
+                entry.weight.unwrap_or_default(),
+            );
+        }
+        impact
+    }
+
+    /// Impact of items grouped by category and material, as `(category, material, items, weight_g)`.
+    pub fn grouped_impact<'a>(
+        &self,
+        groups: impl IntoIterator<Item = (Option<&'a str>, Option<&'a str>, u64, f64)>,
+    ) -> Impact {
+        let mut impact = self.empty();
+        for (category, material, items, weight_g) in groups {
+            self.add(&mut impact, category, material, items, weight_g);
+        }
+        impact
+    }
+}
@@ -0,0 +1,708 @@
+//! Bulk import of reports without photos, e.g. paper cleanup logs kept before Delitter.
+//! Every report gets an import key derived from its `id` (or position and date), so
+//! importing a file again skips the reports it created before.
+
+use std::collections::{HashMap, HashSet};
+
+use futures::TryStreamExt;
+use log::error;
+use mongodb::{
+    Database,
+    bson::{self, DateTime, Document, doc, oid::ObjectId},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::{
+    models::{
+        litter::{AnalysisStatus, Entry, GeoPoint, Litter},
+        user::User,
+    },
+    services::{
+        achievement,
+        litter::{parse_date, validate_position},
+        validation::{FieldError, invalid},
+    },
+};
+
+pub const MAX_IMPORT_BYTES: usize = 20 * 1024 * 1024;
+const MAX_IMPORT_ROWS: usize = 20_000;
+/// Largest `count` of a row, rows stand for at most this many identical items.
+const MAX_ITEM_COUNT: usize = 1000;
+/// Items of a file after expanding `count`.
+const MAX_IMPORT_ITEMS: usize = 100_000;
+/// MongoDB's document limit less room for the update itself, all reports of a user live in
+/// one document.
+const MAX_USER_BYTES: usize = 16 * 1024 * 1024 - 64 * 1024;
+const MAX_TEXT_LEN: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    Csv,
+    Geojson,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RowError {
+    /// Line of the CSV file (the header is line 1) or number of the GeoJSON feature
+    /// (from 1), 0 for the file as a whole
+    pub row: usize,
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    /// CSV rows or GeoJSON features read
+    pub rows: usize,
+    /// Reports in the file, rows of the same report are merged
+    pub reports: usize,
+    pub items: usize,
+    /// Reports imported before, they are skipped
+    pub duplicates: usize,
+    /// Reports created, 0 on dry runs and for files with errors
+    pub created: usize,
+    pub errors: Vec<RowError>,
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    /// Another import for the user added some of the reports meanwhile.
+    Conflict,
+    NetworkError,
+}
+
+impl From<mongodb::error::Error> for ImportError {
+    fn from(e: mongodb::error::Error) -> Self {
+        error!("Error when importing litter: {:?}", e);
+        ImportError::NetworkError
+    }
+}
+
+/// Item columns of a row as read from the file, before validation.
+#[derive(Debug, Default)]
+struct RawItem {
+    category: Option<String>,
+    material: Option<String>,
+    brand: Option<String>,
+    weight: Option<String>,
+    count: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct RawRow {
+    id: Option<String>,
+    lat: Option<String>,
+    lng: Option<String>,
+    date: Option<String>,
+    items: Vec<RawItem>,
+}
+
+#[derive(Debug)]
+struct ImportedReport {
+    /// Row the report starts on
+    row: usize,
+    id: Option<String>,
+    lat: f64,
+    lng: f64,
+    date: DateTime,
+    /// Items with how many times they were found, expanded only once the file is accepted.
+    items: Vec<(Entry, usize)>,
+}
+
+impl ImportedReport {
+    fn item_count(&self) -> usize {
+        self.items.iter().map(|(_, count)| count).sum()
+    }
+
+    fn key(&self) -> String {
+        let source = match &self.id {
+            Some(id) => format!("id:{id}"),
+            None => format!(
+                "at:{:.6},{:.6},{}",
+                self.lat,
+                self.lng,
+                self.date.timestamp_millis()
+            ),
+        };
+        Sha256::digest(source.as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    fn into_litter(self, import_key: String) -> Litter {
+        Litter {
+            _id: ObjectId::new(),
+            lng: self.lng,
+            lat: self.lat,
+            location: Some(GeoPoint::new(self.lng, self.lat)),
+            file: None,
+            r#type: String::new(),
+            entries: self
+                .items
+                .into_iter()
+                .flat_map(|(entry, count)| std::iter::repeat_n(entry, count))
+                .collect(),
+            time_stamp: self.date,
+            status: Some(AnalysisStatus::Done),
+            import_key: Some(import_key),
+            session_id: None,
+        }
+    }
+}
+
+fn text(field: &str, value: Option<String>) -> Result<Option<String>, FieldError> {
+    let Some(value) = value
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+    else {
+        return Ok(None);
+    };
+    if value.chars().count() > MAX_TEXT_LEN {
+        return Err(invalid(
+            field,
+            format!("must be at most {MAX_TEXT_LEN} characters"),
+        ));
+    }
+    Ok(Some(value))
+}
+
+/// Decimal numbers with a point or a comma.
+fn number(field: &str, value: Option<&str>) -> Result<Option<f64>, FieldError> {
+    let Some(value) = value.map(str::trim).filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+    value
+        .replace(',', ".")
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite())
+        .map(Some)
+        .ok_or_else(|| invalid(field, "must be a number"))
+}
+
+fn check<T>(errors: &mut Vec<FieldError>, result: Result<T, FieldError>) -> Option<T> {
+    result.map_err(|e| errors.push(e)).ok()
+}
+
+/// Item of one item column set and its `count`.
+fn item_entry(prefix: &str, item: RawItem, errors: &mut Vec<FieldError>) -> Option<(Entry, usize)> {
+    let field = |name: &str| format!("{prefix}{name}");
+
+    let category = check(errors, text(&field("category"), item.category));
+    let material = check(errors, text(&field("material"), item.material));
+    let brand = check(errors, text(&field("brand"), item.brand));
+    let weight = check(
+        errors,
+        number(&field("weight_g"), item.weight.as_deref()).and_then(|w| match w {
+            Some(w) if w < 0.0 => Err(invalid(&field("weight_g"), "must not be negative")),
+            w => Ok(w),
+        }),
+    );
+    let count = check(
+        errors,
+        number(&field("count"), item.count.as_deref()).and_then(|c| match c {
+            None => Ok(1),
+            Some(c) if c.fract() == 0.0 && (0.0..=MAX_ITEM_COUNT as f64).contains(&c) => {
+                Ok(c as usize)
+            }
+            Some(_) => Err(invalid(
+                &field("count"),
+                format!("must be a whole number from 0 to {MAX_ITEM_COUNT}"),
+            )),
+        }),
+    );
+
+    let entry = Entry {
+        category: category?,
+        material: material?,
+        weight: weight?,
+        brand: brand?,
+        confidence: None,
+    };
+    Some((entry, count?))
+}
+
+fn validate(
+    raw: RawRow,
+    items_prefix: impl Fn(usize) -> String,
+) -> Result<ImportedReport, Vec<FieldError>> {
+    let mut errors = vec![];
+
+    let id = check(&mut errors, text("id", raw.id)).flatten();
+    let mut required = |field: &str, value: Option<&str>| match number(field, value) {
+        Ok(Some(n)) => Some(n),
+        Ok(None) => {
+            errors.push(invalid(field, "is required"));
+            None
+        }
+        Err(e) => {
+            errors.push(e);
+            None
+        }
+    };
+    let lat = required("lat", raw.lat.as_deref());
+    let lng = required("lng", raw.lng.as_deref());
+    if let (Some(lat), Some(lng)) = (lat, lng) {
+        errors.extend(validate_position(lat, lng));
+    }
+
+    let date = match raw.date.as_deref().map(str::trim).filter(|d| !d.is_empty()) {
+        None => {
+            errors.push(invalid("date", "is required"));
+            None
+        }
+        Some(value) => match parse_date("date", value) {
+            Ok(date) if date > DateTime::now() => {
+                errors.push(invalid("date", "must not be in the future"));
+                None
+            }
+            Ok(date) => Some(date),
+            Err(e) => {
+                errors.push(e);
+                None
+            }
+        },
+    };
+
+    let mut items = vec![];
+    for (i, item) in raw.items.into_iter().enumerate() {
+        items.extend(item_entry(&items_prefix(i), item, &mut errors));
+    }
+
+    match (lat, lng, date) {
+        (Some(lat), Some(lng), Some(date)) if errors.is_empty() => Ok(ImportedReport {
+            row: 0,
+            id,
+            lat,
+            lng,
+            date,
+            items,
+        }),
+        _ => Err(errors),
+    }
+}
+
+/// Reports and errors of a file, rows of the same report merged.
+#[derive(Default)]
+struct ParsedFile {
+    rows: usize,
+    items: usize,
+    reports: Vec<ImportedReport>,
+    by_key: HashMap<String, usize>,
+    errors: Vec<RowError>,
+}
+
+impl ParsedFile {
+    fn error(&mut self, row: usize, field: &str, message: impl Into<String>) {
+        self.errors.push(RowError {
+            row,
+            field: field.to_string(),
+            message: message.into(),
+        });
+    }
+
+    /// Counts a row, false once the file has too many.
+    fn next_row(&mut self, row: usize) -> bool {
+        if self.rows == MAX_IMPORT_ROWS {
+            self.error(
+                row,
+                "file",
+                format!("must have at most {MAX_IMPORT_ROWS} rows"),
+            );
+            return false;
+        }
+        self.rows += 1;
+        true
+    }
+
+    /// Adds a row to its report, false once the file has too many items.
+    fn add(&mut self, row: usize, raw: RawRow, items_prefix: impl Fn(usize) -> String) -> bool {
+        let mut report = match validate(raw, items_prefix) {
+            Ok(report) => report,
+            Err(errors) => {
+                for e in errors {
+                    self.error(row, &e.field, e.message);
+                }
+                return true;
+            }
+        };
+        report.row = row;
+
+        self.items += report.item_count();
+        if self.items > MAX_IMPORT_ITEMS {
+            self.error(
+                row,
+                "file",
+                format!("must have at most {MAX_IMPORT_ITEMS} items, counting `count`"),
+            );
+            return false;
+        }
+
+        let key = report.key();
+        let Some(&index) = self.by_key.get(&key) else {
+            self.by_key.insert(key, self.reports.len());
+            self.reports.push(report);
+            return true;
+        };
+
+        let existing = &mut self.reports[index];
+        if (existing.lat, existing.lng, existing.date) != (report.lat, report.lng, report.date) {
+            let first = existing.row;
+            self.error(
+                row,
+                "id",
+                format!("has another position or date than row {first} of the same report"),
+            );
+            return true;
+        }
+        existing.items.extend(report.items);
+        true
+    }
+}
+
+fn parse_csv(data: &[u8], parsed: &mut ParsedFile) {
+    let data = data.strip_prefix(b"\xef\xbb\xbf").unwrap_or(data);
+    // Spreadsheets in decimal comma locales save with semicolons
+    let header_line = data.split(|b| *b == b'\n').next().unwrap_or_default();
+    let count = |c: u8| header_line.iter().filter(|b| **b == c).count();
+    let delimiter = if count(b';') > count(b',') {
+        b';'
+    } else {
+        b','
+    };
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(data);
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => return parsed.error(1, "file", format!("is not valid CSV: {e}")),
+    };
+    let column = |names: &[&str]| {
+        headers
+            .iter()
+            .position(|h| names.contains(&h.trim().to_lowercase().as_str()))
+    };
+
+    let id = column(&["id", "report_id"]);
+    let lat = column(&["lat", "latitude"]);
+    let lng = column(&["lng", "lon", "longitude"]);
+    let date = column(&["date"]);
+    let category = column(&["category"]);
+    let material = column(&["material"]);
+    let brand = column(&["brand"]);
+    let weight = column(&["weight_g", "weight"]);
+    let item_count = column(&["count"]);
+    if lat.is_none() || lng.is_none() || date.is_none() {
+        return parsed.error(1, "header", "must have lat, lng and date columns");
+    }
+
+    for (i, record) in reader.records().enumerate() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                let row = e.position().map_or(i + 2, |p| p.line() as usize);
+                if !parsed.next_row(row) {
+                    return;
+                }
+                parsed.error(row, "file", format!("is not valid CSV: {e}"));
+                continue;
+            }
+        };
+        let row = record.position().map_or(i + 2, |p| p.line() as usize);
+        if !parsed.next_row(row) {
+            return;
+        }
+
+        let get = |column: Option<usize>| column.and_then(|c| record.get(c)).map(str::to_string);
+        let raw = RawRow {
+            id: get(id),
+            lat: get(lat),
+            lng: get(lng),
+            date: get(date),
+            items: vec![RawItem {
+                category: get(category),
+                material: get(material),
+                brand: get(brand),
+                weight: get(weight),
+                count: get(item_count),
+            }],
+        };
+        if !parsed.add(row, raw, |_| String::new()) {
+            return;
+        }
+    }
+}
+
+/// Value of a GeoJSON property as text, as if read from CSV.
+fn json_text(value: Option<&Value>) -> Option<String> {
+    match value? {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+fn json_item(properties: &serde_json::Map<String, Value>) -> RawItem {
+    RawItem {
+        category: json_text(properties.get("category")),
+        material: json_text(properties.get("material")),
+        brand: json_text(properties.get("brand")),
+        weight: json_text(
+            properties
+                .get("weight_g")
+                .or_else(|| properties.get("weight")),
+        ),
+        count: json_text(properties.get("count")),
+    }
+}
+
+fn parse_geojson(data: &[u8], parsed: &mut ParsedFile) {
+    let collection: Value = match serde_json::from_slice(data) {
+        Ok(collection) => collection,
+        Err(e) => return parsed.error(0, "file", format!("is not valid JSON: {e}")),
+    };
+    let features = match (collection.get("type"), collection.get("features")) {
+        (Some(Value::String(t)), Some(Value::Array(features))) if t == "FeatureCollection" => {
+            features
+        }
+        _ => return parsed.error(0, "file", "must be a GeoJSON FeatureCollection"),
+    };
+
+    let empty = serde_json::Map::new();
+    for (i, feature) in features.iter().enumerate() {
+        let row = i + 1;
+        if !parsed.next_row(row) {
+            return;
+        }
+
+        let geometry = feature.get("geometry");
+        let coordinates = match (
+            geometry.and_then(|g| g.get("type")),
+            geometry.and_then(|g| g.get("coordinates")),
+        ) {
+            (Some(Value::String(t)), Some(Value::Array(c))) if t == "Point" && c.len() >= 2 => c,
+            _ => {
+                parsed.error(row, "geometry", "must be a Point");
+                continue;
+            }
+        };
+
+        let properties = feature
+            .get("properties")
+            .and_then(Value::as_object)
+            .unwrap_or(&empty);
+        let items = match properties.get("entries") {
+            Some(Value::Array(entries)) => entries
+                .iter()
+                .map(|e| e.as_object().map(json_item).unwrap_or_default())
+                .collect(),
+            _ if [
+                "category", "material", "brand", "weight_g", "weight", "count",
+            ]
+            .iter()
+            .any(|k| properties.contains_key(*k)) =>
+            {
+                vec![json_item(properties)]
+            }
+            _ => vec![],
+        };
+        let raw = RawRow {
+            id: json_text(properties.get("id").or_else(|| feature.get("id"))),
+            lng: json_text(coordinates.first()),
+            lat: json_text(coordinates.get(1)),
+            date: json_text(properties.get("date")),
+            items,
+        };
+        let flat = !matches!(properties.get("entries"), Some(Value::Array(_)));
+        let added = parsed.add(row, raw, |i| {
+            if flat {
+                String::new()
+            } else {
+                format!("entries[{i}].")
+            }
+        });
+        if !added {
+            return;
+        }
+    }
+}
+
+/// What the user has stored: the import keys of the reports imported before and the size
+/// of the user document.
+#[derive(Debug, Default)]
+struct Stored {
+    keys: HashSet<String>,
+    bytes: usize,
+}
+
+async fn stored(db: &Database, user_id: ObjectId) -> Result<Stored, ImportError> {
+    let user = db
+        .collection::<Document>("users")
+        .aggregate([
+            doc! { "$match": { "_id": user_id } },
+            doc! { "$project": {
+                "keys": "$litter.import_key",
+                "bytes": { "$bsonSize": "$$ROOT" },
+            } },
+        ])
+        .await?
+        .try_next()
+        .await?;
+    let Some(user) = user else {
+        return Ok(Stored::default());
+    };
+
+    Ok(Stored {
+        keys: user
+            .get_array("keys")
+            .into_iter()
+            .flatten()
+            .filter_map(|k| k.as_str())
+            .map(str::to_string)
+            .collect(),
+        bytes: user.get_i32("bytes").map_or(0, |b| b as usize),
+    })
+}
+
+/// Reports of the file not imported before.
+fn new_reports(reports: Vec<ImportedReport>, existing: &HashSet<String>) -> Vec<Litter> {
+    reports
+        .into_iter()
+        .filter_map(|r| {
+            let key = r.key();
+            (!existing.contains(&key)).then(|| r.into_litter(key))
+        })
+        .collect()
+}
+
+/// Validates `data` and, unless `dry_run` is set or a row is invalid, adds its reports to
+/// the user's litter. Reports imported before are skipped.
+pub async fn import(
+    db: &Database,
+    user_id: ObjectId,
+    format: ImportFormat,
+    data: &[u8],
+    dry_run: bool,
+) -> Result<ImportReport, ImportError> {
+    let mut parsed = ParsedFile::default();
+    match format {
+        ImportFormat::Csv => parse_csv(data, &mut parsed),
+        ImportFormat::Geojson => parse_geojson(data, &mut parsed),
+    }
+    if parsed.rows == 0 && parsed.errors.is_empty() {
+        parsed.error(0, "file", "contains no rows");
+    }
+
+    let stored = stored(db, user_id).await?;
+    let mut report = ImportReport {
+        dry_run,
+        rows: parsed.rows,
+        reports: parsed.reports.len(),
+        items: parsed.items,
+        duplicates: 0,
+        created: 0,
+        errors: parsed.errors,
+    };
+
+    let new = new_reports(parsed.reports, &stored.keys);
+    report.duplicates = report.reports - new.len();
+    if !report.errors.is_empty() || new.is_empty() {
+        return Ok(report);
+    }
+
+    let litter = bson::to_bson(&new).map_err(|e| {
+        error!("Failed to serialize imported litter: {:?}", e);
+        ImportError::NetworkError
+    })?;
+    let bytes = bson::to_vec(&doc! { "litter": &litter }).map_or(usize::MAX, |b| b.len());
+    if stored.bytes.saturating_add(bytes) > MAX_USER_BYTES {
+        report.errors.push(RowError {
+            row: 0,
+            field: "file".to_string(),
+            message: "has more reports than fit into the account, import fewer at once".to_string(),
+        });
+        return Ok(report);
+    }
+    if dry_run {
+        return Ok(report);
+    }
+
+    let keys: Vec<&str> = new.iter().filter_map(|l| l.import_key.as_deref()).collect();
+    // The key condition keeps concurrent imports of the same file from adding reports twice
+    let result = db
+        .collection::<User>("users")
+        .update_one(
+            doc! { "_id": user_id, "litter.import_key": { "$nin": keys } },
+            doc! { "$push": { "litter": { "$each": litter } } },
+        )
+        .await?;
+    if result.matched_count == 0 {
+        return Err(ImportError::Conflict);
+    }
+
+    report.created = new.len();
+    achievement::check(db, user_id).await;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(csv: &str) -> ParsedFile {
+        let mut parsed = ParsedFile::default();
+        parse_csv(csv.as_bytes(), &mut parsed);
+        parsed
+    }
+
+    #[test]
+    fn limits_expanded_items() {
+        let mut csv = "id,lat,lng,date,category,count\n".to_string();
+        let rows = MAX_IMPORT_ITEMS / MAX_ITEM_COUNT;
+        for i in 0..rows {
+            csv.push_str(&format!(
+                "r{i},47.37,8.54,2024-05-01,can,{MAX_ITEM_COUNT}\n"
+            ));
+        }
+        let parsed = parse(&csv);
+        assert!(parsed.errors.is_empty());
+        assert_eq!(parsed.items, MAX_IMPORT_ITEMS);
+
+        csv.push_str("last,47.37,8.54,2024-05-01,can,1\n");
+        csv.push_str("after,47.37,8.54,2024-05-01,can,1\n");
+        let parsed = parse(&csv);
+        assert_eq!(parsed.errors.len(), 1);
+        assert_eq!(parsed.errors[0].row, rows + 2);
+        assert_eq!(parsed.errors[0].field, "file");
+        assert_eq!(parsed.reports.len(), rows);
+    }
+
+    #[test]
+    fn reimport_skips_created_reports() {
+        let csv = "id,lat,lng,date,category,count\n\
+                   a,47.37,8.54,2024-05-01,can,2\n\
+                   a,47.37,8.54,2024-05-01,bottle,1\n\
+                   ,46.95,7.44,2024-05-02,butt,3\n";
+
+        let first = new_reports(parse(csv).reports, &HashSet::new());
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0].entries.len(), 3);
+        assert_eq!(first[1].entries.len(), 3);
+
+        let existing: HashSet<String> = first.iter().filter_map(|l| l.import_key.clone()).collect();
+        assert!(new_reports(parse(csv).reports, &existing).is_empty());
+
+        let grown = format!("{csv}b,47.37,8.54,2024-05-03,can,1\n");
+        let second = new_reports(parse(&grown).reports, &existing);
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].entries.len(), 1);
+    }
+}
@@ -0,0 +1,587 @@
+//! Leaderboards of collected items, weight and cleanup sessions. Totals per user, period
+//! and region are materialised into the `leaderboard` collection every
+//! `LEADERBOARD_REFRESH_SECS`, so ranking reads a few small documents. Regions are the
+//! bounding boxes in `leaderboard_regions.json`, replaced with `LEADERBOARD_REGIONS_FILE`.
+
+use std::{env, fs, sync::LazyLock, time::Duration};
+
+use futures::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{Bson, DateTime, Document, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    models::user::username_collation,
+    services::{
+        cluster::number,
+        validation::{FieldError, invalid, validate_username},
+    },
+};
+
+pub const DEFAULT_LEADERBOARD_SIZE: i64 = 50;
+pub const MAX_LEADERBOARD_SIZE: i64 = 100;
+
+static REGIONS: LazyLock<Vec<LeaderboardRegion>> = LazyLock::new(load_regions);
+
+static REFRESH_INTERVAL: LazyLock<Duration> =
+    LazyLock::new(|| match env::var("LEADERBOARD_REFRESH_SECS") {
+        Ok(v) => v
+            .parse()
+            .ok()
+            .filter(|s| *s >= 10)
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| panic!("LEADERBOARD_REFRESH_SECS must be a number of at least 10")),
+        Err(_) => Duration::from_secs(600),
+    });
+
+#[derive(Debug, Deserialize)]
+struct RegionFile {
+    regions: Vec<LeaderboardRegion>,
+}
+
+/// Area with its own leaderboard. Reports inside several regions count for each.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LeaderboardRegion {
+    pub code: String,
+    pub name: String,
+    /// `[min_lng, min_lat, max_lng, max_lat]`
+    pub bbox: [f64; 4],
+}
+
+fn load_regions() -> Vec<LeaderboardRegion> {
+    let file: RegionFile = match env::var("LEADERBOARD_REGIONS_FILE") {
+        Ok(path) => {
+            let raw = fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("Failed to read LEADERBOARD_REGIONS_FILE {path}: {e}"));
+            serde_json::from_str(&raw)
+                .unwrap_or_else(|e| panic!("Invalid leaderboard regions in {path}: {e}"))
+        }
+        Err(_) => serde_json::from_str(include_str!("leaderboard_regions.json"))
+            .expect("Built-in leaderboard regions are valid"),
+    };
+    for region in &file.regions {
+        let [min_lng, min_lat, max_lng, max_lat] = region.bbox;
+        assert!(
+            !region.code.is_empty() && min_lng < max_lng && min_lat < max_lat,
+            "Leaderboard region {:?} needs a code and a bbox with minimum below maximum",
+            region.code
+        );
+    }
+    file.regions
+}
+
+/// Loads the regions and refresh interval, so broken settings stop the server at startup.
+pub fn init() {
+    LazyLock::force(&REGIONS);
+    LazyLock::force(&REFRESH_INTERVAL);
+}
+
+pub fn regions() -> &'static [LeaderboardRegion] {
+    &REGIONS
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum LeaderboardError {
+    InvalidInput(Vec<FieldError>),
+    NetworkError,
+}
+
+impl From<mongodb::error::Error> for LeaderboardError {
+    fn from(e: mongodb::error::Error) -> Self {
+        error!("Error when accessing the leaderboard in db: {:?}", e);
+        LeaderboardError::NetworkError
+    }
+}
+
+fn collection(db: &Database) -> Collection<Document> {
+    db.collection::<Document>("leaderboard")
+}
+
+/// What users are ranked by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// Detected pieces of litter
+    #[default]
+    Items,
+    /// Estimated weight in grams
+    Weight,
+    /// Cleanup sessions with at least one report
+    Sessions,
+}
+
+impl Metric {
+    fn field(&self) -> &'static str {
+        match self {
+            Metric::Items => "items",
+            Metric::Weight => "weight",
+            Metric::Sessions => "sessions",
+        }
+    }
+}
+
+/// Time the totals cover: the current week (from Monday, UTC), the current month or all time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    #[default]
+    Week,
+    Month,
+    AllTime,
+}
+
+impl Period {
+    const ALL: [Period; 3] = [Period::Week, Period::Month, Period::AllTime];
+
+    fn key(&self) -> &'static str {
+        match self {
+            Period::Week => "week",
+            Period::Month => "month",
+            Period::AllTime => "all_time",
+        }
+    }
+}
+
+/// Who is ranked against each other.
+#[derive(Debug, Clone)]
+pub enum Board {
+    Global,
+    /// Code of a leaderboard region
+    Region(String),
+    /// Members of a group, including those who opted out of public ranking
+    Group(Vec<ObjectId>),
+}
+
+impl Board {
+    /// Region key of the materialised totals, empty for totals over all reports.
+    fn region(&self) -> &str {
+        match self {
+            Board::Region(code) => code,
+            _ => "",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct LeaderboardEntry {
+    /// Equal totals share a rank
+    pub rank: u64,
+    /// Pseudonym, or the username if none is set
+    pub name: String,
+    /// Whether this is the current user
+    pub you: bool,
+    pub items: u64,
+    /// Estimated weight in grams
+    pub weight: f64,
+    pub sessions: u64,
+    pub reports: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Leaderboard {
+    pub metric: Metric,
+    pub period: Period,
+    /// Time of the last refresh, missing before the first one
+    pub updated_at: Option<String>,
+    /// Highest totals first
+    pub entries: Vec<LeaderboardEntry>,
+    /// The current user's place, also when below the listed entries. Missing without
+    /// reports in the period or when opted out of public ranking.
+    pub me: Option<LeaderboardEntry>,
+}
+
+pub async fn ensure_indexes(db: &Database) {
+    let mut indexes: Vec<IndexModel> = [Metric::Items, Metric::Weight, Metric::Sessions]
+        .iter()
+        .map(|metric| {
+            IndexModel::builder()
+                .keys(doc! { "period": 1, "region": 1, metric.field(): -1, "user": 1 })
+                .options(
+                    IndexOptions::builder()
+                        .name(Some(format!("leaderboard_{}", metric.field())))
+                        .build(),
+                )
+                .build()
+        })
+        .collect();
+    indexes.push(
+        IndexModel::builder()
+            .keys(doc! { "user": 1 })
+            .options(
+                IndexOptions::builder()
+                    .name(Some("leaderboard_user".to_string()))
+                    .build(),
+            )
+            .build(),
+    );
+
+    if let Err(e) = collection(db).create_indexes(indexes).await {
+        error!("Failed to ensure indexes for 'leaderboard': {:?}", e);
+    }
+}
+
+/// Codes of the regions containing the report, as an aggregation expression.
+fn region_codes() -> Document {
+    let regions: Vec<Document> = REGIONS
+        .iter()
+        .map(|r| {
+            let [min_lng, min_lat, max_lng, max_lat] = r.bbox;
+            doc! {
+                "code": &r.code,
+                "min_lng": min_lng, "min_lat": min_lat,
+                "max_lng": max_lng, "max_lat": max_lat,
+            }
+        })
+        .collect();
+
+    doc! { "$map": {
+        "input": { "$filter": {
+            "input": { "$literal": regions },
+            "as": "r",
+            "cond": { "$and": [
+                { "$gte": ["$litter.lng", "$$r.min_lng"] }, { "$lte": ["$litter.lng", "$$r.max_lng"] },
+                { "$gte": ["$litter.lat", "$$r.min_lat"] }, { "$lte": ["$litter.lat", "$$r.max_lat"] },
+            ] },
+        } },
+        "as": "r",
+        "in": "$$r.code",
+    } }
+}
+
+/// Recomputes the totals of `period` for all users and regions.
+async fn refresh_period(
+    db: &Database,
+    period: Period,
+    run: DateTime,
+) -> Result<(), LeaderboardError> {
+    let mut pipeline = vec![
+        doc! { "$match": { "disabled": { "$ne": true } } },
+        doc! { "$project": { "litter.file": 0 } },
+        doc! { "$unwind": "$litter" },
+    ];
+    let unit = match period {
+        Period::Week => Some("week"),
+        Period::Month => Some("month"),
+        Period::AllTime => None,
+    };
+    if let Some(unit) = unit {
+        pipeline.push(doc! { "$match": { "$expr": { "$gte": [
+            "$litter.time_stamp",
+            { "$dateTrunc": { "date": "$$NOW", "unit": unit, "startOfWeek": "monday" } },
+        ] } } });
+    }
+    pipeline.extend([
+        doc! { "$project": {
+            "session": "$litter.session_id",
+            "items": { "$size": { "$ifNull": ["$litter.entries", []] } },
+            "weight": { "$sum": "$litter.entries.weight" },
+            "regions": { "$concatArrays": [[""], region_codes()] },
+        } },
+        doc! { "$unwind": "$regions" },
+        doc! { "$group": {
+            "_id": { "user": "$_id", "region": "$regions" },
+            "items": { "$sum": "$items" },
+            "weight": { "$sum": "$weight" },
+            "reports": { "$sum": 1 },
+            "sessions": { "$addToSet": "$session" },
+        } },
+        doc! { "$project": {
+            "_id": { "user": "$_id.user", "period": period.key(), "region": "$_id.region" },
+            "user": "$_id.user",
+            "period": period.key(),
+            "region": "$_id.region",
+            "items": 1,
+            "weight": 1,
+            "reports": 1,
+            "sessions": { "$size": { "$setDifference": ["$sessions", [null]] } },
+            "updated_at": run,
+        } },
+        doc! { "$merge": {
+            "into": "leaderboard",
+            "on": "_id",
+            "whenMatched": "replace",
+            "whenNotMatched": "insert",
+        } },
+    ]);
+
+    db.collection::<Document>("users")
+        .aggregate(pipeline)
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    // Users whose reports all left the period, were deleted or got disabled.
+    collection(db)
+        .delete_many(doc! { "period": period.key(), "updated_at": { "$lt": run } })
+        .await?;
+    Ok(())
+}
+
+pub async fn refresh(db: &Database) -> Result<(), LeaderboardError> {
+    let run = DateTime::now();
+    for period in Period::ALL {
+        refresh_period(db, period, run).await?;
+    }
+    info!(
+        "Refreshed leaderboards in {} ms",
+        DateTime::now().timestamp_millis() - run.timestamp_millis()
+    );
+    Ok(())
+}
+
+/// Refreshes the leaderboards now and then every `LEADERBOARD_REFRESH_SECS`.
+pub fn spawn_refresh(db: Database) {
+    tokio::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(*REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = refresh(&db).await {
+                error!("Failed to refresh leaderboards: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Joins the account of each entry and drops those that must not be shown.
+fn visible_stages(board: &Board) -> Vec<Document> {
+    let mut visible = doc! { "account.disabled": { "$ne": true } };
+    if !matches!(board, Board::Group(_)) {
+        visible.insert("account.leaderboard_opt_out", doc! { "$ne": true });
+    }
+    vec![
+        doc! { "$lookup": {
+            "from": "users",
+            "localField": "user",
+            "foreignField": "_id",
+            "pipeline": [
+                { "$project": { "username": 1, "pseudonym": 1, "leaderboard_opt_out": 1, "disabled": 1 } },
+            ],
+            "as": "account",
+        } },
+        doc! { "$unwind": "$account" },
+        doc! { "$match": visible },
+    ]
+}
+
+fn entry(doc: &Document, rank: u64, user_id: ObjectId) -> LeaderboardEntry {
+    let account = doc.get_document("account").ok();
+    let name = account
+        .and_then(|a| a.get_str("pseudonym").ok())
+        .or_else(|| account.and_then(|a| a.get_str("username").ok()))
+        .unwrap_or_default();
+    LeaderboardEntry {
+        rank,
+        name: name.to_string(),
+        you: doc.get_object_id("user").is_ok_and(|id| id == user_id),
+        items: number(doc, "items") as u64,
+        weight: number(doc, "weight"),
+        sessions: number(doc, "sessions") as u64,
+        reports: number(doc, "reports") as u64,
+    }
+}
+
+/// Ranks the users of `board` by `metric` over `period`.
+pub async fn leaderboard(
+    db: &Database,
+    user_id: ObjectId,
+    board: Board,
+    metric: Metric,
+    period: Period,
+    limit: Option<i64>,
+) -> Result<Leaderboard, LeaderboardError> {
+    let mut errors = vec![];
+    let limit = limit.unwrap_or(DEFAULT_LEADERBOARD_SIZE);
+    if !(1..=MAX_LEADERBOARD_SIZE).contains(&limit) {
+        errors.push(invalid(
+            "limit",
+            format!("must be between 1 and {MAX_LEADERBOARD_SIZE}"),
+        ));
+    }
+    if let Board::Region(code) = &board
+        && !REGIONS.iter().any(|r| &r.code == code)
+    {
+        let codes: Vec<&str> = REGIONS.iter().map(|r| r.code.as_str()).collect();
+        errors.push(invalid(
+            "region",
+            format!("must be one of {}", codes.join(", ")),
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(LeaderboardError::InvalidInput(errors));
+    }
+
+    let field = metric.field();
+    let mut scope = doc! { "period": period.key(), "region": board.region() };
+    if let Board::Group(members) = &board {
+        scope.insert("user", doc! { "$in": members });
+    }
+
+    let mut pipeline = vec![
+        doc! { "$match": scope.clone() },
+        doc! { "$match": { field: { "$gt": 0 } } },
+        doc! { "$sort": { field: -1, "user": 1 } },
+    ];
+    pipeline.extend(visible_stages(&board));
+    pipeline.push(doc! { "$limit": limit });
+
+    let entries = collection(db);
+    let docs: Vec<Document> = entries.aggregate(pipeline).await?.try_collect().await?;
+
+    let mut ranked: Vec<LeaderboardEntry> = Vec::with_capacity(docs.len());
+    let mut previous: Option<(f64, u64)> = None;
+    for (i, doc) in docs.iter().enumerate() {
+        let value = number(doc, field);
+        let rank = match previous {
+            Some((v, rank)) if v == value => rank,
+            _ => i as u64 + 1,
+        };
+        previous = Some((value, rank));
+        ranked.push(entry(doc, rank, user_id));
+    }
+
+    let mut own = scope.clone();
+    own.insert("user", user_id);
+    let mut own_pipeline = vec![doc! { "$match": own }];
+    own_pipeline.extend(visible_stages(&board));
+    let own: Option<Document> = entries.aggregate(own_pipeline).await?.try_next().await?;
+    let me = match own {
+        Some(doc) if number(&doc, field) > 0.0 => {
+            let mut ahead = vec![
+                doc! { "$match": scope },
+                doc! { "$match": { field: { "$gt": doc.get(field).cloned().unwrap_or(Bson::Null) } } },
+            ];
+            ahead.extend(visible_stages(&board));
+            ahead.push(doc! { "$count": "ahead" });
+            let counted: Vec<Document> = entries.aggregate(ahead).await?.try_collect().await?;
+            let ahead = counted.first().map_or(0.0, |d| number(d, "ahead")) as u64;
+            Some(entry(&doc, ahead + 1, user_id))
+        }
+        _ => None,
+    };
+
+    let updated_at = docs
+        .first()
+        .and_then(|d| d.get_datetime("updated_at").ok())
+        .and_then(|t| t.try_to_rfc3339_string().ok());
+
+    Ok(Leaderboard {
+        metric,
+        period,
+        updated_at,
+        entries: ranked,
+        me,
+    })
+}
+
+/// How the user appears on leaderboards.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct LeaderboardSettings {
+    /// Left out of global and regional leaderboards, still ranked within the user's groups
+    #[serde(default)]
+    pub opt_out: bool,
+    /// Shown instead of the username, same rules as usernames
+    #[serde(default)]
+    pub pseudonym: Option<String>,
+}
+
+pub async fn settings(
+    db: &Database,
+    user_id: ObjectId,
+) -> Result<LeaderboardSettings, LeaderboardError> {
+    let user = db
+        .collection::<Document>("users")
+        .find_one(doc! { "_id": user_id })
+        .projection(doc! { "leaderboard_opt_out": 1, "pseudonym": 1 })
+        .await?
+        .ok_or(LeaderboardError::NetworkError)?;
+
+    Ok(LeaderboardSettings {
+        opt_out: user.get_bool("leaderboard_opt_out").unwrap_or_default(),
+        pseudonym: user.get_str("pseudonym").ok().map(str::to_string),
+    })
+}
+
+/// Whether another user already appears under `name`. New accounts check this too,
+/// so usernames and pseudonyms never collide.
+pub(crate) async fn pseudonym_taken(db: &Database, name: &str) -> mongodb::error::Result<bool> {
+    let taken = db
+        .collection::<Document>("users")
+        .find_one(doc! { "pseudonym": name })
+        .projection(doc! { "_id": 1 })
+        .collation(username_collation())
+        .await?;
+    Ok(taken.is_some())
+}
+
+/// Changes how the user appears. Pseudonyms must differ from other users' usernames and
+/// pseudonyms, so nobody can pose as someone else.
+pub async fn update_settings(
+    db: &Database,
+    user_id: ObjectId,
+    settings: LeaderboardSettings,
+) -> Result<LeaderboardSettings, LeaderboardError> {
+    let users = db.collection::<Document>("users");
+    let pseudonym = settings
+        .pseudonym
+        .as_deref()
+        .map(str::trim)
+        .filter(|p| !p.is_empty());
+
+    if let Some(pseudonym) = pseudonym {
+        let errors: Vec<FieldError> = validate_username(pseudonym)
+            .into_iter()
+            .map(|e| invalid("pseudonym", e.message))
+            .collect();
+        if !errors.is_empty() {
+            return Err(LeaderboardError::InvalidInput(errors));
+        }
+
+        let taken = users
+            .find_one(doc! {
+                "_id": { "$ne": user_id },
+                "$or": [{ "username": pseudonym }, { "pseudonym": pseudonym }],
+            })
+            .projection(doc! { "_id": 1 })
+            .collation(username_collation())
+            .await?;
+        if taken.is_some() {
+            return Err(LeaderboardError::InvalidInput(vec![invalid(
+                "pseudonym",
+                "is already used by someone else",
+            )]));
+        }
+    }
+
+    let update = match pseudonym {
+        Some(pseudonym) => doc! {
+            "$set": { "leaderboard_opt_out": settings.opt_out, "pseudonym": pseudonym },
+        },
+        None => doc! {
+            "$set": { "leaderboard_opt_out": settings.opt_out },
+            "$unset": { "pseudonym": "" },
+        },
+    };
+    // The unique index catches two users picking the same pseudonym at once
+    match users.update_one(doc! { "_id": user_id }, update).await {
+        Err(e) if e.to_string().contains("E11000") => {
+            return Err(LeaderboardError::InvalidInput(vec![invalid(
+                "pseudonym",
+                "is already used by someone else",
+            )]));
+        }
+        res => res?,
+    };
+
+    info!(
+        "{} changed leaderboard settings (opt out: {})",
+        user_id, settings.opt_out
+    );
+    Ok(LeaderboardSettings {
+        opt_out: settings.opt_out,
+        pseudonym: pseudonym.map(str::to_string),
+    })
+}
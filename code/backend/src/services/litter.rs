@@ -0,0 +1,608 @@
+use actix_web::web;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use futures::{Stream, StreamExt, TryStreamExt};
+use log::{error, info};
+use mongodb::{
+    Database,
+    bson::{self, DateTime, Document, doc, oid::ObjectId},
+    options::{Collation, CollationStrength},
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    models::{
+        litter::{AnalysisStatus, Litter},
+        user::User,
+    },
+    services::validation::{FieldError, invalid},
+};
+
+pub const MAX_PAGE_SIZE: i64 = 500;
+/// Largest radius of a radius search, in metres.
+pub const MAX_RADIUS_M: f64 = 1_000_000.0;
+pub const MAX_POLYGON_POINTS: usize = 500;
+/// Radius used by MongoDB to turn distances into radians.
+const EARTH_RADIUS_M: f64 = 6_378_100.0;
+
+#[derive(Debug, Clone, Serialize)]
+pub enum LitterError {
+    InvalidInput(Vec<FieldError>),
+    NetworkError,
+    /// Building a response failed, e.g. writing a CSV file.
+    InternalError,
+}
+
+impl From<mongodb::error::Error> for LitterError {
+    fn from(e: mongodb::error::Error) -> Self {
+        error!("Error when querying litter: {:?}", e);
+        LitterError::NetworkError
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    /// Oldest first
+    #[default]
+    Asc,
+    /// Newest first
+    Desc,
+}
+
+/// Whose reports a query looks at.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LitterScope {
+    /// The reports of the signed in user
+    #[default]
+    Own,
+    /// Reports of all active users, without images and owners
+    Community,
+}
+
+/// Area a report must lie in. Edges of boxes and polygons follow great circles.
+#[derive(Debug, Clone)]
+pub enum GeoArea {
+    BBox {
+        min_lng: f64,
+        min_lat: f64,
+        max_lng: f64,
+        max_lat: f64,
+    },
+    Radius {
+        lng: f64,
+        lat: f64,
+        metres: f64,
+    },
+    /// Ring of `[lng, lat]` points, closed automatically.
+    Polygon(Vec<[f64; 2]>),
+}
+
+/// Checks that a position is a valid WGS84 coordinate.
+pub fn validate_position(lat: f64, lng: f64) -> Vec<FieldError> {
+    let mut errors = vec![];
+    if !(-90.0..=90.0).contains(&lat) {
+        errors.push(invalid("lat", "must be between -90 and 90"));
+    }
+    if !(-180.0..=180.0).contains(&lng) {
+        errors.push(invalid("lng", "must be between -180 and 180"));
+    }
+    errors
+}
+
+impl GeoArea {
+    pub fn validate(&self) -> Vec<FieldError> {
+        match self {
+            GeoArea::BBox {
+                min_lng,
+                min_lat,
+                max_lng,
+                max_lat,
+            } => {
+                let mut errors = validate_position(*min_lat, *min_lng);
+                errors.extend(validate_position(*max_lat, *max_lng));
+                if min_lat >= max_lat || min_lng >= max_lng {
+                    errors.push(invalid(
+                        "bbox",
+                        "minimum must be below maximum (boxes across the antimeridian are not supported)",
+                    ));
+                }
+                errors
+            }
+            GeoArea::Radius { lng, lat, metres } => {
+                let mut errors = validate_position(*lat, *lng);
+                if !(*metres > 0.0 && *metres <= MAX_RADIUS_M) {
+                    errors.push(invalid(
+                        "radius",
+                        format!("must be between 0 and {MAX_RADIUS_M} metres"),
+                    ));
+                }
+                errors
+            }
+            GeoArea::Polygon(points) => {
+                let mut errors: Vec<FieldError> = points
+                    .iter()
+                    .flat_map(|[lng, lat]| validate_position(*lat, *lng))
+                    .collect();
+                if !(3..=MAX_POLYGON_POINTS).contains(&points.len()) {
+                    errors.push(invalid(
+                        "points",
+                        format!("must have between 3 and {MAX_POLYGON_POINTS} points"),
+                    ));
+                }
+                errors
+            }
+        }
+    }
+
+    /// `$geoWithin` operator for a GeoJSON point field.
+    pub(crate) fn geo_within(&self) -> Document {
+        match self {
+            GeoArea::BBox {
+                min_lng,
+                min_lat,
+                max_lng,
+                max_lat,
+            } => polygon_within(&[
+                [*min_lng, *min_lat],
+                [*max_lng, *min_lat],
+                [*max_lng, *max_lat],
+                [*min_lng, *max_lat],
+            ]),
+            GeoArea::Radius { lng, lat, metres } => doc! {
+                "$geoWithin": { "$centerSphere": [[*lng, *lat], *metres / EARTH_RADIUS_M] }
+            },
+            GeoArea::Polygon(points) => polygon_within(points),
+        }
+    }
+}
+
+fn polygon_within(points: &[[f64; 2]]) -> Document {
+    let mut ring: Vec<Vec<f64>> = points.iter().map(|p| p.to_vec()).collect();
+    if points.first() != points.last()
+        && let Some(first) = points.first()
+    {
+        ring.push(first.to_vec());
+    }
+
+    doc! { "$geoWithin": { "$geometry": { "type": "Polygon", "coordinates": [ring] } } }
+}
+
+/// Restricts the reports of a user. Category, material and brand match case-insensitively
+/// and must all hold for the same entry.
+#[derive(Debug, Clone, Default)]
+pub struct LitterFilter {
+    pub from: Option<DateTime>,
+    pub to: Option<DateTime>,
+    pub category: Option<String>,
+    pub material: Option<String>,
+    pub brand: Option<String>,
+    pub status: Option<AnalysisStatus>,
+    pub area: Option<GeoArea>,
+    /// Only reports of these users, e.g. the members of a group
+    pub owners: Option<Vec<ObjectId>>,
+}
+
+impl LitterFilter {
+    /// `$match` for documents shaped like `Litter`.
+    pub(crate) fn to_match(&self) -> Document {
+        let mut filter = doc! {};
+
+        let mut time = doc! {};
+        if let Some(from) = self.from {
+            time.insert("$gte", from);
+        }
+        if let Some(to) = self.to {
+            time.insert("$lt", to);
+        }
+        if !time.is_empty() {
+            filter.insert("time_stamp", time);
+        }
+
+        if let Some(area) = &self.area {
+            filter.insert("location", area.geo_within());
+        }
+
+        let mut entry = doc! {};
+        for (field, value) in [
+            ("category", &self.category),
+            ("material", &self.material),
+            ("brand", &self.brand),
+        ] {
+            if let Some(value) = value {
+                entry.insert(field, value.trim());
+            }
+        }
+        if !entry.is_empty() {
+            filter.insert("entries", doc! { "$elemMatch": entry });
+        }
+
+        // Reports from before the status was stored count as analysed once they have entries.
+        if let Some(status) = self.status {
+            let status_filter = match status {
+                AnalysisStatus::Pending => doc! { "$or": [
+                    { "status": "pending" },
+                    { "status": null, "entries.0": { "$exists": false } },
+                ] },
+                AnalysisStatus::Done => doc! { "$or": [
+                    { "status": "done" },
+                    { "status": null, "entries.0": { "$exists": true } },
+                ] },
+                AnalysisStatus::Failed => doc! { "status": "failed" },
+            };
+            filter.insert("$and", vec![status_filter]);
+        }
+
+        filter
+    }
+}
+
+/// Position after the last report of a page, handed out as an opaque string.
+#[derive(Debug, Clone, Copy)]
+pub struct Cursor {
+    time_stamp: DateTime,
+    id: ObjectId,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!(
+            "{}:{}",
+            self.time_stamp.timestamp_millis(),
+            self.id.to_hex()
+        ))
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, FieldError> {
+        let invalid = || FieldError {
+            field: "cursor".to_string(),
+            message: "is not a valid cursor".to_string(),
+        };
+
+        let raw = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+        let raw = String::from_utf8(raw).map_err(|_| invalid())?;
+        let (millis, id) = raw.split_once(':').ok_or_else(invalid)?;
+
+        Ok(Cursor {
+            time_stamp: DateTime::from_millis(millis.parse().map_err(|_| invalid())?),
+            id: ObjectId::parse_str(id).map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PageRequest {
+    pub cursor: Option<Cursor>,
+    /// `None` returns every matching own report, other scopes are paged by `MAX_PAGE_SIZE`.
+    pub limit: Option<i64>,
+    pub order: SortOrder,
+    /// Only honoured for own reports with a `limit`.
+    pub include_images: bool,
+}
+
+pub struct LitterPage {
+    pub items: Vec<Litter>,
+    /// Number of reports matching the filter, over all pages.
+    pub total: u64,
+    pub next_cursor: Option<String>,
+}
+
+/// Parses an RFC 3339 timestamp or a plain `YYYY-MM-DD` date (midnight UTC).
+pub fn parse_date(field: &str, value: &str) -> Result<DateTime, FieldError> {
+    let value = value.trim();
+    let parsed = if value.len() == 10 {
+        DateTime::parse_rfc3339_str(format!("{value}T00:00:00Z"))
+    } else {
+        DateTime::parse_rfc3339_str(value)
+    };
+
+    parsed.map_err(|_| FieldError {
+        field: field.to_string(),
+        message: "must be a date (YYYY-MM-DD) or an RFC 3339 timestamp".to_string(),
+    })
+}
+
+/// Parses `min_lng,min_lat,max_lng,max_lat`.
+pub fn parse_bbox(bbox: &str) -> Result<GeoArea, FieldError> {
+    let values = bbox
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>();
+
+    match values.as_deref() {
+        Ok(&[min_lng, min_lat, max_lng, max_lat]) => Ok(GeoArea::BBox {
+            min_lng,
+            min_lat,
+            max_lng,
+            max_lat,
+        }),
+        _ => Err(invalid("bbox", "must be `min_lng,min_lat,max_lng,max_lat`")),
+    }
+}
+
+/// Filter of the `from`, `to` and `bbox` query parameters, with the errors of all of them.
+pub fn range_filter(
+    from: Option<&str>,
+    to: Option<&str>,
+    bbox: Option<&str>,
+) -> Result<LitterFilter, Vec<FieldError>> {
+    let mut errors = vec![];
+    let from = from.map(|v| parse_date("from", v));
+    let to = to.map(|v| parse_date("to", v));
+    let area = bbox.map(parse_bbox);
+
+    let filter = LitterFilter {
+        from: from.and_then(|r| r.map_err(|e| errors.push(e)).ok()),
+        to: to.and_then(|r| r.map_err(|e| errors.push(e)).ok()),
+        area: area.and_then(|r| r.map_err(|e| errors.push(e)).ok()),
+        ..Default::default()
+    };
+    if errors.is_empty() {
+        Ok(filter)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Compares strings the way the filters promise, ignoring case and accents.
+pub(crate) fn filter_collation() -> Collation {
+    Collation::builder()
+        .locale("en")
+        .strength(CollationStrength::Primary)
+        .build()
+}
+
+/// Stages producing one document per report of `scope` that matches `filter`.
+pub(crate) fn litter_stages(
+    user_id: ObjectId,
+    scope: LitterScope,
+    filter: &LitterFilter,
+) -> Vec<Document> {
+    report_stages(user_id, scope, filter, true)
+}
+
+/// Like [`litter_stages`], but drops the photos before unwinding, for pipelines that never
+/// look at them.
+pub(crate) fn litter_stages_without_images(
+    user_id: ObjectId,
+    scope: LitterScope,
+    filter: &LitterFilter,
+) -> Vec<Document> {
+    report_stages(user_id, scope, filter, false)
+}
+
+fn report_stages(
+    user_id: ObjectId,
+    scope: LitterScope,
+    filter: &LitterFilter,
+    images: bool,
+) -> Vec<Document> {
+    let mut users = match scope {
+        LitterScope::Own => doc! { "_id": user_id },
+        LitterScope::Community => doc! { "disabled": { "$ne": true } },
+    };
+    if let Some(owners) = &filter.owners {
+        users = doc! { "$and": [users, { "_id": { "$in": owners } }] };
+    }
+    // Lets the 2dsphere index narrow down the users before unwinding.
+    if let Some(area) = &filter.area {
+        users.insert("litter.location", area.geo_within());
+    }
+
+    let mut stages = vec![doc! { "$match": users }];
+    if !images {
+        stages.push(doc! { "$project": { "litter.file": 0 } });
+    }
+    stages.extend([
+        doc! { "$unwind": "$litter" },
+        doc! { "$replaceRoot": { "newRoot": "$litter" } },
+        doc! { "$match": filter.to_match() },
+    ]);
+    stages
+}
+
+/// Invalid geometries (e.g. self-intersecting polygons) are the client's fault.
+fn query_error(e: mongodb::error::Error) -> LitterError {
+    if let mongodb::error::ErrorKind::Command(command) = e.kind.as_ref()
+        && command.code == 2
+    {
+        info!("Rejected geo query: {}", command.message);
+        return LitterError::InvalidInput(vec![invalid("area", "is not a valid geometry")]);
+    }
+    e.into()
+}
+
+pub async fn list_litter(
+    db: web::Data<Database>,
+    user_id: ObjectId,
+    scope: LitterScope,
+    filter: &LitterFilter,
+    page: &PageRequest,
+) -> Result<LitterPage, LitterError> {
+    if let Some(area) = &filter.area {
+        let errors = area.validate();
+        if !errors.is_empty() {
+            return Err(LitterError::InvalidInput(errors));
+        }
+    }
+
+    if let Some(limit) = page.limit
+        && !(1..=MAX_PAGE_SIZE).contains(&limit)
+    {
+        return Err(LitterError::InvalidInput(vec![FieldError {
+            field: "limit".to_string(),
+            message: format!("must be between 1 and {MAX_PAGE_SIZE}"),
+        }]));
+    }
+
+    // Only the user's own reports may be listed in one go, others are paged.
+    let limit = match page.limit {
+        None if scope != LitterScope::Own || filter.owners.is_some() => Some(MAX_PAGE_SIZE),
+        limit => limit,
+    };
+
+    let users = db.collection::<User>("users");
+    let mut base = litter_stages(user_id, scope, filter);
+    // Photos of other users are never handed out, and own ones only a page at a time.
+    // Dropped before sorting, which would otherwise hold every photo in memory.
+    if !page.include_images || scope == LitterScope::Community || limit.is_none() {
+        base.push(doc! { "$project": { "file": 0 } });
+    }
+
+    let mut count_pipeline = base.clone();
+    count_pipeline.push(doc! { "$count": "total" });
+
+    let (direction, comparison) = match page.order {
+        SortOrder::Asc => (1, "$gt"),
+        SortOrder::Desc => (-1, "$lt"),
+    };
+    let mut items_pipeline = base;
+    if let Some(cursor) = page.cursor {
+        items_pipeline.push(doc! { "$match": { "$or": [
+            { "time_stamp": { comparison: cursor.time_stamp } },
+            { "time_stamp": cursor.time_stamp, "_id": { comparison: cursor.id } },
+        ] } });
+    }
+    items_pipeline.push(doc! { "$sort": { "time_stamp": direction, "_id": direction } });
+    if let Some(limit) = limit {
+        // One more than asked for tells whether there is a next page.
+        items_pipeline.push(doc! { "$limit": limit + 1 });
+    }
+
+    let count = async {
+        let counts: Vec<Document> = users
+            .aggregate(count_pipeline)
+            .collation(filter_collation())
+            .await?
+            .try_collect()
+            .await?;
+        Ok::<_, mongodb::error::Error>(
+            counts
+                .first()
+                .and_then(|d| {
+                    d.get_i32("total")
+                        .map(i64::from)
+                        .or_else(|_| d.get_i64("total"))
+                        .ok()
+                })
+                .unwrap_or_default() as u64,
+        )
+    };
+    let items = async {
+        let docs: Vec<Document> = users
+            .aggregate(items_pipeline)
+            .collation(filter_collation())
+            .await?
+            .try_collect()
+            .await?;
+        Ok::<_, mongodb::error::Error>(docs)
+    };
+    let (total, docs) = futures::try_join!(count, items).map_err(query_error)?;
+
+    let mut items = docs
+        .into_iter()
+        .map(bson::from_document::<Litter>)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            error!("Stored litter is malformed: {:?}", e);
+            LitterError::NetworkError
+        })?;
+
+    let mut next_cursor = None;
+    if let Some(limit) = limit
+        && items.len() as i64 > limit
+    {
+        items.truncate(limit as usize);
+        next_cursor = items.last().map(|last| {
+            Cursor {
+                time_stamp: last.time_stamp,
+                id: last._id,
+            }
+            .encode()
+        });
+    }
+
+    Ok(LitterPage {
+        items,
+        total,
+        next_cursor,
+    })
+}
+
+/// Every report of `scope` matching `filter`, without images, read from the database while
+/// the stream is consumed. Invalid queries are reported before the first item.
+pub async fn litter_stream(
+    db: &Database,
+    user_id: ObjectId,
+    scope: LitterScope,
+    filter: &LitterFilter,
+    order: SortOrder,
+) -> Result<impl Stream<Item = Result<Litter, LitterError>> + use<>, LitterError> {
+    if let Some(area) = &filter.area {
+        let errors = area.validate();
+        if !errors.is_empty() {
+            return Err(LitterError::InvalidInput(errors));
+        }
+    }
+
+    let direction = match order {
+        SortOrder::Asc => 1,
+        SortOrder::Desc => -1,
+    };
+    let mut pipeline = litter_stages_without_images(user_id, scope, filter);
+    pipeline.push(doc! { "$sort": { "time_stamp": direction, "_id": direction } });
+
+    let cursor = db
+        .collection::<User>("users")
+        .aggregate(pipeline)
+        .collation(filter_collation())
+        .await
+        .map_err(query_error)?;
+
+    Ok(cursor.map(|doc| {
+        let doc = doc?;
+        bson::from_document::<Litter>(doc).map_err(|e| {
+            error!("Stored litter is malformed: {:?}", e);
+            LitterError::NetworkError
+        })
+    }))
+}
+
+/// Adds the GeoJSON `location` to reports stored before it existed.
+/// Reports with coordinates out of range are left alone, the index would reject them.
+pub async fn backfill_locations(db: &Database) {
+    let valid = doc! { "$and": [
+        { "$gte": ["$$this.lat", -90] }, { "$lte": ["$$this.lat", 90] },
+        { "$gte": ["$$this.lng", -180] }, { "$lte": ["$$this.lng", 180] },
+    ] };
+    let update = vec![doc! { "$set": { "litter": { "$map": {
+        "input": "$litter",
+        "in": { "$cond": [
+            { "$and": [{ "$eq": [{ "$type": "$$this.location" }, "missing"] }, valid] },
+            { "$mergeObjects": ["$$this", { "location": {
+                "type": "Point",
+                "coordinates": ["$$this.lng", "$$this.lat"],
+            } }] },
+            "$$this",
+        ] },
+    } } } }];
+
+    match db
+        .collection::<User>("users")
+        .update_many(
+            doc! { "litter": { "$elemMatch": { "location": { "$exists": false } } } },
+            update,
+        )
+        .await
+    {
+        Ok(res) if res.modified_count > 0 => {
+            info!(
+                "Added GeoJSON locations to the reports of {} users",
+                res.modified_count
+            )
+        }
+        Ok(_) => {}
+        Err(e) => error!("Failed to backfill litter locations: {:?}", e),
+    }
+}
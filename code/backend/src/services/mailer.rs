@@ -0,0 +1,127 @@
+use std::{
+    env,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor, message::Mailbox,
+    transport::smtp::authentication::Credentials,
+};
+use log::{info, warn};
+
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Transport used to deliver account mails (verification, password reset) and notifications.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: Mail) -> anyhow::Result<()>;
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        host: &str,
+        port: u16,
+        credentials: Option<(String, String)>,
+        from: &str,
+    ) -> anyhow::Result<Self> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?.port(port);
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: from.parse()?,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: Mail) -> anyhow::Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(mail.to.parse()?)
+            .subject(mail.subject)
+            .body(mail.body)?;
+
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+/// Writes mails to a directory, or only logs them when no directory is given.
+/// Meant for local development and tests.
+pub struct FileMailer {
+    dir: Option<PathBuf>,
+}
+
+impl FileMailer {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: Mail) -> anyhow::Result<()> {
+        let content = format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            mail.to, mail.subject, mail.body
+        );
+
+        match &self.dir {
+            Some(dir) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+                let path = dir.join(format!("{now}.eml"));
+                tokio::fs::create_dir_all(dir).await?;
+                tokio::fs::write(&path, content).await?;
+                info!("📧 Mail to {} written to {}", mail.to, path.display());
+            }
+            None => info!("📧 Mail (not sent)\n{content}"),
+        }
+        Ok(())
+    }
+}
+
+/// Builds the mailer selected by `MAIL_TRANSPORT` (`smtp` or `file`, default `file`).
+pub fn from_env() -> Arc<dyn Mailer> {
+    let transport = env::var("MAIL_TRANSPORT").unwrap_or_else(|_| "file".to_string());
+
+    if transport == "smtp" {
+        let host = env::var("SMTP_HOST").expect("SMTP_HOST not set");
+        let port = env::var("SMTP_PORT")
+            .map(|p| p.parse().expect("SMTP_PORT must be a valid 16-bit integer"))
+            .unwrap_or(587);
+        let credentials = match (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD")) {
+            (Ok(username), Ok(password)) => Some((username, password)),
+            _ => None,
+        };
+        let from =
+            env::var("MAIL_FROM").unwrap_or_else(|_| "Delitter <noreply@delitter.app>".to_string());
+
+        let mailer = SmtpMailer::new(&host, port, credentials, &from)
+            .expect("Failed to configure SMTP mailer");
+        return Arc::new(mailer);
+    }
+
+    if transport != "file" {
+        warn!("Unknown MAIL_TRANSPORT '{transport}', falling back to file mailer");
+    }
+    Arc::new(FileMailer::new(
+        env::var("MAIL_OUTBOX_DIR").ok().map(PathBuf::from),
+    ))
+}
@@ -1,5 +1,27 @@
+pub mod account;
+pub mod achievement;
+pub mod admin;
+pub mod api_token;
 pub mod auth;
+pub mod brand_report;
+pub mod challenge;
+pub mod cleanup_session;
+pub mod cluster;
+pub mod event;
+pub mod export;
+pub mod group;
+pub mod impact;
+pub mod import;
+pub mod leaderboard;
 pub mod litter;
+pub mod mailer;
+pub mod mvt;
+pub mod oidc;
+pub mod pdf;
+pub mod rate_limit;
+pub mod stats;
+pub mod totp;
+pub mod track;
+pub mod validation;
 
 pub mod analyzer;
-
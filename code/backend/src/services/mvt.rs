@@ -0,0 +1,460 @@
+//! Mapbox Vector Tiles (specification 2.1) of the reports. The format is a small protobuf
+//! message, so it is encoded by hand.
+
+use std::{collections::HashMap, sync::Arc};
+
+use futures::{TryStreamExt, try_join};
+use mongodb::{
+    Database,
+    bson::{Bson, Document, doc, oid::ObjectId},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    models::user::User,
+    services::{
+        cluster::{
+            MAX_ZOOM, TileCache, TileKey, dominant_category, grid_cell_fields, number, tile_stages,
+            tiles_per_axis, world_position,
+        },
+        litter::{LitterError, LitterScope},
+        validation::FieldError,
+    },
+};
+
+/// Coordinate range of a tile, the common default of map libraries.
+pub const EXTENT: u32 = 4096;
+/// Density cells along each tile edge.
+const DENSITY_CELLS: f64 = 32.0;
+/// Tiles with more reports only carry the density layer.
+pub const MAX_TILE_POINTS: usize = 20_000;
+
+const GEOM_POINT: u64 = 1;
+const CMD_MOVE_TO: u32 = 1;
+
+pub struct EncodedTile {
+    pub bytes: Vec<u8>,
+    /// Quoted strong validator derived from the tile content.
+    pub etag: String,
+}
+
+pub type VectorTileCache = TileCache<EncodedTile>;
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.key(field, 0);
+        self.varint(value);
+    }
+
+    fn double(&mut self, field: u32, value: f64) {
+        self.key(field, 1);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        self.key(field, 2);
+        self.varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn packed(&mut self, field: u32, values: &[u32]) {
+        let mut inner = Writer::default();
+        for value in values {
+            inner.varint(u64::from(*value));
+        }
+        self.bytes(field, &inner.buf);
+    }
+}
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+/// Property value, doubles are kept as bits so values can be deduplicated.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Value {
+    String(String),
+    Double(u64),
+    Uint(u64),
+}
+
+impl Value {
+    fn double(value: f64) -> Self {
+        Value::Double(value.to_bits())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        match self {
+            Value::String(s) => writer.bytes(1, s.as_bytes()),
+            Value::Double(bits) => writer.double(3, f64::from_bits(*bits)),
+            Value::Uint(v) => writer.uint(5, *v),
+        }
+        writer.buf
+    }
+}
+
+struct Layer {
+    name: &'static str,
+    keys: Vec<&'static str>,
+    values: Vec<Value>,
+    value_index: HashMap<Value, u32>,
+    features: Writer,
+    feature_count: usize,
+}
+
+impl Layer {
+    fn new(name: &'static str) -> Self {
+        Layer {
+            name,
+            keys: Vec::new(),
+            values: Vec::new(),
+            value_index: HashMap::new(),
+            features: Writer::default(),
+            feature_count: 0,
+        }
+    }
+
+    fn key_index(&mut self, key: &'static str) -> u32 {
+        match self.keys.iter().position(|k| *k == key) {
+            Some(i) => i as u32,
+            None => {
+                self.keys.push(key);
+                self.keys.len() as u32 - 1
+            }
+        }
+    }
+
+    fn value_index(&mut self, value: Value) -> u32 {
+        if let Some(i) = self.value_index.get(&value) {
+            return *i;
+        }
+        let i = self.values.len() as u32;
+        self.values.push(value.clone());
+        self.value_index.insert(value, i);
+        i
+    }
+
+    /// Adds a point at tile coordinates `x`, `y`.
+    fn add_point(&mut self, x: i32, y: i32, properties: Vec<(&'static str, Value)>) {
+        let mut tags = Vec::with_capacity(properties.len() * 2);
+        for (key, value) in properties {
+            tags.push(self.key_index(key));
+            tags.push(self.value_index(value));
+        }
+
+        let mut feature = Writer::default();
+        feature.packed(2, &tags);
+        feature.uint(3, GEOM_POINT);
+        feature.packed(4, &[CMD_MOVE_TO | 1 << 3, zigzag(x), zigzag(y)]);
+
+        self.features.bytes(2, &feature.buf);
+        self.feature_count += 1;
+    }
+
+    fn encode_into(self, tile: &mut Writer) {
+        if self.feature_count == 0 {
+            return;
+        }
+        let mut layer = Writer::default();
+        layer.uint(15, 2);
+        layer.bytes(1, self.name.as_bytes());
+        layer.buf.extend_from_slice(&self.features.buf);
+        for key in &self.keys {
+            layer.bytes(3, key.as_bytes());
+        }
+        for value in &self.values {
+            layer.bytes(4, &value.encode());
+        }
+        layer.uint(5, u64::from(EXTENT));
+        tile.bytes(3, &layer.buf);
+    }
+}
+
+/// Tile coordinate of a position, points on the far edges are pulled inside.
+fn tile_coordinate(world: f64, tile: u32) -> i32 {
+    let max = f64::from(EXTENT - 1);
+    ((world - f64::from(tile)) * f64::from(EXTENT))
+        .floor()
+        .clamp(0.0, max) as i32
+}
+
+async fn tile_points(
+    db: &Database,
+    user_id: ObjectId,
+    scope: LitterScope,
+    key: TileKey,
+) -> Result<Vec<Document>, LitterError> {
+    let mut pipeline = tile_stages(user_id, scope, key);
+    pipeline.extend([
+        doc! { "$sort": { "time_stamp": 1, "_id": 1 } },
+        doc! { "$limit": MAX_TILE_POINTS as i64 + 1 },
+        doc! { "$project": {
+            "lat": 1,
+            "lng": 1,
+            "time_stamp": 1,
+            "weight": { "$sum": "$entries.weight" },
+            "categories": "$entries.category",
+        } },
+    ]);
+
+    Ok(db
+        .collection::<User>("users")
+        .aggregate(pipeline)
+        .await?
+        .try_collect()
+        .await?)
+}
+
+async fn tile_density(
+    db: &Database,
+    user_id: ObjectId,
+    scope: LitterScope,
+    key: TileKey,
+) -> Result<Vec<Document>, LitterError> {
+    let mut project = doc! { "weight": { "$sum": "$entries.weight" } };
+    project.extend(grid_cell_fields(tiles_per_axis(key.z) * DENSITY_CELLS));
+
+    let mut pipeline = tile_stages(user_id, scope, key);
+    pipeline.extend([
+        doc! { "$project": project },
+        doc! { "$group": {
+            "_id": { "x": "$cx", "y": "$cy" },
+            "count": { "$sum": 1 },
+            "total_weight": { "$sum": "$weight" },
+        } },
+        doc! { "$sort": { "_id.x": 1, "_id.y": 1 } },
+    ]);
+
+    Ok(db
+        .collection::<User>("users")
+        .aggregate(pipeline)
+        .await?
+        .try_collect()
+        .await?)
+}
+
+fn encode_tile(key: TileKey, points: &[Document], density: &[Document]) -> Vec<u8> {
+    let mut litter = Layer::new("litter");
+    // A truncated point layer would hide reports without notice, the density layer covers them.
+    if points.len() <= MAX_TILE_POINTS {
+        for point in points {
+            let (wx, wy) = world_position(number(point, "lng"), number(point, "lat"), key.z);
+            let mut properties = Vec::with_capacity(4);
+            if let Ok(id) = point.get_object_id("_id") {
+                properties.push(("id", Value::String(id.to_hex())));
+            }
+            let categories = point
+                .get_array("categories")
+                .into_iter()
+                .flatten()
+                .filter_map(Bson::as_str);
+            if let Some(category) = dominant_category(categories) {
+                properties.push(("category", Value::String(category)));
+            }
+            properties.push(("weight", Value::double(number(point, "weight"))));
+            if let Ok(date) = point.get_datetime("time_stamp")
+                && let Ok(date) = date.try_to_rfc3339_string()
+            {
+                properties.push(("date", Value::String(date)));
+            }
+            litter.add_point(
+                tile_coordinate(wx, key.x),
+                tile_coordinate(wy, key.y),
+                properties,
+            );
+        }
+    }
+
+    let cell_size = f64::from(EXTENT) / DENSITY_CELLS;
+    let mut cells = Layer::new("density");
+    for group in density {
+        let Ok(cell) = group.get_document("_id") else {
+            continue;
+        };
+        // Cell centre, relative to the tile's first cell
+        let cx = number(cell, "x") - f64::from(key.x) * DENSITY_CELLS + 0.5;
+        let cy = number(cell, "y") - f64::from(key.y) * DENSITY_CELLS + 0.5;
+        cells.add_point(
+            (cx * cell_size) as i32,
+            (cy * cell_size) as i32,
+            vec![
+                ("count", Value::Uint(number(group, "count") as u64)),
+                ("weight", Value::double(number(group, "total_weight"))),
+            ],
+        );
+    }
+
+    let mut tile = Writer::default();
+    litter.encode_into(&mut tile);
+    cells.encode_into(&mut tile);
+    tile.buf
+}
+
+fn etag(bytes: &[u8]) -> String {
+    let hash: String = Sha256::digest(bytes)[..16]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("\"{hash}\"")
+}
+
+/// Vector tile `z`/`x`/`y` with a `litter` layer of single reports and a `density`
+/// layer of reports aggregated on a 32x32 grid.
+pub async fn litter_tile(
+    db: &Database,
+    cache: &VectorTileCache,
+    user_id: ObjectId,
+    scope: LitterScope,
+    z: u8,
+    x: u32,
+    y: u32,
+) -> Result<Arc<EncodedTile>, LitterError> {
+    if z > MAX_ZOOM {
+        return Err(LitterError::InvalidInput(vec![FieldError {
+            field: "z".to_string(),
+            message: format!("must be between 0 and {MAX_ZOOM}"),
+        }]));
+    }
+    let tiles = 1u32 << z;
+    if x >= tiles || y >= tiles {
+        return Err(LitterError::InvalidInput(vec![FieldError {
+            field: "x/y".to_string(),
+            message: format!("must be below {tiles} at zoom {z}"),
+        }]));
+    }
+
+    let key = TileKey {
+        owner: (scope == LitterScope::Own).then_some(user_id),
+        z,
+        x,
+        y,
+    };
+    if let Some(cached) = cache.get(&key) {
+        return Ok(cached);
+    }
+
+    let (points, density) = try_join!(
+        tile_points(db, user_id, scope, key),
+        tile_density(db, user_id, scope, key),
+    )?;
+    let bytes = encode_tile(key, &points, &density);
+    let tile = Arc::new(EncodedTile {
+        etag: etag(&bytes),
+        bytes,
+    });
+    cache.insert(key, tile.clone());
+    Ok(tile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_varints() {
+        for (value, bytes) in [
+            (0, vec![0x00]),
+            (1, vec![0x01]),
+            (150, vec![0x96, 0x01]),
+            (300, vec![0xac, 0x02]),
+            (u64::from(EXTENT), vec![0x80, 0x20]),
+        ] {
+            let mut writer = Writer::default();
+            writer.varint(value);
+            assert_eq!(writer.buf, bytes, "varint of {value}");
+        }
+    }
+
+    #[test]
+    fn zigzags_parameters() {
+        for (value, encoded) in [
+            (0, 0),
+            (-1, 1),
+            (1, 2),
+            (-2, 3),
+            (i32::MAX, u32::MAX - 1),
+            (i32::MIN, u32::MAX),
+        ] {
+            assert_eq!(zigzag(value), encoded, "zigzag of {value}");
+        }
+    }
+
+    #[test]
+    fn encodes_point_layer() {
+        // The point example of the specification, section 4.3.5.1
+        let mut layer = Layer::new("p");
+        layer.add_point(25, 17, vec![("count", Value::Uint(1))]);
+        let mut tile = Writer::default();
+        layer.encode_into(&mut tile);
+
+        #[rustfmt::skip]
+        let expected = vec![
+            0x1a, 0x20, // layers, 32 bytes
+            0x78, 0x02, // version 2
+            0x0a, 0x01, b'p', // name
+            0x12, 0x0b, // feature, 11 bytes
+            0x12, 0x02, 0x00, 0x00, // tags: key 0, value 0
+            0x18, 0x01, // type point
+            0x22, 0x03, 0x09, 0x32, 0x22, // geometry: MoveTo(25, 17)
+            0x1a, 0x05, b'c', b'o', b'u', b'n', b't', // keys
+            0x22, 0x02, 0x28, 0x01, // values: uint 1
+            0x28, 0x80, 0x20, // extent 4096
+        ];
+        assert_eq!(tile.buf, expected);
+    }
+
+    #[test]
+    fn shares_keys_and_values() {
+        let mut layer = Layer::new("p");
+        layer.add_point(0, 0, vec![("count", Value::Uint(1))]);
+        layer.add_point(1, 1, vec![("count", Value::Uint(1))]);
+        layer.add_point(2, 2, vec![("weight", Value::double(1.5))]);
+
+        assert_eq!(layer.keys, ["count", "weight"]);
+        assert_eq!(layer.values.len(), 2);
+        assert_eq!(layer.feature_count, 3);
+    }
+
+    #[test]
+    fn skips_empty_layers() {
+        let mut tile = Writer::default();
+        Layer::new("p").encode_into(&mut tile);
+        assert!(tile.buf.is_empty());
+    }
+
+    #[test]
+    fn clamps_coordinates_to_the_tile() {
+        let max = EXTENT as i32 - 1;
+        assert_eq!(tile_coordinate(3.0, 3), 0);
+        assert_eq!(tile_coordinate(3.5, 3), EXTENT as i32 / 2);
+        assert_eq!(tile_coordinate(4.0 - 1e-9, 3), max);
+        // Far edges belong to the neighbours, but are pulled inside
+        assert_eq!(tile_coordinate(4.0, 3), max);
+        assert_eq!(tile_coordinate(2.999, 3), 0);
+
+        let (west, north) = world_position(-180.0, 90.0, 0);
+        let (east, south) = world_position(180.0, -90.0, 0);
+        assert_eq!(tile_coordinate(west, 0), 0);
+        assert_eq!(tile_coordinate(north, 0), 0);
+        assert_eq!(tile_coordinate(east, 0), max);
+        assert_eq!(tile_coordinate(south, 0), max);
+    }
+}
@@ -0,0 +1,779 @@
+use std::{
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    cookie::{Cookie, SameSite, time},
+    web,
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, jwk::JwkSet};
+use log::{error, info};
+use mongodb::{
+    Collection, Database,
+    bson::{DateTime, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    models::user::{ExternalIdentity, User},
+    services::{
+        auth::{SigninOutcome, issue_challenge, issue_session_token, random_urlsafe},
+        leaderboard::pseudonym_taken,
+        validation::{USERNAME_MAX_LEN, USERNAME_MIN_LEN},
+    },
+};
+
+/// How long a started login may take until the callback arrives.
+const LOGIN_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Cookie binding a started login to the browser that started it.
+pub const STATE_COOKIE: &str = "oidc_state";
+
+#[derive(Debug, Clone, Serialize)]
+pub enum OidcError {
+    InvalidState,
+    ProviderError,
+    InvalidIdToken,
+    AccountDisabled,
+    IdentityAlreadyLinked,
+    NetworkError,
+}
+
+impl OidcError {
+    /// Short code handed to the frontend when the login fails.
+    pub fn code(&self) -> &'static str {
+        match self {
+            OidcError::InvalidState => "invalid_state",
+            OidcError::ProviderError => "provider_error",
+            OidcError::InvalidIdToken => "invalid_id_token",
+            OidcError::AccountDisabled => "account_disabled",
+            OidcError::IdentityAlreadyLinked => "identity_already_linked",
+            OidcError::NetworkError => "network_error",
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for OidcError {
+    fn from(e: mongodb::error::Error) -> Self {
+        error!("Error when accessing db during OIDC login: {:?}", e);
+        OidcError::NetworkError
+    }
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(e: reqwest::Error) -> Self {
+        error!("Error when talking to the identity provider: {:?}", e);
+        OidcError::ProviderError
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+    pub scopes: String,
+}
+
+impl OidcConfig {
+    /// Reads the provider settings, `None` if `OIDC_ISSUER_URL` is not set.
+    pub fn from_env() -> Option<Self> {
+        let issuer_url = env::var("OIDC_ISSUER_URL").ok()?;
+
+        Some(Self {
+            issuer_url: issuer_url.trim_end_matches('/').to_string(),
+            client_id: env::var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID not set"),
+            client_secret: env::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_url: env::var("OIDC_REDIRECT_URL").expect("OIDC_REDIRECT_URL not set"),
+            scopes: env::var("OIDC_SCOPES").unwrap_or_else(|_| "openid profile email".to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    preferred_username: Option<String>,
+    name: Option<String>,
+}
+
+/// A login waiting for its callback, keyed by the `state` parameter.
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingLogin {
+    #[serde(rename = "_id")]
+    state: String,
+    code_verifier: String,
+    nonce: String,
+    /// Set when an already signed in user links the identity to their account.
+    link_user: Option<ObjectId>,
+    expires_at: DateTime,
+}
+
+pub struct OidcClient {
+    config: OidcConfig,
+    http: reqwest::Client,
+    pending: Collection<PendingLogin>,
+}
+
+impl OidcClient {
+    pub async fn new(config: OidcConfig, db: &Database) -> Self {
+        let pending = db.collection::<PendingLogin>("oidc_logins");
+
+        let ttl_index = mongodb::IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::ZERO)
+                    .name(Some("oidc_login_ttl".to_string()))
+                    .build(),
+            )
+            .build();
+        if let Err(e) = pending.create_index(ttl_index).await {
+            error!("Failed to ensure TTL index for 'oidc_logins': {:?}", e);
+        }
+
+        Self {
+            config,
+            http: reqwest::Client::new(),
+            pending,
+        }
+    }
+
+    async fn metadata(&self) -> Result<ProviderMetadata, OidcError> {
+        let metadata: ProviderMetadata = self
+            .http
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                self.config.issuer_url
+            ))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if metadata.issuer.trim_end_matches('/') != self.config.issuer_url {
+            error!(
+                "Issuer mismatch: configured {}, provider says {}",
+                self.config.issuer_url, metadata.issuer
+            );
+            return Err(OidcError::ProviderError);
+        }
+        Ok(metadata)
+    }
+
+    /// Starts an authorization code flow with PKCE. Returns the provider URL to send the browser
+    /// to and the cookie the callback requires, so a `state` can't be completed in another
+    /// browser (login CSRF, linking a foreign identity).
+    pub async fn start_login(
+        &self,
+        link_user: Option<ObjectId>,
+    ) -> Result<(String, Cookie<'static>), OidcError> {
+        let metadata = self.metadata().await?;
+
+        let state = random_urlsafe(24);
+        let nonce = random_urlsafe(24);
+        let code_verifier = random_urlsafe(48);
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| OidcError::NetworkError)?;
+        self.pending
+            .insert_one(PendingLogin {
+                state: state.clone(),
+                code_verifier,
+                nonce: nonce.clone(),
+                link_user,
+                expires_at: DateTime::from_millis((now + LOGIN_TTL).as_millis() as i64),
+            })
+            .await?;
+
+        let url = self.authorization_url(&metadata, &state, &nonce, &code_challenge)?;
+        Ok((url, self.state_cookie(state_hash(&state))))
+    }
+
+    /// Provider URL asking for a code bound to `code_challenge` (S256) and `nonce`.
+    fn authorization_url(
+        &self,
+        metadata: &ProviderMetadata,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+    ) -> Result<String, OidcError> {
+        let url = reqwest::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", self.config.redirect_url.as_str()),
+                ("scope", self.config.scopes.as_str()),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| {
+            error!("Invalid authorization endpoint: {}", e);
+            OidcError::ProviderError
+        })?;
+        Ok(url.to_string())
+    }
+
+    /// Cookie holding the hash of the `state`, sent back only to the callback. An empty value
+    /// removes it.
+    pub fn state_cookie(&self, value: String) -> Cookie<'static> {
+        let redirect = reqwest::Url::parse(&self.config.redirect_url).ok();
+        let mut cookie = Cookie::build(STATE_COOKIE, value)
+            .path(
+                redirect
+                    .as_ref()
+                    .map_or("/".to_string(), |u| u.path().to_string()),
+            )
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .secure(redirect.is_some_and(|u| u.scheme() == "https"))
+            .finish();
+        if cookie.value().is_empty() {
+            cookie.make_removal();
+        } else {
+            cookie.set_max_age(time::Duration::seconds(LOGIN_TTL.as_secs() as i64));
+        }
+        cookie
+    }
+
+    /// Completes the flow: redeems `code`, validates the id token and signs the linked user in.
+    /// Accounts with 2FA get a challenge for `signin_two_factor` instead of a session, like
+    /// password signin. `cookie` is the value of [`STATE_COOKIE`] sent with the callback.
+    pub async fn finish_login(
+        &self,
+        db: web::Data<Database>,
+        code: &str,
+        state: &str,
+        cookie: Option<&str>,
+    ) -> Result<(ObjectId, SigninOutcome), OidcError> {
+        check_state(cookie, state)?;
+
+        let pending = self
+            .pending
+            .find_one_and_delete(doc! { "_id": state, "expires_at": { "$gt": DateTime::now() } })
+            .await?
+            .ok_or(OidcError::InvalidState)?;
+
+        let metadata = self.metadata().await?;
+        let claims = self
+            .redeem(&metadata, code, &pending.code_verifier, &pending.nonce)
+            .await?;
+        let identity = ExternalIdentity {
+            issuer: metadata.issuer.trim_end_matches('/').to_string(),
+            subject: claims.sub.clone(),
+        };
+
+        let user = match pending.link_user {
+            Some(user_id) => link_identity(&db, user_id, identity).await?,
+            None => find_or_create_user(&db, identity, &claims).await?,
+        };
+
+        if user.disabled {
+            info!("OIDC login to disabled account {:?}", user._id);
+            return Err(OidcError::AccountDisabled);
+        }
+
+        let id = user._id.expect("Id is always there when reading");
+        let outcome = if user.two_factor.as_ref().is_some_and(|t| t.enabled) {
+            issue_challenge(id, &user.username).map(SigninOutcome::TwoFactorRequired)
+        } else {
+            issue_session_token(id, &user).map(SigninOutcome::Session)
+        }
+        .map_err(|e| {
+            error!("Error when creating jwt: {}", e);
+            OidcError::NetworkError
+        })?;
+
+        Ok((id, outcome))
+    }
+
+    /// Exchanges `code` with the PKCE `code_verifier` and returns the claims of the id token,
+    /// which has to carry `nonce`.
+    async fn redeem(
+        &self,
+        metadata: &ProviderMetadata,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, OidcError> {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.config.redirect_url.as_str()),
+            ("client_id", self.config.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+
+        let tokens: TokenResponse = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        self.validate_id_token(metadata, &tokens.id_token, nonce)
+            .await
+    }
+
+    async fn validate_id_token(
+        &self,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, OidcError> {
+        let header = jsonwebtoken::decode_header(id_token).map_err(|e| {
+            info!("Malformed id token: {}", e);
+            OidcError::InvalidIdToken
+        })?;
+
+        let key = match header.alg {
+            // Symmetric id tokens are signed with the client secret.
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secret = self
+                    .config
+                    .client_secret
+                    .as_ref()
+                    .ok_or(OidcError::InvalidIdToken)?;
+                DecodingKey::from_secret(secret.as_bytes())
+            }
+            _ => {
+                let jwks: JwkSet = self
+                    .http
+                    .get(&metadata.jwks_uri)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                let jwk = match &header.kid {
+                    Some(kid) => jwks.find(kid),
+                    None => jwks.keys.first(),
+                }
+                .ok_or(OidcError::InvalidIdToken)?;
+                DecodingKey::from_jwk(jwk).map_err(|_| OidcError::InvalidIdToken)?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+
+        let claims = jsonwebtoken::decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map_err(|e| {
+                info!("Id token rejected: {}", e);
+                OidcError::InvalidIdToken
+            })?
+            .claims;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            info!("Id token nonce does not match");
+            return Err(OidcError::InvalidIdToken);
+        }
+        Ok(claims)
+    }
+}
+
+fn state_hash(state: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(state.as_bytes()))
+}
+
+/// Whether the callback's `state` belongs to the login started in this browser.
+fn check_state(cookie: Option<&str>, state: &str) -> Result<(), OidcError> {
+    if cookie.is_some_and(|c| !c.is_empty() && c == state_hash(state)) {
+        Ok(())
+    } else {
+        info!("OIDC callback without matching state cookie");
+        Err(OidcError::InvalidState)
+    }
+}
+
+fn identity_filter(identity: &ExternalIdentity) -> mongodb::bson::Document {
+    doc! {
+        "external_identities": {
+            "$elemMatch": { "issuer": &identity.issuer, "subject": &identity.subject }
+        }
+    }
+}
+
+async fn find_by_identity(
+    db: &web::Data<Database>,
+    identity: &ExternalIdentity,
+) -> Result<Option<User>, OidcError> {
+    Ok(db
+        .collection::<User>("users")
+        .find_one(identity_filter(identity))
+        .projection(doc! { "litter": 0 })
+        .await?)
+}
+
+async fn link_identity(
+    db: &web::Data<Database>,
+    user_id: ObjectId,
+    identity: ExternalIdentity,
+) -> Result<User, OidcError> {
+    if let Some(existing) = find_by_identity(db, &identity).await? {
+        if existing._id == Some(user_id) {
+            return Ok(existing);
+        }
+        return Err(OidcError::IdentityAlreadyLinked);
+    }
+
+    let users = db.collection::<User>("users");
+    users
+        .update_one(
+            doc! { "_id": user_id },
+            doc! { "$push": { "external_identities": {
+                "issuer": &identity.issuer,
+                "subject": &identity.subject,
+            } } },
+        )
+        .await?;
+    info!(
+        "Linked {}/{} to {}",
+        identity.issuer, identity.subject, user_id
+    );
+
+    users
+        .find_one(doc! { "_id": user_id })
+        .projection(doc! { "litter": 0 })
+        .await?
+        .ok_or(OidcError::InvalidState)
+}
+
+/// Finds the user of an identity. Unknown identities are linked to the local account
+/// with the same verified email, or get a fresh account.
+async fn find_or_create_user(
+    db: &web::Data<Database>,
+    identity: ExternalIdentity,
+    claims: &IdTokenClaims,
+) -> Result<User, OidcError> {
+    if let Some(user) = find_by_identity(db, &identity).await? {
+        return Ok(user);
+    }
+
+    let verified_email = claims
+        .email
+        .as_deref()
+        .filter(|_| claims.email_verified == Some(true))
+        .and_then(crate::services::account::normalize_email);
+
+    if let Some(email) = &verified_email
+        && let Some(user) = db
+            .collection::<User>("users")
+            .find_one(doc! { "email": email, "email_verified": true })
+            .projection(doc! { "litter": 0 })
+            .await?
+    {
+        let id = user._id.expect("Id is always there when reading");
+        return link_identity(db, id, identity).await;
+    }
+
+    let base = username_candidate(claims);
+    for attempt in 0..5 {
+        let username = if attempt == 0 {
+            base.clone()
+        } else {
+            let suffix = &random_urlsafe(3).to_lowercase().replace(['-', '_'], "x");
+            format!(
+                "{}-{}",
+                &base[..base.len().min(USERNAME_MAX_LEN - 5)],
+                suffix
+            )
+        };
+        if pseudonym_taken(db, &username).await? {
+            continue;
+        }
+
+        let user = User {
+            username,
+            // External accounts have no local password until they reset it.
+            password_hash: String::new(),
+            email: verified_email.clone(),
+            email_verified: verified_email.is_some(),
+            external_identities: vec![identity.clone()],
+            ..Default::default()
+        };
+
+        match user.persist(db).await {
+            Ok(id) => {
+                info!(
+                    "Created user {} for {}/{}",
+                    id, identity.issuer, identity.subject
+                );
+                return Ok(User {
+                    _id: Some(id),
+                    ..user
+                });
+            }
+            Err(e) if e.to_string().contains("E11000") => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    error!("Could not find a free username for {}", base);
+    Err(OidcError::NetworkError)
+}
+
+/// Derives a username that passes signup validation from the id token claims.
+fn username_candidate(claims: &IdTokenClaims) -> String {
+    let raw = claims
+        .preferred_username
+        .clone()
+        .or_else(|| {
+            claims
+                .email
+                .as_ref()
+                .and_then(|e| e.split('@').next().map(str::to_string))
+        })
+        .or_else(|| claims.name.clone())
+        .unwrap_or_default();
+
+    let mut username: String = raw
+        .chars()
+        .filter_map(|c| match c {
+            c if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') => Some(c),
+            ' ' => Some('.'),
+            _ => None,
+        })
+        .skip_while(|c| !c.is_ascii_alphanumeric())
+        .take(USERNAME_MAX_LEN)
+        .collect();
+
+    if username.len() < USERNAME_MIN_LEN {
+        username = format!("scout{username}");
+    }
+    username
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../bin/mock_idp.rs"]
+mod mock_idp;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_ID: &str = "delitter";
+    const CLIENT_SECRET: &str = "mock-secret";
+
+    /// Starts the mock provider on a free port, approving every login as `subject`.
+    fn start_idp(subject: &str) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let state = mock_idp::State::new(mock_idp::Config {
+            issuer: issuer.clone(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: CLIENT_SECRET.to_string(),
+            subject: subject.to_string(),
+            email: format!("{subject}@example.org"),
+        });
+        actix_web::rt::spawn(mock_idp::serve(listener, state).unwrap());
+        issuer
+    }
+
+    fn config(issuer: &str) -> OidcConfig {
+        OidcConfig {
+            issuer_url: issuer.to_string(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: Some(CLIENT_SECRET.to_string()),
+            redirect_url: "http://localhost:8080/v1/auth/oidc/callback".to_string(),
+            scopes: "openid profile email".to_string(),
+        }
+    }
+
+    /// Follows the authorization URL like a browser already signed in at the provider and
+    /// returns `code` and `state` of the redirect back.
+    async fn authorize(url: &str) -> (String, String) {
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let response = http.get(url).send().await.unwrap();
+        assert_eq!(response.status(), 302);
+        let location = response.headers()["location"].to_str().unwrap();
+        let location = reqwest::Url::parse(location).unwrap();
+        let param = |name: &str| {
+            location
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .unwrap()
+        };
+        (param("code"), param("state"))
+    }
+
+    /// Runs a whole login with the state cookie `start_login` set.
+    async fn login(
+        client: &OidcClient,
+        db: &web::Data<Database>,
+        link_user: Option<ObjectId>,
+    ) -> Result<(ObjectId, SigninOutcome), OidcError> {
+        let (url, cookie) = client.start_login(link_user).await?;
+        let (code, state) = authorize(&url).await;
+        client
+            .finish_login(db.clone(), &code, &state, Some(cookie.value()))
+            .await
+    }
+
+    #[test]
+    fn state_cookie_must_match() {
+        let state = random_urlsafe(24);
+        let cookie = state_hash(&state);
+
+        assert!(check_state(Some(&cookie), &state).is_ok());
+        assert!(check_state(Some(&cookie), &random_urlsafe(24)).is_err());
+        assert!(check_state(Some(&state), &state).is_err());
+        assert!(check_state(Some(""), &state).is_err());
+        assert!(check_state(None, &state).is_err());
+    }
+
+    #[actix_web::test]
+    async fn redeems_codes_with_pkce_and_nonce() {
+        let issuer = start_idp("scout");
+        // Redeeming codes does not touch the database, the client never connects
+        let pending = mongodb::Client::with_uri_str("mongodb://127.0.0.1:1")
+            .await
+            .unwrap()
+            .database("unused")
+            .collection("oidc_logins");
+        let client = OidcClient {
+            config: config(&issuer),
+            http: reqwest::Client::new(),
+            pending,
+        };
+        let metadata = client.metadata().await.unwrap();
+
+        let verifier = random_urlsafe(48);
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        let url = client
+            .authorization_url(&metadata, "state", "nonce", &challenge)
+            .unwrap();
+
+        let (code, state) = authorize(&url).await;
+        assert_eq!(state, "state");
+        let stolen = client
+            .redeem(&metadata, &code, &random_urlsafe(48), "nonce")
+            .await;
+        assert!(matches!(stolen, Err(OidcError::ProviderError)));
+
+        let (code, _) = authorize(&url).await;
+        let other_login = client.redeem(&metadata, &code, &verifier, "other").await;
+        assert!(matches!(other_login, Err(OidcError::InvalidIdToken)));
+
+        let (code, _) = authorize(&url).await;
+        let claims = client
+            .redeem(&metadata, &code, &verifier, "nonce")
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, "scout");
+        assert_eq!(claims.email.as_deref(), Some("scout@example.org"));
+        assert_eq!(claims.email_verified, Some(true));
+        let replayed = client.redeem(&metadata, &code, &verifier, "nonce").await;
+        assert!(matches!(replayed, Err(OidcError::ProviderError)));
+    }
+
+    /// Needs a MongoDB server at `TEST_MONGODB_URI` (default `mongodb://localhost:27017`),
+    /// run with `cargo test -- --ignored`. Works in a throwaway database.
+    #[actix_web::test]
+    #[ignore = "needs MongoDB"]
+    async fn signs_in_and_links_accounts() {
+        let uri = env::var("TEST_MONGODB_URI")
+            .unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+        let db = web::Data::new(
+            mongodb::Client::with_uri_str(uri)
+                .await
+                .unwrap()
+                .database(&format!("oidc_test_{}", ObjectId::new())),
+        );
+
+        // Unknown identities get an account named after them
+        let scouts = OidcClient::new(config(&start_idp("scout")), &db).await;
+        let (scout, outcome) = login(&scouts, &db, None).await.unwrap();
+        assert!(matches!(outcome, SigninOutcome::Session(_)));
+        let user = db
+            .collection::<User>("users")
+            .find_one(doc! { "_id": scout })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.username, "scout");
+        assert_eq!(login(&scouts, &db, None).await.unwrap().0, scout);
+
+        // A callback only completes once
+        let (url, cookie) = scouts.start_login(None).await.unwrap();
+        let (code, state) = authorize(&url).await;
+        let cookie = Some(cookie.value());
+        assert!(
+            scouts
+                .finish_login(db.clone(), &code, &state, cookie)
+                .await
+                .is_ok()
+        );
+        let replayed = scouts.finish_login(db.clone(), &code, &state, cookie).await;
+        assert!(matches!(replayed, Err(OidcError::InvalidState)));
+
+        // Signed in users link another identity, which then signs in to their account
+        let leader = User {
+            username: "leader".to_string(),
+            ..Default::default()
+        }
+        .persist(&db)
+        .await
+        .unwrap();
+        let rovers = OidcClient::new(config(&start_idp("rover")), &db).await;
+        assert_eq!(login(&rovers, &db, Some(leader)).await.unwrap().0, leader);
+        assert_eq!(login(&rovers, &db, None).await.unwrap().0, leader);
+        let taken = login(&scouts, &db, Some(leader)).await;
+        assert!(matches!(taken, Err(OidcError::IdentityAlreadyLinked)));
+
+        // A local account with the same verified email is linked instead of a new one
+        let guide = User {
+            username: "guide".to_string(),
+            email: Some("guide@example.org".to_string()),
+            email_verified: true,
+            ..Default::default()
+        }
+        .persist(&db)
+        .await
+        .unwrap();
+        let guides = OidcClient::new(config(&start_idp("guide")), &db).await;
+        assert_eq!(login(&guides, &db, None).await.unwrap().0, guide);
+
+        Database::drop(&db).await.unwrap();
+    }
+}
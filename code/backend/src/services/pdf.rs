@@ -0,0 +1,252 @@
+//! Small PDF documents for reports: A4 pages with Helvetica text, lines, boxes and JPEG photos.
+//! Coordinates are in points from the bottom left corner of the page.
+
+use pdf_writer::{Content, Filter, Finish, Name, Pdf, Rect, Ref, Str, TextStr};
+
+pub const PAGE_WIDTH: f32 = 595.0;
+pub const PAGE_HEIGHT: f32 = 842.0;
+pub const MARGIN: f32 = 50.0;
+
+const FONT_REGULAR: Name = Name(b"F1");
+const FONT_BOLD: Name = Name(b"F2");
+
+struct Page {
+    id: Ref,
+    content: Content,
+    images: Vec<(String, Ref)>,
+}
+
+pub struct PdfDocument {
+    pdf: Pdf,
+    next_id: i32,
+    catalog: Ref,
+    page_tree: Ref,
+    regular: Ref,
+    bold: Ref,
+    pages: Vec<Page>,
+}
+
+/// Size and colour components of a baseline or progressive JPEG, read from its SOF marker.
+fn jpeg_info(data: &[u8]) -> Option<(u16, u16, u8)> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut i = 2;
+    while i + 4 <= data.len() {
+        if data[i] != 0xff {
+            return None;
+        }
+        let marker = data[i + 1];
+        if marker == 0xff {
+            // Fill byte before a marker
+            i += 1;
+            continue;
+        }
+        let length = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        // SOF0 to SOF15, except DHT, JPG and DAC which share the range
+        if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+            let sof = data.get(i + 4..i + 10)?;
+            let height = u16::from_be_bytes([sof[1], sof[2]]);
+            let width = u16::from_be_bytes([sof[3], sof[4]]);
+            return Some((width, height, sof[5]));
+        }
+        i += 2 + length;
+    }
+    None
+}
+
+/// Text in the WinAnsi encoding of the standard fonts, other characters become `?`.
+fn win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c {
+            '€' => 0x80,
+            '‚' => 0x82,
+            '„' => 0x84,
+            '…' => 0x85,
+            '‘' => 0x91,
+            '’' => 0x92,
+            '“' => 0x93,
+            '”' => 0x94,
+            '–' => 0x96,
+            '—' => 0x97,
+            '™' => 0x99,
+            _ => match c as u32 {
+                code @ (0x20..=0x7e | 0xa0..=0xff) => code as u8,
+                _ => b'?',
+            },
+        })
+        .collect()
+}
+
+impl PdfDocument {
+    pub fn new(title: &str) -> Self {
+        let mut pdf = Pdf::new();
+        let info = Ref::new(1);
+        pdf.document_info(info)
+            .title(TextStr(title))
+            .creator(TextStr("Delitter"));
+
+        let mut doc = PdfDocument {
+            pdf,
+            next_id: 6,
+            catalog: Ref::new(2),
+            page_tree: Ref::new(3),
+            regular: Ref::new(4),
+            bold: Ref::new(5),
+            pages: Vec::new(),
+        };
+        doc.new_page();
+        doc
+    }
+
+    fn alloc(&mut self) -> Ref {
+        let id = Ref::new(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn content(&mut self) -> &mut Content {
+        &mut self
+            .pages
+            .last_mut()
+            .expect("A page is always open")
+            .content
+    }
+
+    /// Starts a new page, following drawing calls go there.
+    pub fn new_page(&mut self) {
+        let id = self.alloc();
+        self.pages.push(Page {
+            id,
+            content: Content::new(),
+            images: Vec::new(),
+        });
+    }
+
+    pub fn text(&mut self, x: f32, y: f32, size: f32, bold: bool, text: &str) {
+        let font = if bold { FONT_BOLD } else { FONT_REGULAR };
+        let text = win_ansi(text);
+        let content = self.content();
+        content.begin_text();
+        content.set_font(font, size);
+        content.next_line(x, y);
+        content.show(Str(&text));
+        content.end_text();
+    }
+
+    pub fn line(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, gray: f32) {
+        let content = self.content();
+        content.save_state();
+        content.set_line_width(0.5);
+        content.set_stroke_gray(gray);
+        content.move_to(x1, y1);
+        content.line_to(x2, y2);
+        content.stroke();
+        content.restore_state();
+    }
+
+    /// Filled box, `rgb` components from 0 to 1.
+    pub fn fill_rect(&mut self, x: f32, y: f32, width: f32, height: f32, rgb: [f32; 3]) {
+        let content = self.content();
+        content.save_state();
+        content.set_fill_rgb(rgb[0], rgb[1], rgb[2]);
+        content.rect(x, y, width, height);
+        content.fill_nonzero();
+        content.restore_state();
+    }
+
+    pub fn stroke_rect(&mut self, x: f32, y: f32, width: f32, height: f32, gray: f32) {
+        let content = self.content();
+        content.save_state();
+        content.set_line_width(0.5);
+        content.set_stroke_gray(gray);
+        content.rect(x, y, width, height);
+        content.stroke();
+        content.restore_state();
+    }
+
+    /// Places a JPEG photo scaled into the box at `x`, `y`. Returns the drawn width, or
+    /// `None` if `data` is not a JPEG that can be embedded as is.
+    pub fn jpeg(
+        &mut self,
+        data: &[u8],
+        x: f32,
+        y: f32,
+        max_width: f32,
+        max_height: f32,
+    ) -> Option<f32> {
+        let (width, height, components) = jpeg_info(data)?;
+        if width == 0 || height == 0 || ![1, 3, 4].contains(&components) {
+            return None;
+        }
+
+        let id = self.alloc();
+        let mut image = self.pdf.image_xobject(id, data);
+        image.filter(Filter::DctDecode);
+        image.width(i32::from(width));
+        image.height(i32::from(height));
+        match components {
+            1 => image.color_space().device_gray(),
+            3 => image.color_space().device_rgb(),
+            _ => {
+                image.color_space().device_cmyk();
+                // Adobe writes CMYK JPEGs inverted
+                image.decode([1.0, 0.0, 1.0, 0.0, 1.0, 0.0, 1.0, 0.0]);
+            }
+        }
+        image.bits_per_component(8);
+        image.finish();
+
+        let scale = (max_width / f32::from(width)).min(max_height / f32::from(height));
+        let (w, h) = (f32::from(width) * scale, f32::from(height) * scale);
+
+        let page = self.pages.last_mut().expect("A page is always open");
+        let name = format!("Im{}", id.get());
+        page.content.save_state();
+        page.content
+            .transform([w, 0.0, 0.0, h, x, y + max_height - h]);
+        page.content.x_object(Name(name.as_bytes()));
+        page.content.restore_state();
+        page.images.push((name, id));
+        Some(w)
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.pdf.catalog(self.catalog).pages(self.page_tree);
+        self.pdf
+            .pages(self.page_tree)
+            .kids(self.pages.iter().map(|p| p.id))
+            .count(self.pages.len() as i32);
+
+        for page in std::mem::take(&mut self.pages) {
+            let content_id = self.alloc();
+            let mut writer = self.pdf.page(page.id);
+            writer
+                .media_box(Rect::new(0.0, 0.0, PAGE_WIDTH, PAGE_HEIGHT))
+                .parent(self.page_tree)
+                .contents(content_id);
+            let mut resources = writer.resources();
+            resources
+                .fonts()
+                .pair(FONT_REGULAR, self.regular)
+                .pair(FONT_BOLD, self.bold);
+            let mut x_objects = resources.x_objects();
+            for (name, id) in &page.images {
+                x_objects.pair(Name(name.as_bytes()), *id);
+            }
+            x_objects.finish();
+            resources.finish();
+            writer.finish();
+            self.pdf.stream(content_id, &page.content.finish());
+        }
+
+        for (id, font) in [(self.regular, "Helvetica"), (self.bold, "Helvetica-Bold")] {
+            self.pdf
+                .type1_font(id)
+                .base_font(Name(font.as_bytes()))
+                .encoding_predefined(Name(b"WinAnsiEncoding"));
+        }
+
+        self.pdf.finish()
+    }
+}
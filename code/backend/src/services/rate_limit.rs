@@ -0,0 +1,617 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::http::header::{self, HeaderMap};
+use async_trait::async_trait;
+use log::{error, info, warn};
+use mongodb::{
+    Collection, Database,
+    bson::{DateTime, Document, doc},
+    options::{IndexOptions, ReturnDocument},
+};
+
+/// In-memory sliding window limiter keyed by an arbitrary string (ip, email, ...).
+pub struct RateLimiter {
+    max_hits: usize,
+    window: Duration,
+    hits: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(max_hits: usize, window: Duration) -> Self {
+        Self {
+            max_hits,
+            window,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a hit for `key` and returns whether it is still within the limit.
+    pub fn check(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().expect("rate limiter lock poisoned");
+
+        hits.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|t| now.duration_since(*t) > self.window)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        let times = hits.entry(key.to_string()).or_default();
+        if times.len() >= self.max_hits {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+/// When and for how long a key is locked after repeated failures.
+#[derive(Debug, Clone)]
+pub struct LockoutPolicy {
+    /// Failures before the first lockout.
+    pub threshold: u32,
+    /// Length of the first lockout, doubled for every further failure.
+    pub base: Duration,
+    pub max: Duration,
+    /// Failures older than this are forgotten.
+    pub failure_window: Duration,
+}
+
+impl LockoutPolicy {
+    fn lock_duration(&self, failures: u32) -> Option<Duration> {
+        if failures < self.threshold {
+            return None;
+        }
+        let exponent = (failures - self.threshold).min(31);
+        Some(self.base.saturating_mul(1 << exponent).min(self.max))
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+fn remaining(until_millis: i64, now: i64) -> Option<Duration> {
+    (until_millis > now).then(|| Duration::from_millis((until_millis - now) as u64))
+}
+
+/// Storage for request counters and failure lockouts.
+#[async_trait]
+pub trait LimiterStore: Send + Sync {
+    /// Counts a request for `key` in the current fixed window.
+    /// Returns how long to wait if more than `max` requests were made.
+    async fn hit(&self, key: &str, max: u32, window: Duration) -> anyhow::Result<Option<Duration>>;
+    /// Remaining lockout of `key`, if any.
+    async fn locked_for(&self, key: &str) -> anyhow::Result<Option<Duration>>;
+    /// Records a failed attempt and locks `key` once the policy threshold is reached.
+    async fn record_failure(&self, key: &str, policy: &LockoutPolicy) -> anyhow::Result<()>;
+    async fn reset_failures(&self, key: &str) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Default)]
+struct MemoryEntry {
+    window_start: i64,
+    count: u32,
+    failures: u32,
+    last_failure: i64,
+    locked_until: i64,
+    expires_at: i64,
+}
+
+#[derive(Default)]
+pub struct MemoryLimiterStore {
+    entries: Mutex<HashMap<String, MemoryEntry>>,
+    last_prune: Mutex<i64>,
+}
+
+impl MemoryLimiterStore {
+    fn prune(&self, now: i64) {
+        let mut last_prune = self.last_prune.lock().expect("limiter lock poisoned");
+        if now - *last_prune < 60_000 {
+            return;
+        }
+        *last_prune = now;
+        self.entries
+            .lock()
+            .expect("limiter lock poisoned")
+            .retain(|_, e| e.expires_at > now);
+    }
+}
+
+#[async_trait]
+impl LimiterStore for MemoryLimiterStore {
+    async fn hit(&self, key: &str, max: u32, window: Duration) -> anyhow::Result<Option<Duration>> {
+        let now = now_millis();
+        self.prune(now);
+
+        let window = window.as_millis() as i64;
+        let mut entries = self.entries.lock().expect("limiter lock poisoned");
+        let entry = entries.entry(key.to_string()).or_default();
+
+        if entry.window_start + window <= now {
+            entry.window_start = now;
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.expires_at = entry.expires_at.max(entry.window_start + window);
+
+        if entry.count > max {
+            return Ok(remaining(entry.window_start + window, now));
+        }
+        Ok(None)
+    }
+
+    async fn locked_for(&self, key: &str) -> anyhow::Result<Option<Duration>> {
+        let entries = self.entries.lock().expect("limiter lock poisoned");
+        Ok(entries
+            .get(key)
+            .and_then(|e| remaining(e.locked_until, now_millis())))
+    }
+
+    async fn record_failure(&self, key: &str, policy: &LockoutPolicy) -> anyhow::Result<()> {
+        let now = now_millis();
+        let failure_window = policy.failure_window.as_millis() as i64;
+        let mut entries = self.entries.lock().expect("limiter lock poisoned");
+        let entry = entries.entry(key.to_string()).or_default();
+
+        if entry.last_failure + failure_window > now {
+            entry.failures += 1;
+        } else {
+            entry.failures = 1;
+        }
+        entry.last_failure = now;
+        entry.expires_at = entry.expires_at.max(now + failure_window);
+
+        if let Some(lock) = policy.lock_duration(entry.failures) {
+            entry.locked_until = now + lock.as_millis() as i64;
+            entry.expires_at = entry.expires_at.max(entry.locked_until);
+        }
+        Ok(())
+    }
+
+    async fn reset_failures(&self, key: &str) -> anyhow::Result<()> {
+        if let Some(entry) = self
+            .entries
+            .lock()
+            .expect("limiter lock poisoned")
+            .get_mut(key)
+        {
+            entry.failures = 0;
+            entry.locked_until = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps counters in the `rate_limits` collection so they are shared between instances.
+pub struct MongoLimiterStore {
+    collection: Collection<Document>,
+}
+
+impl MongoLimiterStore {
+    pub async fn new(db: &Database) -> Self {
+        let collection = db.collection::<Document>("rate_limits");
+
+        let ttl_index = mongodb::IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::ZERO)
+                    .name(Some("rate_limit_ttl".to_string()))
+                    .build(),
+            )
+            .build();
+        if let Err(e) = collection.create_index(ttl_index).await {
+            error!("Failed to ensure TTL index for 'rate_limits': {:?}", e);
+        }
+
+        Self { collection }
+    }
+}
+
+#[async_trait]
+impl LimiterStore for MongoLimiterStore {
+    async fn hit(&self, key: &str, max: u32, window: Duration) -> anyhow::Result<Option<Duration>> {
+        let now = now_millis();
+        let window = window.as_millis() as i64;
+        let cutoff = DateTime::from_millis(now - window);
+        let now_dt = DateTime::from_millis(now);
+
+        let entry = self
+            .collection
+            .find_one_and_update(
+                doc! { "_id": key },
+                vec![
+                    doc! { "$set": {
+                        "_expired": { "$not": [{ "$gt": ["$window_start", cutoff] }] },
+                    } },
+                    doc! { "$set": {
+                        "window_start": { "$cond": ["$_expired", now_dt, "$window_start"] },
+                        "count": { "$cond": ["$_expired", 1, { "$add": ["$count", 1] }] },
+                        "expires_at": { "$max": ["$expires_at", DateTime::from_millis(now + window)] },
+                    } },
+                    doc! { "$unset": "_expired" },
+                ],
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("upsert returned no document"))?;
+
+        let count = entry.get_i32("count").unwrap_or_default();
+        if count > max as i32 {
+            let window_start = entry.get_datetime("window_start")?.timestamp_millis();
+            return Ok(remaining(window_start + window, now));
+        }
+        Ok(None)
+    }
+
+    async fn locked_for(&self, key: &str) -> anyhow::Result<Option<Duration>> {
+        let entry = self
+            .collection
+            .find_one(doc! { "_id": key })
+            .projection(doc! { "locked_until": 1 })
+            .await?;
+
+        Ok(entry
+            .and_then(|e| e.get_datetime("locked_until").ok().copied())
+            .and_then(|until| remaining(until.timestamp_millis(), now_millis())))
+    }
+
+    async fn record_failure(&self, key: &str, policy: &LockoutPolicy) -> anyhow::Result<()> {
+        let now = now_millis();
+        let failure_window = policy.failure_window.as_millis() as i64;
+        let cutoff = DateTime::from_millis(now - failure_window);
+
+        let entry = self
+            .collection
+            .find_one_and_update(
+                doc! { "_id": key },
+                vec![doc! { "$set": {
+                    "failures": { "$cond": [
+                        { "$gt": ["$last_failure", cutoff] },
+                        { "$add": [{ "$ifNull": ["$failures", 0] }, 1] },
+                        1,
+                    ] },
+                    "last_failure": DateTime::from_millis(now),
+                    "expires_at": { "$max": ["$expires_at", DateTime::from_millis(now + failure_window)] },
+                } }],
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("upsert returned no document"))?;
+
+        let failures = entry.get_i32("failures").unwrap_or_default().max(0) as u32;
+        if let Some(lock) = policy.lock_duration(failures) {
+            let until = DateTime::from_millis(now + lock.as_millis() as i64);
+            self.collection
+                .update_one(
+                    doc! { "_id": key },
+                    doc! { "$max": { "locked_until": until, "expires_at": until } },
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn reset_failures(&self, key: &str) -> anyhow::Result<()> {
+        self.collection
+            .update_one(
+                doc! { "_id": key },
+                doc! { "$set": { "failures": 0 }, "$unset": { "locked_until": "" } },
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthLimitConfig {
+    pub ip_per_minute: u32,
+    pub username_per_minute: u32,
+    pub ip_lockout: LockoutPolicy,
+    pub username_lockout: LockoutPolicy,
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(v) => v
+            .parse()
+            .unwrap_or_else(|_| panic!("{name} must be a valid number")),
+        Err(_) => default,
+    }
+}
+
+impl AuthLimitConfig {
+    pub fn from_env() -> Self {
+        let base = Duration::from_secs(env_or("AUTH_LOCKOUT_BASE_SECONDS", 30));
+        let max = Duration::from_secs(env_or("AUTH_LOCKOUT_MAX_SECONDS", 60 * 60));
+        let failure_window = Duration::from_secs(env_or("AUTH_FAILURE_WINDOW_SECONDS", 60 * 60));
+
+        Self {
+            ip_per_minute: env_or("AUTH_RATE_LIMIT_IP_PER_MINUTE", 30),
+            username_per_minute: env_or("AUTH_RATE_LIMIT_USERNAME_PER_MINUTE", 10),
+            ip_lockout: LockoutPolicy {
+                threshold: env_or("AUTH_LOCKOUT_IP_THRESHOLD", 20),
+                base,
+                max,
+                failure_window,
+            },
+            username_lockout: LockoutPolicy {
+                threshold: env_or("AUTH_LOCKOUT_USERNAME_THRESHOLD", 5),
+                base,
+                max,
+                failure_window,
+            },
+        }
+    }
+}
+
+/// Request throttling and failure lockouts for the authentication endpoints.
+pub struct AuthLimiter {
+    store: Arc<dyn LimiterStore>,
+    config: AuthLimitConfig,
+}
+
+impl AuthLimiter {
+    pub fn new(store: Arc<dyn LimiterStore>, config: AuthLimitConfig) -> Self {
+        Self { store, config }
+    }
+
+    /// Uses the store selected by `AUTH_RATE_LIMIT_BACKEND` (`memory` or `mongo`, default `memory`).
+    pub async fn from_env(db: &Database) -> Self {
+        let backend = env::var("AUTH_RATE_LIMIT_BACKEND").unwrap_or_else(|_| "memory".to_string());
+        let store: Arc<dyn LimiterStore> = match backend.as_str() {
+            "mongo" => Arc::new(MongoLimiterStore::new(db).await),
+            "memory" => Arc::new(MemoryLimiterStore::default()),
+            other => {
+                warn!("Unknown AUTH_RATE_LIMIT_BACKEND '{other}', using in-memory limiter");
+                Arc::new(MemoryLimiterStore::default())
+            }
+        };
+        info!("🛡️ Auth rate limiter backed by {backend}");
+
+        Self::new(store, AuthLimitConfig::from_env())
+    }
+
+    /// Checks lockouts and request rates for a client.
+    /// Returns how long the client has to wait if the request must be rejected.
+    ///
+    /// Store errors are logged and let the request through.
+    pub async fn check(&self, ip: &str, username: Option<&str>) -> Option<Duration> {
+        let minute = Duration::from_secs(60);
+        let ip_key = format!("ip:{ip}");
+
+        let mut checks = vec![
+            self.store.locked_for(&ip_key).await,
+            self.store
+                .hit(&ip_key, self.config.ip_per_minute, minute)
+                .await,
+        ];
+        if let Some(username) = username {
+            let user_key = format!("user:{username}");
+            checks.push(self.store.locked_for(&user_key).await);
+            checks.push(
+                self.store
+                    .hit(&user_key, self.config.username_per_minute, minute)
+                    .await,
+            );
+        }
+
+        checks
+            .into_iter()
+            .filter_map(|r| {
+                r.unwrap_or_else(|e| {
+                    error!("Rate limiter store failed: {:?}", e);
+                    None
+                })
+            })
+            .max()
+    }
+
+    pub async fn record_failure(&self, ip: &str, username: Option<&str>) {
+        if let Err(e) = self
+            .store
+            .record_failure(&format!("ip:{ip}"), &self.config.ip_lockout)
+            .await
+        {
+            error!("Rate limiter store failed: {:?}", e);
+        }
+        if let Some(username) = username
+            && let Err(e) = self
+                .store
+                .record_failure(&format!("user:{username}"), &self.config.username_lockout)
+                .await
+        {
+            error!("Rate limiter store failed: {:?}", e);
+        }
+    }
+
+    /// Forgets the failures of a username after a successful login.
+    /// The ip counter is kept, otherwise one valid account would reset it for all others.
+    pub async fn record_success(&self, username: &str) {
+        if let Err(e) = self.store.reset_failures(&format!("user:{username}")).await {
+            error!("Rate limiter store failed: {:?}", e);
+        }
+    }
+}
+
+/// Proxies allowed to report the client address in `X-Forwarded-For`, from the comma
+/// separated addresses and CIDR ranges in `TRUSTED_PROXIES`. Empty by default, so the
+/// header is ignored unless the server runs behind a known proxy.
+static TRUSTED_PROXIES: LazyLock<Vec<ProxyRange>> = LazyLock::new(|| {
+    env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            ProxyRange::parse(p)
+                .unwrap_or_else(|| panic!("TRUSTED_PROXIES contains an invalid address {p}"))
+        })
+        .collect()
+});
+
+/// Parses `TRUSTED_PROXIES`, so a typo stops the server at startup.
+pub fn init() {
+    LazyLock::force(&TRUSTED_PROXIES);
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ProxyRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+/// Address as a number and its width in bits.
+fn ip_bits(ip: IpAddr) -> (u128, u32) {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => (u32::from(ip) as u128, 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    }
+}
+
+impl ProxyRange {
+    fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (value.parse().ok()?, None),
+        };
+        let (_, width) = ip_bits(addr);
+        let prefix = prefix.unwrap_or(width);
+        (prefix <= width).then_some(ProxyRange { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let ((net, width), (ip, ip_width)) = (ip_bits(self.addr), ip_bits(ip));
+        let shift = width - self.prefix;
+        width == ip_width
+            && net.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+fn forwarded_client(
+    peer: Option<SocketAddr>,
+    headers: &HeaderMap,
+    trusted: &[ProxyRange],
+) -> String {
+    let Some(peer) = peer.map(|p| p.ip().to_canonical()) else {
+        return "unknown".to_string();
+    };
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|r| r.contains(ip));
+    if !is_trusted(peer) {
+        return peer.to_string();
+    }
+
+    // Clients can prepend anything, so the last address not added by a trusted proxy counts.
+    let forwarded: Vec<IpAddr> = headers
+        .get_all(header::X_FORWARDED_FOR)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_canonical())
+        .collect();
+    forwarded
+        .into_iter()
+        .rev()
+        .find(|ip| !is_trusted(*ip))
+        .unwrap_or(peer)
+        .to_string()
+}
+
+/// Address of the client for rate limiting: the peer, or what a trusted proxy forwarded.
+pub fn client_ip(peer: Option<SocketAddr>, headers: &HeaderMap) -> String {
+    forwarded_client(peer, headers, &TRUSTED_PROXIES)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(forwarded: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::X_FORWARDED_FOR,
+            HeaderValue::from_str(forwarded).unwrap(),
+        );
+        headers
+    }
+
+    fn peer(ip: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip.parse().unwrap(), 443))
+    }
+
+    #[test]
+    fn ignores_forwarded_header_from_untrusted_peers() {
+        let trusted = [ProxyRange::parse("10.0.0.0/8").unwrap()];
+        let headers = headers("1.2.3.4");
+
+        assert_eq!(
+            forwarded_client(peer("203.0.113.9"), &headers, &[]),
+            "203.0.113.9"
+        );
+        assert_eq!(
+            forwarded_client(peer("203.0.113.9"), &headers, &trusted),
+            "203.0.113.9"
+        );
+    }
+
+    #[test]
+    fn takes_last_untrusted_forwarded_address() {
+        let trusted = [
+            ProxyRange::parse("10.0.0.0/8").unwrap(),
+            ProxyRange::parse("192.0.2.1").unwrap(),
+        ];
+        let headers = headers("6.6.6.6, 198.51.100.7, 192.0.2.1");
+
+        assert_eq!(
+            forwarded_client(peer("10.1.2.3"), &headers, &trusted),
+            "198.51.100.7"
+        );
+        assert_eq!(
+            forwarded_client(peer("::ffff:10.1.2.3"), &headers, &trusted),
+            "198.51.100.7"
+        );
+        assert_eq!(
+            forwarded_client(peer("10.1.2.3"), &HeaderMap::new(), &trusted),
+            "10.1.2.3"
+        );
+    }
+
+    #[test]
+    fn parses_proxy_ranges() {
+        assert!(
+            ProxyRange::parse("0.0.0.0/0")
+                .unwrap()
+                .contains("8.8.8.8".parse().unwrap())
+        );
+        assert!(
+            ProxyRange::parse("fd00::/8")
+                .unwrap()
+                .contains("fd12::1".parse().unwrap())
+        );
+        assert!(
+            !ProxyRange::parse("fd00::/8")
+                .unwrap()
+                .contains("10.0.0.1".parse().unwrap())
+        );
+        assert!(ProxyRange::parse("10.0.0.0/33").is_none());
+        assert!(ProxyRange::parse("proxy").is_none());
+    }
+}
@@ -0,0 +1,251 @@
+use actix_web::web;
+use futures::TryStreamExt;
+use mongodb::{
+    Database,
+    bson::{Bson, Document, doc, oid::ObjectId},
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    models::user::User,
+    services::{
+        cluster::number,
+        impact::{Impact, Region},
+        litter::{LitterError, LitterFilter, LitterScope, filter_collation, litter_stages},
+    },
+};
+
+/// Longest breakdown returned, the rest is summed up in `other_items` and `other_weight`.
+pub const MAX_BREAKDOWN: usize = 50;
+
+/// Length of the buckets of the time series, weeks start on Monday.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Interval {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl Interval {
+    fn unit(&self) -> &'static str {
+        match self {
+            Interval::Day => "day",
+            Interval::Week => "week",
+            Interval::Month => "month",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BreakdownEntry {
+    /// Category, material or brand, `null` for items the analysis could not tell
+    pub key: Option<String>,
+    pub items: u64,
+    /// Estimated weight in grams
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct Breakdown {
+    /// Largest groups first
+    pub entries: Vec<BreakdownEntry>,
+    /// Items of the groups beyond the first 50
+    pub other_items: u64,
+    pub other_weight: f64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TimeBucket {
+    /// Start of the bucket (UTC)
+    pub start: String,
+    pub reports: u64,
+    pub items: u64,
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct LitterStats {
+    pub reports: u64,
+    /// Detected pieces of litter over all reports
+    pub items: u64,
+    /// Estimated weight in grams
+    pub total_weight: f64,
+    pub categories: Breakdown,
+    pub materials: Breakdown,
+    pub brands: Breakdown,
+    /// Buckets without reports are left out
+    pub series: Vec<TimeBucket>,
+    pub impact: Impact,
+}
+
+/// Facet grouping the entries by `field`: the largest `MAX_BREAKDOWN` groups ranked `_id` 1
+/// and up with their `key`, then a single group `_id: MAX_BREAKDOWN + 1` summing the rest.
+pub(crate) fn breakdown_facet(field: &str) -> Vec<Document> {
+    let other = MAX_BREAKDOWN as i64 + 1;
+    vec![
+        doc! { "$unwind": "$entries" },
+        doc! { "$group": {
+            "_id": format!("$entries.{field}"),
+            "items": { "$sum": 1 },
+            "weight": { "$sum": "$entries.weight" },
+        } },
+        doc! { "$setWindowFields": {
+            "sortBy": { "items": -1, "_id": 1 },
+            "output": { "rank": { "$documentNumber": {} } },
+        } },
+        doc! { "$group": {
+            "_id": { "$min": ["$rank", other] },
+            "key": { "$first": "$_id" },
+            "items": { "$sum": "$items" },
+            "weight": { "$sum": "$weight" },
+        } },
+        doc! { "$sort": { "_id": 1 } },
+        doc! { "$limit": other },
+    ]
+}
+
+pub(crate) fn breakdown(groups: Option<&Vec<Bson>>) -> Breakdown {
+    let mut breakdown = Breakdown::default();
+    for group in groups.into_iter().flatten().filter_map(Bson::as_document) {
+        let items = number(group, "items") as u64;
+        let weight = number(group, "weight");
+        if number(group, "_id") as usize <= MAX_BREAKDOWN {
+            breakdown.entries.push(BreakdownEntry {
+                key: group.get_str("key").ok().map(str::to_string),
+                items,
+                weight,
+            });
+        } else {
+            breakdown.other_items += items;
+            breakdown.other_weight += weight;
+        }
+    }
+    breakdown
+}
+
+/// Totals, breakdowns, a time series and the impact in `region` of the reports of `scope` that match `filter`.
+pub async fn litter_stats(
+    db: web::Data<Database>,
+    user_id: ObjectId,
+    scope: LitterScope,
+    filter: &LitterFilter,
+    interval: Interval,
+    region: Region,
+) -> Result<LitterStats, LitterError> {
+    if let Some(area) = &filter.area {
+        let errors = area.validate();
+        if !errors.is_empty() {
+            return Err(LitterError::InvalidInput(errors));
+        }
+    }
+
+    let mut pipeline = litter_stages(user_id, scope, filter);
+    pipeline.extend([
+        doc! { "$project": { "time_stamp": 1, "entries": 1 } },
+        doc! { "$facet": {
+            "totals": [
+                { "$group": {
+                    "_id": null,
+                    "reports": { "$sum": 1 },
+                    "items": { "$sum": { "$size": { "$ifNull": ["$entries", []] } } },
+                    "weight": { "$sum": { "$sum": "$entries.weight" } },
+                } },
+            ],
+            "categories": breakdown_facet("category"),
+            "materials": breakdown_facet("material"),
+            "brands": breakdown_facet("brand"),
+            "impact": [
+                { "$unwind": "$entries" },
+                { "$group": {
+                    "_id": { "category": "$entries.category", "material": "$entries.material" },
+                    "items": { "$sum": 1 },
+                    "weight": { "$sum": "$entries.weight" },
+                } },
+            ],
+            "series": [
+                { "$group": {
+                    "_id": { "$dateTrunc": {
+                        "date": "$time_stamp",
+                        "unit": interval.unit(),
+                        "startOfWeek": "monday",
+                    } },
+                    "reports": { "$sum": 1 },
+                    "items": { "$sum": { "$size": { "$ifNull": ["$entries", []] } } },
+                    "weight": { "$sum": { "$sum": "$entries.weight" } },
+                } },
+                { "$sort": { "_id": 1 } },
+            ],
+        } },
+    ]);
+
+    // Breakdowns group with the filter collation, so "PET" and "pet" count as one.
+    let results: Vec<Document> = db
+        .collection::<User>("users")
+        .aggregate(pipeline)
+        .collation(filter_collation())
+        .await?
+        .try_collect()
+        .await?;
+    let Some(result) = results.first() else {
+        return Ok(LitterStats {
+            impact: region.grouped_impact([]),
+            ..Default::default()
+        });
+    };
+
+    let mut stats = LitterStats {
+        categories: breakdown(result.get_array("categories").ok()),
+        materials: breakdown(result.get_array("materials").ok()),
+        brands: breakdown(result.get_array("brands").ok()),
+        ..Default::default()
+    };
+    if let Some(totals) = result
+        .get_array("totals")
+        .ok()
+        .and_then(|t| t.first())
+        .and_then(Bson::as_document)
+    {
+        stats.reports = number(totals, "reports") as u64;
+        stats.items = number(totals, "items") as u64;
+        stats.total_weight = number(totals, "weight");
+    }
+    stats.series = result
+        .get_array("series")
+        .into_iter()
+        .flatten()
+        .filter_map(Bson::as_document)
+        .filter_map(|bucket| {
+            Some(TimeBucket {
+                start: bucket
+                    .get_datetime("_id")
+                    .ok()?
+                    .try_to_rfc3339_string()
+                    .ok()?,
+                reports: number(bucket, "reports") as u64,
+                items: number(bucket, "items") as u64,
+                weight: number(bucket, "weight"),
+            })
+        })
+        .collect();
+    stats.impact = region.grouped_impact(
+        result
+            .get_array("impact")
+            .into_iter()
+            .flatten()
+            .filter_map(Bson::as_document)
+            .filter_map(|group| {
+                let key = group.get_document("_id").ok()?;
+                Some((
+                    key.get_str("category").ok(),
+                    key.get_str("material").ok(),
+                    number(group, "items") as u64,
+                    number(group, "weight"),
+                ))
+            }),
+    );
+
+    Ok(stats)
+}
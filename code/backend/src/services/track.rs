@@ -0,0 +1,573 @@
+//! GPS tracks of cleanup sessions: GPX and KML files in and out, and simplification
+//! for display. Simplification uses `TRACK_SIMPLIFY_TOLERANCE_M` unless a request
+//! asks for another tolerance.
+
+use std::{env, fmt::Write, sync::LazyLock};
+
+use mongodb::bson::DateTime;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use crate::{
+    models::{
+        cleanup_session::TrackPoint,
+        litter::{Entry, Litter},
+    },
+    services::{
+        cleanup_session::SessionSummary, litter::validate_position, validation::FieldError,
+    },
+};
+
+pub const MAX_TRACK_FILE_BYTES: usize = 10 * 1024 * 1024;
+/// Largest tolerance a request may ask for, in metres.
+pub const MAX_TOLERANCE_M: f64 = 1000.0;
+const METRES_PER_DEGREE: f64 = 6_371_000.0 * std::f64::consts::PI / 180.0;
+
+static DEFAULT_TOLERANCE_M: LazyLock<f64> =
+    LazyLock::new(|| match env::var("TRACK_SIMPLIFY_TOLERANCE_M") {
+        Ok(v) => v
+            .parse()
+            .ok()
+            .filter(|t| (0.0..=MAX_TOLERANCE_M).contains(t))
+            .unwrap_or_else(|| {
+                panic!("TRACK_SIMPLIFY_TOLERANCE_M must be a number from 0 to {MAX_TOLERANCE_M}")
+            }),
+        Err(_) => 5.0,
+    });
+
+/// Reads the configured tolerance, so a broken value stops the server at startup.
+pub fn init() {
+    LazyLock::force(&DEFAULT_TOLERANCE_M);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackFormat {
+    Gpx,
+    Kml,
+}
+
+fn file_error(message: impl Into<String>) -> FieldError {
+    FieldError {
+        field: "file".to_string(),
+        message: message.into(),
+    }
+}
+
+/// Tolerance of a request in metres, the configured one if `None`.
+pub fn tolerance(value: Option<f64>) -> Result<f64, FieldError> {
+    match value {
+        None => Ok(*DEFAULT_TOLERANCE_M),
+        Some(t) if (0.0..=MAX_TOLERANCE_M).contains(&t) => Ok(t),
+        Some(_) => Err(FieldError {
+            field: "tolerance".to_string(),
+            message: format!("must be from 0 to {MAX_TOLERANCE_M} metres"),
+        }),
+    }
+}
+
+/// Distance of `p` from the line through `a` and `b`, all in metres on a local plane.
+fn line_distance(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length_sq = dx * dx + dy * dy;
+    if length_sq == 0.0 {
+        return ((p.0 - a.0).powi(2) + (p.1 - a.1).powi(2)).sqrt();
+    }
+    let t = (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length_sq).clamp(0.0, 1.0);
+    ((p.0 - a.0 - t * dx).powi(2) + (p.1 - a.1 - t * dy).powi(2)).sqrt()
+}
+
+/// Douglas-Peucker simplification of one segment, keeping its first and last point.
+fn simplify_segment(points: &[TrackPoint], tolerance_m: f64) -> Vec<TrackPoint> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+
+    // Equirectangular projection around the segment, exact enough for a walk
+    let scale = points[0].lat.to_radians().cos();
+    let xy: Vec<(f64, f64)> = points
+        .iter()
+        .map(|p| (p.lng * scale * METRES_PER_DEGREE, p.lat * METRES_PER_DEGREE))
+        .collect();
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+    let mut ranges = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = ranges.pop() {
+        let farthest = (first + 1..last)
+            .map(|i| (i, line_distance(xy[i], xy[first], xy[last])))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((i, distance)) = farthest
+            && distance > tolerance_m
+        {
+            keep[i] = true;
+            ranges.push((first, i));
+            ranges.push((i, last));
+        }
+    }
+
+    points
+        .iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|(p, _)| p.clone())
+        .collect()
+}
+
+/// Track sorted by segment, and by time within segments whose points all have one.
+/// Other segments keep the order of the file they came from.
+pub fn ordered(mut track: Vec<TrackPoint>) -> Vec<TrackPoint> {
+    track.sort_by_key(|p| p.segment);
+    for segment in track.chunk_by_mut(|a, b| a.segment == b.segment) {
+        if segment.iter().all(|p| p.time.is_some()) {
+            segment.sort_by_key(|p| p.time);
+        }
+    }
+    track
+}
+
+/// Splits an ordered track into its segments.
+pub fn segments(track: &[TrackPoint]) -> impl Iterator<Item = &[TrackPoint]> {
+    track.chunk_by(|a, b| a.segment == b.segment)
+}
+
+/// Track with points closer than `tolerance_m` to the simplified line left out.
+pub fn simplify(track: &[TrackPoint], tolerance_m: f64) -> Vec<TrackPoint> {
+    if tolerance_m <= 0.0 {
+        return track.to_vec();
+    }
+    segments(track)
+        .flat_map(|segment| simplify_segment(segment, tolerance_m))
+        .collect()
+}
+
+fn point(lat: f64, lng: f64, time: Option<DateTime>) -> Result<TrackPoint, FieldError> {
+    if let Some(e) = validate_position(lat, lng).pop() {
+        return Err(file_error(format!(
+            "has a point whose {} {}",
+            e.field, e.message
+        )));
+    }
+    Ok(TrackPoint {
+        lat,
+        lng,
+        time,
+        segment: 0,
+    })
+}
+
+fn parse_time(node: Option<roxmltree::Node>) -> Option<DateTime> {
+    node.and_then(|n| n.text())
+        .and_then(|t| DateTime::parse_rfc3339_str(t.trim()).ok())
+}
+
+fn child<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &str,
+) -> Option<roxmltree::Node<'a, 'input>> {
+    node.children().find(|c| c.tag_name().name() == name)
+}
+
+fn elements<'a, 'input: 'a>(
+    doc: &'a roxmltree::Document<'input>,
+    name: &'a str,
+) -> impl Iterator<Item = roxmltree::Node<'a, 'input>> + 'a {
+    doc.descendants()
+        .filter(move |n| n.tag_name().name() == name)
+}
+
+/// Track segments of a GPX file, routes and waypoints are ignored.
+fn parse_gpx(doc: &roxmltree::Document) -> Result<Vec<Vec<TrackPoint>>, FieldError> {
+    elements(doc, "trkseg")
+        .map(|segment| {
+            segment
+                .children()
+                .filter(|n| n.tag_name().name() == "trkpt")
+                .map(|p| {
+                    let coordinate = |name: &str| {
+                        p.attribute(name)
+                            .and_then(|v| v.trim().parse::<f64>().ok())
+                            .ok_or_else(|| file_error(format!("has a trkpt without valid {name}")))
+                    };
+                    point(
+                        coordinate("lat")?,
+                        coordinate("lon")?,
+                        parse_time(child(p, "time")),
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// `lng,lat[,alt]` of a `LineString` or, in `gx:coord`, `lng lat [alt]`.
+fn kml_coordinate(value: &str, track: bool) -> Result<(f64, f64), FieldError> {
+    let mut parts: Box<dyn Iterator<Item = &str>> = if track {
+        Box::new(value.split_whitespace())
+    } else {
+        Box::new(value.split(','))
+    };
+    let mut parts = parts.by_ref().map(|p| p.trim().parse::<f64>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(lng)), Some(Ok(lat))) => Ok((lat, lng)),
+        _ => Err(file_error(format!("has an invalid coordinate {value}"))),
+    }
+}
+
+/// Lines of a KML file: `LineString`s without times and `gx:Track`s with them.
+/// Points, such as exported finds, are ignored.
+fn parse_kml(doc: &roxmltree::Document) -> Result<Vec<Vec<TrackPoint>>, FieldError> {
+    let mut segments = vec![];
+
+    for line in elements(doc, "LineString") {
+        let coordinates = child(line, "coordinates")
+            .and_then(|c| c.text())
+            .unwrap_or("");
+        let segment = coordinates
+            .split_whitespace()
+            .map(|c| {
+                let (lat, lng) = kml_coordinate(c, false)?;
+                point(lat, lng, None)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        segments.push(segment);
+    }
+
+    for track in elements(doc, "Track") {
+        let times = track
+            .children()
+            .filter(|n| n.tag_name().name() == "when")
+            .map(|n| parse_time(Some(n)));
+        let coordinates = track
+            .children()
+            .filter(|n| n.tag_name().name() == "coord")
+            .map(|n| kml_coordinate(n.text().unwrap_or(""), true));
+        let segment = coordinates
+            .zip(times.chain(std::iter::repeat(None)))
+            .map(|(coordinate, time)| {
+                let (lat, lng) = coordinate?;
+                point(lat, lng, time)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        segments.push(segment);
+    }
+
+    Ok(segments)
+}
+
+/// Segments of a GPX or KML file, numbered from 0 and without empty ones.
+pub fn parse(format: TrackFormat, data: &[u8]) -> Result<Vec<TrackPoint>, FieldError> {
+    let text = std::str::from_utf8(data).map_err(|_| file_error("must be UTF-8 encoded"))?;
+    let doc = roxmltree::Document::parse(text)
+        .map_err(|e| file_error(format!("is not valid XML: {e}")))?;
+
+    let root = doc.root_element().tag_name().name();
+    let segments = match (format, root) {
+        (TrackFormat::Gpx, "gpx") => parse_gpx(&doc)?,
+        (TrackFormat::Kml, "kml") => parse_kml(&doc)?,
+        (TrackFormat::Gpx, _) => return Err(file_error("must be a GPX file")),
+        (TrackFormat::Kml, _) => return Err(file_error("must be a KML file")),
+    };
+
+    let track: Vec<TrackPoint> = segments
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .enumerate()
+        .flat_map(|(i, segment)| {
+            segment.into_iter().map(move |p| TrackPoint {
+                segment: i as u32,
+                ..p
+            })
+        })
+        .collect();
+    if track.is_empty() {
+        return Err(file_error("contains no track"));
+    }
+    Ok(track)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn rfc3339(time: DateTime) -> String {
+    time.try_to_rfc3339_string().unwrap_or_default()
+}
+
+/// Name and description of a find, e.g. `bottle, can` and `3 items, 45 g`.
+fn find_text(entries: &[Entry]) -> (String, String) {
+    let mut categories: Vec<&str> = entries
+        .iter()
+        .filter_map(|e| e.category.as_deref())
+        .collect();
+    categories.sort_unstable();
+    categories.dedup();
+    let name = if categories.is_empty() {
+        "Litter".to_string()
+    } else {
+        categories.join(", ")
+    };
+
+    let weight: f64 = entries.iter().filter_map(|e| e.weight).sum();
+    let items = match entries.len() {
+        1 => "1 item".to_string(),
+        n => format!("{n} items"),
+    };
+    (name, format!("{items}, {weight:.0} g"))
+}
+
+fn session_name(session: &SessionSummary) -> String {
+    session.name.clone().unwrap_or_else(|| {
+        format!(
+            "Cleanup {}",
+            &session.started_at[..10.min(session.started_at.len())]
+        )
+    })
+}
+
+/// GPX 1.1 file with the finds as waypoints and the track as one `trkseg` per segment.
+pub fn to_gpx(session: &SessionSummary, track: &[TrackPoint], finds: &[Litter]) -> String {
+    let name = escape(&session_name(session));
+    let mut gpx = String::new();
+    let _ = write!(
+        gpx,
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <gpx version=\"1.1\" creator=\"Delitter\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n\
+         <metadata><name>{name}</name><time>{}</time></metadata>\n",
+        session.started_at
+    );
+
+    for find in finds {
+        let (find_name, description) = find_text(&find.entries);
+        let _ = writeln!(
+            gpx,
+            "<wpt lat=\"{}\" lon=\"{}\"><time>{}</time><name>{}</name><desc>{}</desc><type>litter</type></wpt>",
+            find.lat,
+            find.lng,
+            rfc3339(find.time_stamp),
+            escape(&find_name),
+            escape(&description)
+        );
+    }
+
+    let _ = writeln!(gpx, "<trk><name>{name}</name>");
+    for segment in segments(track) {
+        gpx.push_str("<trkseg>\n");
+        for p in segment {
+            let time = p
+                .time
+                .map(|t| format!("<time>{}</time>", rfc3339(t)))
+                .unwrap_or_default();
+            let _ = writeln!(
+                gpx,
+                "<trkpt lat=\"{}\" lon=\"{}\">{time}</trkpt>",
+                p.lat, p.lng
+            );
+        }
+        gpx.push_str("</trkseg>\n");
+    }
+    gpx.push_str("</trk>\n</gpx>\n");
+    gpx
+}
+
+/// KML 2.2 file with the track as one `LineString` per segment and a folder of finds.
+pub fn to_kml(session: &SessionSummary, track: &[TrackPoint], finds: &[Litter]) -> String {
+    let name = escape(&session_name(session));
+    let mut kml = String::new();
+    let _ = write!(
+        kml,
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <kml xmlns=\"http://www.opengis.net/kml/2.2\">\n<Document><name>{name}</name>\n\
+         <Placemark><name>{name}</name><MultiGeometry>\n"
+    );
+    for segment in segments(track) {
+        let coordinates: Vec<String> = segment
+            .iter()
+            .map(|p| format!("{},{}", p.lng, p.lat))
+            .collect();
+        let _ = writeln!(
+            kml,
+            "<LineString><tessellate>1</tessellate><coordinates>{}</coordinates></LineString>",
+            coordinates.join(" ")
+        );
+    }
+    kml.push_str("</MultiGeometry></Placemark>\n<Folder><name>Finds</name>\n");
+
+    for find in finds {
+        let (find_name, description) = find_text(&find.entries);
+        let _ = writeln!(
+            kml,
+            "<Placemark><name>{}</name><description>{}</description><TimeStamp><when>{}</when></TimeStamp><Point><coordinates>{},{}</coordinates></Point></Placemark>",
+            escape(&find_name),
+            escape(&description),
+            rfc3339(find.time_stamp),
+            find.lng,
+            find.lat
+        );
+    }
+    kml.push_str("</Folder>\n</Document>\n</kml>\n");
+    kml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(lat: f64, lng: f64, segment: u32, time: Option<i64>) -> TrackPoint {
+        TrackPoint {
+            lat,
+            lng,
+            time: time.map(DateTime::from_millis),
+            segment,
+        }
+    }
+
+    fn positions(track: &[TrackPoint]) -> Vec<(f64, f64, u32)> {
+        track.iter().map(|p| (p.lat, p.lng, p.segment)).collect()
+    }
+
+    fn session() -> SessionSummary {
+        SessionSummary {
+            id: "0".to_string(),
+            name: Some("Rhine & river".to_string()),
+            owner: "alice".to_string(),
+            participants: vec!["alice".to_string()],
+            invited: vec![],
+            started_at: "2024-05-01T08:00:00Z".to_string(),
+            ended_at: None,
+            active: false,
+            duration_s: 0,
+            distance_m: 0.0,
+            track_points: 0,
+            reports: 0,
+            items: 0,
+            total_weight: 0.0,
+        }
+    }
+
+    #[test]
+    fn simplify_drops_points_near_the_line() {
+        // 0.001° are about 111 m: east with a 1 m wobble, then a corner north
+        let track = vec![
+            at(0.0, 0.0, 0, None),
+            at(0.000009, 0.001, 0, None),
+            at(0.0, 0.002, 0, None),
+            at(0.001, 0.002, 0, None),
+        ];
+        assert_eq!(
+            positions(&simplify(&track, 5.0)),
+            vec![(0.0, 0.0, 0), (0.0, 0.002, 0), (0.001, 0.002, 0)]
+        );
+        // The corner is 99 m from the line between the ends
+        assert_eq!(
+            positions(&simplify(&track, 100.0)),
+            vec![(0.0, 0.0, 0), (0.001, 0.002, 0)]
+        );
+        assert_eq!(simplify(&track, 0.0).len(), 4);
+    }
+
+    #[test]
+    fn simplify_keeps_segment_ends() {
+        let track = vec![
+            at(0.0, 0.0, 0, None),
+            at(0.0, 0.001, 0, None),
+            at(0.0, 0.002, 0, None),
+            at(0.0, 0.003, 1, None),
+            at(0.0, 0.004, 1, None),
+        ];
+        assert_eq!(
+            positions(&simplify(&track, 5.0)),
+            vec![
+                (0.0, 0.0, 0),
+                (0.0, 0.002, 0),
+                (0.0, 0.003, 1),
+                (0.0, 0.004, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn orders_by_segment_and_time() {
+        let track = vec![
+            at(1.0, 0.0, 1, Some(3_000)),
+            at(2.0, 0.0, 0, None),
+            at(3.0, 0.0, 1, Some(1_000)),
+            at(4.0, 0.0, 0, Some(500)),
+            at(5.0, 0.0, 1, Some(2_000)),
+        ];
+        let lats: Vec<f64> = ordered(track).iter().map(|p| p.lat).collect();
+        // Segment 0 has a point without time and keeps the order of arrival
+        assert_eq!(lats, vec![2.0, 4.0, 3.0, 5.0, 1.0]);
+    }
+
+    #[test]
+    fn gpx_round_trip() {
+        let track = vec![
+            at(47.5, 7.5, 0, Some(1_714_550_400_000)),
+            at(47.501, 7.502, 0, Some(1_714_550_460_000)),
+            at(47.51, 7.51, 1, None),
+        ];
+        let gpx = to_gpx(&session(), &track, &[]);
+        assert!(gpx.contains("<name>Rhine &amp; river</name>"));
+
+        let parsed = parse(TrackFormat::Gpx, gpx.as_bytes()).unwrap();
+        assert_eq!(positions(&parsed), positions(&track));
+        let times: Vec<_> = parsed.iter().map(|p| p.time).collect();
+        assert_eq!(times, track.iter().map(|p| p.time).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn kml_round_trip() {
+        let track = vec![
+            at(47.5, 7.5, 0, Some(1_714_550_400_000)),
+            at(47.501, 7.502, 0, None),
+            at(47.51, 7.51, 1, None),
+            at(47.52, 7.52, 1, None),
+        ];
+        let kml = to_kml(&session(), &track, &[]);
+
+        let parsed = parse(TrackFormat::Kml, kml.as_bytes()).unwrap();
+        assert_eq!(positions(&parsed), positions(&track));
+        // Line strings carry no times
+        assert!(parsed.iter().all(|p| p.time.is_none()));
+    }
+
+    #[test]
+    fn parses_kml_tracks_with_times() {
+        let kml = r#"<kml xmlns="http://www.opengis.net/kml/2.2" xmlns:gx="http://www.google.com/kml/ext/2.2">
+            <Placemark><gx:Track>
+                <when>2024-05-01T08:00:00Z</when><when>2024-05-01T08:01:00Z</when>
+                <gx:coord>7.5 47.5 260</gx:coord><gx:coord>7.502 47.501 261</gx:coord>
+            </gx:Track></Placemark></kml>"#;
+        let parsed = parse(TrackFormat::Kml, kml.as_bytes()).unwrap();
+        assert_eq!(positions(&parsed), vec![(47.5, 7.5, 0), (47.501, 7.502, 0)]);
+        assert_eq!(
+            parsed[1].time,
+            Some(DateTime::from_millis(1_714_550_460_000))
+        );
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let gpx = to_gpx(&session(), &[at(47.5, 7.5, 0, None)], &[]);
+        assert_eq!(
+            parse(TrackFormat::Kml, gpx.as_bytes()).unwrap_err().message,
+            "must be a KML file"
+        );
+        assert_eq!(
+            parse(TrackFormat::Gpx, b"<gpx></gpx>").unwrap_err().message,
+            "contains no track"
+        );
+        assert!(
+            parse(
+                TrackFormat::Gpx,
+                b"<gpx><trk><trkseg><trkpt lat=\"91\" lon=\"0\"/></trkseg></trk></gpx>"
+            )
+            .is_err()
+        );
+    }
+}
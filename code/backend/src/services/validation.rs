@@ -0,0 +1,210 @@
+use std::{collections::HashSet, sync::LazyLock};
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+pub const USERNAME_MIN_LEN: usize = 3;
+pub const USERNAME_MAX_LEN: usize = 32;
+pub const PASSWORD_MIN_LEN: usize = 8;
+/// Upper bound so nobody can make us hash megabytes with Argon2.
+pub const PASSWORD_MAX_LEN: usize = 128;
+
+static COMMON_PASSWORDS: LazyLock<HashSet<&'static str>> =
+    LazyLock::new(|| include_str!("common_passwords.txt").lines().collect());
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &str, message: impl Into<String>) -> Self {
+        Self {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Error of a single field, as collected by the services before answering `422`.
+pub fn invalid(field: &str, message: impl Into<String>) -> FieldError {
+    FieldError::new(field, message)
+}
+
+pub fn validate_username(username: &str) -> Vec<FieldError> {
+    let mut errors = vec![];
+    let len = username.chars().count();
+
+    if !(USERNAME_MIN_LEN..=USERNAME_MAX_LEN).contains(&len) {
+        errors.push(FieldError::new(
+            "username",
+            format!("must be between {USERNAME_MIN_LEN} and {USERNAME_MAX_LEN} characters long"),
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        errors.push(FieldError::new(
+            "username",
+            "may only contain letters, digits, '.', '_' and '-'",
+        ));
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        errors.push(FieldError::new(
+            "username",
+            "must start with a letter or digit",
+        ));
+    }
+
+    errors
+}
+
+pub fn validate_password(password: &str, username: &str) -> Vec<FieldError> {
+    let mut errors = vec![];
+    let len = password.chars().count();
+
+    if len < PASSWORD_MIN_LEN {
+        errors.push(FieldError::new(
+            "password",
+            format!("must be at least {PASSWORD_MIN_LEN} characters long"),
+        ));
+    }
+    if len > PASSWORD_MAX_LEN {
+        errors.push(FieldError::new(
+            "password",
+            format!("must be at most {PASSWORD_MAX_LEN} characters long"),
+        ));
+    }
+
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ]
+    .into_iter()
+    .filter(|present| *present)
+    .count();
+    // Long passphrases are fine without mixing character classes.
+    if classes < 2 && len < 16 {
+        errors.push(FieldError::new(
+            "password",
+            "must mix at least two of lowercase, uppercase, digits and symbols, or be at least 16 characters long",
+        ));
+    }
+
+    let lowered = password.to_lowercase();
+    if COMMON_PASSWORDS.contains(lowered.as_str()) {
+        errors.push(FieldError::new("password", "is too common"));
+    }
+    if !username.is_empty() && lowered.contains(&username.to_lowercase()) {
+        errors.push(FieldError::new("password", "must not contain the username"));
+    }
+
+    errors
+}
+
+pub fn validate_signup(username: &str, password: &str) -> Vec<FieldError> {
+    let mut errors = validate_username(username);
+    errors.extend(validate_password(password, username));
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(errors: Vec<FieldError>) -> Vec<String> {
+        errors.into_iter().map(|e| e.message).collect()
+    }
+
+    #[test]
+    fn accepts_usual_usernames() {
+        for username in ["bob", "Anna.Meier", "scout_42", "x-y", &"a".repeat(32)] {
+            assert!(validate_username(username).is_empty(), "{username}");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_usernames() {
+        assert_eq!(
+            messages(validate_username("ab")),
+            ["must be between 3 and 32 characters long"]
+        );
+        assert_eq!(
+            messages(validate_username(&"a".repeat(33))),
+            ["must be between 3 and 32 characters long"]
+        );
+        assert_eq!(
+            messages(validate_username("bob smith")),
+            ["may only contain letters, digits, '.', '_' and '-'"]
+        );
+        assert_eq!(
+            messages(validate_username("zoë")),
+            ["may only contain letters, digits, '.', '_' and '-'"]
+        );
+        assert_eq!(
+            messages(validate_username("_bob")),
+            ["must start with a letter or digit"]
+        );
+        assert_eq!(validate_username("").len(), 2);
+    }
+
+    #[test]
+    fn checks_password_length() {
+        assert_eq!(
+            messages(validate_password("aB3$", "bob")),
+            ["must be at least 8 characters long"]
+        );
+        let long = format!("aB3${}", "x".repeat(PASSWORD_MAX_LEN));
+        assert_eq!(
+            messages(validate_password(&long, "bob")),
+            ["must be at most 128 characters long"]
+        );
+    }
+
+    #[test]
+    fn requires_two_classes_or_a_passphrase() {
+        let mixed = "must mix at least two of lowercase, uppercase, digits and symbols, or be at least 16 characters long";
+        assert_eq!(messages(validate_password("gravelpath", "bob")), [mixed]);
+        assert_eq!(messages(validate_password("84736251940", "bob")), [mixed]);
+        assert!(validate_password("gravelPath", "bob").is_empty());
+        assert!(validate_password("gravel path", "bob").is_empty());
+        assert!(validate_password("riverbankcleanups", "bob").is_empty());
+    }
+
+    #[test]
+    fn rejects_common_passwords() {
+        assert_eq!(
+            messages(validate_password("Password1", "bob")),
+            ["is too common"]
+        );
+        assert_eq!(
+            messages(validate_password("ILOVEYOU", "bob")),
+            [
+                "must mix at least two of lowercase, uppercase, digits and symbols, or be at least 16 characters long",
+                "is too common"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_passwords_containing_the_username() {
+        assert_eq!(
+            messages(validate_password("xAnna.Meier7", "anna.meier")),
+            ["must not contain the username"]
+        );
+        assert!(validate_password("xAnna.Meier7", "").is_empty());
+    }
+
+    #[test]
+    fn collects_signup_errors_of_both_fields() {
+        let fields: Vec<String> = validate_signup("a", "short")
+            .into_iter()
+            .map(|e| e.field)
+            .collect();
+        assert_eq!(fields, ["username", "password", "password"]);
+    }
+}